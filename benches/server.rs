@@ -0,0 +1,65 @@
+use anyhow::Error;
+use criterion::{criterion_group, criterion_main, Criterion};
+use fehler::throws;
+use shs::{Request, Server, TestRequest, TrieRouter};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+#[throws]
+fn hello(req: &mut Request) {
+    req.write_text("hello");
+}
+
+#[throws]
+fn json(req: &mut Request) {
+    req.write_json(&serde_json::json!({"id": req.path_param::<u32>("id")?}))?;
+}
+
+/// Server with a hundred routes, roughly like a mid-sized API.
+fn big_server() -> Server<Error> {
+    let mut server = Server::new("127.0.0.1:0").unwrap();
+    for i in 0..100 {
+        let route = format!("GET /api/v1/resource{}/:id", i);
+        server.route(&route, &json).unwrap();
+    }
+    server.route("GET /hello", &hello).unwrap();
+    server
+}
+
+fn round_trip(c: &mut Criterion) {
+    let mut server: Server<Error> = Server::new("127.0.0.1:0").unwrap();
+    server.route("GET /hello", &hello).unwrap();
+    let running = server.launch_in_background().unwrap();
+    let address = running.local_addr();
+
+    c.bench_function("round_trip_hello", |b| {
+        let mut output = Vec::new();
+        b.iter(|| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream
+                .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            output.clear();
+            stream.read_to_end(&mut output).unwrap();
+        })
+    });
+    running.stop().unwrap();
+}
+
+fn dispatch(c: &mut Criterion) {
+    let req = TestRequest::new("GET /api/v1/resource99/123").unwrap();
+
+    let server = big_server();
+    c.bench_function("dispatch_linear", |b| {
+        b.iter(|| server.test_request(&req).unwrap())
+    });
+
+    let mut server = big_server();
+    server.set_router(TrieRouter::new()).unwrap();
+    c.bench_function("dispatch_trie", |b| {
+        b.iter(|| server.test_request(&req).unwrap())
+    });
+}
+
+criterion_group!(benches, round_trip, dispatch);
+criterion_main!(benches);
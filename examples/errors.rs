@@ -1,5 +1,4 @@
 use fehler::throws;
-use serde::Serialize;
 use shs::{Request, RequestError, Server, StatusCode};
 
 #[derive(Debug, thiserror::Error)]
@@ -8,11 +7,8 @@ enum Error {
     EmptyMessage,
     #[error("message too long: {0}")]
     MessageTooLong(usize),
-}
-
-#[derive(Serialize)]
-struct Resp {
-    name: String,
+    #[error("invalid number: {0}")]
+    InvalidNumber(#[from] std::num::ParseIntError),
 }
 
 fn msg_handler(req: &mut Request) -> Result<(), Error> {
@@ -27,11 +23,22 @@ fn msg_handler(req: &mut Request) -> Result<(), Error> {
     }
 }
 
+// This handler has its own error type, which is converted to the
+// server's error type with `From`
+fn double_handler(req: &mut Request) -> Result<(), std::num::ParseIntError> {
+    let num: i64 = req.path_param::<String>("num").unwrap().parse()?;
+    req.write_text(&(num * 2).to_string());
+    Ok(())
+}
+
 fn error_handler(req: &mut Request, err: &RequestError<Error>) {
     match err {
         RequestError::NotFound => {
             req.set_status(StatusCode::NotFound);
         }
+        RequestError::Panic(_) => {
+            req.set_status(StatusCode::InternalServerError);
+        }
         RequestError::Custom(Error::EmptyMessage) => {
             req.write_text("empty message");
             req.set_status(StatusCode::BadRequest);
@@ -40,6 +47,10 @@ fn error_handler(req: &mut Request, err: &RequestError<Error>) {
             req.write_text(&format!("message too long: {}", len));
             req.set_status(StatusCode::BadRequest);
         }
+        RequestError::Custom(Error::InvalidNumber(err)) => {
+            req.write_text(&format!("invalid number: {}", err));
+            req.set_status(StatusCode::BadRequest);
+        }
     }
 }
 
@@ -49,6 +60,7 @@ fn main() {
 
     let mut server = Server::new("127.0.0.1:1234")?;
     server.route("POST /:message", &msg_handler)?;
+    server.route_with_error("GET /double/:num", &double_handler)?;
     server.set_error_handler(&error_handler);
     server.launch()?;
 }
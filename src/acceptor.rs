@@ -0,0 +1,343 @@
+//! Serving connections from other transports, such as TLS wrappers or
+//! in-memory streams, with [`Server::from_acceptor`].
+
+use crate::body::SendFile;
+use crate::builder::Listen;
+use crate::stream::{Connection, Stream};
+use crate::{Server, ServerBuilder};
+use std::fmt::{self, Debug, Display};
+use std::io::{self, IoSlice, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Source of connections for a server created with
+/// [`Server::from_acceptor`]. Each stream it returns carries one
+/// request and response, like a TCP connection.
+///
+/// The server shares each stream behind a lock between the connection
+/// thread and anything writing the response from elsewhere, as for
+/// streaming responses and handler timeouts, so streams only need to
+/// be `Send`. A stream
+/// can't time out reads by itself, so the read and header timeouts
+/// don't apply; set them on the underlying transport if needed. Since
+/// one handle waiting to read would block the others, `CONNECT`
+/// tunnels aren't supported.
+///
+/// Example usage:
+/// ```
+/// use shs::Acceptor;
+/// use std::io;
+/// use std::net::{TcpListener, TcpStream};
+///
+/// /// Accepts TCP connections, where a TLS handshake could go.
+/// struct Wrapped(TcpListener);
+///
+/// impl Acceptor for Wrapped {
+///     type Stream = TcpStream;
+///
+///     fn accept(&self) -> io::Result<TcpStream> {
+///         let (stream, _addr) = self.0.accept()?;
+///         Ok(stream)
+///     }
+/// }
+/// ```
+pub trait Acceptor: Send + Sync {
+    /// The type of connection accepted.
+    type Stream: Read + Write + Send + 'static;
+
+    /// Wait for the next connection. An error stops the server.
+    fn accept(&self) -> io::Result<Self::Stream>;
+
+    /// Get the address of the client on the other end of `stream`,
+    /// returned by [`Request::peer_addr`] and checked by the IP
+    /// filters and limits. The default is `None`.
+    ///
+    /// [`Request::peer_addr`]: crate::Request::peer_addr
+    fn peer_addr(&self, _stream: &Self::Stream) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Make a call to [`Acceptor::accept`] that's waiting return,
+    /// with a connection or an error, so that the server can stop.
+    /// It's called from another thread when a background server is
+    /// stopped. The default does nothing, in which case the server
+    /// stops once the next connection arrives.
+    fn wake(&self) {}
+}
+
+/// [`Acceptor`] with the stream type erased, so the server can hold
+/// any of them.
+pub(crate) trait AnyAcceptor: Send + Sync {
+    fn accept(&self) -> io::Result<(Connection, Option<SocketAddr>)>;
+
+    fn wake(&self);
+}
+
+impl<A: Acceptor> AnyAcceptor for A {
+    fn accept(&self) -> io::Result<(Connection, Option<SocketAddr>)> {
+        let stream = Acceptor::accept(self)?;
+        let peer_addr = self.peer_addr(&stream);
+        Ok((Box::new(SharedStream::new(stream)), peer_addr))
+    }
+
+    fn wake(&self) {
+        Acceptor::wake(self)
+    }
+}
+
+impl Debug for dyn AnyAcceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Acceptor")
+    }
+}
+
+struct Shared<S> {
+    stream: S,
+    read_closed: bool,
+    write_closed: bool,
+}
+
+/// Handle to a stream from an [`Acceptor`]. Handles are cloned by
+/// sharing the stream behind a lock, and shutting down is emulated by
+/// failing reads and writes through every handle.
+struct SharedStream<S>(Arc<Mutex<Shared<S>>>);
+
+impl<S> SharedStream<S> {
+    fn new(stream: S) -> SharedStream<S> {
+        SharedStream(Arc::new(Mutex::new(Shared {
+            stream,
+            read_closed: false,
+            write_closed: false,
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Shared<S>> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+fn write_closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "connection was shut down")
+}
+
+impl<S: Read> Read for SharedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut shared = self.lock();
+        if shared.read_closed {
+            return Ok(0);
+        }
+        shared.stream.read(buf)
+    }
+}
+
+impl<S: Write> Write for SharedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut shared = self.lock();
+        if shared.write_closed {
+            return Err(write_closed());
+        }
+        shared.stream.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let mut shared = self.lock();
+        if shared.write_closed {
+            return Err(write_closed());
+        }
+        shared.stream.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut shared = self.lock();
+        if shared.write_closed {
+            return Err(write_closed());
+        }
+        shared.stream.flush()
+    }
+}
+
+impl<S: Write> SendFile for SharedStream<S> {}
+
+impl<S: Read + Write + Send + 'static> Stream for SharedStream<S> {
+    fn try_clone(&self) -> io::Result<Connection> {
+        Ok(Box::new(SharedStream(self.0.clone())))
+    }
+
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let mut shared = self.lock();
+        if how != Shutdown::Write {
+            shared.read_closed = true;
+        }
+        if how != Shutdown::Read && !shared.write_closed {
+            shared.write_closed = true;
+            shared.stream.flush()?;
+        }
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    fn close(&self) {
+        let _ = self.lock().stream.flush();
+    }
+
+    fn is_full_duplex(&self) -> bool {
+        false
+    }
+}
+
+impl ServerBuilder {
+    /// Create a builder for a server that gets its connections from
+    /// `acceptor`. See [`Server::from_acceptor`].
+    ///
+    /// The TCP options, such as [`nodelay`] and [`backlog`], have no
+    /// effect.
+    ///
+    /// [`nodelay`]: ServerBuilder::nodelay
+    /// [`backlog`]: ServerBuilder::backlog
+    pub fn from_acceptor(acceptor: impl Acceptor + 'static) -> ServerBuilder {
+        ServerBuilder::with_listen(Ok(Listen::Acceptor(Arc::new(acceptor))))
+    }
+}
+
+impl<E: Debug + Display + 'static> Server<E> {
+    /// Create a Server that gets its connections from `acceptor`
+    /// instead of a TCP listener, for transports that shs doesn't
+    /// provide, such as TLS or in-memory streams.
+    /// [`BackgroundServer::local_addr`] isn't available for such a
+    /// server.
+    ///
+    /// [`BackgroundServer::local_addr`]: crate::BackgroundServer::local_addr
+    pub fn from_acceptor(acceptor: impl Acceptor + 'static) -> Server<E> {
+        ServerBuilder::from_acceptor(acceptor)
+            .build()
+            .expect("building from an acceptor can't fail")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Request;
+    use anyhow::Error;
+    use fehler::throws;
+    use std::io::Cursor;
+    use std::sync::mpsc;
+
+    /// Connection that reads a canned request and hands the response
+    /// back when it's dropped.
+    struct MemoryStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+        done: mpsc::Sender<Vec<u8>>,
+    }
+
+    impl Read for MemoryStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MemoryStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for MemoryStream {
+        fn drop(&mut self) {
+            let _ = self.done.send(std::mem::take(&mut self.output));
+        }
+    }
+
+    /// Hands out queued streams; `None` is queued to wake it.
+    struct MemoryAcceptor {
+        queue: Mutex<mpsc::Receiver<Option<MemoryStream>>>,
+        waker: Mutex<mpsc::Sender<Option<MemoryStream>>>,
+    }
+
+    impl Acceptor for MemoryAcceptor {
+        type Stream = MemoryStream;
+
+        fn accept(&self) -> io::Result<MemoryStream> {
+            match self.queue.lock().unwrap().recv() {
+                Ok(Some(stream)) => Ok(stream),
+                _ => Err(io::Error::other("no more connections")),
+            }
+        }
+
+        fn peer_addr(&self, _stream: &MemoryStream) -> Option<SocketAddr> {
+            Some("10.0.0.1:1234".parse().unwrap())
+        }
+
+        fn wake(&self) {
+            let _ = self.waker.lock().unwrap().send(None);
+        }
+    }
+
+    #[throws]
+    fn peer(req: &mut Request) {
+        req.write_text(&req.peer_addr().unwrap().to_string());
+    }
+
+    #[test]
+    fn test_acceptor() {
+        let (sender, receiver) = mpsc::channel();
+        let acceptor = MemoryAcceptor {
+            queue: Mutex::new(receiver),
+            waker: Mutex::new(sender.clone()),
+        };
+        let mut server: Server<Error> = Server::from_acceptor(acceptor);
+        server.route("GET /peer", &peer).unwrap();
+        let running = server.launch_in_background().unwrap();
+
+        for _ in 0..2 {
+            let (done, response) = mpsc::channel();
+            let input = b"GET /peer HTTP/1.1\r\nHost: localhost\r\n\r\n";
+            sender
+                .send(Some(MemoryStream {
+                    input: Cursor::new(input.to_vec()),
+                    output: Vec::new(),
+                    done,
+                }))
+                .unwrap();
+            let response = String::from_utf8(
+                response.recv_timeout(Duration::from_secs(10)).unwrap(),
+            )
+            .unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.ends_with("\r\n\r\n10.0.0.1:1234"));
+        }
+        // Stopping wakes the acceptor
+        running.stop().unwrap();
+    }
+
+    #[test]
+    fn test_shared_stream_shutdown() {
+        let stream = SharedStream::new(Cursor::new(b"data".to_vec()));
+        let mut clone = stream.try_clone().unwrap();
+        stream.shutdown(Shutdown::Both).unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(clone.read(&mut buf).unwrap(), 0);
+        assert_eq!(
+            clone.write(b"more").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+        assert!(!stream.is_full_duplex());
+    }
+}
@@ -0,0 +1,180 @@
+//! One log line per request, as text or JSON.
+
+use crate::{HeaderName, Request};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Format of the access log enabled with [`Server::set_access_log`].
+/// Lines are logged at info level with the target `shs::access`, so
+/// they can be routed separately from other logs.
+///
+/// [`Server::set_access_log`]: crate::Server::set_access_log
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccessLogFormat {
+    /// Similar to the Common Log Format, with the duration and user
+    /// agent added:
+    ///
+    /// `10.0.0.1 - - [Sun, 06 Nov 1994 08:49:37 GMT] "GET /a HTTP/1.1" 200 5 3ms "curl/8.0"`
+    Text,
+    /// One JSON object per request, with the fields `timestamp`,
+    /// `method`, `path`, `status`, `bytes`, `duration_ms`, `client_ip`,
+    /// `request_id`, and `user_agent`. Missing values are `null`.
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    timestamp: String,
+    method: &'a str,
+    path: String,
+    status: u16,
+    bytes: Option<u64>,
+    duration_ms: f64,
+    client_ip: Option<IpAddr>,
+    request_id: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    #[serde(skip)]
+    time: SystemTime,
+}
+
+impl<'a> Entry<'a> {
+    fn new(
+        req: &'a Request,
+        bytes: Option<u64>,
+        duration: Duration,
+        time: SystemTime,
+    ) -> Entry<'a> {
+        let header = |name: &str| {
+            req.headers()
+                .get(&HeaderName::new(name.into()))
+                .map(String::as_str)
+        };
+        let path = match req.url().query() {
+            Some(query) => format!("{}?{}", req.url().path(), query),
+            None => req.url().path().into(),
+        };
+        Entry {
+            timestamp: rfc3339(time),
+            method: &req.method,
+            path,
+            status: req.status_code(),
+            bytes,
+            duration_ms: duration.as_secs_f64() * 1000.0,
+            client_ip: req.client_ip(),
+            // Middleware may have generated one for the response
+            request_id: req
+                .resp_headers
+                .get("X-Request-Id")
+                .or_else(|| header("X-Request-Id")),
+            user_agent: header("User-Agent"),
+            time,
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let or_dash =
+            |value: Option<String>| value.unwrap_or_else(|| "-".into());
+        format!(
+            "{} - - [{}] \"{} {} HTTP/1.1\" {} {} {}ms \"{}\"",
+            or_dash(self.client_ip.map(|ip| ip.to_string())),
+            httpdate::fmt_http_date(self.time),
+            self.method,
+            self.path,
+            self.status,
+            or_dash(self.bytes.map(|b| b.to_string())),
+            self.duration_ms.round(),
+            self.user_agent.unwrap_or("-").replace('"', "\\\""),
+        )
+    }
+}
+
+/// Format a time as RFC 3339 in UTC with millisecond precision.
+fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Convert days since the epoch to a civil date, from Howard
+    // Hinnant's `civil_from_days`
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Log a finished request. `bytes` is the size of the body sent, if
+/// known.
+pub(crate) fn log(
+    format: AccessLogFormat,
+    req: &Request,
+    bytes: Option<u64>,
+    duration: Duration,
+) {
+    let entry = Entry::new(req, bytes, duration, SystemTime::now());
+    let line = match format {
+        AccessLogFormat::Text => entry.to_text(),
+        // Serializing strings and numbers can't fail
+        AccessLogFormat::Json => {
+            serde_json::to_string(&entry).unwrap_or_default()
+        }
+    };
+    log::info!(target: "shs::access", "{}", line);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_req, StatusCode};
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_millis(951_782_400_250)),
+            "2000-02-29T00:00:00.250Z"
+        );
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_secs(1_767_225_599)),
+            "2025-12-31T23:59:59.000Z"
+        );
+    }
+
+    #[test]
+    fn test_entry() {
+        let mut req = test_req(
+            "GET",
+            "/a?b=c",
+            &[("User-Agent", "curl/8"), ("X-Request-Id", "abc")],
+        );
+        req.client_ip = Some("10.0.0.1".parse().unwrap());
+        req.set_status(StatusCode::NotFound);
+        let entry =
+            Entry::new(&req, Some(5), Duration::from_millis(3), UNIX_EPOCH);
+        assert_eq!(
+            serde_json::to_string(&entry).unwrap(),
+            r#"{"timestamp":"1970-01-01T00:00:00.000Z","method":"GET","path":"/a?b=c","status":404,"bytes":5,"duration_ms":3.0,"client_ip":"10.0.0.1","request_id":"abc","user_agent":"curl/8"}"#
+        );
+        assert_eq!(
+            entry.to_text(),
+            "10.0.0.1 - - [Thu, 01 Jan 1970 00:00:00 GMT] \
+             \"GET /a?b=c HTTP/1.1\" 404 5 3ms \"curl/8\""
+        );
+    }
+}
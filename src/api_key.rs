@@ -0,0 +1,158 @@
+//! Authentication with an API key sent in a header or query parameter.
+
+use crate::{HeaderName, Next, Request, StatusCode};
+
+type Validator = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+/// Identity of the key that authenticated a request, as returned by
+/// the [`ApiKeyAuth`] validator. Stored in the request's extensions, so
+/// logging and rate limiting can tell clients apart.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ApiKeyIdentity(pub String);
+
+/// Middleware requiring an API key, added to a route with
+/// [`RouteHandle::api_key_auth`].
+///
+/// The key is taken from the `X-Api-Key` header by default. Requests
+/// without a key, or with a blank one, get 401, and requests whose key
+/// the validator rejects get 403.
+///
+/// Example usage:
+/// ```
+/// use shs::ApiKeyAuth;
+///
+/// let auth = ApiKeyAuth::new(|key| {
+///     (key == "let-me-in").then(|| "ci-bot".to_string())
+/// })
+/// .query_param("api_key");
+/// ```
+///
+/// [`RouteHandle::api_key_auth`]: crate::RouteHandle::api_key_auth
+pub struct ApiKeyAuth {
+    header: Option<String>,
+    query_param: Option<String>,
+    validator: Box<Validator>,
+}
+
+impl ApiKeyAuth {
+    /// Create API key authentication. `validator` returns an identity
+    /// for a valid key, or `None` to reject it.
+    pub fn new(
+        validator: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) -> ApiKeyAuth {
+        ApiKeyAuth {
+            header: Some("X-Api-Key".into()),
+            query_param: None,
+            validator: Box::new(validator),
+        }
+    }
+
+    /// Set the header the key is read from, or `None` to not read it
+    /// from a header.
+    pub fn header(mut self, name: Option<&str>) -> ApiKeyAuth {
+        self.header = name.map(Into::into);
+        self
+    }
+
+    /// Also accept the key in this query parameter. The header is
+    /// checked first. Keys in URLs tend to end up in logs, so prefer
+    /// the header where clients allow it.
+    pub fn query_param(mut self, name: &str) -> ApiKeyAuth {
+        self.query_param = Some(name.into());
+        self
+    }
+
+    /// Get the key from the header, or else the query parameter. An
+    /// empty or all-whitespace value is treated as absent.
+    fn key(&self, req: &Request) -> Option<String> {
+        let present = |key: &String| !key.trim().is_empty();
+        let from_header = self.header.as_ref().and_then(|name| {
+            req.headers()
+                .get(&HeaderName::new(name.clone()))
+                .filter(|key| present(key))
+                .cloned()
+        });
+        from_header.or_else(|| {
+            let param = self.query_param.as_ref()?;
+            req.url()
+                .query_pairs()
+                .find(|(name, _)| name == param)
+                .map(|(_, value)| value.into_owned())
+                .filter(present)
+        })
+    }
+
+    /// Check the request's key, continuing with `next` if it's valid.
+    pub fn run<E>(
+        &self,
+        req: &mut Request,
+        next: Next<'_, E>,
+    ) -> Result<(), E> {
+        let key = match self.key(req) {
+            Some(key) => key,
+            None => {
+                req.set_status(StatusCode::Unauthorized);
+                return Ok(());
+            }
+        };
+        match (self.validator)(&key) {
+            Some(identity) => {
+                req.extensions_mut().insert(ApiKeyIdentity(identity));
+                next.run(req)
+            }
+            None => {
+                log::warn!(
+                    "rejecting invalid API key for {}",
+                    req.url().path()
+                );
+                req.set_status(StatusCode::Forbidden);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Server, TestRequest};
+    use anyhow::Error;
+    use fehler::throws;
+
+    #[test]
+    fn test_api_key_auth() {
+        #[throws]
+        fn whoami(req: &mut Request) {
+            let identity = req.extensions().get::<ApiKeyIdentity>().unwrap();
+            let identity = identity.0.clone();
+            req.write_text(&identity);
+        }
+
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /whoami", &whoami).unwrap().api_key_auth(
+            ApiKeyAuth::new(|key| (key == "k1").then(|| "bot".into()))
+                .query_param("key"),
+        );
+
+        let get = |path: &str, key: Option<&str>| {
+            let mut req = TestRequest::new(&format!("GET {}", path)).unwrap();
+            if let Some(key) = key {
+                req.set_header("X-Api-Key", key);
+            }
+            server.test_request(&req).unwrap()
+        };
+        assert_eq!(get("/whoami", None).status, StatusCode::Unauthorized);
+        assert_eq!(get("/whoami", Some("nope")).status, StatusCode::Forbidden);
+        let resp = get("/whoami", Some("k1"));
+        assert_eq!(resp.status, StatusCode::Ok);
+        assert_eq!(resp.body, b"bot");
+        assert_eq!(get("/whoami?key=k1", None).status, StatusCode::Ok);
+        assert_eq!(get("/whoami?key=k2", None).status, StatusCode::Forbidden);
+
+        // Blank keys are treated as absent
+        assert_eq!(get("/whoami", Some("")).status, StatusCode::Unauthorized);
+        assert_eq!(get("/whoami", Some(" ")).status, StatusCode::Unauthorized);
+        assert_eq!(get("/whoami?key=+", None).status, StatusCode::Unauthorized);
+        assert_eq!(get("/whoami?key=k1", Some(" ")).status, StatusCode::Ok);
+    }
+}
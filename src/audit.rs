@@ -0,0 +1,170 @@
+//! Copies of each request and response, passed to an [`AuditSink`].
+
+use crate::{HeaderName, Request, StatusCode};
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+/// Number of records that can wait for the sink before new ones are
+/// dropped.
+const QUEUE_SIZE: usize = 1024;
+
+/// A completed request and its response, passed to an [`AuditSink`].
+/// Bodies are truncated to the limit given to
+/// [`Server::set_audit_sink`].
+///
+/// [`Server::set_audit_sink`]: crate::Server::set_audit_sink
+#[derive(Clone, Debug)]
+pub struct AuditRecord {
+    /// Request method.
+    pub method: String,
+
+    /// Request URL.
+    pub url: Url,
+
+    /// Address of the peer, if known.
+    pub peer_addr: Option<SocketAddr>,
+
+    /// Request headers.
+    pub request_headers: HashMap<HeaderName, String>,
+
+    /// Start of the request body, as received.
+    pub request_body: Vec<u8>,
+
+    /// Full length of the request body.
+    pub request_body_len: u64,
+
+    /// Response code.
+    pub status: StatusCode,
+
+    /// Response headers, not including `Content-Length` or
+    /// `Transfer-Encoding`.
+    pub response_headers: HashMap<HeaderName, String>,
+
+    /// Values of the `Set-Cookie` headers.
+    pub response_cookies: Vec<String>,
+
+    /// Start of the response body, before compression.
+    pub response_body: Vec<u8>,
+
+    /// Full length of the response body, or `None` if the handler
+    /// streamed it.
+    pub response_body_len: Option<u64>,
+
+    /// Time from reading the request head to having the response
+    /// ready to send.
+    pub duration: Duration,
+}
+
+/// Receiver of [`AuditRecord`]s, registered with
+/// [`Server::set_audit_sink`]. It's called on a dedicated thread, so
+/// a slow sink doesn't delay responses; if it falls too far behind,
+/// records are dropped with a warning.
+///
+/// This is implemented for closures.
+///
+/// [`Server::set_audit_sink`]: crate::Server::set_audit_sink
+pub trait AuditSink: Send + 'static {
+    /// Handle one record.
+    fn record(&mut self, record: AuditRecord);
+}
+
+impl<F: FnMut(AuditRecord) + Send + 'static> AuditSink for F {
+    fn record(&mut self, record: AuditRecord) {
+        self(record)
+    }
+}
+
+/// Sending side of the audit thread.
+#[derive(Clone)]
+pub(crate) struct Auditor {
+    sender: SyncSender<AuditRecord>,
+    body_limit: usize,
+}
+
+impl Auditor {
+    /// Spawn a thread that passes records to `sink`. The thread exits
+    /// once every clone of the auditor is dropped.
+    pub(crate) fn spawn(
+        mut sink: impl AuditSink,
+        body_limit: usize,
+    ) -> Auditor {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::Builder::new()
+            .name("shs-audit".into())
+            .spawn(move || {
+                for record in receiver {
+                    sink.record(record);
+                }
+            })
+            .expect("failed to spawn audit thread");
+        Auditor { sender, body_limit }
+    }
+
+    /// Copy the request half of the record. This is done before the
+    /// handler runs, since it may take the body.
+    pub(crate) fn start(&self, req: &Request) -> AuditRecord {
+        let mut request_body = Vec::new();
+        if let Ok(reader) = req.req_body.reader() {
+            let _ = reader
+                .take(self.body_limit as u64)
+                .read_to_end(&mut request_body);
+        }
+        AuditRecord {
+            method: req.method.clone(),
+            url: req.url.clone(),
+            peer_addr: req.peer_addr,
+            request_headers: req.req_headers.clone(),
+            request_body,
+            request_body_len: req.req_body.len().unwrap_or_default(),
+            status: StatusCode::Ok,
+            response_headers: HashMap::new(),
+            response_cookies: Vec::new(),
+            response_body: Vec::new(),
+            response_body_len: None,
+            duration: Duration::default(),
+        }
+    }
+
+    /// Fill in the response half of the record and queue it for the
+    /// sink.
+    pub(crate) fn finish(
+        &self,
+        mut record: AuditRecord,
+        req: &Request,
+        duration: Duration,
+    ) {
+        record.status = req.status;
+        record.response_headers = req
+            .resp_headers
+            .iter()
+            .map(|(name, value)| (HeaderName::new(name.into()), value.into()))
+            .collect();
+        record.response_cookies =
+            req.resp_cookies.iter().map(|c| c.to_string()).collect();
+        if !req.streamed {
+            record.response_body_len = Some(req.resp_body.len());
+            if !req.route_options.no_log_body {
+                record.response_body =
+                    req.resp_body.peek(self.body_limit).unwrap_or_default();
+            }
+        }
+        if req.route_options.no_log_body {
+            // The request body was copied before the route was known
+            record.request_body.clear();
+        }
+        record.duration = duration;
+        match self.sender.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                log::warn!("audit sink is behind, dropping record");
+            }
+            // The sink panicked, which has already been reported
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
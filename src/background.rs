@@ -0,0 +1,61 @@
+use crate::shutdown::Shutdown;
+use anyhow::{anyhow, Error};
+use fehler::throws;
+use log::error;
+use std::net::SocketAddr;
+use std::thread::JoinHandle;
+
+/// A server running on a background thread, returned by
+/// [`Server::launch_in_background`]. Dropping it stops the server; use
+/// [`stop`] instead to find out if the server failed.
+///
+/// [`Server::launch_in_background`]: crate::Server::launch_in_background
+/// [`stop`]: BackgroundServer::stop
+pub struct BackgroundServer {
+    pub(crate) shutdown: Shutdown,
+    pub(crate) local_addr: Option<SocketAddr>,
+    pub(crate) thread: Option<JoinHandle<Result<(), Error>>>,
+    /// Copy of the listener for [`BackgroundServer::hand_over`].
+    #[cfg(unix)]
+    pub(crate) listener: Option<std::net::TcpListener>,
+}
+
+impl BackgroundServer {
+    /// Get the address the server is listening on. This is useful
+    /// when the server was created with port 0 to pick any free
+    /// port.
+    ///
+    /// Panics if the server isn't listening on a TCP address, as for
+    /// a named pipe or an [`Acceptor`].
+    ///
+    /// [`Acceptor`]: crate::Acceptor
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+            .expect("the server isn't listening on a TCP address")
+    }
+
+    /// Stop accepting connections, wait for the requests in progress
+    /// to finish, and return the server's result.
+    #[throws]
+    pub fn stop(mut self) {
+        self.stop_and_join()?
+    }
+
+    #[throws]
+    fn stop_and_join(&mut self) {
+        self.shutdown.trigger();
+        if let Some(thread) = self.thread.take() {
+            thread
+                .join()
+                .map_err(|_| anyhow!("server thread panicked"))??;
+        }
+    }
+}
+
+impl Drop for BackgroundServer {
+    fn drop(&mut self) {
+        if let Err(err) = self.stop_and_join() {
+            error!("background server failed: {}", err);
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use fehler::{throw, throws};
+use std::fs::File;
+use std::io::{self, IoSlice, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+
+/// Bodies up to this size are copied into the same buffer as the
+/// response head so that both go out in one write. Larger bodies are
+/// sent with a vectored write instead, to avoid the copy.
+const COALESCE_LIMIT: usize = 16 * 1024;
+
+/// Destination for a response body that may be able to copy files
+/// without passing the data through user space.
+pub(crate) trait SendFile: Write {
+    /// Write `len` bytes from the current position of `file`. Fails
+    /// if the file ends first, since `len` has already been sent as
+    /// the `Content-Length`.
+    fn send_file(&mut self, file: File, len: u64) -> io::Result<()> {
+        if io::copy(&mut file.take(len), self)? != len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file is shorter than expected",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// On Linux, `io::copy` from a file to a `TcpStream` already uses
+/// `sendfile` or `splice` internally, so the default implementation is
+/// used there. On macOS `sendfile` is called directly. Elsewhere the
+/// data is copied through a buffer.
+impl SendFile for TcpStream {
+    #[cfg(target_os = "macos")]
+    fn send_file(&mut self, file: File, len: u64) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut offset = (&file).stream_position()?;
+        let end = offset + len;
+        while offset < end {
+            let mut sent = (end - offset) as libc::off_t;
+            // Safety: both descriptors are valid for the duration of
+            // the call, and `sent` is a valid pointer.
+            let ret = unsafe {
+                libc::sendfile(
+                    file.as_raw_fd(),
+                    self.as_raw_fd(),
+                    offset as libc::off_t,
+                    &mut sent,
+                    std::ptr::null_mut(),
+                    0,
+                )
+            };
+            if ret == -1 {
+                let err = io::Error::last_os_error();
+                // On these errors `sent` holds the partial progress. A
+                // write timeout is also reported as WouldBlock, but
+                // with nothing sent.
+                let retry = match err.kind() {
+                    io::ErrorKind::Interrupted => true,
+                    io::ErrorKind::WouldBlock => sent > 0,
+                    _ => false,
+                };
+                if !retry {
+                    return Err(err);
+                }
+            } else if sent == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file is shorter than expected",
+                ));
+            }
+            offset += sent as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Write every buffer, retrying partial writes.
+#[throws(io::Error)]
+fn write_all_vectored(out: &mut impl Write, mut bufs: &mut [IoSlice<'_>]) {
+    while !bufs.is_empty() {
+        match out.write_vectored(bufs) {
+            Ok(0) => {
+                throw!(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole response"
+                ))
+            }
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => throw!(err),
+        }
+    }
+}
+
+/// Response body. Files are kept open and copied to the connection
+/// when the response is written, rather than read into memory.
+pub(crate) enum ResponseBody {
+    Bytes(Vec<u8>),
+    File { file: File, len: u64 },
+}
+
+impl Default for ResponseBody {
+    fn default() -> ResponseBody {
+        ResponseBody::Bytes(Vec::new())
+    }
+}
+
+impl ResponseBody {
+    /// Length of the body in bytes.
+    pub(crate) fn len(&self) -> u64 {
+        match self {
+            ResponseBody::Bytes(bytes) => bytes.len() as u64,
+            ResponseBody::File { len, .. } => *len,
+        }
+    }
+
+    /// Copy up to `limit` bytes from the start of the body, leaving
+    /// it ready to be written.
+    #[throws(io::Error)]
+    pub(crate) fn peek(&self, limit: usize) -> Vec<u8> {
+        match self {
+            ResponseBody::Bytes(bytes) => {
+                bytes[..bytes.len().min(limit)].to_vec()
+            }
+            ResponseBody::File { file, .. } => {
+                let mut file: &File = file;
+                let pos = file.stream_position()?;
+                let mut bytes = Vec::new();
+                let result =
+                    (&mut file).take(limit as u64).read_to_end(&mut bytes);
+                file.seek(SeekFrom::Start(pos))?;
+                result?;
+                bytes
+            }
+        }
+    }
+
+    /// Write `head`, the already serialized status line and headers,
+    /// followed by the body, using as few writes as possible.
+    #[throws(io::Error)]
+    pub(crate) fn write_with_head(
+        self,
+        mut head: Vec<u8>,
+        out: &mut impl SendFile,
+    ) {
+        match self {
+            ResponseBody::Bytes(bytes) if bytes.len() <= COALESCE_LIMIT => {
+                head.extend_from_slice(&bytes);
+                out.write_all(&head)?;
+            }
+            ResponseBody::Bytes(bytes) => write_all_vectored(
+                out,
+                &mut [IoSlice::new(&head), IoSlice::new(&bytes)],
+            )?,
+            ResponseBody::File { file, len } => {
+                out.write_all(&head)?;
+                out.send_file(file, len)?;
+            }
+        }
+        out.flush()?;
+    }
+
+    /// Read the whole body into memory.
+    #[throws(io::Error)]
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        match self {
+            ResponseBody::Bytes(bytes) => bytes,
+            ResponseBody::File { file, len } => {
+                let mut bytes = Vec::with_capacity(len as usize);
+                file.take(len).read_to_end(&mut bytes)?;
+                bytes
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writer that records the size of each write call, accepting at
+    /// most `max` bytes per call.
+    struct Recorder {
+        writes: Vec<usize>,
+        out: Vec<u8>,
+        max: usize,
+    }
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(
+            &mut self,
+            bufs: &[IoSlice<'_>],
+        ) -> io::Result<usize> {
+            let mut n = 0;
+            for buf in bufs {
+                let take = buf.len().min(self.max - n);
+                self.out.extend_from_slice(&buf[..take]);
+                n += take;
+            }
+            self.writes.push(n);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SendFile for Recorder {}
+
+    fn write(body: Vec<u8>, max: usize) -> Recorder {
+        let mut recorder = Recorder {
+            writes: Vec::new(),
+            out: Vec::new(),
+            max,
+        };
+        ResponseBody::Bytes(body)
+            .write_with_head(b"head".to_vec(), &mut recorder)
+            .unwrap();
+        recorder
+    }
+
+    #[test]
+    fn test_write_with_head() {
+        let recorder = write(b"body".to_vec(), usize::MAX);
+        assert_eq!(recorder.writes, [8]);
+        assert_eq!(recorder.out, b"headbody");
+
+        let large = vec![b'x'; COALESCE_LIMIT + 1];
+        let recorder = write(large.clone(), usize::MAX);
+        assert_eq!(recorder.writes, [large.len() + 4]);
+
+        // Partial writes are continued
+        let recorder = write(large.clone(), 1000);
+        assert_eq!(recorder.out.len(), large.len() + 4);
+        assert!(recorder.out.starts_with(b"headxxx"));
+    }
+
+    #[test]
+    fn test_peek() {
+        let body = ResponseBody::Bytes(b"body".to_vec());
+        assert_eq!(body.peek(2).unwrap(), b"bo");
+        assert_eq!(body.peek(10).unwrap(), b"body");
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"file body").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let body = ResponseBody::File { file, len: 9 };
+        assert_eq!(body.peek(4).unwrap(), b"file");
+        assert_eq!(body.into_bytes().unwrap(), b"file body");
+    }
+
+    #[test]
+    fn test_send_file_short() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"short").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut recorder = Recorder {
+            writes: Vec::new(),
+            out: Vec::new(),
+            max: usize::MAX,
+        };
+        let err = ResponseBody::File { file, len: 10 }
+            .write_with_head(b"head".to_vec(), &mut recorder)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(recorder.out, b"headshort");
+    }
+
+    #[test]
+    fn test_send_file() {
+        use std::io::Seek;
+        use std::net::TcpListener;
+
+        let mut file = tempfile::tempfile().unwrap();
+        let data = vec![b'x'; 100_000];
+        file.write_all(&data).unwrap();
+        file.write_all(b"extra").unwrap();
+        file.seek(io::SeekFrom::Start(0)).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _addr) = listener.accept().unwrap();
+        let sender = std::thread::spawn(move || {
+            let body = ResponseBody::File {
+                file,
+                len: data.len() as u64,
+            };
+            body.write_with_head(b"head".to_vec(), &mut stream).unwrap();
+        });
+        let mut output = Vec::new();
+        client.read_to_end(&mut output).unwrap();
+        sender.join().unwrap();
+        assert_eq!(output.len(), 100_004);
+        assert!(output.starts_with(b"headxxx"));
+        assert!(output.ends_with(b"xxx"));
+    }
+}
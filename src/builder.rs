@@ -0,0 +1,556 @@
+use crate::acceptor::AnyAcceptor;
+use crate::ip_filter::IpFilter;
+use crate::ip_limit::{IpLimits, IpTracker};
+use crate::load::{ConnectionCount, LoadOptions};
+use crate::route::RouteTable;
+use crate::stream::Listener;
+use crate::{default_error_handler, Server, Settings};
+use anyhow::Error;
+use fehler::{throw, throws};
+use socket2::{Domain, Socket, Type};
+use std::fmt::{Debug, Display};
+use std::io;
+use std::net::{AddrParseError, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Options applied to the listening socket and to accepted streams.
+#[derive(Clone, Debug)]
+pub(crate) struct SocketOptions {
+    nodelay: bool,
+    reuse_address: bool,
+    reuse_port: bool,
+    backlog: i32,
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions {
+        // Match what std's TcpListener::bind does.
+        SocketOptions {
+            nodelay: false,
+            reuse_address: !cfg!(windows),
+            reuse_port: false,
+            backlog: 128,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Create a listener bound to `address`.
+    #[throws]
+    pub(crate) fn bind(&self, address: SocketAddr) -> TcpListener {
+        let socket =
+            Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+        socket.set_reuse_address(self.reuse_address)?;
+        #[cfg(all(
+            unix,
+            not(any(target_os = "solaris", target_os = "illumos"))
+        ))]
+        socket.set_reuse_port(self.reuse_port)?;
+        socket.bind(&address.into())?;
+        socket.listen(self.backlog)?;
+        socket.into()
+    }
+
+    /// Apply per-stream options to an accepted connection.
+    #[throws]
+    pub(crate) fn configure_stream(&self, stream: &TcpStream) {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+    }
+}
+
+/// Where the server gets connections from.
+pub(crate) enum Listen {
+    /// Bind a new listener to the address when the server launches.
+    Address(SocketAddr),
+
+    /// Use a listener that is already bound.
+    Listener(TcpListener),
+
+    /// Create a named pipe with this name when the server launches.
+    #[cfg(windows)]
+    NamedPipe(String),
+
+    /// Get connections from an [`Acceptor`](crate::Acceptor).
+    Acceptor(Arc<dyn AnyAcceptor>),
+}
+
+impl Listen {
+    /// Get the listener, binding it if necessary.
+    #[throws]
+    pub(crate) fn into_listener(self, options: &SocketOptions) -> Listener {
+        match self {
+            Listen::Address(address) => Listener::Tcp(options.bind(address)?),
+            Listen::Listener(listener) => Listener::Tcp(listener),
+            #[cfg(windows)]
+            Listen::NamedPipe(name) => Listener::NamedPipe(
+                crate::named_pipe::PipeListener::bind(&name)?,
+            ),
+            Listen::Acceptor(acceptor) => Listener::Acceptor(acceptor),
+        }
+    }
+}
+
+/// Options for the threads that handle connections.
+#[derive(Clone)]
+pub(crate) struct ThreadOptions {
+    name_prefix: String,
+    stack_size: Option<usize>,
+    init: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+impl Default for ThreadOptions {
+    fn default() -> ThreadOptions {
+        ThreadOptions {
+            name_prefix: "shs-handler".into(),
+            stack_size: None,
+            init: None,
+        }
+    }
+}
+
+/// Invalid configuration, reported by [`ServerBuilder::build`].
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    /// The address to listen on couldn't be parsed.
+    #[error("invalid address: {0}")]
+    InvalidAddress(#[from] AddrParseError),
+
+    /// A setting has a value that can't work, such as a limit of zero.
+    #[error("invalid {name}: {reason}")]
+    InvalidValue {
+        /// Name of the builder method that set the value.
+        name: &'static str,
+        /// Why the value is invalid.
+        reason: &'static str,
+    },
+}
+
+impl ThreadOptions {
+    /// Spawn a thread that runs the init hook, if any, followed by `f`.
+    /// The thread is named with the prefix and `id`.
+    pub(crate) fn spawn(
+        &self,
+        id: usize,
+        f: impl FnOnce() + Send + 'static,
+    ) -> io::Result<JoinHandle<()>> {
+        let mut builder =
+            thread::Builder::new().name(format!("{}-{}", self.name_prefix, id));
+        if let Some(stack_size) = self.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        let init = self.init.clone();
+        builder.spawn(move || {
+            if let Some(init) = init {
+                init();
+            }
+            f();
+        })
+    }
+}
+
+/// Builder for a [`Server`] with non-default settings, created with
+/// [`Server::builder`] or [`ServerBuilder::new`]. Settings are checked
+/// by [`ServerBuilder::build`], so mistakes are reported before the
+/// server binds or launches.
+///
+/// Example usage:
+/// ```no_run
+/// use anyhow::Error;
+/// use shs::{Server, ServerBuilder};
+/// use std::time::Duration;
+///
+/// let server: Server<Error> = ServerBuilder::new("127.0.0.1:1234")
+///     .nodelay(true)
+///     .backlog(1024)
+///     .max_connections(Some(256))
+///     .read_timeout(Some(Duration::from_secs(10)))
+///     .max_header_bytes(16 * 1024)
+///     .build()?;
+/// # Ok::<(), Error>(())
+/// ```
+pub struct ServerBuilder {
+    listen: Result<Listen, AddrParseError>,
+    socket_options: SocketOptions,
+    thread_options: ThreadOptions,
+    load_options: LoadOptions,
+    ip_limits: Option<IpLimits>,
+    settings: Settings,
+    /// Set by [`ServerBuilder::inherited`].
+    #[cfg(unix)]
+    pub(crate) handover_ready: Option<std::os::unix::net::UnixStream>,
+}
+
+impl ServerBuilder {
+    /// Create a builder for a server that will listen on `address`.
+    pub fn new(address: &str) -> ServerBuilder {
+        ServerBuilder::with_listen(address.parse().map(Listen::Address))
+    }
+
+    /// Create a builder for a server that accepts connections from an
+    /// existing listener. See [`Server::from_listener`].
+    ///
+    /// The listener is used as is, so the options that apply to
+    /// binding ([`reuse_address`], [`reuse_port`], and [`backlog`])
+    /// have no effect.
+    ///
+    /// [`reuse_address`]: ServerBuilder::reuse_address
+    /// [`reuse_port`]: ServerBuilder::reuse_port
+    /// [`backlog`]: ServerBuilder::backlog
+    pub fn from_listener(listener: TcpListener) -> ServerBuilder {
+        ServerBuilder::with_listen(Ok(Listen::Listener(listener)))
+    }
+
+    pub(crate) fn with_listen(
+        listen: Result<Listen, AddrParseError>,
+    ) -> ServerBuilder {
+        ServerBuilder {
+            listen,
+            socket_options: SocketOptions::default(),
+            thread_options: ThreadOptions::default(),
+            load_options: LoadOptions::default(),
+            ip_limits: None,
+            settings: Settings::default(),
+            #[cfg(unix)]
+            handover_ready: None,
+        }
+    }
+
+    /// Set `TCP_NODELAY` on accepted streams. This disables Nagle's
+    /// algorithm, which reduces latency for small responses. The
+    /// default is `false`.
+    pub fn nodelay(mut self, nodelay: bool) -> ServerBuilder {
+        self.socket_options.nodelay = nodelay;
+        self
+    }
+
+    /// Set `SO_REUSEADDR` on the listening socket. The default is
+    /// `true` on Unix and `false` on Windows, same as
+    /// `std::net::TcpListener`.
+    pub fn reuse_address(mut self, reuse: bool) -> ServerBuilder {
+        self.socket_options.reuse_address = reuse;
+        self
+    }
+
+    /// Set `SO_REUSEPORT` on the listening socket, allowing several
+    /// processes to listen on the same port. Only has an effect on
+    /// Unix. The default is `false`.
+    pub fn reuse_port(mut self, reuse: bool) -> ServerBuilder {
+        self.socket_options.reuse_port = reuse;
+        self
+    }
+
+    /// Set the maximum length of the queue of pending connections. The
+    /// default is 128.
+    pub fn backlog(mut self, backlog: i32) -> ServerBuilder {
+        self.socket_options.backlog = backlog;
+        self
+    }
+
+    /// Set the prefix for the names of connection threads. Each thread
+    /// is named with the prefix followed by a dash and a number that
+    /// increases with each connection, e.g. `shs-handler-3`. The
+    /// default is `"shs-handler"`.
+    pub fn thread_name_prefix(mut self, prefix: &str) -> ServerBuilder {
+        self.thread_options.name_prefix = prefix.into();
+        self
+    }
+
+    /// Set the stack size in bytes for connection threads. The default
+    /// is the same as `std::thread::spawn`.
+    pub fn thread_stack_size(mut self, size: usize) -> ServerBuilder {
+        self.thread_options.stack_size = Some(size);
+        self
+    }
+
+    /// Set a function to call at the start of each connection thread,
+    /// before the request is read. This can be used to set up
+    /// thread-local state, such as a database connection that
+    /// handlers on that thread will use.
+    pub fn thread_init(
+        mut self,
+        init: impl Fn() + Send + Sync + 'static,
+    ) -> ServerBuilder {
+        self.thread_options.init = Some(Arc::new(init));
+        self
+    }
+
+    /// Set a timeout for each read from a connection. If no data
+    /// arrives within the timeout the connection is closed. The
+    /// default is `None`, meaning reads can block indefinitely.
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> ServerBuilder {
+        self.settings.read_timeout = timeout;
+        self
+    }
+
+    /// Set how long a client has to send the complete request line and
+    /// headers. This catches clients that trickle in data slowly
+    /// enough that the [`read_timeout`] never triggers. When it
+    /// expires the client gets a 408 response. The default is 30
+    /// seconds.
+    ///
+    /// [`read_timeout`]: ServerBuilder::read_timeout
+    pub fn header_timeout(
+        mut self,
+        timeout: Option<Duration>,
+    ) -> ServerBuilder {
+        self.settings.header_timeout = timeout;
+        self
+    }
+
+    /// Set a timeout for each write to a connection. If a client stops
+    /// reading the response for this long the connection is closed, so
+    /// it can't hold a worker thread forever. The default is 30
+    /// seconds.
+    pub fn write_timeout(mut self, timeout: Option<Duration>) -> ServerBuilder {
+        self.settings.write_timeout = timeout;
+        self
+    }
+
+    /// Set the maximum number of connections handled at the same
+    /// time, each on its own thread. When the limit is reached, new
+    /// connections wait in the accept queue until one finishes. The
+    /// default is `None`, meaning no limit.
+    pub fn max_connections(mut self, max: Option<usize>) -> ServerBuilder {
+        self.load_options.max_connections = max;
+        self
+    }
+
+    /// Shed load by answering new connections with `503 Service
+    /// Unavailable` and a `Retry-After` of `retry_after` (rounded to
+    /// whole seconds) once `threshold` connections are being handled,
+    /// instead of letting latency grow without bound. The request
+    /// isn't read, so this costs little even under heavy load. The
+    /// default is `None`, meaning connections are never shed.
+    ///
+    /// If [`max_connections`] is also set, it should be higher than
+    /// `threshold`, otherwise new connections wait in the accept queue
+    /// and never reach the point of being shed.
+    ///
+    /// [`max_connections`]: ServerBuilder::max_connections
+    pub fn shed_load(
+        mut self,
+        threshold: Option<usize>,
+        retry_after: Duration,
+    ) -> ServerBuilder {
+        self.load_options.shed_above = threshold;
+        self.load_options.retry_after = retry_after;
+        self
+    }
+
+    /// Limit the connections and requests each client IP can make.
+    /// Clients over a limit are rejected before their request is
+    /// read. The default is no limits.
+    pub fn ip_limits(mut self, limits: IpLimits) -> ServerBuilder {
+        self.ip_limits = Some(limits);
+        self
+    }
+
+    /// Same as [`Server::set_max_request_line_len`].
+    pub fn max_request_line_len(mut self, max: usize) -> ServerBuilder {
+        self.settings.parse_options.max_request_line = max;
+        self
+    }
+
+    /// Same as [`Server::set_max_header_bytes`].
+    pub fn max_header_bytes(mut self, max: usize) -> ServerBuilder {
+        self.settings.parse_options.max_header_bytes = max;
+        self
+    }
+
+    /// Same as [`Server::set_max_header_count`].
+    pub fn max_header_count(mut self, max: usize) -> ServerBuilder {
+        self.settings.parse_options.max_headers = max;
+        self
+    }
+
+    /// Same as [`Server::set_max_decompressed_size`].
+    pub fn max_decompressed_size(mut self, max: usize) -> ServerBuilder {
+        self.settings.max_decompressed_size = max;
+        self
+    }
+
+    /// Same as [`Server::set_max_body_size`].
+    pub fn max_body_size(mut self, max: Option<usize>) -> ServerBuilder {
+        self.settings.max_body_size = max;
+        self
+    }
+
+    /// Same as [`Server::set_spool_threshold`].
+    pub fn spool_threshold(
+        mut self,
+        threshold: Option<usize>,
+    ) -> ServerBuilder {
+        self.settings.spool_threshold = threshold;
+        self
+    }
+
+    /// Same as [`Server::set_strict_parsing`].
+    pub fn strict_parsing(mut self, strict: bool) -> ServerBuilder {
+        self.settings.parse_options.strict = strict;
+        self
+    }
+
+    /// Same as [`Server::set_server_header`].
+    pub fn server_header(mut self, value: Option<&str>) -> ServerBuilder {
+        self.settings.server_header = value.map(Into::into);
+        self
+    }
+
+    #[throws(ConfigError)]
+    fn validate(&self) {
+        let invalid = |name, reason| ConfigError::InvalidValue { name, reason };
+        let positive = "must be greater than zero";
+        if self.socket_options.backlog <= 0 {
+            throw!(invalid("backlog", positive));
+        }
+        if self.load_options.max_connections == Some(0) {
+            throw!(invalid("max_connections", positive));
+        }
+        if self.load_options.shed_above == Some(0) {
+            throw!(invalid("shed_load", positive));
+        }
+        if let Some(limits) = &self.ip_limits {
+            if limits.max_connections == Some(0) {
+                throw!(invalid(
+                    "ip_limits",
+                    "max_connections must be positive"
+                ));
+            }
+            if let Some((max, window)) = limits.max_requests {
+                if max == 0 || window == Duration::from_secs(0) {
+                    throw!(invalid(
+                        "ip_limits",
+                        "max_requests must be positive"
+                    ));
+                }
+            }
+        }
+        if self.thread_options.name_prefix.contains('\0') {
+            throw!(invalid("thread_name_prefix", "must not contain NUL"));
+        }
+        let zero = Some(Duration::from_secs(0));
+        if self.settings.read_timeout == zero {
+            throw!(invalid("read_timeout", "use None to disable"));
+        }
+        if self.settings.header_timeout == zero {
+            throw!(invalid("header_timeout", "use None to disable"));
+        }
+        if self.settings.write_timeout == zero {
+            throw!(invalid("write_timeout", "use None to disable"));
+        }
+        let parse = &self.settings.parse_options;
+        if parse.max_request_line == 0 {
+            throw!(invalid("max_request_line_len", positive));
+        }
+        if parse.max_header_bytes == 0 {
+            throw!(invalid("max_header_bytes", positive));
+        }
+        if parse.max_headers == 0 {
+            throw!(invalid("max_header_count", positive));
+        }
+    }
+
+    /// Create the server. This fails if the address or any setting is
+    /// invalid.
+    #[throws(ConfigError)]
+    pub fn build<E: Debug + Display + 'static>(self) -> Server<E> {
+        self.validate()?;
+        Server {
+            listen: self.listen?,
+            socket_options: self.socket_options,
+            thread_options: self.thread_options,
+            load_options: self.load_options,
+            connections: ConnectionCount::default(),
+            ip_filter: IpFilter::default(),
+            jobs: Vec::new(),
+            #[cfg(unix)]
+            handover_ready: self.handover_ready,
+            ip_tracker: self
+                .ip_limits
+                .map(|limits| Arc::new(IpTracker::new(limits))),
+            routes: RouteTable::default(),
+            error_handler: Arc::new(default_error_handler),
+            settings: self.settings,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::RwLock;
+
+    #[test]
+    fn test_socket_options() {
+        let options = SocketOptions {
+            nodelay: true,
+            reuse_port: true,
+            backlog: 16,
+            ..SocketOptions::default()
+        };
+        let listener = options.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap());
+        let (stream, _addr) = listener.accept().unwrap();
+        options.configure_stream(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+    }
+
+    #[test]
+    fn test_validate() {
+        let build = |builder: ServerBuilder| builder.build::<Error>().err();
+        let builder = || ServerBuilder::new("127.0.0.1:1234");
+        assert!(build(builder()).is_none());
+        assert!(matches!(
+            build(ServerBuilder::new("nope")),
+            Some(ConfigError::InvalidAddress(_))
+        ));
+        for (builder, expected) in [
+            (builder().backlog(0), "backlog"),
+            (builder().max_connections(Some(0)), "max_connections"),
+            (
+                builder().read_timeout(Some(Duration::from_secs(0))),
+                "read_timeout",
+            ),
+            (
+                builder().write_timeout(Some(Duration::from_secs(0))),
+                "write_timeout",
+            ),
+            (builder().max_header_count(0), "max_header_count"),
+        ] {
+            match build(builder) {
+                Some(ConfigError::InvalidValue { name, .. }) => {
+                    assert_eq!(name, expected)
+                }
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_thread_options() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        static INIT: AtomicBool = AtomicBool::new(false);
+        let options = ThreadOptions {
+            name_prefix: "test".into(),
+            stack_size: Some(256 * 1024),
+            init: Some(Arc::new(|| INIT.store(true, Ordering::SeqCst))),
+        };
+        let name = Arc::new(RwLock::new(None));
+        let name2 = name.clone();
+        options
+            .spawn(7, move || {
+                assert!(INIT.load(Ordering::SeqCst));
+                *name2.write().unwrap() =
+                    thread::current().name().map(String::from);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(name.read().unwrap().as_deref(), Some("test-7"));
+    }
+}
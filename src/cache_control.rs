@@ -0,0 +1,160 @@
+use crate::body::ResponseBody;
+use crate::{HeaderName, Request, StatusCode};
+use std::fmt::{self, Display};
+use std::time::{Duration, SystemTime};
+
+/// Typed `Cache-Control` response header value.
+///
+/// Example usage:
+/// ```
+/// use shs::CacheControl;
+/// use std::time::Duration;
+///
+/// let cc = CacheControl::new()
+///     .public()
+///     .max_age(Duration::from_secs(3600))
+///     .immutable();
+/// assert_eq!(cc.to_string(), "public, max-age=3600, immutable");
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CacheControl {
+    visibility: Option<&'static str>,
+    no_store: bool,
+    no_cache: bool,
+    must_revalidate: bool,
+    max_age: Option<Duration>,
+    s_maxage: Option<Duration>,
+    immutable: bool,
+}
+
+impl CacheControl {
+    /// Create an empty `CacheControl`.
+    pub fn new() -> CacheControl {
+        CacheControl::default()
+    }
+
+    /// Allow shared caches (e.g. proxies) to store the response.
+    pub fn public(mut self) -> CacheControl {
+        self.visibility = Some("public");
+        self
+    }
+
+    /// Only allow the client's private cache to store the response.
+    pub fn private(mut self) -> CacheControl {
+        self.visibility = Some("private");
+        self
+    }
+
+    /// Don't store the response in any cache.
+    pub fn no_store(mut self) -> CacheControl {
+        self.no_store = true;
+        self
+    }
+
+    /// Caches must revalidate with the server before each reuse.
+    pub fn no_cache(mut self) -> CacheControl {
+        self.no_cache = true;
+        self
+    }
+
+    /// Caches must revalidate once the response is stale.
+    pub fn must_revalidate(mut self) -> CacheControl {
+        self.must_revalidate = true;
+        self
+    }
+
+    /// How long the response stays fresh. Sub-second precision is
+    /// truncated.
+    pub fn max_age(mut self, age: Duration) -> CacheControl {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// Like `max_age`, but only for shared caches.
+    pub fn s_maxage(mut self, age: Duration) -> CacheControl {
+        self.s_maxage = Some(age);
+        self
+    }
+
+    /// The response will never change while fresh, so clients don't
+    /// need to revalidate it even on reload.
+    pub fn immutable(mut self) -> CacheControl {
+        self.immutable = true;
+        self
+    }
+}
+
+impl Display for CacheControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts: Vec<String> = Vec::new();
+        if let Some(visibility) = self.visibility {
+            parts.push(visibility.into());
+        }
+        if self.no_store {
+            parts.push("no-store".into());
+        }
+        if self.no_cache {
+            parts.push("no-cache".into());
+        }
+        if self.must_revalidate {
+            parts.push("must-revalidate".into());
+        }
+        if let Some(age) = self.max_age {
+            parts.push(format!("max-age={}", age.as_secs()));
+        }
+        if let Some(age) = self.s_maxage {
+            parts.push(format!("s-maxage={}", age.as_secs()));
+        }
+        if self.immutable {
+            parts.push("immutable".into());
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Truncate to whole seconds, the precision of HTTP dates.
+pub(crate) fn to_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Request {
+    /// Set the `Cache-Control` response header.
+    pub fn set_cache_control(&mut self, cache_control: &CacheControl) {
+        self.set_header("Cache-Control", &cache_control.to_string());
+    }
+
+    /// Get the `If-Modified-Since` request header, if present and
+    /// valid.
+    pub fn if_modified_since(&self) -> Option<SystemTime> {
+        self.req_headers
+            .get(&HeaderName::new("If-Modified-Since".into()))
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+    }
+
+    /// Set the `Last-Modified` response header and answer
+    /// `If-Modified-Since`.
+    ///
+    /// If this is a GET or HEAD request whose `If-Modified-Since` is
+    /// not older than `last_modified`, the status is set to 304 (not
+    /// modified), the body is cleared, and `true` is returned; the
+    /// handler should then skip producing the body.
+    pub fn check_last_modified(&mut self, last_modified: SystemTime) -> bool {
+        self.set_header(
+            "Last-Modified",
+            &httpdate::fmt_http_date(last_modified),
+        );
+        if self.method != "GET" && self.method != "HEAD" {
+            return false;
+        }
+        match self.if_modified_since() {
+            Some(since) if to_secs(last_modified) <= to_secs(since) => {
+                self.set_status(StatusCode::NotModified);
+                self.resp_body = ResponseBody::default();
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,826 @@
+//! Running CGI programs and FastCGI applications as handlers, with
+//! [`Server::route_cgi`] and [`Server::route_fastcgi`], so that shs can
+//! front existing scripts.
+//!
+//! Both pass the request to the script as the meta-variables of RFC
+//! 3875, with each request header as an `HTTP_` variable, and take the
+//! response from the script's output: its `Status`, `Location`, and
+//! other header lines, a blank line, then the body.
+
+use crate::body::ResponseBody;
+use crate::cookie::parse_set_cookie;
+use crate::{Request, RouteHandle, Server, StatusCode};
+use anyhow::{anyhow, Context, Error};
+use fehler::{throw, throws};
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fmt::{Debug, Display};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// CGI program run for each request of a route added with
+/// [`Server::route_cgi`].
+///
+/// The program gets the request body on standard input, and what it
+/// writes to standard error is logged. Only `PATH` (and on Windows
+/// `SystemRoot`) is passed on from the server's own environment, so
+/// that the server's secrets don't leak to scripts; add anything else
+/// the program needs with [`Cgi::env`].
+///
+/// Example usage:
+/// ```
+/// use shs::Cgi;
+///
+/// let cgi = Cgi::new("/usr/bin/perl")
+///     .arg("/srv/cgi-bin/guestbook.pl")
+///     .env("GUESTBOOK_DIR", "/srv/guestbook")
+///     .current_dir("/srv/cgi-bin");
+/// ```
+#[derive(Clone, Debug)]
+pub struct Cgi {
+    program: PathBuf,
+    args: Vec<OsString>,
+    env: Vec<(String, String)>,
+    current_dir: Option<PathBuf>,
+}
+
+impl Cgi {
+    /// Run `program` for each request.
+    pub fn new(program: impl Into<PathBuf>) -> Cgi {
+        Cgi {
+            program: program.into(),
+            args: Vec::new(),
+            env: Vec::new(),
+            current_dir: None,
+        }
+    }
+
+    /// Pass an argument to the program, such as the script for an
+    /// interpreter to run.
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Cgi {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Set an environment variable for the program, replacing the
+    /// meta-variable of the same name if there is one.
+    pub fn env(mut self, name: &str, value: &str) -> Cgi {
+        self.env.push((name.into(), value.into()));
+        self
+    }
+
+    /// Run the program in `dir` rather than the server's working
+    /// directory.
+    pub fn current_dir(mut self, dir: impl Into<PathBuf>) -> Cgi {
+        self.current_dir = Some(dir.into());
+        self
+    }
+
+    #[throws]
+    fn run(&self, req: &mut Request, script_segments: Option<usize>) {
+        let vars = meta_variables(req, script_segments, &self.env)?;
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .env_clear()
+            .envs(passed_env())
+            .envs(vars)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(dir) = &self.current_dir {
+            command.current_dir(dir);
+        }
+        let mut child = command.spawn().with_context(|| {
+            format!("failed to run CGI program {}", self.program.display())
+        })?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let mut output = Vec::new();
+        let body = req.spooled_body();
+        // Feed the body and drain standard error alongside reading the
+        // output, so that none of the pipes fills up and blocks
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                // The program may not read the whole body
+                let _ = body
+                    .reader()
+                    .and_then(|mut reader| io::copy(&mut reader, &mut stdin));
+            });
+            scope.spawn(move || {
+                let mut errors = Vec::new();
+                if stderr.read_to_end(&mut errors).is_ok() {
+                    log_stderr(&errors);
+                }
+            });
+            child
+                .stdout
+                .take()
+                .expect("stdout is piped")
+                .read_to_end(&mut output)
+        })?;
+        let status = child.wait()?;
+        if output.is_empty() && !status.success() {
+            throw!(anyhow!(
+                "CGI program {} failed: {}",
+                self.program.display(),
+                status
+            ));
+        }
+        write_response(req, output)?;
+    }
+}
+
+/// The variables passed on from the server's environment.
+fn passed_env() -> impl Iterator<Item = (&'static str, OsString)> {
+    let names: &[&str] = if cfg!(windows) {
+        &["PATH", "SystemRoot"]
+    } else {
+        &["PATH"]
+    };
+    names
+        .iter()
+        .filter_map(|name| Some((*name, std::env::var_os(name)?)))
+}
+
+/// Where a FastCGI application listens.
+#[derive(Clone, Debug)]
+enum Address {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Connection to a FastCGI application.
+trait Socket: Read + Write {}
+
+impl<T: Read + Write> Socket for T {}
+
+/// FastCGI application, such as PHP-FPM, that handles the requests of
+/// a route added with [`Server::route_fastcgi`].
+///
+/// Each request gets its own connection to the application. What the
+/// application reports as errors is logged. Applications that serve
+/// more than one script usually need `SCRIPT_FILENAME`, which can be
+/// set with [`FastCgi::env`].
+///
+/// Example usage:
+/// ```
+/// use shs::FastCgi;
+///
+/// let php = FastCgi::new("127.0.0.1:9000")
+///     .env("SCRIPT_FILENAME", "/srv/www/index.php");
+/// ```
+#[derive(Clone, Debug)]
+pub struct FastCgi {
+    address: Address,
+    env: Vec<(String, String)>,
+}
+
+// Record types and other constants from the FastCGI specification
+const FCGI_VERSION: u8 = 1;
+const FCGI_BEGIN_REQUEST: u8 = 1;
+const FCGI_END_REQUEST: u8 = 3;
+const FCGI_PARAMS: u8 = 4;
+const FCGI_STDIN: u8 = 5;
+const FCGI_STDOUT: u8 = 6;
+const FCGI_STDERR: u8 = 7;
+const FCGI_RESPONDER: u16 = 1;
+
+/// Each connection carries a single request, so they all use the same
+/// ID.
+const REQUEST_ID: u16 = 1;
+
+/// Largest content of a record that keeps it 8-byte aligned, as the
+/// specification recommends.
+const MAX_CONTENT: usize = 65528;
+
+impl FastCgi {
+    /// Connect to the application at the TCP `address`, such as
+    /// `"127.0.0.1:9000"`.
+    pub fn new(address: &str) -> FastCgi {
+        FastCgi {
+            address: Address::Tcp(address.into()),
+            env: Vec::new(),
+        }
+    }
+
+    /// Connect to the application at the Unix socket `path`.
+    #[cfg(unix)]
+    pub fn unix(path: impl Into<PathBuf>) -> FastCgi {
+        FastCgi {
+            address: Address::Unix(path.into()),
+            env: Vec::new(),
+        }
+    }
+
+    /// Pass a parameter to the application, replacing the
+    /// meta-variable of the same name if there is one.
+    pub fn env(mut self, name: &str, value: &str) -> FastCgi {
+        self.env.push((name.into(), value.into()));
+        self
+    }
+
+    #[throws]
+    fn connect(&self) -> Box<dyn Socket> {
+        let socket: Box<dyn Socket> = match &self.address {
+            Address::Tcp(address) => {
+                Box::new(TcpStream::connect(address).with_context(|| {
+                    format!("failed to connect to FastCGI at {}", address)
+                })?)
+            }
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                Box::new(UnixStream::connect(path).with_context(|| {
+                    format!(
+                        "failed to connect to FastCGI at {}",
+                        path.display()
+                    )
+                })?)
+            }
+        };
+        socket
+    }
+
+    #[throws]
+    fn run(&self, req: &mut Request, script_segments: Option<usize>) {
+        let vars = meta_variables(req, script_segments, &self.env)?;
+        let mut socket = self.connect()?;
+
+        // The application is expected to read the whole request before
+        // it answers, so there's no need to read and write at once
+        let mut writer = BufWriter::new(&mut socket);
+        let mut begin = [0; 8];
+        begin[..2].copy_from_slice(&FCGI_RESPONDER.to_be_bytes());
+        // Flags are left zero so the application closes the connection
+        write_record(&mut writer, FCGI_BEGIN_REQUEST, &begin)?;
+        let mut params = Vec::new();
+        for (name, value) in &vars {
+            encode_length(&mut params, name.len());
+            encode_length(&mut params, value.len());
+            params.extend_from_slice(name.as_bytes());
+            params.extend_from_slice(value.as_bytes());
+        }
+        for chunk in params.chunks(MAX_CONTENT) {
+            write_record(&mut writer, FCGI_PARAMS, chunk)?;
+        }
+        write_record(&mut writer, FCGI_PARAMS, &[])?;
+        let mut body = req.body_reader()?;
+        let mut buf = vec![0; MAX_CONTENT];
+        loop {
+            let len = body.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            write_record(&mut writer, FCGI_STDIN, &buf[..len])?;
+        }
+        write_record(&mut writer, FCGI_STDIN, &[])?;
+        writer.flush()?;
+        drop(writer);
+        drop(body);
+
+        let mut reader = BufReader::new(socket);
+        let mut output = Vec::new();
+        let mut errors = Vec::new();
+        loop {
+            let mut header = [0; 8];
+            reader.read_exact(&mut header).context(
+                "FastCGI application closed the connection before \
+                 ending the request",
+            )?;
+            let len = usize::from(u16::from_be_bytes([header[4], header[5]]));
+            let padding = usize::from(header[6]);
+            let mut content = vec![0; len + padding];
+            reader.read_exact(&mut content)?;
+            content.truncate(len);
+            match header[1] {
+                FCGI_STDOUT => output.extend_from_slice(&content),
+                FCGI_STDERR => errors.extend_from_slice(&content),
+                FCGI_END_REQUEST => break,
+                // Records for other requests or management records
+                // don't concern this request
+                _ => {}
+            }
+        }
+        log_stderr(&errors);
+        write_response(req, output)?;
+    }
+}
+
+fn write_record(
+    writer: &mut impl Write,
+    kind: u8,
+    content: &[u8],
+) -> io::Result<()> {
+    let len = u16::try_from(content.len()).expect("record is too long");
+    let padding = (8 - content.len() % 8) % 8;
+    let [len_high, len_low] = len.to_be_bytes();
+    let [id_high, id_low] = REQUEST_ID.to_be_bytes();
+    writer.write_all(&[
+        FCGI_VERSION,
+        kind,
+        id_high,
+        id_low,
+        len_high,
+        len_low,
+        padding as u8,
+        0,
+    ])?;
+    writer.write_all(content)?;
+    writer.write_all(&[0; 8][..padding])
+}
+
+/// Encode the length of a parameter's name or value: one byte if it's
+/// short, otherwise four with the top bit set.
+fn encode_length(params: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        params.push(len as u8);
+    } else {
+        params.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+/// Find where the path matched before the route's wildcard ends, if
+/// it has one, from the number of segments before the wildcard.
+fn script_segments(route: &str) -> Option<usize> {
+    let pattern = route.split_whitespace().nth(1)?;
+    pattern
+        .trim_start_matches('/')
+        .split('/')
+        .position(|segment| segment.starts_with('*'))
+}
+
+/// Split `path` into `SCRIPT_NAME`, the first `script_segments`
+/// segments, and `PATH_INFO`, the rest. Without a wildcard the whole
+/// path is the script.
+fn split_path(path: &str, script_segments: Option<usize>) -> (&str, &str) {
+    let segments = match script_segments {
+        Some(segments) => segments,
+        None => return (path, ""),
+    };
+    let mut end = 0;
+    for _ in 0..segments {
+        match path[end + 1..].find('/') {
+            Some(index) => end += index + 1,
+            None => return (path, ""),
+        }
+    }
+    path.split_at(end)
+}
+
+/// Build the meta-variables describing `req` (RFC 3875 §4.1), followed
+/// by the configured `env`, which replaces variables of the same name.
+#[throws]
+fn meta_variables(
+    req: &Request,
+    script_segments: Option<usize>,
+    env: &[(String, String)],
+) -> Vec<(String, String)> {
+    let url = req.url();
+    let (script_name, path_info) = split_path(url.path(), script_segments);
+    let path_info = percent_encoding::percent_decode_str(path_info)
+        .decode_utf8_lossy()
+        .into_owned();
+    let mut vars = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE", "shs".into()),
+        ("SERVER_PROTOCOL", req.version().into()),
+        ("SERVER_NAME", url.host_str().unwrap_or_default().into()),
+        (
+            "SERVER_PORT",
+            url.port_or_known_default().unwrap_or(80).to_string(),
+        ),
+        ("REQUEST_METHOD", req.method().into()),
+        ("SCRIPT_NAME", script_name.into()),
+        ("PATH_INFO", path_info),
+        ("QUERY_STRING", url.query().unwrap_or_default().into()),
+        ("REQUEST_URI", url[url::Position::BeforePath..].into()),
+    ];
+    if url.scheme() == "https" {
+        vars.push(("HTTPS", "on".into()));
+    }
+    if let Some(ip) = req.client_ip() {
+        vars.push(("REMOTE_ADDR", ip.to_string()));
+    }
+    if let Some(addr) = req.peer_addr() {
+        vars.push(("REMOTE_PORT", addr.port().to_string()));
+    }
+    let len = req.spooled_body().len()?;
+    if len > 0 {
+        vars.push(("CONTENT_LENGTH", len.to_string()));
+    }
+    let mut vars: Vec<(String, String)> = vars
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+    for (name, value) in req.headers() {
+        // Underscores would be indistinguishable from dashes once
+        // mapped, letting a client header like X_Test replace X-Test
+        // or Content_Length replace CONTENT_LENGTH. Such names are
+        // dropped, as nginx and Apache do.
+        if name.contains('_') {
+            continue;
+        }
+        let var = match name.to_ascii_uppercase().replace('-', "_") {
+            // Already passed as CONTENT_LENGTH
+            var if var == "CONTENT_LENGTH" => continue,
+            var if var == "CONTENT_TYPE" => var,
+            // HTTP_PROXY would be mistaken for proxy settings by many
+            // HTTP clients ("httpoxy")
+            var if var == "PROXY" => continue,
+            var => format!("HTTP_{}", var),
+        };
+        vars.push((var, value.clone()));
+    }
+    for (name, value) in env {
+        vars.retain(|(var, _)| var != name);
+        vars.push((name.clone(), value.clone()));
+    }
+    vars
+}
+
+fn log_stderr(errors: &[u8]) {
+    for line in String::from_utf8_lossy(errors).lines() {
+        log::warn!(target: "shs::cgi", "{}", line);
+    }
+}
+
+/// Split a script's output at the blank line after the headers, which
+/// may end in `\r\n` or just `\n`.
+fn split_headers(output: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut start = 0;
+    loop {
+        let end = start + output[start..].iter().position(|b| *b == b'\n')?;
+        let line = &output[start..end];
+        if line.is_empty() || line == b"\r" {
+            return Some((&output[..start], &output[end + 1..]));
+        }
+        start = end + 1;
+    }
+}
+
+/// Use a script's output (RFC 3875 §6) as the response. `Status` sets
+/// the status, which is `302 Found` if there's only a `Location`, and
+/// the other headers are copied, except the framing headers the server
+/// sets itself.
+#[throws]
+fn write_response(req: &mut Request, output: Vec<u8>) {
+    let (headers, body) = split_headers(&output)
+        .ok_or_else(|| anyhow!("CGI response has no end of headers"))?;
+    let headers = std::str::from_utf8(headers)
+        .map_err(|_| anyhow!("CGI response headers are not valid UTF-8"))?;
+    let mut status = None;
+    let mut has_location = false;
+    let mut copied: Vec<(&str, String)> = Vec::new();
+    for line in headers.lines() {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow!("invalid CGI response header {:?}", line))?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case("Status") {
+            let (code, reason) = value.split_once(' ').unwrap_or((value, ""));
+            let code: u16 = code
+                .parse()
+                .map_err(|_| anyhow!("invalid CGI status {:?}", value))?;
+            status = Some((code, reason.trim()));
+        } else if name.eq_ignore_ascii_case("Set-Cookie") {
+            match parse_set_cookie(value) {
+                Some(cookie) => req.set_cookie(cookie),
+                None => {
+                    throw!(anyhow!("invalid Set-Cookie header {:?}", value))
+                }
+            }
+        } else if name.eq_ignore_ascii_case("Content-Length")
+            || name.eq_ignore_ascii_case("Transfer-Encoding")
+        {
+            continue;
+        } else {
+            has_location |= name.eq_ignore_ascii_case("Location");
+            // Repeated headers are joined, since the response keeps one
+            // value per name
+            match copied
+                .iter_mut()
+                .find(|(copied, _)| copied.eq_ignore_ascii_case(name))
+            {
+                Some((_, joined)) => {
+                    joined.push_str(", ");
+                    joined.push_str(value);
+                }
+                None => copied.push((name, value.into())),
+            }
+        }
+    }
+    match status {
+        Some((code, reason)) => match StatusCode::try_from(code) {
+            Ok(status) => req.set_status(status),
+            Err(_) => req.set_status_with_reason(
+                code,
+                if reason.is_empty() { "Unknown" } else { reason },
+            )?,
+        },
+        None if has_location => req.set_status(StatusCode::Found),
+        None => req.set_status(StatusCode::Ok),
+    }
+    for (name, value) in copied {
+        req.try_set_header(name, &value)?;
+    }
+    req.resp_body = ResponseBody::Bytes(body.to_vec());
+}
+
+impl<E: Debug + Display + From<Error> + 'static> Server<E> {
+    /// Add a route handled by running the CGI program `cgi` for each
+    /// request. If the route's path ends in a wildcard, the part
+    /// before it is the script's `SCRIPT_NAME` and the rest is its
+    /// `PATH_INFO`; otherwise the whole path is `SCRIPT_NAME`. Each
+    /// route has a single method, so add the route once for each
+    /// method the script handles.
+    ///
+    /// The whole output is collected before the response is sent, so
+    /// the script can't stream its response.
+    ///
+    /// ```no_run
+    /// # use anyhow::Error;
+    /// # use shs::{Cgi, Server};
+    /// let mut server = Server::<Error>::new("127.0.0.1:1234")?;
+    /// let cgi = Cgi::new("/srv/cgi-bin/app.cgi");
+    /// server.route_cgi("GET /app/*path", cgi.clone())?;
+    /// server.route_cgi("POST /app/*path", cgi)?;
+    /// server.launch()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    #[throws]
+    pub fn route_cgi(&mut self, route: &str, cgi: Cgi) -> RouteHandle<'_, E> {
+        let segments = script_segments(route);
+        let handler = move |req: &mut Request| -> Result<(), E> {
+            cgi.run(req, segments)?;
+            Ok(())
+        };
+        let id = self.routes.add(route, Box::new(handler))?;
+        RouteHandle { server: self, id }
+    }
+
+    /// Add a route handled by passing each request to the FastCGI
+    /// application `fastcgi`. The request is described to it as for
+    /// [`Server::route_cgi`].
+    ///
+    /// ```no_run
+    /// # use anyhow::Error;
+    /// # use shs::{FastCgi, Server};
+    /// let mut server = Server::<Error>::new("127.0.0.1:1234")?;
+    /// let php = FastCgi::new("127.0.0.1:9000")
+    ///     .env("SCRIPT_FILENAME", "/srv/www/index.php");
+    /// server.route_fastcgi("GET /*path", php)?;
+    /// server.launch()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    #[throws]
+    pub fn route_fastcgi(
+        &mut self,
+        route: &str,
+        fastcgi: FastCgi,
+    ) -> RouteHandle<'_, E> {
+        let segments = script_segments(route);
+        let handler = move |req: &mut Request| -> Result<(), E> {
+            fastcgi.run(req, segments)?;
+            Ok(())
+        };
+        let id = self.routes.add(route, Box::new(handler))?;
+        RouteHandle { server: self, id }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestRequest;
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!(script_segments("GET /cgi-bin/*path"), Some(1));
+        assert_eq!(script_segments("GET /*path"), Some(0));
+        assert_eq!(script_segments("GET /app.cgi"), None);
+        assert_eq!(split_path("/cgi-bin/a/b", Some(1)), ("/cgi-bin", "/a/b"));
+        assert_eq!(split_path("/a/b", Some(0)), ("", "/a/b"));
+        assert_eq!(split_path("/app.cgi", None), ("/app.cgi", ""));
+    }
+
+    #[test]
+    fn test_meta_variables() {
+        let mut req =
+            TestRequest::new_with_body("POST /cgi-bin/a%20b?x=1", b"hello")
+                .unwrap();
+        req.set_header("X-Test", "yes");
+        req.set_header("Proxy", "evil");
+        req.set_header("Content-Type", "text/plain");
+        req.set_header("X_Test", "forged");
+        req.set_header("Content_Length", "999");
+        req.set_header("Content_Type", "text/html");
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        let handler = |req: &mut Request| -> Result<(), Error> {
+            let env = [("SERVER_NAME".to_string(), "override".to_string())];
+            let vars = meta_variables(req, Some(1), &env)?;
+            for name in &["HTTP_X_TEST", "CONTENT_LENGTH", "CONTENT_TYPE"] {
+                let count = vars.iter().filter(|(var, _)| var == name).count();
+                assert_eq!(count, 1, "{}", name);
+            }
+            let vars: HashMap<_, _> = vars.into_iter().collect();
+            assert_eq!(vars["REQUEST_METHOD"], "POST");
+            assert_eq!(vars["SCRIPT_NAME"], "/cgi-bin");
+            assert_eq!(vars["PATH_INFO"], "/a b");
+            assert_eq!(vars["QUERY_STRING"], "x=1");
+            assert_eq!(vars["REQUEST_URI"], "/cgi-bin/a%20b?x=1");
+            assert_eq!(vars["CONTENT_LENGTH"], "5");
+            assert_eq!(vars["CONTENT_TYPE"], "text/plain");
+            assert_eq!(vars["HTTP_X_TEST"], "yes");
+            assert_eq!(vars["SERVER_NAME"], "override");
+            assert!(!vars.contains_key("HTTP_PROXY"));
+            Ok(())
+        };
+        server
+            .routes
+            .add("POST /cgi-bin/*path", Box::new(handler))
+            .unwrap();
+        server.test_request(&req).unwrap();
+    }
+
+    fn respond(output: &[u8]) -> Result<Request, Error> {
+        let mut req = crate::test_req("GET", "/", &[]);
+        write_response(&mut req, output.to_vec())?;
+        Ok(req)
+    }
+
+    #[test]
+    fn test_write_response() {
+        let req = respond(
+            b"Status: 404 Not Found\r\nX-A: 1\r\nx-a: 2\r\n\
+              Content-Length: 99\r\nSet-Cookie: a=1\r\n\r\nbody",
+        )
+        .unwrap();
+        assert_eq!(req.status_code(), 404);
+        assert_eq!(req.resp_headers.get("X-A"), Some("1, 2"));
+        assert!(req.resp_headers.get("Content-Length").is_none());
+        assert_eq!(req.resp_body.len(), 4);
+
+        let req = respond(b"Location: /elsewhere\n\n").unwrap();
+        assert_eq!(req.status_code(), 302);
+
+        let req = respond(b"Status: 599 Gone Fishing\n\n").unwrap();
+        assert_eq!(req.status_code(), 599);
+
+        assert!(respond(b"Content-Type: text/plain\n").is_err());
+        assert!(respond(b"not a header\n\n").is_err());
+        assert!(respond(b"Status: abc\n\n").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_route_cgi() {
+        use crate::HeaderName;
+
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("app.cgi");
+        std::fs::write(
+            &script,
+            "echo 'Status: 201 Created'\n\
+             echo 'Content-Type: text/plain'\n\
+             echo \"X-Path-Info: $PATH_INFO\"\n\
+             echo 'oops' >&2\n\
+             echo\n\
+             printf '%s %s ' \"$REQUEST_METHOD\" \"$GREETING\"\n\
+             cat\n",
+        )
+        .unwrap();
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        let cgi = Cgi::new("/bin/sh").arg(&script).env("GREETING", "hi");
+        server.route_cgi("POST /cgi-bin/*path", cgi).unwrap();
+        let missing = Cgi::new(dir.path().join("missing"));
+        server.route_cgi("GET /missing", missing).unwrap();
+
+        let req =
+            TestRequest::new_with_body("POST /cgi-bin/x/y", b"body").unwrap();
+        let resp = server.test_request(&req).unwrap();
+        assert_eq!(resp.status, StatusCode::Created);
+        assert_eq!(
+            resp.headers[&HeaderName::new("X-Path-Info".into())],
+            "/x/y"
+        );
+        assert_eq!(
+            resp.headers[&HeaderName::new("Content-Type".into())],
+            "text/plain"
+        );
+        assert_eq!(resp.body, b"POST hi body");
+
+        let req = TestRequest::new("GET /missing").unwrap();
+        assert!(server.test_request(&req).is_err());
+    }
+
+    /// Read one FastCGI record, returning its type and content.
+    fn read_record(reader: &mut impl Read) -> (u8, Vec<u8>) {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header).unwrap();
+        let len = usize::from(u16::from_be_bytes([header[4], header[5]]));
+        let mut content = vec![0; len + usize::from(header[6])];
+        reader.read_exact(&mut content).unwrap();
+        content.truncate(len);
+        (header[1], content)
+    }
+
+    fn decode_length(params: &mut &[u8]) -> usize {
+        if params[0] < 0x80 {
+            let len = params[0];
+            *params = &params[1..];
+            usize::from(len)
+        } else {
+            let len = u32::from_be_bytes([
+                params[0] & 0x7f,
+                params[1],
+                params[2],
+                params[3],
+            ]);
+            *params = &params[4..];
+            len as usize
+        }
+    }
+
+    /// Answer one request the way a FastCGI application would, echoing
+    /// the method and body.
+    fn fake_application(listener: TcpListener) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let (kind, _) = read_record(&mut stream);
+        assert_eq!(kind, FCGI_BEGIN_REQUEST);
+        let mut params = Vec::new();
+        loop {
+            let (kind, content) = read_record(&mut stream);
+            assert_eq!(kind, FCGI_PARAMS);
+            if content.is_empty() {
+                break;
+            }
+            params.extend(content);
+        }
+        let mut vars = HashMap::new();
+        let mut rest = &params[..];
+        while !rest.is_empty() {
+            let name_len = decode_length(&mut rest);
+            let value_len = decode_length(&mut rest);
+            let name = String::from_utf8(rest[..name_len].to_vec()).unwrap();
+            let value = String::from_utf8(
+                rest[name_len..name_len + value_len].to_vec(),
+            )
+            .unwrap();
+            rest = &rest[name_len + value_len..];
+            vars.insert(name, value);
+        }
+        let mut body = Vec::new();
+        loop {
+            let (kind, content) = read_record(&mut stream);
+            assert_eq!(kind, FCGI_STDIN);
+            if content.is_empty() {
+                break;
+            }
+            body.extend(content);
+        }
+
+        let mut output = format!(
+            "Content-Type: text/plain\r\n\r\n{} {} ",
+            vars["REQUEST_METHOD"],
+            vars["HTTP_X_LONG"].len()
+        )
+        .into_bytes();
+        output.extend(body);
+        write_record(&mut stream, FCGI_STDERR, b"warning").unwrap();
+        for chunk in output.chunks(MAX_CONTENT) {
+            write_record(&mut stream, FCGI_STDOUT, chunk).unwrap();
+        }
+        write_record(&mut stream, FCGI_STDOUT, &[]).unwrap();
+        write_record(&mut stream, FCGI_END_REQUEST, &[0; 8]).unwrap();
+    }
+
+    #[test]
+    fn test_route_fastcgi() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let application = thread::spawn(move || fake_application(listener));
+
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        server
+            .route_fastcgi("PUT /app/*path", FastCgi::new(&address))
+            .unwrap();
+        // A body larger than one record, and a parameter whose length
+        // takes four bytes
+        let body = vec![b'x'; 100_000];
+        let mut req = TestRequest::new_with_body("PUT /app/x", &body).unwrap();
+        req.set_header("X-Long", &"y".repeat(300));
+        let resp = server.test_request(&req).unwrap();
+        application.join().unwrap();
+        assert_eq!(resp.status, StatusCode::Ok);
+        assert!(resp.body.starts_with(b"PUT 300 xxx"));
+        assert_eq!(resp.body.len(), "PUT 300 ".len() + body.len());
+    }
+}
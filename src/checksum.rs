@@ -0,0 +1,396 @@
+//! Checking request bodies against the checksums clients send with
+//! them, and sending checksums of response bodies. Requires the
+//! `checksum` feature.
+
+use crate::body::ResponseBody;
+use crate::{HeaderName, Next, Request, StatusCode};
+use anyhow::{anyhow, Error};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use fehler::{throw, throws};
+use md5::Md5;
+use sha2::{Digest, Sha256, Sha512};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Hash algorithm of a body checksum.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChecksumAlgorithm {
+    /// MD5, as used by `Content-MD5`. It only guards against
+    /// accidental corruption.
+    Md5,
+    /// SHA-256.
+    Sha256,
+    /// SHA-512.
+    Sha512,
+}
+
+impl ChecksumAlgorithm {
+    /// Key of the algorithm in `Content-Digest` (RFC 9530 §5).
+    fn key(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Md5 => "md5",
+            ChecksumAlgorithm::Sha256 => "sha-256",
+            ChecksumAlgorithm::Sha512 => "sha-512",
+        }
+    }
+
+    fn from_key(key: &str) -> Option<ChecksumAlgorithm> {
+        [
+            ChecksumAlgorithm::Md5,
+            ChecksumAlgorithm::Sha256,
+            ChecksumAlgorithm::Sha512,
+        ]
+        .iter()
+        .copied()
+        .find(|algorithm| algorithm.key().eq_ignore_ascii_case(key))
+    }
+
+    /// Hash everything `reader` produces.
+    #[throws(io::Error)]
+    fn digest(self, reader: impl Read) -> Vec<u8> {
+        match self {
+            ChecksumAlgorithm::Md5 => hash::<Md5>(reader)?,
+            ChecksumAlgorithm::Sha256 => hash::<Sha256>(reader)?,
+            ChecksumAlgorithm::Sha512 => hash::<Sha512>(reader)?,
+        }
+    }
+}
+
+#[throws(io::Error)]
+fn hash<D: Digest>(mut reader: impl Read) -> Vec<u8> {
+    let mut hasher = D::new();
+    let mut buf = [0; 8192];
+    loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+    }
+    hasher.finalize().to_vec()
+}
+
+/// Decode a checksum sent as hex or base64.
+fn decode(value: &str) -> Option<Vec<u8>> {
+    let value = value.trim();
+    let is_hex = value.len().is_multiple_of(2)
+        && !value.is_empty()
+        && value.bytes().all(|b| b.is_ascii_hexdigit());
+    if is_hex {
+        // Hex is tried first, since a hex digest read as base64 would
+        // have the wrong length anyway
+        let bytes = (0..value.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok())
+            .collect();
+        return bytes;
+    }
+    STANDARD.decode(value).ok()
+}
+
+/// Verifies request bodies against the checksum the client sent, and
+/// optionally adds a checksum to responses. Add it to a route with
+/// [`RouteHandle::checksum`]. Requires the `checksum` feature.
+///
+/// By default a `Content-MD5` header (RFC 1864) or a `Content-Digest`
+/// header (RFC 9530) is checked; more headers can be added with
+/// [`BodyChecksum::header`]. A body that doesn't match any of them is
+/// rejected with 400, as is a checksum that can't be parsed. Requests
+/// without a checksum are let through unless
+/// [`BodyChecksum::require`] is set. A body sent with a
+/// `Content-Encoding` is checked after it's decoded.
+///
+/// Example usage:
+/// ```
+/// use shs::{BodyChecksum, ChecksumAlgorithm};
+///
+/// let checksum = BodyChecksum::new()
+///     .header("X-Checksum-Sha256", ChecksumAlgorithm::Sha256)
+///     .require()
+///     .respond_with(ChecksumAlgorithm::Sha256);
+/// ```
+///
+/// [`RouteHandle::checksum`]: crate::RouteHandle::checksum
+#[derive(Clone, Debug)]
+pub struct BodyChecksum {
+    headers: Vec<(String, ChecksumAlgorithm)>,
+    required: bool,
+    response: Option<ChecksumAlgorithm>,
+}
+
+impl Default for BodyChecksum {
+    fn default() -> BodyChecksum {
+        BodyChecksum::new()
+    }
+}
+
+impl BodyChecksum {
+    /// Check `Content-MD5` and `Content-Digest`.
+    pub fn new() -> BodyChecksum {
+        BodyChecksum {
+            headers: Vec::new(),
+            required: false,
+            response: None,
+        }
+    }
+
+    /// Also check the header `name`, whose value is the checksum of
+    /// the body with `algorithm`, in hex or base64.
+    pub fn header(
+        mut self,
+        name: &str,
+        algorithm: ChecksumAlgorithm,
+    ) -> BodyChecksum {
+        self.headers.push((name.into(), algorithm));
+        self
+    }
+
+    /// Reject requests with a body but no checksum with 400.
+    pub fn require(mut self) -> BodyChecksum {
+        self.required = true;
+        self
+    }
+
+    /// Add a checksum of the response body to responses, computed
+    /// with `algorithm` once the body is final, after compression.
+    /// MD5 is sent as `Content-MD5` and the others as `Content-Digest`.
+    /// Streamed responses don't get one.
+    pub fn respond_with(
+        mut self,
+        algorithm: ChecksumAlgorithm,
+    ) -> BodyChecksum {
+        self.response = Some(algorithm);
+        self
+    }
+
+    /// Get the checksums sent with the request.
+    #[throws]
+    fn expected(&self, req: &Request) -> Vec<(ChecksumAlgorithm, Vec<u8>)> {
+        let get = |name: &str| req.headers().get(&HeaderName::new(name.into()));
+        let mut expected = Vec::new();
+        if let Some(value) = get("Content-MD5") {
+            let digest = STANDARD
+                .decode(value.trim())
+                .map_err(|_| anyhow!("invalid Content-MD5 {:?}", value))?;
+            expected.push((ChecksumAlgorithm::Md5, digest));
+        }
+        if let Some(value) = get("Content-Digest") {
+            for member in value.split(',') {
+                let (key, digest) =
+                    member.split_once('=').ok_or_else(|| {
+                        anyhow!("invalid Content-Digest {:?}", value)
+                    })?;
+                // Digests of unknown algorithms are ignored (RFC 9530 §2)
+                let algorithm = match ChecksumAlgorithm::from_key(key.trim()) {
+                    Some(algorithm) => algorithm,
+                    None => continue,
+                };
+                let digest = digest
+                    .trim()
+                    .strip_prefix(':')
+                    .and_then(|digest| digest.strip_suffix(':'))
+                    .and_then(|digest| STANDARD.decode(digest).ok())
+                    .ok_or_else(|| {
+                        anyhow!("invalid Content-Digest {:?}", value)
+                    })?;
+                expected.push((algorithm, digest));
+            }
+        }
+        for (name, algorithm) in &self.headers {
+            if let Some(value) = get(name) {
+                let digest = decode(value)
+                    .ok_or_else(|| anyhow!("invalid {} {:?}", name, value))?;
+                expected.push((*algorithm, digest));
+            }
+        }
+        expected
+    }
+
+    /// Check the request body, rejecting the request if it doesn't
+    /// match, and continue with `next` otherwise.
+    pub fn run<E>(
+        &self,
+        req: &mut Request,
+        next: Next<'_, E>,
+    ) -> Result<(), E> {
+        match self.check(req) {
+            Ok(true) => {}
+            Ok(false) => {
+                req.set_status(StatusCode::BadRequest);
+                req.write_text("request body doesn't match its checksum");
+                return Ok(());
+            }
+            Err(err) => {
+                log::warn!("rejecting {}: {}", req.url().path(), err);
+                req.set_status(StatusCode::BadRequest);
+                req.write_text(&err.to_string());
+                return Ok(());
+            }
+        }
+        req.content_digest = self.response;
+        next.run(req)
+    }
+
+    /// Check whether the body matches every checksum sent with it.
+    #[throws]
+    fn check(&self, req: &Request) -> bool {
+        let expected = self.expected(req)?;
+        if expected.is_empty() {
+            if self.required && !req.spooled_body().is_empty()? {
+                throw!(anyhow!("missing request body checksum"));
+            }
+            return true;
+        }
+        for (algorithm, digest) in expected {
+            if algorithm.digest(req.body_reader()?)? != digest {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl Request {
+    /// Add the response checksum requested with
+    /// [`BodyChecksum::respond_with`], once the body is final.
+    pub(crate) fn add_content_digest(&mut self) {
+        let algorithm = match self.content_digest {
+            Some(algorithm) => algorithm,
+            None => return,
+        };
+        if matches!(
+            self.status,
+            StatusCode::NoContent | StatusCode::NotModified
+        ) {
+            return;
+        }
+        let digest = match response_digest(&self.resp_body, algorithm) {
+            Ok(digest) => STANDARD.encode(digest),
+            Err(err) => {
+                log::error!("failed to compute response checksum: {}", err);
+                return;
+            }
+        };
+        match algorithm {
+            ChecksumAlgorithm::Md5 => self.set_header("Content-MD5", &digest),
+            _ => self.set_header(
+                "Content-Digest",
+                &format!("{}=:{}:", algorithm.key(), digest),
+            ),
+        }
+    }
+}
+
+/// Hash the response body, leaving a file body ready to be sent.
+#[throws(Error)]
+fn response_digest(
+    body: &ResponseBody,
+    algorithm: ChecksumAlgorithm,
+) -> Vec<u8> {
+    match body {
+        ResponseBody::Bytes(bytes) => algorithm.digest(&bytes[..])?,
+        ResponseBody::File { file, len } => {
+            let mut file: &File = file;
+            let pos = file.stream_position()?;
+            let result = algorithm.digest((&mut file).take(*len));
+            file.seek(SeekFrom::Start(pos))?;
+            result?
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Server, TestRequest, TestResponse};
+
+    #[fehler::throws]
+    fn echo(req: &mut Request) {
+        let body = req.body().to_vec();
+        req.write_bytes(&body);
+    }
+
+    fn header(resp: &TestResponse, name: &str) -> Option<String> {
+        resp.headers.get(&HeaderName::new(name.into())).cloned()
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode("00ff").unwrap(), [0, 255]);
+        assert_eq!(decode("AP8=").unwrap(), [0, 255]);
+        assert!(decode("not base64!").is_none());
+    }
+
+    #[test]
+    fn test_body_checksum() {
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        let checksum = BodyChecksum::new()
+            .header("X-Checksum", ChecksumAlgorithm::Sha256)
+            .respond_with(ChecksumAlgorithm::Sha256);
+        server
+            .route("POST /echo", &echo)
+            .unwrap()
+            .checksum(checksum);
+        server
+            .route("POST /required", &echo)
+            .unwrap()
+            .checksum(BodyChecksum::new().require());
+
+        let post = |path: &str, headers: &[(&str, &str)]| {
+            let mut req = TestRequest::new_with_body(path, b"hello").unwrap();
+            for (name, value) in headers {
+                req.set_header(name, value);
+            }
+            server.test_request(&req).unwrap()
+        };
+        // MD5 and SHA-256 of "hello"
+        let md5 = "XUFAKrxLKna5cZ2REBfFkg==";
+        let sha256 = "LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+        let sha256_hex =
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        let resp = post("POST /echo", &[]);
+        assert_eq!(resp.status, StatusCode::Ok);
+        assert_eq!(
+            header(&resp, "Content-Digest").unwrap(),
+            format!("sha-256=:{}:", sha256)
+        );
+        let resp = post("POST /echo", &[("Content-MD5", md5)]);
+        assert_eq!(resp.status, StatusCode::Ok);
+        let digest = format!("md5=:{}:, sha-256=:{}:", md5, sha256);
+        let resp = post("POST /echo", &[("Content-Digest", &digest)]);
+        assert_eq!(resp.status, StatusCode::Ok);
+        let resp = post("POST /echo", &[("X-Checksum", sha256_hex)]);
+        assert_eq!(resp.status, StatusCode::Ok);
+        // Unknown algorithms are ignored
+        let resp = post("POST /echo", &[("Content-Digest", "crc32c=:AAAA:")]);
+        assert_eq!(resp.status, StatusCode::Ok);
+
+        let resp = post("POST /echo", &[("Content-MD5", sha256)]);
+        assert_eq!(resp.status, StatusCode::BadRequest);
+        assert!(header(&resp, "Content-Digest").is_none());
+        let resp = post("POST /echo", &[("X-Checksum", md5)]);
+        assert_eq!(resp.status, StatusCode::BadRequest);
+        let resp = post("POST /echo", &[("Content-MD5", "???")]);
+        assert_eq!(resp.status, StatusCode::BadRequest);
+
+        let resp = post("POST /required", &[]);
+        assert_eq!(resp.status, StatusCode::BadRequest);
+        assert_eq!(resp.body, b"missing request body checksum");
+        let resp = post("POST /required", &[("Content-MD5", md5)]);
+        assert_eq!(resp.status, StatusCode::Ok);
+        assert!(header(&resp, "Content-MD5").is_none());
+    }
+
+    #[test]
+    fn test_response_digest_of_file() {
+        let mut file = tempfile::tempfile().unwrap();
+        std::io::Write::write_all(&mut file, b"xxhello").unwrap();
+        file.seek(SeekFrom::Start(2)).unwrap();
+        let body = ResponseBody::File { file, len: 5 };
+        let digest = response_digest(&body, ChecksumAlgorithm::Md5).unwrap();
+        assert_eq!(STANDARD.encode(digest), "XUFAKrxLKna5cZ2REBfFkg==");
+        assert_eq!(body.into_bytes().unwrap(), b"hello");
+    }
+}
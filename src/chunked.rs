@@ -0,0 +1,136 @@
+//! Decoding of request bodies sent with `Transfer-Encoding: chunked`.
+
+use std::io::{self, BufRead, Read};
+
+/// Maximum length of a chunk size line or trailer line. Chunk
+/// extensions are allowed but ignored.
+const MAX_LINE: u64 = 4096;
+
+/// Maximum total size of the trailers after the last chunk.
+const MAX_TRAILERS: usize = 16 * 1024;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid chunked body: {}", message),
+    )
+}
+
+/// Reader that decodes a chunked body, stopping after the last chunk
+/// and its trailers so that nothing past the body is consumed.
+/// Trailers are read but discarded.
+pub(crate) struct ChunkedReader<R> {
+    inner: R,
+    /// Bytes left in the current chunk.
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub(crate) fn new(inner: R) -> ChunkedReader<R> {
+        ChunkedReader {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        (&mut self.inner).take(MAX_LINE).read_line(&mut line)?;
+        if !line.ends_with('\n') {
+            return Err(if line.len() as u64 == MAX_LINE {
+                invalid("line too long")
+            } else {
+                io::ErrorKind::UnexpectedEof.into()
+            });
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+
+    /// Read the size line of the next chunk.
+    fn read_size(&mut self) -> io::Result<u64> {
+        let line = self.read_line()?;
+        let size = line.split(';').next().unwrap_or("").trim_end();
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid("bad chunk size"));
+        }
+        u64::from_str_radix(size, 16).map_err(|_| invalid("chunk too large"))
+    }
+
+    fn read_trailers(&mut self) -> io::Result<()> {
+        let mut total = 0;
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                return Ok(());
+            }
+            total += line.len();
+            if total > MAX_TRAILERS {
+                return Err(invalid("trailers too large"));
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.remaining = self.read_size()?;
+            if self.remaining == 0 {
+                self.read_trailers()?;
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let max = buf.len().min(self.remaining as usize);
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n as u64;
+        if self.remaining == 0 && !self.read_line()?.is_empty() {
+            return Err(invalid("missing line end after chunk"));
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(mut input: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let mut body = Vec::new();
+        ChunkedReader::new(&mut input).read_to_end(&mut body)?;
+        Ok((body, input.to_vec()))
+    }
+
+    #[test]
+    fn test_decode() {
+        let (body, rest) =
+            decode(b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\n\r\nGET")
+                .unwrap();
+        assert_eq!(body, b"hello, world");
+        assert_eq!(rest, b"GET");
+
+        // Trailers are skipped, and bare LF line ends accepted
+        let (body, rest) = decode(b"A\nabcdefghij\n0\nX-Sum: 1\n\n").unwrap();
+        assert_eq!(body, b"abcdefghij");
+        assert!(rest.is_empty());
+
+        for input in &[
+            &b"5\r\nhello"[..],
+            b"5\r\nhelloX\r\n0\r\n\r\n",
+            b"-5\r\nhello\r\n0\r\n\r\n",
+            b"0x5\r\nhello\r\n0\r\n\r\n",
+            b"fffffffffffffffffffff\r\n",
+            b"0\r\n",
+        ] {
+            assert!(decode(input).is_err(), "{:?}", input);
+        }
+    }
+}
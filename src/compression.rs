@@ -0,0 +1,339 @@
+use crate::body::ResponseBody;
+use crate::{Body, HeaderName, Request, StatusCode};
+use anyhow::Error;
+use fehler::{throw, throws};
+use std::io::{Read, Write};
+
+/// Response compression settings, used with
+/// [`Server::set_compression`].
+///
+/// Responses are compressed when the client's `Accept-Encoding`
+/// allows it, the body is at least [`min_size`] bytes, the content
+/// type looks compressible (text, JSON, JavaScript, XML, SVG), and the
+/// handler didn't set `Content-Encoding` itself. File bodies are not
+/// compressed.
+///
+/// Gzip is always available. Brotli (`br`) is available with the
+/// `brotli` feature and is preferred over gzip when the client accepts
+/// both equally.
+///
+/// [`Server::set_compression`]: crate::Server::set_compression
+/// [`min_size`]: Compression::min_size
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Compression {
+    min_size: usize,
+    gzip: bool,
+    brotli: bool,
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression {
+            min_size: 1024,
+            gzip: true,
+            brotli: cfg!(feature = "brotli"),
+        }
+    }
+}
+
+impl Compression {
+    /// Set the smallest body size that will be compressed. The default
+    /// is 1024 bytes.
+    pub fn min_size(mut self, min_size: usize) -> Compression {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Enable or disable gzip. The default is enabled.
+    pub fn gzip(mut self, enabled: bool) -> Compression {
+        self.gzip = enabled;
+        self
+    }
+
+    /// Enable or disable brotli. The default is enabled if the
+    /// `brotli` feature is on; without that feature this has no
+    /// effect.
+    pub fn brotli(mut self, enabled: bool) -> Compression {
+        self.brotli = enabled && cfg!(feature = "brotli");
+        self
+    }
+
+    /// Encodings to offer, most preferred first.
+    fn available(&self) -> Vec<&'static str> {
+        let mut encodings = Vec::new();
+        if self.brotli {
+            encodings.push("br");
+        }
+        if self.gzip {
+            encodings.push("gzip");
+        }
+        encodings
+    }
+}
+
+/// Pick the encoding to use given an `Accept-Encoding` value. The
+/// encoding with the highest q-value wins; ties go to the earlier
+/// entry in `available`.
+pub(crate) fn choose_encoding(
+    accept: &str,
+    available: &[&'static str],
+) -> Option<&'static str> {
+    let mut weights: Vec<(&str, f32)> = Vec::new();
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        if name.is_empty() {
+            continue;
+        }
+        let q = parts
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse().ok())
+            .unwrap_or(1.0);
+        weights.push((name, q));
+    }
+    let weight = |encoding: &str| {
+        weights
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(encoding))
+            .or_else(|| weights.iter().find(|(name, _)| *name == "*"))
+            .map(|(_, q)| *q)
+            .unwrap_or(0.0)
+    };
+
+    let mut best: Option<(&'static str, f32)> = None;
+    for encoding in available {
+        let q = weight(encoding);
+        if q > 0.0 && best.map(|(_, b)| q > b).unwrap_or(true) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    essence.starts_with("text/")
+        || essence == "application/json"
+        || essence == "application/javascript"
+        || essence == "application/xml"
+        || essence == "image/svg+xml"
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+}
+
+#[throws]
+pub(crate) fn compress(encoding: &str, data: &[u8]) -> Vec<u8> {
+    match encoding {
+        #[cfg(feature = "brotli")]
+        "br" => {
+            let mut out = Vec::new();
+            {
+                let mut writer = brotli::CompressorWriter::new(
+                    &mut out, 4096, /*quality=*/ 5, /*lg_window=*/ 22,
+                );
+                writer.write_all(data)?;
+            }
+            out
+        }
+        _ => {
+            let mut encoder = flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::default(),
+            );
+            encoder.write_all(data)?;
+            encoder.finish()?
+        }
+    }
+}
+
+/// Default for [`Server::set_max_decompressed_size`].
+///
+/// [`Server::set_max_decompressed_size`]: crate::Server::set_max_decompressed_size
+pub(crate) const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+
+/// Decode `data` with a single content coding, reading at most
+/// `limit + 1` bytes of output so that oversized bodies can be
+/// detected without inflating them fully.
+#[throws(StatusCode)]
+fn decompress(encoding: &str, data: &[u8], limit: usize) -> Vec<u8> {
+    let limit = limit as u64 + 1;
+    let mut out = Vec::new();
+    let result = match encoding.to_ascii_lowercase().as_str() {
+        "identity" => return data.to_vec(),
+        "gzip" | "x-gzip" => flate2::read::MultiGzDecoder::new(data)
+            .take(limit)
+            .read_to_end(&mut out),
+        "deflate" => {
+            // "deflate" is supposed to be zlib-wrapped, but some
+            // clients send a raw deflate stream
+            let zlib = flate2::read::ZlibDecoder::new(data)
+                .take(limit)
+                .read_to_end(&mut out);
+            if zlib.is_err() {
+                out.clear();
+                flate2::read::DeflateDecoder::new(data)
+                    .take(limit)
+                    .read_to_end(&mut out)
+            } else {
+                zlib
+            }
+        }
+        #[cfg(feature = "brotli")]
+        "br" => brotli::Decompressor::new(data, 4096)
+            .take(limit)
+            .read_to_end(&mut out),
+        _ => throw!(StatusCode::UnsupportedMediaType),
+    };
+    match result {
+        Ok(_) if out.len() as u64 >= limit => {
+            throw!(StatusCode::PayloadTooLarge)
+        }
+        Ok(_) => out,
+        Err(_) => throw!(StatusCode::BadRequest),
+    }
+}
+
+impl Request {
+    /// Undo the request's `Content-Encoding`, if any, so that handlers
+    /// always see the decoded body. The `Content-Encoding` header is
+    /// removed afterwards.
+    ///
+    /// On failure the status to respond with is returned: 415 for an
+    /// unsupported encoding, 413 if the decoded body would be larger
+    /// than `limit`, or 400 if the body is corrupt.
+    ///
+    /// Bodies spooled to a file are left as they are, along with the
+    /// header.
+    #[throws(StatusCode)]
+    pub(crate) fn decompress_body(&mut self, limit: usize) {
+        let name = HeaderName::new("Content-Encoding".into());
+        let encodings = match self.req_headers.get(&name) {
+            Some(value) if self.req_body.bytes().is_some() => value.clone(),
+            _ => return,
+        };
+        // Codings are listed in the order they were applied
+        for encoding in encodings.split(',').rev() {
+            let encoding = encoding.trim();
+            if encoding.is_empty() {
+                continue;
+            }
+            let body = decompress(encoding, self.body(), limit)?;
+            self.req_body = Body::InMemory(body);
+        }
+        self.req_headers.remove(&name);
+    }
+
+    /// Compress the response body if the settings and the request
+    /// allow it.
+    #[throws]
+    pub(crate) fn compress_response(&mut self, compression: &Compression) {
+        if self.route_options.no_compress
+            || matches!(
+                self.status,
+                StatusCode::NoContent | StatusCode::NotModified
+            )
+            || self.resp_header("Content-Encoding").is_some()
+            || !self
+                .resp_header("Content-Type")
+                .map(is_compressible)
+                .unwrap_or(false)
+        {
+            return;
+        }
+        let large_enough = matches!(
+            &self.resp_body,
+            ResponseBody::Bytes(body) if body.len() >= compression.min_size
+        );
+        if !large_enough {
+            return;
+        }
+
+        // The response now depends on Accept-Encoding even if it ends
+        // up not being compressed
+        let vary = match self.resp_header("Vary") {
+            Some(vary) => format!("{}, Accept-Encoding", vary),
+            None => "Accept-Encoding".into(),
+        };
+        self.set_header("Vary", &vary);
+
+        let encoding = match self
+            .req_headers
+            .get(&HeaderName::new("Accept-Encoding".into()))
+            .and_then(|accept| {
+                choose_encoding(accept, &compression.available())
+            }) {
+            Some(encoding) => encoding,
+            None => return,
+        };
+        if let ResponseBody::Bytes(body) = &self.resp_body {
+            self.resp_body = ResponseBody::Bytes(compress(encoding, body)?);
+            self.set_header("Content-Encoding", encoding);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choose_encoding() {
+        let both = ["br", "gzip"];
+        assert_eq!(choose_encoding("gzip, br", &both), Some("br"));
+        assert_eq!(choose_encoding("gzip;q=1, br;q=0.5", &both), Some("gzip"));
+        assert_eq!(choose_encoding("identity", &both), None);
+        assert_eq!(choose_encoding("*", &both), Some("br"));
+        assert_eq!(choose_encoding("*, br;q=0", &both), Some("gzip"));
+        assert_eq!(choose_encoding("br", &["gzip"]), None);
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        assert!(is_compressible("text/html; charset=UTF-8"));
+        assert!(is_compressible("application/json"));
+        assert!(!is_compressible("image/png"));
+    }
+
+    #[cfg(feature = "brotli")]
+    #[test]
+    fn test_brotli() {
+        use std::io::Read;
+
+        let data = "hello ".repeat(100);
+        let compressed = compress("br", data.as_bytes()).unwrap();
+        assert!(compressed.len() < data.len());
+        let mut decoded = String::new();
+        brotli::Decompressor::new(&compressed[..], 4096)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn test_decompress() {
+        let data = b"hello world";
+        let gz = compress("gzip", data).unwrap();
+        assert_eq!(decompress("gzip", &gz, 100).unwrap(), data);
+        assert_eq!(decompress("GZIP", &gz, 100).unwrap(), data);
+        assert_eq!(
+            decompress("gzip", &gz, 5).unwrap_err(),
+            StatusCode::PayloadTooLarge
+        );
+        assert_eq!(
+            decompress("gzip", b"garbage", 100).unwrap_err(),
+            StatusCode::BadRequest
+        );
+        assert_eq!(
+            decompress("compress", data, 100).unwrap_err(),
+            StatusCode::UnsupportedMediaType
+        );
+
+        let mut encoder = flate2::write::DeflateEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        );
+        encoder.write_all(data).unwrap();
+        let raw_deflate = encoder.finish().unwrap();
+        assert_eq!(decompress("deflate", &raw_deflate, 100).unwrap(), data);
+    }
+}
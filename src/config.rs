@@ -0,0 +1,219 @@
+//! Server settings loaded from the environment or a TOML file.
+
+use crate::ServerBuilder;
+use anyhow::{anyhow, Context, Error};
+use fehler::throws;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Deployment settings that can be changed without recompiling. Every
+/// setting is optional; unset ones keep the [`ServerBuilder`] default.
+///
+/// In a TOML file the keys are the field names:
+/// ```toml
+/// address = "0.0.0.0:8080"
+/// read_timeout = 10
+/// max_connections = 256
+/// log_level = "info"
+/// ```
+///
+/// Example usage:
+/// ```no_run
+/// use anyhow::Error;
+/// use shs::{Server, ServerConfig};
+///
+/// let config = ServerConfig::from_toml("server.toml")?;
+/// let server: Server<Error> = config.builder("127.0.0.1:8080").build()?;
+/// # Ok::<(), Error>(())
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address to listen on, e.g. `"0.0.0.0:8080"`.
+    pub address: Option<String>,
+    /// See [`ServerBuilder::read_timeout`], in seconds. Zero disables
+    /// the timeout.
+    pub read_timeout: Option<u64>,
+    /// See [`ServerBuilder::header_timeout`], in seconds. Zero
+    /// disables the timeout.
+    pub header_timeout: Option<u64>,
+    /// See [`ServerBuilder::write_timeout`], in seconds. Zero disables
+    /// the timeout.
+    pub write_timeout: Option<u64>,
+    /// See [`ServerBuilder::max_connections`].
+    pub max_connections: Option<usize>,
+    /// See [`ServerBuilder::max_request_line_len`].
+    pub max_request_line_len: Option<usize>,
+    /// See [`ServerBuilder::max_header_bytes`].
+    pub max_header_bytes: Option<usize>,
+    /// See [`ServerBuilder::max_header_count`].
+    pub max_header_count: Option<usize>,
+    /// See [`ServerBuilder::max_decompressed_size`].
+    pub max_decompressed_size: Option<usize>,
+    /// See [`ServerBuilder::max_body_size`].
+    pub max_body_size: Option<usize>,
+    /// Log level such as `"info"` or `"debug"`. The server doesn't
+    /// set up logging itself; pass this to the logger of your choice,
+    /// or to `log::set_max_level`.
+    pub log_level: Option<LevelFilter>,
+}
+
+#[throws]
+fn env_var<T>(prefix: &str, name: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    let key = format!("{}{}", prefix, name.to_uppercase());
+    match env::var(&key) {
+        Ok(value) => Some(
+            value
+                .parse()
+                .map_err(|err| anyhow!("invalid value for {}: {}", key, err))?,
+        ),
+        Err(env::VarError::NotPresent) => None,
+        Err(err) => Err(err).with_context(|| format!("invalid {}", key))?,
+    }
+}
+
+impl ServerConfig {
+    /// Load settings from environment variables named after the
+    /// fields with an `SHS_` prefix, for example `SHS_ADDRESS` and
+    /// `SHS_MAX_CONNECTIONS`.
+    #[throws]
+    pub fn from_env() -> ServerConfig {
+        ServerConfig::from_env_with_prefix("SHS_")?
+    }
+
+    /// Same as [`ServerConfig::from_env`], but with a different prefix
+    /// for the variable names.
+    #[throws]
+    pub fn from_env_with_prefix(prefix: &str) -> ServerConfig {
+        ServerConfig {
+            address: env_var(prefix, "address")?,
+            read_timeout: env_var(prefix, "read_timeout")?,
+            header_timeout: env_var(prefix, "header_timeout")?,
+            write_timeout: env_var(prefix, "write_timeout")?,
+            max_connections: env_var(prefix, "max_connections")?,
+            max_request_line_len: env_var(prefix, "max_request_line_len")?,
+            max_header_bytes: env_var(prefix, "max_header_bytes")?,
+            max_header_count: env_var(prefix, "max_header_count")?,
+            max_decompressed_size: env_var(prefix, "max_decompressed_size")?,
+            max_body_size: env_var(prefix, "max_body_size")?,
+            log_level: env_var(prefix, "log_level")?,
+        }
+    }
+
+    /// Load settings from a TOML file. Unknown keys are an error, so
+    /// typos don't go unnoticed.
+    #[throws]
+    pub fn from_toml(path: impl AsRef<Path>) -> ServerConfig {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        ServerConfig::from_toml_str(&contents)
+            .with_context(|| format!("invalid config {}", path.display()))?
+    }
+
+    /// Parse settings from a TOML string.
+    #[throws]
+    pub fn from_toml_str(s: &str) -> ServerConfig {
+        toml::from_str(s)?
+    }
+
+    /// Create a builder with these settings, listening on `address`
+    /// or on `default_address` if no address was configured.
+    pub fn builder(&self, default_address: &str) -> ServerBuilder {
+        let address = self.address.as_deref().unwrap_or(default_address);
+        self.apply(ServerBuilder::new(address))
+    }
+
+    /// Apply the settings other than the address to `builder`.
+    pub fn apply(&self, mut builder: ServerBuilder) -> ServerBuilder {
+        let seconds = |secs| match secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        if let Some(secs) = self.read_timeout {
+            builder = builder.read_timeout(seconds(secs));
+        }
+        if let Some(secs) = self.header_timeout {
+            builder = builder.header_timeout(seconds(secs));
+        }
+        if let Some(secs) = self.write_timeout {
+            builder = builder.write_timeout(seconds(secs));
+        }
+        if let Some(max) = self.max_connections {
+            builder = builder.max_connections(Some(max));
+        }
+        if let Some(max) = self.max_request_line_len {
+            builder = builder.max_request_line_len(max);
+        }
+        if let Some(max) = self.max_header_bytes {
+            builder = builder.max_header_bytes(max);
+        }
+        if let Some(max) = self.max_header_count {
+            builder = builder.max_header_count(max);
+        }
+        if let Some(max) = self.max_decompressed_size {
+            builder = builder.max_decompressed_size(max);
+        }
+        if let Some(max) = self.max_body_size {
+            builder = builder.max_body_size(Some(max));
+        }
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_toml() {
+        let config = ServerConfig::from_toml_str(
+            r#"
+            address = "0.0.0.0:8080"
+            read_timeout = 0
+            max_connections = 16
+            log_level = "debug"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            ServerConfig {
+                address: Some("0.0.0.0:8080".into()),
+                read_timeout: Some(0),
+                max_connections: Some(16),
+                log_level: Some(LevelFilter::Debug),
+                ..ServerConfig::default()
+            }
+        );
+        config.builder("127.0.0.1:1").build::<Error>().unwrap();
+
+        assert!(ServerConfig::from_toml_str("adress = \"x\"").is_err());
+    }
+
+    #[test]
+    fn test_from_env() {
+        // A unique prefix keeps this from racing with other tests
+        env::set_var("SHS_TEST_ADDRESS", "127.0.0.1:9000");
+        env::set_var("SHS_TEST_MAX_HEADER_COUNT", "20");
+        env::set_var("SHS_TEST_LOG_LEVEL", "warn");
+        let config = ServerConfig::from_env_with_prefix("SHS_TEST_").unwrap();
+        assert_eq!(config.address.as_deref(), Some("127.0.0.1:9000"));
+        assert_eq!(config.max_header_count, Some(20));
+        assert_eq!(config.log_level, Some(LevelFilter::Warn));
+        assert_eq!(config.read_timeout, None);
+
+        env::set_var("SHS_TEST_MAX_HEADER_COUNT", "lots");
+        assert!(ServerConfig::from_env_with_prefix("SHS_TEST_").is_err());
+    }
+}
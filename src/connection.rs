@@ -0,0 +1,90 @@
+//! Noticing clients that went away while a handler is running.
+
+use std::io::{self, Read};
+use std::net::{Shutdown, TcpStream};
+
+/// Error for writes to a client that has closed the connection.
+/// Handlers that produce a response bit by bit can check for it, for
+/// example with `err.downcast_ref::<ClientDisconnected>()`, and stop
+/// working early.
+#[derive(Debug, thiserror::Error)]
+#[error("client disconnected")]
+pub struct ClientDisconnected;
+
+/// Whether a write failed because the client went away.
+pub(crate) fn is_disconnect(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// Check without blocking whether the peer is still connected. A peer
+/// that has closed or reset the connection, or half-closed it for
+/// writing, counts as gone.
+pub(crate) fn is_connected(stream: &TcpStream) -> bool {
+    // The request body has already been read, so the only data that
+    // can be waiting is a pipelined request; peek so it isn't lost
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let mut buf = [0; 1];
+    let connected = match stream.peek(&mut buf) {
+        Ok(0) => false,
+        Ok(_) => true,
+        Err(err) => err.kind() == io::ErrorKind::WouldBlock,
+    };
+    let _ = stream.set_nonblocking(false);
+    connected
+}
+
+/// Most data to discard in [`close_gracefully`].
+const DRAIN_LIMIT: usize = 64 * 1024;
+
+/// Close a connection after the response has been written. If the
+/// client pipelined more requests after the one handled, closing with
+/// them unread would make the kernel reset the connection, and the
+/// client could lose the response before reading it. So the write
+/// side is shut down first, then whatever has already arrived is read
+/// and discarded, without waiting for more.
+pub(crate) fn close_gracefully(stream: &TcpStream) {
+    if stream.shutdown(Shutdown::Write).is_err()
+        || stream.set_nonblocking(true).is_err()
+    {
+        return;
+    }
+    let mut buf = [0; 4096];
+    let mut drained = 0;
+    while drained < DRAIN_LIMIT {
+        match (&*stream).read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => drained += n,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_is_connected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _addr) = listener.accept().unwrap();
+        assert!(is_connected(&stream));
+
+        drop(client);
+        let start = Instant::now();
+        while is_connected(&stream) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,281 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// Value of the `SameSite` cookie attribute.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SameSite {
+    /// Only send the cookie with same-site requests.
+    Strict,
+    /// Also send the cookie when following a link to the site.
+    Lax,
+    /// Send the cookie with cross-site requests too. Browsers require
+    /// `Secure` with this.
+    None,
+}
+
+/// Cookie to send to the client with [`Request::set_cookie`].
+///
+/// The value is sent as-is, so it must only contain characters allowed
+/// in a cookie value (no whitespace, double quotes, commas,
+/// semicolons, or backslashes).
+///
+/// Example usage:
+/// ```
+/// use shs::{Cookie, SameSite};
+/// use std::time::Duration;
+///
+/// let cookie = Cookie::new("theme", "dark")
+///     .path(Some("/"))
+///     .max_age(Some(Duration::from_secs(3600)))
+///     .same_site(Some(SameSite::Lax));
+/// assert_eq!(
+///     cookie.to_string(),
+///     "theme=dark; Path=/; Max-Age=3600; HttpOnly; SameSite=Lax"
+/// );
+/// ```
+///
+/// [`Request::set_cookie`]: crate::Request::set_cookie
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    expires: Option<SystemTime>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    /// Create a cookie. `HttpOnly` is set by default; everything else
+    /// is unset.
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name: name.into(),
+            value: value.into(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: true,
+            same_site: None,
+        }
+    }
+
+    /// Get the cookie's name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the cookie's value.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Replace the cookie's value.
+    pub fn set_value(&mut self, value: &str) {
+        self.value = value.into();
+    }
+
+    /// Set the `Path` attribute.
+    pub fn path(mut self, path: Option<&str>) -> Cookie {
+        self.path = path.map(|v| v.into());
+        self
+    }
+
+    /// Set the `Domain` attribute.
+    pub fn domain(mut self, domain: Option<&str>) -> Cookie {
+        self.domain = domain.map(|v| v.into());
+        self
+    }
+
+    /// Set the `Max-Age` attribute. Without this or `Expires` the
+    /// cookie lasts until the browser is closed.
+    pub fn max_age(mut self, max_age: Option<Duration>) -> Cookie {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Set the `Expires` attribute.
+    pub fn expires(mut self, expires: Option<SystemTime>) -> Cookie {
+        self.expires = expires;
+        self
+    }
+
+    /// Set the `Secure` attribute, so that the cookie is only sent
+    /// over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Cookie {
+        self.secure = secure;
+        self
+    }
+
+    /// Set the `HttpOnly` attribute, so that the cookie isn't visible
+    /// to scripts.
+    pub fn http_only(mut self, http_only: bool) -> Cookie {
+        self.http_only = http_only;
+        self
+    }
+
+    /// Set the `SameSite` attribute.
+    pub fn same_site(mut self, same_site: Option<SameSite>) -> Cookie {
+        self.same_site = same_site;
+        self
+    }
+
+    /// Check if a client receiving this cookie should delete it rather
+    /// than store it.
+    pub(crate) fn is_expired(&self) -> bool {
+        self.max_age == Some(Duration::from_secs(0))
+            || self
+                .expires
+                .is_some_and(|expires| expires <= SystemTime::now())
+    }
+
+    /// Turn this into a cookie that tells the client to delete it.
+    pub(crate) fn into_removal(mut self) -> Cookie {
+        self.value.clear();
+        self.max_age = Some(Duration::from_secs(0));
+        self.expires = Some(SystemTime::UNIX_EPOCH);
+        self
+    }
+}
+
+/// Formats the cookie as the value of a `Set-Cookie` header.
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", httpdate::fmt_http_date(expires))?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={:?}", same_site)?;
+        }
+        Ok(())
+    }
+}
+
+/// Find a cookie in the value of a `Cookie` request header.
+pub(crate) fn find_cookie<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header.split(';').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        if key.trim() == name {
+            // Values may be quoted (RFC 6265 §4.1.1)
+            let value = value.trim();
+            Some(
+                value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value),
+            )
+        } else {
+            None
+        }
+    })
+}
+
+/// Parse the value of a `Set-Cookie` header. Unknown attributes are
+/// ignored, as browsers do (RFC 6265 §5.2).
+pub(crate) fn parse_set_cookie(header: &str) -> Option<Cookie> {
+    let mut parts = header.split(';');
+    let (name, value) = parts.next()?.split_once('=')?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    // Only the attributes present are set, so start without HttpOnly
+    let mut cookie = Cookie::new(name, value.trim()).http_only(false);
+    for attr in parts {
+        let (key, value) = match attr.split_once('=') {
+            Some((key, value)) => (key.trim(), Some(value.trim())),
+            None => (attr.trim(), None),
+        };
+        match (key.to_ascii_lowercase().as_str(), value) {
+            ("path", value) => cookie.path = value.map(Into::into),
+            ("domain", value) => cookie.domain = value.map(Into::into),
+            ("max-age", Some(value)) => {
+                // A zero or negative age means the cookie has expired
+                cookie.max_age = Some(Duration::from_secs(
+                    value.parse::<i64>().ok()?.max(0) as u64,
+                ));
+            }
+            ("expires", Some(value)) => {
+                cookie.expires = httpdate::parse_http_date(value).ok();
+            }
+            ("secure", _) => cookie.secure = true,
+            ("httponly", _) => cookie.http_only = true,
+            ("samesite", Some(value)) => {
+                cookie.same_site = match value.to_ascii_lowercase().as_str() {
+                    "strict" => Some(SameSite::Strict),
+                    "lax" => Some(SameSite::Lax),
+                    "none" => Some(SameSite::None),
+                    _ => None,
+                };
+            }
+            _ => {}
+        }
+    }
+    Some(cookie)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_set_cookie() {
+        let cookie = Cookie::new("theme", "dark")
+            .path(Some("/"))
+            .domain(Some("example.com"))
+            .max_age(Some(Duration::from_secs(3600)))
+            .expires(Some(SystemTime::UNIX_EPOCH))
+            .secure(true)
+            .same_site(Some(SameSite::Lax));
+        assert_eq!(parse_set_cookie(&cookie.to_string()), Some(cookie));
+
+        let cookie = parse_set_cookie("a=1;secure; Unknown=x").unwrap();
+        assert_eq!(cookie.to_string(), "a=1; Secure");
+        assert!(parse_set_cookie("novalue").is_none());
+        assert!(parse_set_cookie("=1").is_none());
+        assert!(parse_set_cookie("a=1; Max-Age=soon").is_none());
+
+        assert!(!parse_set_cookie("a=1; Max-Age=60").unwrap().is_expired());
+        assert!(Cookie::new("a", "1").into_removal().is_expired());
+    }
+
+    #[test]
+    fn test_find_cookie() {
+        let header = "a=1; b=\"two\";c=3";
+        assert_eq!(find_cookie(header, "a"), Some("1"));
+        assert_eq!(find_cookie(header, "b"), Some("two"));
+        assert_eq!(find_cookie(header, "c"), Some("3"));
+        assert_eq!(find_cookie(header, "d"), None);
+    }
+
+    #[test]
+    fn test_removal() {
+        let cookie = Cookie::new("a", "1").path(Some("/")).into_removal();
+        assert_eq!(
+            cookie.to_string(),
+            "a=; Path=/; Max-Age=0; Expires=Thu, 01 Jan 1970 00:00:00 GMT; \
+             HttpOnly"
+        );
+    }
+}
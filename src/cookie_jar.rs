@@ -0,0 +1,223 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, Error};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use fehler::{throw, throws};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Length of an AES-GCM nonce in bytes.
+const NONCE_LEN: usize = 12;
+
+/// Secret key used by [`CookieJar`] to sign and encrypt cookies.
+///
+/// Separate signing and encryption keys are derived from the master
+/// key, so the same master key can safely be used for both.
+#[derive(Clone)]
+pub struct Key {
+    signing: [u8; 32],
+    encryption: [u8; 32],
+}
+
+impl Key {
+    /// Create a key from secret bytes, for example loaded from a
+    /// config file so that cookies stay valid across restarts. At
+    /// least 32 bytes are required.
+    #[throws]
+    pub fn from_bytes(master: &[u8]) -> Key {
+        if master.len() < 32 {
+            throw!(anyhow!("cookie key must be at least 32 bytes"));
+        }
+        let derive = |label: &[u8]| {
+            let mut mac = <HmacSha256 as Mac>::new_from_slice(master)
+                .expect("HMAC accepts keys of any length");
+            mac.update(label);
+            let out: [u8; 32] = mac.finalize().into_bytes().into();
+            out
+        };
+        Key {
+            signing: derive(b"shs cookie signing"),
+            encryption: derive(b"shs cookie encryption"),
+        }
+    }
+
+    /// Generate a random key. Cookies signed with it become invalid
+    /// when the server restarts.
+    pub fn generate() -> Key {
+        Key::from_bytes(&Aes256Gcm::generate_key(OsRng))
+            .expect("generated key has the right length")
+    }
+
+    fn mac(&self, name: &str, value: &[u8]) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.signing)
+            .expect("HMAC accepts keys of any length");
+        // The name is included so that a value can't be moved to a
+        // different cookie
+        mac.update(name.as_bytes());
+        mac.update(b"=");
+        mac.update(value);
+        mac
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(&self.encryption.into())
+    }
+}
+
+/// Hide the key material in debug output.
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// Set of keys for signing and encrypting cookie values, so that the
+/// client can't tamper with them. Set it with
+/// [`Server::set_cookie_jar`], then use [`Request::signed_cookie`]
+/// and related methods.
+///
+/// Signed values can be read by the client but not changed. Private
+/// values are encrypted as well, so the client can neither read nor
+/// change them.
+///
+/// New cookies are always protected with the current key. To rotate
+/// keys, add the previous keys with [`CookieJar::old_key`]; cookies
+/// protected with them are still accepted until they expire.
+///
+/// Example usage:
+/// ```
+/// use shs::{CookieJar, Key};
+///
+/// let jar = CookieJar::new(Key::generate()).old_key(Key::generate());
+/// let signed = jar.sign("user", "alice");
+/// assert_eq!(jar.verify("user", &signed).as_deref(), Some("alice"));
+/// assert_eq!(jar.verify("other", &signed), None);
+/// ```
+///
+/// [`Server::set_cookie_jar`]: crate::Server::set_cookie_jar
+/// [`Request::signed_cookie`]: crate::Request::signed_cookie
+#[derive(Clone, Debug)]
+pub struct CookieJar {
+    /// Current key first, followed by old keys.
+    keys: Vec<Key>,
+}
+
+impl CookieJar {
+    /// Create a jar that protects cookies with `key`.
+    pub fn new(key: Key) -> CookieJar {
+        CookieJar { keys: vec![key] }
+    }
+
+    /// Add a previous key that is still accepted when reading
+    /// cookies, but never used for new ones.
+    pub fn old_key(mut self, key: Key) -> CookieJar {
+        self.keys.push(key);
+        self
+    }
+
+    /// Sign a cookie value. The result is safe to use as a cookie
+    /// value regardless of the characters in `value`.
+    pub fn sign(&self, name: &str, value: &str) -> String {
+        let tag = self.keys[0].mac(name, value.as_bytes()).finalize();
+        format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(tag.into_bytes()),
+            URL_SAFE_NO_PAD.encode(value)
+        )
+    }
+
+    /// Check a value produced by [`CookieJar::sign`], returning the
+    /// original value if the signature matches any key.
+    pub fn verify(&self, name: &str, signed: &str) -> Option<String> {
+        let (tag, value) = signed.split_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        let value = URL_SAFE_NO_PAD.decode(value).ok()?;
+        self.keys
+            .iter()
+            .find(|key| key.mac(name, &value).verify_slice(&tag).is_ok())?;
+        String::from_utf8(value).ok()
+    }
+
+    /// Encrypt and authenticate a cookie value.
+    pub fn encrypt(&self, name: &str, value: &str) -> String {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: value.as_bytes(),
+            aad: name.as_bytes(),
+        };
+        let ciphertext = self.keys[0]
+            .cipher()
+            .encrypt(&nonce, payload)
+            .expect("encrypting into a Vec can't fail");
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&ciphertext);
+        URL_SAFE_NO_PAD.encode(data)
+    }
+
+    /// Decrypt a value produced by [`CookieJar::encrypt`], trying
+    /// each key in turn.
+    pub fn decrypt(&self, name: &str, encrypted: &str) -> Option<String> {
+        let data = URL_SAFE_NO_PAD.decode(encrypted).ok()?;
+        if data.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let nonce = Nonce::from_slice(nonce);
+        let plaintext = self.keys.iter().find_map(|key| {
+            let payload = Payload {
+                msg: ciphertext,
+                aad: name.as_bytes(),
+            };
+            key.cipher().decrypt(nonce, payload).ok()
+        })?;
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        let jar = CookieJar::new(Key::from_bytes(&[1; 32]).unwrap());
+        let signed = jar.sign("a", "hello; world");
+        assert!(!signed.contains(';'));
+        assert_eq!(jar.verify("a", &signed).as_deref(), Some("hello; world"));
+
+        // Tampered value
+        let (tag, _) = signed.split_once('.').unwrap();
+        let forged = format!("{}.{}", tag, URL_SAFE_NO_PAD.encode("admin"));
+        assert_eq!(jar.verify("a", &forged), None);
+        assert_eq!(jar.verify("a", "garbage"), None);
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let jar = CookieJar::new(Key::generate());
+        let encrypted = jar.encrypt("a", "secret");
+        assert!(!encrypted.contains("secret"));
+        assert_eq!(jar.decrypt("a", &encrypted).as_deref(), Some("secret"));
+        assert_eq!(jar.decrypt("b", &encrypted), None);
+        assert_eq!(jar.decrypt("a", "AAAA"), None);
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old = Key::from_bytes(&[1; 32]).unwrap();
+        let signed = CookieJar::new(old.clone()).sign("a", "1");
+        let encrypted = CookieJar::new(old.clone()).encrypt("a", "2");
+
+        let jar = CookieJar::new(Key::from_bytes(&[2; 32]).unwrap());
+        assert_eq!(jar.verify("a", &signed), None);
+        let jar = jar.old_key(old);
+        assert_eq!(jar.verify("a", &signed).as_deref(), Some("1"));
+        assert_eq!(jar.decrypt("a", &encrypted).as_deref(), Some("2"));
+
+        assert!(Key::from_bytes(&[0; 16]).is_err());
+    }
+}
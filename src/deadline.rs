@@ -0,0 +1,99 @@
+use crate::stream::Connection;
+use std::io::{self, IoSlice, Read, Write};
+use std::time::{Duration, Instant};
+
+/// Wrapper around a connection that bounds each read by a timeout, and
+/// optionally bounds the total time spent reading by a deadline.
+///
+/// A per-read timeout alone isn't enough to stop a client that sends
+/// one byte just before each timeout expires; the deadline covers
+/// that case.
+pub(crate) struct DeadlineStream {
+    stream: Connection,
+    read_timeout: Option<Duration>,
+    deadline: Option<Instant>,
+}
+
+impl DeadlineStream {
+    pub(crate) fn new(
+        stream: Connection,
+        read_timeout: Option<Duration>,
+    ) -> DeadlineStream {
+        DeadlineStream {
+            stream,
+            read_timeout,
+            deadline: None,
+        }
+    }
+
+    /// Get the underlying connection.
+    pub(crate) fn get_mut(&mut self) -> &mut Connection {
+        &mut self.stream
+    }
+
+    /// Set or clear the point after which reads fail with
+    /// `ErrorKind::TimedOut`.
+    pub(crate) fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline {
+            Some(deadline) => {
+                let remaining =
+                    deadline.saturating_duration_since(Instant::now());
+                if remaining == Duration::from_secs(0) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "deadline expired",
+                    ));
+                }
+                Some(self.read_timeout.map_or(remaining, |t| t.min(remaining)))
+            }
+            None => self.read_timeout,
+        };
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.stream.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _addr) = listener.accept().unwrap();
+        let mut stream = DeadlineStream::new(Box::new(stream), None);
+
+        let start = Instant::now();
+        stream.set_deadline(Some(start + Duration::from_millis(50)));
+        let mut buf = [0; 1];
+        assert!(stream.read(&mut buf).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(
+            stream.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+}
@@ -0,0 +1,66 @@
+//! Route listing served by [`Server::enable_debug_routes`].
+//!
+//! [`Server::enable_debug_routes`]: crate::Server::enable_debug_routes
+
+use crate::html::escape_html;
+use crate::{HeaderName, Request, RouteInfo};
+use std::fmt::Write;
+
+fn render_html(routes: &[RouteInfo]) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><title>Routes</title></head><body>\n\
+         <table>\n<tr><th>Method</th><th>Pattern</th><th>Name</th>\
+         <th>Middleware</th><th>Guards</th></tr>\n",
+    );
+    for route in routes {
+        // Writing to a String can't fail
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape_html(&route.method),
+            escape_html(&route.pattern),
+            escape_html(route.name.as_deref().unwrap_or("")),
+            route.middleware,
+            route.guards,
+        );
+    }
+    html.push_str("</table>\n</body></html>\n");
+    html
+}
+
+/// Write the route listing as HTML if the client asks for it, as
+/// browsers do, and as JSON otherwise.
+pub(crate) fn write_listing(req: &mut Request, routes: &[RouteInfo]) {
+    let wants_html = req
+        .headers()
+        .get(&HeaderName::new("Accept".into()))
+        .is_some_and(|accept| accept.contains("text/html"));
+    if wants_html {
+        let html = render_html(routes);
+        req.write_text(&html);
+        req.set_content_type("text/html; charset=UTF-8");
+    } else if let Err(err) = req.write_json(&routes) {
+        log::error!("failed to serialize routes: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_html() {
+        let routes = vec![RouteInfo {
+            method: "GET".into(),
+            pattern: "/<script>".into(),
+            name: Some("a&b".into()),
+            middleware: 1,
+            guards: 0,
+        }];
+        let html = render_html(&routes);
+        assert!(html.contains(
+            "<tr><td>GET</td><td>/&lt;script&gt;</td><td>a&amp;b</td>\
+             <td>1</td><td>0</td></tr>"
+        ));
+    }
+}
@@ -0,0 +1,516 @@
+//! HTTP Digest access authentication (RFC 7616).
+
+use crate::token_auth::strip_scheme;
+use crate::{HeaderName, Next, Request, StatusCode};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Limit on outstanding nonces. Unauthenticated clients can create
+/// nonces, so this keeps them from growing the table without bound.
+const MAX_NONCES: usize = 10_000;
+
+/// Hash algorithm used by [`DigestAuth`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DigestAlgorithm {
+    /// `MD5`, for old clients.
+    Md5,
+    /// `SHA-256`.
+    Sha256,
+}
+
+impl DigestAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            DigestAlgorithm::Md5 => "MD5",
+            DigestAlgorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    /// Hash `data` and format it as lowercase hex.
+    fn hex_digest(self, data: &str) -> String {
+        match self {
+            DigestAlgorithm::Md5 => hex(&Md5::digest(data)),
+            DigestAlgorithm::Sha256 => hex(&Sha256::digest(data)),
+        }
+    }
+}
+
+/// Stored credential for a user, returned by the lookup function of
+/// [`DigestAuth`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DigestCredential {
+    /// Plain-text password.
+    Password(String),
+    /// Hex hash of `username:realm:password` computed with the
+    /// configured algorithm, so the password itself needn't be stored.
+    Ha1(String),
+}
+
+/// Name of a user authenticated by [`DigestAuth`], stored in the
+/// request's extensions.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DigestUser(pub String);
+
+type Lookup = dyn Fn(&str) -> Option<DigestCredential> + Send + Sync;
+
+struct NonceState {
+    issued: Instant,
+    /// Highest nonce count used so far, to reject replays.
+    count: u32,
+}
+
+enum Failure {
+    Invalid,
+    /// The nonce expired; the client can retry with a new one without
+    /// asking the user again.
+    Stale,
+}
+
+/// Middleware requiring Digest authentication, added to a route with
+/// [`RouteHandle::digest_auth`]. Unlike Basic authentication the
+/// password is never sent, which matters when TLS isn't available.
+///
+/// Only `qop="auth"` is supported. Nonces are kept in memory, each can
+/// be used until it expires, and nonce counts must increase so that
+/// requests can't be replayed. On success the user name is stored in
+/// the extensions as a [`DigestUser`].
+///
+/// Example usage:
+/// ```
+/// use shs::{DigestAlgorithm, DigestAuth, DigestCredential};
+///
+/// let auth = DigestAuth::new("admin@example.com", |username| {
+///     (username == "admin")
+///         .then(|| DigestCredential::Password("hunter2".into()))
+/// })
+/// .algorithm(DigestAlgorithm::Md5);
+/// ```
+///
+/// [`RouteHandle::digest_auth`]: crate::RouteHandle::digest_auth
+pub struct DigestAuth {
+    realm: String,
+    algorithm: DigestAlgorithm,
+    nonce_ttl: Duration,
+    opaque: String,
+    lookup: Box<Lookup>,
+    nonces: Mutex<HashMap<String, NonceState>>,
+}
+
+impl DigestAuth {
+    /// Create Digest authentication for `realm`. `lookup` gets the
+    /// credential for a user name, or `None` if there's no such user.
+    /// By default SHA-256 is used and nonces last five minutes.
+    pub fn new(
+        realm: &str,
+        lookup: impl Fn(&str) -> Option<DigestCredential> + Send + Sync + 'static,
+    ) -> DigestAuth {
+        DigestAuth {
+            realm: realm.into(),
+            algorithm: DigestAlgorithm::Sha256,
+            nonce_ttl: Duration::from_secs(5 * 60),
+            opaque: random_hex().unwrap_or_default(),
+            lookup: Box::new(lookup),
+            nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set the hash algorithm.
+    pub fn algorithm(mut self, algorithm: DigestAlgorithm) -> DigestAuth {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Set how long a nonce can be used.
+    pub fn nonce_ttl(mut self, ttl: Duration) -> DigestAuth {
+        self.nonce_ttl = ttl;
+        self
+    }
+
+    /// Check the request's credentials, continuing with `next` if they
+    /// are valid and responding with 401 otherwise.
+    pub fn run<E>(
+        &self,
+        req: &mut Request,
+        next: Next<'_, E>,
+    ) -> Result<(), E> {
+        let params = req
+            .headers()
+            .get(&HeaderName::new("Authorization".into()))
+            .and_then(|value| strip_scheme(value, "Digest"))
+            .map(parse_auth_params);
+        let stale = match params.map(|params| self.verify(req, &params)) {
+            Some(Ok(username)) => {
+                req.extensions_mut().insert(DigestUser(username));
+                return next.run(req);
+            }
+            Some(Err(Failure::Stale)) => true,
+            Some(Err(Failure::Invalid)) | None => false,
+        };
+        self.challenge(req, stale);
+        Ok(())
+    }
+
+    fn verify(
+        &self,
+        req: &Request,
+        params: &HashMap<String, String>,
+    ) -> Result<String, Failure> {
+        let param = |name: &str| {
+            params.get(name).map(String::as_str).ok_or(Failure::Invalid)
+        };
+        let username = param("username")?;
+        let nonce = param("nonce")?;
+        let uri = param("uri")?;
+        let cnonce = param("cnonce")?;
+        let nc = param("nc")?;
+        // MD5 is the default when no algorithm is given
+        let algorithm = params.get("algorithm").map_or("MD5", String::as_str);
+        if param("realm")? != self.realm
+            || param("qop")? != "auth"
+            || params.get("opaque").map(String::as_str) != Some(&self.opaque)
+            || !algorithm.eq_ignore_ascii_case(self.algorithm.name())
+            || uri != request_uri(req)
+        {
+            return Err(Failure::Invalid);
+        }
+        let count =
+            u32::from_str_radix(nc, 16).map_err(|_| Failure::Invalid)?;
+
+        // The lock is released before the lookup, which may be slow,
+        // so that it doesn't hold up every other request
+        {
+            let mut nonces = self.nonces.lock().unwrap();
+            let state = nonces.get(nonce).ok_or(Failure::Stale)?;
+            if state.issued.elapsed() > self.nonce_ttl {
+                nonces.remove(nonce);
+                return Err(Failure::Stale);
+            }
+            if count <= state.count {
+                return Err(Failure::Invalid);
+            }
+        }
+
+        let ha1 = match (self.lookup)(username).ok_or(Failure::Invalid)? {
+            DigestCredential::Password(password) => self.algorithm.hex_digest(
+                &format!("{}:{}:{}", username, self.realm, password),
+            ),
+            DigestCredential::Ha1(ha1) => ha1.to_ascii_lowercase(),
+        };
+        let expected =
+            response(self.algorithm, &ha1, nonce, nc, cnonce, &req.method, uri);
+        if !constant_time_eq(expected.as_bytes(), param("response")?.as_bytes())
+        {
+            return Err(Failure::Invalid);
+        }
+        // Only a valid response advances the count, so a forged one
+        // can't use up the nonce. Check the count again in case a
+        // request with the same count was accepted during the lookup.
+        let mut nonces = self.nonces.lock().unwrap();
+        match nonces.get_mut(nonce) {
+            Some(state) if count > state.count => state.count = count,
+            Some(_) => return Err(Failure::Invalid),
+            None => return Err(Failure::Stale),
+        }
+        Ok(username.into())
+    }
+
+    fn new_nonce(&self) -> Option<String> {
+        let nonce = random_hex()?;
+        let mut nonces = self.nonces.lock().unwrap();
+        if nonces.len() >= MAX_NONCES {
+            nonces.retain(|_, state| state.issued.elapsed() <= self.nonce_ttl);
+        }
+        if nonces.len() >= MAX_NONCES {
+            let oldest = nonces
+                .iter()
+                .min_by_key(|(_, state)| state.issued)
+                .map(|(nonce, _)| nonce.clone());
+            if let Some(oldest) = oldest {
+                nonces.remove(&oldest);
+            }
+        }
+        nonces.insert(
+            nonce.clone(),
+            NonceState {
+                issued: Instant::now(),
+                count: 0,
+            },
+        );
+        Some(nonce)
+    }
+
+    fn challenge(&self, req: &mut Request, stale: bool) {
+        let nonce = match self.new_nonce() {
+            Some(nonce) => nonce,
+            None => {
+                log::error!("failed to generate a digest nonce");
+                req.set_status(StatusCode::InternalServerError);
+                return;
+            }
+        };
+        let mut challenge = format!(
+            "Digest realm=\"{}\", qop=\"auth\", algorithm={}, nonce=\"{}\", \
+             opaque=\"{}\"",
+            quote(&self.realm),
+            self.algorithm.name(),
+            nonce,
+            self.opaque
+        );
+        if stale {
+            challenge.push_str(", stale=true");
+        }
+        req.set_status(StatusCode::Unauthorized);
+        req.set_header("WWW-Authenticate", &challenge);
+    }
+}
+
+/// Compute the expected `response` parameter for `qop="auth"`.
+fn response(
+    algorithm: DigestAlgorithm,
+    ha1: &str,
+    nonce: &str,
+    nc: &str,
+    cnonce: &str,
+    method: &str,
+    uri: &str,
+) -> String {
+    let ha2 = algorithm.hex_digest(&format!("{}:{}", method, uri));
+    algorithm.hex_digest(&format!(
+        "{}:{}:{}:{}:auth:{}",
+        ha1, nonce, nc, cnonce, ha2
+    ))
+}
+
+/// Get the request target as the client would have sent it.
+fn request_uri(req: &Request) -> String {
+    match req.url().query() {
+        Some(query) => format!("{}?{}", req.url().path(), query),
+        None => req.url().path().into(),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(s, "{:02x}", b);
+    }
+    s
+}
+
+fn random_hex() -> Option<String> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).ok()?;
+    Some(hex(&bytes))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn quote(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Parse comma-separated `name=value` pairs, where values may be
+/// quoted strings. Names are lowercased.
+pub(crate) fn parse_auth_params(s: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|&c| c == ',' || c.is_whitespace()) {
+            chars.next();
+        }
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|&c| c != '=' && c != ',') {
+            name.push(c);
+        }
+        if chars.next() != Some('=') {
+            if chars.peek().is_none() {
+                return params;
+            }
+            continue;
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|&c| c != ',') {
+                value.push(c);
+            }
+        }
+        params.insert(
+            name.trim().to_ascii_lowercase(),
+            value.trim_end().to_string(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Server, TestRequest};
+    use anyhow::Error;
+    use fehler::throws;
+    use std::sync::OnceLock;
+
+    #[test]
+    fn test_parse_auth_params() {
+        let params = parse_auth_params(
+            r#"username="a\"b", realm="x, y",nc=00000001 , qop=auth"#,
+        );
+        assert_eq!(params["username"], "a\"b");
+        assert_eq!(params["realm"], "x, y");
+        assert_eq!(params["nc"], "00000001");
+        assert_eq!(params["qop"], "auth");
+    }
+
+    #[test]
+    fn test_response() {
+        // Example from RFC 7616 §3.9.1
+        let nonce = "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v";
+        let cnonce = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+        let expected = [
+            (DigestAlgorithm::Md5, "8ca523f5e9506fed4657c9700eebdbec"),
+            (
+                DigestAlgorithm::Sha256,
+                "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1",
+            ),
+        ];
+        for (algorithm, expected) in expected {
+            let ha1 = algorithm
+                .hex_digest("Mufasa:http-auth@example.org:Circle of Life");
+            assert_eq!(
+                response(
+                    algorithm,
+                    &ha1,
+                    nonce,
+                    "00000001",
+                    cnonce,
+                    "GET",
+                    "/dir/index.html"
+                ),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_lookup_without_lock() {
+        static AUTH: OnceLock<DigestAuth> = OnceLock::new();
+        let auth = AUTH.get_or_init(|| {
+            DigestAuth::new("test", |_| {
+                assert!(AUTH.get().unwrap().nonces.try_lock().is_ok());
+                Some(DigestCredential::Password("secret".into()))
+            })
+        });
+
+        let nonce = auth.new_nonce().unwrap();
+        let ha1 = DigestAlgorithm::Sha256.hex_digest("alice:test:secret");
+        let response = response(
+            DigestAlgorithm::Sha256,
+            &ha1,
+            &nonce,
+            "00000001",
+            "abc",
+            "GET",
+            "/",
+        );
+        let params: HashMap<String, String> = [
+            ("username", "alice"),
+            ("realm", "test"),
+            ("nonce", &nonce),
+            ("uri", "/"),
+            ("qop", "auth"),
+            ("nc", "00000001"),
+            ("cnonce", "abc"),
+            ("response", &response),
+            ("opaque", &auth.opaque),
+            ("algorithm", "SHA-256"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        let req = crate::test_req("GET", "/", &[]);
+        assert!(auth.verify(&req, &params).is_ok());
+        assert!(auth.verify(&req, &params).is_err());
+    }
+
+    #[test]
+    fn test_digest_auth() {
+        #[throws]
+        fn whoami(req: &mut Request) {
+            let user = req.extensions().get::<DigestUser>().unwrap().clone();
+            req.write_text(&user.0);
+        }
+
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /whoami", &whoami).unwrap().digest_auth(
+            DigestAuth::new("test", |username| {
+                (username == "alice")
+                    .then(|| DigestCredential::Password("secret".into()))
+            }),
+        );
+
+        let resp =
+            server.test_request(&TestRequest::new("GET /whoami").unwrap());
+        let resp = resp.unwrap();
+        assert_eq!(resp.status, StatusCode::Unauthorized);
+        let challenge =
+            &resp.headers[&HeaderName::new("WWW-Authenticate".into())];
+        let params =
+            parse_auth_params(strip_scheme(challenge, "Digest").unwrap());
+        assert_eq!(params["algorithm"], "SHA-256");
+
+        let authorize = |nc: &str, password: &str| {
+            let ha1 = DigestAlgorithm::Sha256
+                .hex_digest(&format!("alice:test:{}", password));
+            let response = response(
+                DigestAlgorithm::Sha256,
+                &ha1,
+                &params["nonce"],
+                nc,
+                "abc",
+                "GET",
+                "/whoami",
+            );
+            let mut req = TestRequest::new("GET /whoami").unwrap();
+            req.set_header(
+                "Authorization",
+                &format!(
+                    "Digest username=\"alice\", realm=\"test\", \
+                     nonce=\"{}\", uri=\"/whoami\", qop=auth, nc={}, \
+                     cnonce=\"abc\", response=\"{}\", opaque=\"{}\", \
+                     algorithm=SHA-256",
+                    params["nonce"], nc, response, params["opaque"]
+                ),
+            );
+            server.test_request(&req).unwrap()
+        };
+
+        let resp = authorize("00000001", "secret");
+        assert_eq!(resp.status, StatusCode::Ok);
+        assert_eq!(resp.body, b"alice");
+        // Replaying the same nonce count fails
+        assert_eq!(
+            authorize("00000001", "secret").status,
+            StatusCode::Unauthorized
+        );
+        assert_eq!(
+            authorize("00000002", "wrong").status,
+            StatusCode::Unauthorized
+        );
+        assert_eq!(authorize("00000002", "secret").status, StatusCode::Ok);
+    }
+}
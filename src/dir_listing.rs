@@ -0,0 +1,239 @@
+//! Listings of directory contents, enabled with
+//! [`FileOptions::listing`].
+//!
+//! [`FileOptions::listing`]: crate::FileOptions::listing
+
+use crate::html::escape_html;
+use crate::pattern::SEGMENT;
+use crate::{HeaderName, Request};
+use anyhow::Error;
+use fehler::throws;
+use percent_encoding::utf8_percent_encode;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    name: String,
+    dir: bool,
+    size: u64,
+    /// HTTP date.
+    modified: Option<String>,
+    #[serde(skip)]
+    mtime: Option<SystemTime>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+/// Read the entries of `dir`. Hidden files are left out, as are
+/// symlinks unless `follow_symlinks` is set.
+#[throws]
+fn read_entries(dir: &Path, follow_symlinks: bool) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) if !name.starts_with('.') => name,
+            _ => continue,
+        };
+        let mut metadata = entry.metadata()?;
+        if metadata.file_type().is_symlink() {
+            if !follow_symlinks {
+                continue;
+            }
+            match fs::metadata(entry.path()) {
+                Ok(target) => metadata = target,
+                // Dangling link
+                Err(_) => continue,
+            }
+        }
+        let mtime = metadata.modified().ok();
+        entries.push(Entry {
+            name,
+            dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: mtime.map(httpdate::fmt_http_date),
+            mtime,
+        });
+    }
+    entries
+}
+
+/// Sort with directories first, then by `key`, breaking ties by name.
+fn sort_entries(entries: &mut [Entry], key: SortKey, descending: bool) {
+    entries.sort_by(|a, b| {
+        let order = match key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.mtime.cmp(&b.mtime),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+        let order = if descending { order.reverse() } else { order };
+        b.dir.cmp(&a.dir).then(order)
+    });
+}
+
+fn render_html(title: &str, base: &str, entries: &[Entry]) -> String {
+    let title = escape_html(title);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><title>Index of {0}</title></head>\
+         <body>\n<h1>Index of {0}</h1>\n<table>\n<tr>\
+         <th><a href=\"?sort=name\">Name</a></th>\
+         <th><a href=\"?sort=size&amp;order=desc\">Size</a></th>\
+         <th><a href=\"?sort=modified&amp;order=desc\">Modified</a></th>\
+         </tr>\n",
+        title
+    );
+    if base != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td></tr>\n");
+    }
+    for entry in entries {
+        let slash = if entry.dir { "/" } else { "" };
+        let size = if entry.dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        // Writing to a String can't fail
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+            escape_html(base),
+            utf8_percent_encode(&entry.name, SEGMENT),
+            slash,
+            escape_html(&entry.name),
+            slash,
+            size,
+            entry.modified.as_deref().unwrap_or("-"),
+        );
+    }
+    html.push_str("</table>\n</body></html>\n");
+    html
+}
+
+/// Write a listing of `dir` as HTML if the client asks for it, as
+/// browsers do, and as JSON otherwise. The `sort` query parameter
+/// picks the column (`name`, `size`, or `modified`) and `order=desc`
+/// reverses it.
+#[throws]
+pub(crate) fn write_listing(
+    req: &mut Request,
+    dir: &Path,
+    follow_symlinks: bool,
+) {
+    let mut entries = read_entries(dir, follow_symlinks)?;
+    let mut key = SortKey::Name;
+    let mut descending = false;
+    for (name, value) in req.url().query_pairs() {
+        match (&*name, &*value) {
+            ("sort", "size") => key = SortKey::Size,
+            ("sort", "modified") => key = SortKey::Modified,
+            ("order", "desc") => descending = true,
+            _ => {}
+        }
+    }
+    sort_entries(&mut entries, key, descending);
+
+    // Caches must not send the JSON listing to browsers, or the HTML
+    // one to scripts
+    let vary = match req.resp_header("Vary") {
+        Some(vary) => format!("{}, Accept", vary),
+        None => "Accept".into(),
+    };
+    req.set_header("Vary", &vary);
+    let wants_html = req
+        .headers()
+        .get(&HeaderName::new("Accept".into()))
+        .is_some_and(|accept| accept.contains("text/html"));
+    if wants_html {
+        let path = req.url().path();
+        let base = if path.ends_with('/') {
+            path.to_string()
+        } else {
+            format!("{}/", path)
+        };
+        let title = percent_encoding::percent_decode_str(&base)
+            .decode_utf8_lossy()
+            .into_owned();
+        let html = render_html(&title, &base, &entries);
+        req.write_text(&html);
+        req.set_content_type("text/html; charset=UTF-8");
+    } else {
+        req.write_json(&entries)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn entry(name: &str, dir: bool, size: u64, secs: u64) -> Entry {
+        Entry {
+            name: name.into(),
+            dir,
+            size,
+            modified: None,
+            mtime: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_sort_entries() {
+        let mut entries = vec![
+            entry("b.txt", false, 1, 30),
+            entry("sub", true, 0, 10),
+            entry("a.txt", false, 5, 20),
+        ];
+        sort_entries(&mut entries, SortKey::Name, false);
+        assert_eq!(names(&entries), ["sub", "a.txt", "b.txt"]);
+        sort_entries(&mut entries, SortKey::Size, true);
+        assert_eq!(names(&entries), ["sub", "a.txt", "b.txt"]);
+        sort_entries(&mut entries, SortKey::Modified, true);
+        assert_eq!(names(&entries), ["sub", "b.txt", "a.txt"]);
+        sort_entries(&mut entries, SortKey::Name, true);
+        assert_eq!(names(&entries), ["sub", "b.txt", "a.txt"]);
+    }
+
+    #[test]
+    fn test_render_html() {
+        let entries =
+            vec![entry("sub", true, 0, 0), entry("a <b>.txt", false, 3, 0)];
+        let html = render_html("/files/", "/files/", &entries);
+        assert!(html.contains("<title>Index of /files/</title>"));
+        assert!(html.contains("<a href=\"../\">"));
+        assert!(html.contains(
+            "<tr><td><a href=\"/files/sub/\">sub/</a></td><td>-</td>"
+        ));
+        assert!(html.contains(
+            "<a href=\"/files/a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a></td>\
+             <td>3</td>"
+        ));
+    }
+
+    #[test]
+    fn test_read_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "abc").unwrap();
+        fs::write(dir.path().join(".hidden"), "").unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let mut entries = read_entries(dir.path(), false).unwrap();
+        sort_entries(&mut entries, SortKey::Name, false);
+        assert_eq!(names(&entries), ["sub", "a.txt"]);
+        assert_eq!(entries[1].size, 3);
+        assert!(entries[1].modified.is_some());
+    }
+}
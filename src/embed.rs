@@ -0,0 +1,210 @@
+//! Serving files compiled into the binary.
+
+use crate::{mime, HeaderName, Request, RouteHandle, Server, StatusCode};
+use anyhow::Error;
+use fehler::throws;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+
+struct Asset {
+    contents: &'static [u8],
+    content_type: &'static str,
+    etag: String,
+}
+
+/// Files embedded in the binary, served with
+/// [`Server::serve_embedded`]. Paths are relative to the mount point
+/// and use `/` as the separator.
+///
+/// Example usage:
+/// ```
+/// use shs::EmbeddedAssets;
+///
+/// let assets = EmbeddedAssets::new()
+///     .add("index.html", b"<h1>hello</h1>")
+///     .add("css/site.css", include_bytes!("../README.md"));
+/// ```
+///
+/// With the `include-dir` feature, a whole directory can be embedded
+/// with the [`include_dir`](https://docs.rs/include_dir) crate by
+/// converting a `Dir`: `EmbeddedAssets::from(&STATIC)`, where
+/// `STATIC` is declared with `include_dir!("static")`.
+#[derive(Default)]
+pub struct EmbeddedAssets {
+    assets: HashMap<String, Asset>,
+}
+
+impl EmbeddedAssets {
+    /// Create an empty set of assets.
+    pub fn new() -> EmbeddedAssets {
+        EmbeddedAssets::default()
+    }
+
+    /// Add a file. The content type is guessed with [`mime::guess`],
+    /// and the `ETag` is a hash of the contents.
+    pub fn add(
+        mut self,
+        path: &str,
+        contents: &'static [u8],
+    ) -> EmbeddedAssets {
+        let content_type = mime::guess(path, contents);
+        let asset = Asset {
+            contents,
+            content_type,
+            etag: format!("\"{:016x}\"", fnv1a(contents)),
+        };
+        self.assets
+            .insert(path.trim_start_matches('/').into(), asset);
+        self
+    }
+
+    /// Number of files.
+    pub fn len(&self) -> usize {
+        self.assets.len()
+    }
+
+    /// Whether there are no files.
+    pub fn is_empty(&self) -> bool {
+        self.assets.is_empty()
+    }
+
+    /// Find the asset for a path below the mount point. A directory
+    /// path, including the mount point itself, serves its
+    /// `index.html`.
+    fn get(&self, path: &str) -> Option<&Asset> {
+        if path.is_empty() || path.ends_with('/') {
+            self.assets.get(&format!("{}index.html", path))
+        } else {
+            self.assets.get(path)
+        }
+    }
+
+    fn write(&self, req: &mut Request) {
+        let path: String = req.path_param("path").unwrap_or_default();
+        let asset = match self.get(&path) {
+            Some(asset) => asset,
+            None => {
+                req.set_not_found();
+                req.write_text("not found");
+                return;
+            }
+        };
+        req.set_header("ETag", &asset.etag);
+        if etag_matches(req, &asset.etag) {
+            req.set_status(StatusCode::NotModified);
+            return;
+        }
+        req.write_bytes(asset.contents);
+        req.set_content_type(asset.content_type);
+    }
+}
+
+#[cfg(feature = "include-dir")]
+impl From<&'static include_dir::Dir<'static>> for EmbeddedAssets {
+    fn from(dir: &'static include_dir::Dir<'static>) -> EmbeddedAssets {
+        fn add_dir(
+            assets: EmbeddedAssets,
+            dir: &'static include_dir::Dir<'static>,
+        ) -> EmbeddedAssets {
+            let assets = dir.files().fold(assets, |assets, file| {
+                let path = file.path().to_string_lossy().replace('\\', "/");
+                assets.add(&path, file.contents())
+            });
+            dir.dirs().fold(assets, add_dir)
+        }
+        add_dir(EmbeddedAssets::new(), dir)
+    }
+}
+
+/// 64-bit FNV-1a. It's stable across builds and platforms, unlike
+/// `DefaultHasher`, so ETags stay valid when the binary changes
+/// without the file changing.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Check if `If-None-Match` lists `etag` or is `*`. Weak tags match
+/// too, as required for `If-None-Match`.
+fn etag_matches(req: &Request, etag: &str) -> bool {
+    let name = HeaderName::new("If-None-Match".into());
+    req.req_headers.get(&name).is_some_and(|value| {
+        value
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    })
+}
+
+impl<E: Debug + Display + 'static> Server<E> {
+    /// Serve `assets` under the path `prefix`, for example
+    /// `"/assets"`. Responses have an `ETag` and the request's
+    /// `If-None-Match` is checked, so clients can cache them.
+    #[throws]
+    pub fn serve_embedded(
+        &mut self,
+        prefix: &str,
+        assets: EmbeddedAssets,
+    ) -> RouteHandle<'_, E> {
+        let route = format!("GET {}/*path", prefix.trim_end_matches('/'));
+        let handler = move |req: &mut Request| {
+            assets.write(req);
+            Ok(())
+        };
+        let id = self.routes.add(&route, Box::new(handler))?;
+        RouteHandle { server: self, id }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TestRequest, TestResponse};
+
+    #[test]
+    fn test_serve_embedded() {
+        let mut server = Server::<Error>::new("127.0.0.1:1234").unwrap();
+        let assets = EmbeddedAssets::new()
+            .add("index.html", b"<h1>home</h1>")
+            .add("css/site.css", b"body {}")
+            .add("data.bin", b"\x00\x01");
+        server.serve_embedded("/assets/", assets).unwrap();
+
+        let get = |path: &str| {
+            let req = TestRequest::new(&format!("GET {}", path)).unwrap();
+            server.test_request(&req).unwrap()
+        };
+        let header = |resp: &TestResponse, name: &str| {
+            resp.headers.get(&HeaderName::new(name.into())).cloned()
+        };
+        let resp = get("/assets/css/site.css");
+        assert_eq!(resp.status, StatusCode::Ok);
+        assert_eq!(resp.body, b"body {}");
+        assert_eq!(
+            header(&resp, "Content-Type").unwrap(),
+            "text/css; charset=UTF-8"
+        );
+        let etag = header(&resp, "ETag").unwrap();
+        assert_eq!(etag, format!("\"{:016x}\"", fnv1a(b"body {}")));
+
+        assert_eq!(get("/assets/").body, b"<h1>home</h1>");
+        assert_eq!(
+            header(&get("/assets/data.bin"), "Content-Type").unwrap(),
+            "application/octet-stream"
+        );
+        assert_eq!(get("/assets/missing.js").status, StatusCode::NotFound);
+
+        let mut req = TestRequest::new("GET /assets/css/site.css").unwrap();
+        req.set_header("If-None-Match", &format!("W/{}", etag));
+        let resp = server.test_request(&req).unwrap();
+        assert_eq!(resp.status, StatusCode::NotModified);
+        assert!(resp.body.is_empty());
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
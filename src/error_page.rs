@@ -0,0 +1,95 @@
+//! HTML pages for error responses that have no body of their own.
+
+use crate::{Request, Server, StatusCode};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+
+/// Statuses that get a built-in page.
+const BUILT_IN: &[StatusCode] = &[
+    StatusCode::NotFound,
+    StatusCode::MethodNotAllowed,
+    StatusCode::PayloadTooLarge,
+    StatusCode::InternalServerError,
+];
+
+fn built_in_page(status: StatusCode) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{code} {reason}</title></head>\n\
+         <body>\n<h1>{code} {reason}</h1>\n</body>\n</html>\n",
+        code = status,
+        reason = status.canonical_reason()
+    )
+}
+
+/// The built-in pages, used unless replaced with
+/// [`Server::set_error_page`].
+pub(crate) fn default_pages() -> HashMap<StatusCode, String> {
+    BUILT_IN
+        .iter()
+        .map(|&status| (status, built_in_page(status)))
+        .collect()
+}
+
+impl Request {
+    /// Fill in the page for an error status if nothing else wrote a
+    /// body.
+    pub(crate) fn apply_error_page(
+        &mut self,
+        pages: &HashMap<StatusCode, String>,
+    ) {
+        if self.streamed || self.resp_body.len() != 0 {
+            return;
+        }
+        if let Some(page) = pages.get(&self.status) {
+            self.write_bytes(page.as_bytes());
+            self.set_content_type("text/html; charset=utf-8");
+        }
+    }
+}
+
+impl<E: Debug + Display> Server<E> {
+    /// Set the HTML page sent for `status` when neither the handler
+    /// nor the error handler wrote a body, replacing the built-in page
+    /// if there is one. Built-in pages are provided for 404, 405, 413,
+    /// and 500.
+    pub fn set_error_page(&mut self, status: StatusCode, content: &str) {
+        self.settings.error_pages.insert(status, content.into());
+    }
+
+    /// Stop sending a page for `status`, so that those responses have
+    /// an empty body unless one is written.
+    pub fn remove_error_page(&mut self, status: StatusCode) {
+        self.settings.error_pages.remove(&status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_error_page() {
+        let pages = default_pages();
+        let mut req = crate::test_req("GET", "/", &[]);
+        req.set_status(StatusCode::NotFound);
+        req.apply_error_page(&pages);
+        let body = req.resp_body.peek(1000).unwrap();
+        assert!(String::from_utf8(body)
+            .unwrap()
+            .contains("<h1>404 Not Found</h1>"));
+        assert_eq!(
+            req.resp_header("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+
+        // A body that was already written is kept
+        req.write_text("gone fishing");
+        req.apply_error_page(&pages);
+        assert_eq!(req.resp_body.peek(100).unwrap(), b"gone fishing");
+
+        req.write_bytes(b"");
+        req.set_status(StatusCode::Forbidden);
+        req.apply_error_page(&pages);
+        assert_eq!(req.resp_body.len(), 0);
+    }
+}
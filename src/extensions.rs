@@ -0,0 +1,88 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Type map holding at most one value of each type.
+///
+/// Each [`Request`] has one of these so that middleware can pass data,
+/// such as an authenticated user or a request ID, on to handlers.
+///
+/// [`Request`]: crate::Request
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// Create an empty `Extensions`.
+    pub fn new() -> Extensions {
+        Extensions::default()
+    }
+
+    /// Insert a value, returning the previous value of the same type
+    /// if there was one.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+
+    /// Get a reference to the value of type `T`.
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    /// Get a mutable reference to the value of type `T`.
+    pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    /// Remove and return the value of type `T`.
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+
+    /// Remove all values.
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct User(String);
+
+    #[test]
+    fn test_extensions() {
+        let mut ext = Extensions::new();
+        assert!(ext.get::<User>().is_none());
+
+        assert!(ext.insert(User("a".into())).is_none());
+        ext.insert(5u32);
+        assert_eq!(ext.insert(User("b".into())), Some(User("a".into())));
+        assert_eq!(ext.get::<User>(), Some(&User("b".into())));
+
+        *ext.get_mut::<u32>().unwrap() += 1;
+        assert_eq!(ext.remove::<u32>(), Some(6));
+        assert!(ext.get::<u32>().is_none());
+    }
+}
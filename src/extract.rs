@@ -0,0 +1,255 @@
+use crate::{Request, StatusCode};
+use log::error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Shared state added with [`Server::add_state`], keyed by type.
+///
+/// [`Server::add_state`]: crate::Server::add_state
+pub(crate) type StateMap = HashMap<TypeId, Arc<dyn Any + Send + Sync>>;
+
+/// Reason a handler argument couldn't be extracted from a request. The
+/// handler isn't called; instead the status and message are sent as
+/// the response.
+///
+/// Handlers can also fail with a `Rejection`, or an [`anyhow::Error`]
+/// wrapping one, to send it the same way through the built-in error
+/// handlers. [`Request::multipart`] does this for malformed bodies.
+#[derive(Clone, Debug, Eq, PartialEq, thiserror::Error)]
+#[error("{status}: {message}")]
+pub struct Rejection {
+    /// Response status.
+    pub status: StatusCode,
+
+    /// Response body.
+    pub message: String,
+}
+
+impl Rejection {
+    /// Create a new rejection.
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Rejection {
+        Rejection {
+            status,
+            message: message.into(),
+        }
+    }
+
+    /// Get the rejection from a handler error that is one, or that is
+    /// an [`anyhow::Error`] wrapping one.
+    pub(crate) fn find<E: Any>(err: &E) -> Option<&Rejection> {
+        let err = err as &dyn Any;
+        err.downcast_ref::<Rejection>().or_else(|| {
+            err.downcast_ref::<anyhow::Error>()?
+                .downcast_ref::<Rejection>()
+        })
+    }
+}
+
+/// Type that can be used as an argument of a handler added with
+/// [`Server::typed_route`].
+///
+/// [`Server::typed_route`]: crate::Server::typed_route
+pub trait FromRequest: Sized {
+    /// Extract the value from the request.
+    fn from_request(req: &Request) -> Result<Self, Rejection>;
+}
+
+/// Type that can be returned by a handler added with
+/// [`Server::typed_route`].
+///
+/// [`Server::typed_route`]: crate::Server::typed_route
+pub trait IntoResponse {
+    /// Write the value into the response.
+    fn write_response(self, req: &mut Request);
+}
+
+/// Request or response body as JSON.
+///
+/// As an argument, the request body is deserialized, and the request
+/// is rejected with `400 Bad Request` if that fails. As a return
+/// value, the response body is set with [`Request::write_json`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(req: &Request) -> Result<Self, Rejection> {
+        serde_json::from_slice(req.body()).map(Json).map_err(|err| {
+            Rejection::new(
+                StatusCode::BadRequest,
+                format!("invalid JSON: {}", err),
+            )
+        })
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn write_response(self, req: &mut Request) {
+        if let Err(err) = req.write_json(&self.0) {
+            error!("failed to serialize response: {}", err);
+            req.write_status_body(StatusCode::InternalServerError);
+        }
+    }
+}
+
+/// The route's single path parameter, parsed with `FromStr`.
+///
+/// The request is rejected with `400 Bad Request` if parsing fails.
+/// For routes with more than one parameter use [`PathParams`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PathParam<T>(pub T);
+
+impl<T: FromStr> FromRequest for PathParam<T>
+where
+    T::Err: Display,
+{
+    fn from_request(req: &Request) -> Result<Self, Rejection> {
+        let mut values = req.path_params.values();
+        let value = match (values.next(), values.next()) {
+            (Some(value), None) => value,
+            _ => {
+                return Err(Rejection::new(
+                    StatusCode::InternalServerError,
+                    "PathParam requires a route with exactly one parameter",
+                ))
+            }
+        };
+        value.parse().map(PathParam).map_err(|err| {
+            Rejection::new(
+                StatusCode::BadRequest,
+                format!("invalid path parameter {}: {}", value, err),
+            )
+        })
+    }
+}
+
+/// All path parameters, keyed by name.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PathParams(pub HashMap<String, String>);
+
+impl FromRequest for PathParams {
+    fn from_request(req: &Request) -> Result<Self, Rejection> {
+        Ok(PathParams(req.path_params.clone()))
+    }
+}
+
+/// Shared state added with [`Server::add_state`].
+///
+/// If no state of type `T` was added, the request fails with `500
+/// Internal Server Error`.
+///
+/// [`Server::add_state`]: crate::Server::add_state
+#[derive(Debug)]
+pub struct State<T>(pub Arc<T>);
+
+impl<T> Clone for State<T> {
+    fn clone(&self) -> Self {
+        State(self.0.clone())
+    }
+}
+
+impl<T> Deref for State<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Any + Send + Sync> FromRequest for State<T> {
+    fn from_request(req: &Request) -> Result<Self, Rejection> {
+        req.state().map(State).ok_or_else(|| {
+            Rejection::new(
+                StatusCode::InternalServerError,
+                format!("no state of type {}", std::any::type_name::<T>()),
+            )
+        })
+    }
+}
+
+impl IntoResponse for () {
+    fn write_response(self, _req: &mut Request) {}
+}
+
+impl IntoResponse for String {
+    fn write_response(self, req: &mut Request) {
+        req.write_text(&self);
+    }
+}
+
+impl IntoResponse for &str {
+    fn write_response(self, req: &mut Request) {
+        req.write_text(self);
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn write_response(self, req: &mut Request) {
+        req.write_bytes(&self);
+    }
+}
+
+impl IntoResponse for StatusCode {
+    fn write_response(self, req: &mut Request) {
+        req.set_status(self);
+    }
+}
+
+impl<R: IntoResponse> IntoResponse for (StatusCode, R) {
+    fn write_response(self, req: &mut Request) {
+        req.set_status(self.0);
+        self.1.write_response(req);
+    }
+}
+
+/// Function that can be added with [`Server::typed_route`]. This is
+/// implemented for functions of up to six arguments that implement
+/// [`FromRequest`], returning `Result<R, E>` where `R` implements
+/// [`IntoResponse`].
+///
+/// [`Server::typed_route`]: crate::Server::typed_route
+pub trait TypedHandler<Args, E>: Send + Sync + 'static {
+    /// Extract the arguments, call the function, and write the
+    /// response.
+    fn call(&self, req: &mut Request) -> Result<(), E>;
+}
+
+macro_rules! impl_typed_handler {
+    ($($arg:ident),*) => {
+        impl<F, R, E, $($arg,)*> TypedHandler<($($arg,)*), E> for F
+        where
+            F: Fn($($arg),*) -> Result<R, E> + Send + Sync + 'static,
+            R: IntoResponse,
+            $($arg: FromRequest,)*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            fn call(&self, req: &mut Request) -> Result<(), E> {
+                $(
+                    let $arg = match $arg::from_request(req) {
+                        Ok(value) => value,
+                        Err(rejection) => {
+                            req.set_status(rejection.status);
+                            req.write_text(&rejection.message);
+                            return Ok(());
+                        }
+                    };
+                )*
+                self($($arg),*)?.write_response(req);
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_typed_handler!();
+impl_typed_handler!(A1);
+impl_typed_handler!(A1, A2);
+impl_typed_handler!(A1, A2, A3);
+impl_typed_handler!(A1, A2, A3, A4);
+impl_typed_handler!(A1, A2, A3, A4, A5);
+impl_typed_handler!(A1, A2, A3, A4, A5, A6);
@@ -0,0 +1,480 @@
+use crate::body::ResponseBody;
+use crate::compression::choose_encoding;
+use crate::{dir_listing, mime, HeaderName, Request};
+use anyhow::Error;
+use fehler::throws;
+use std::ffi::OsString;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+
+/// Options for [`Request::write_file_with_options`].
+///
+/// Example usage:
+/// ```
+/// use shs::FileOptions;
+///
+/// let options = FileOptions::default()
+///     .root("/srv/static")
+///     .index_file(Some("index.html"))
+///     .listing(true);
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FileOptions {
+    root: Option<PathBuf>,
+    attachment: bool,
+    follow_symlinks: bool,
+    index_file: Option<String>,
+    listing: bool,
+    precompressed: bool,
+}
+
+impl FileOptions {
+    /// Treat the path as relative to `root`, and refuse to serve
+    /// anything that resolves to a location outside of it. Use this
+    /// whenever the path comes from the request.
+    pub fn root(mut self, root: impl Into<PathBuf>) -> FileOptions {
+        self.root = Some(root.into());
+        self
+    }
+
+    /// Add a `Content-Disposition: attachment` header so that browsers
+    /// download the file instead of displaying it. The default is
+    /// `false`.
+    pub fn attachment(mut self, attachment: bool) -> FileOptions {
+        self.attachment = attachment;
+        self
+    }
+
+    /// Allow the file itself to be a symlink. Even when this is set,
+    /// a file under a [`root`](FileOptions::root) must resolve to a
+    /// location inside the root. The default is `false`.
+    pub fn follow_symlinks(mut self, follow: bool) -> FileOptions {
+        self.follow_symlinks = follow;
+        self
+    }
+
+    /// When the path is a directory, serve the file with this name
+    /// inside it, if there is one. The default is `None`.
+    pub fn index_file(mut self, name: Option<&str>) -> FileOptions {
+        self.index_file = name.map(Into::into);
+        self
+    }
+
+    /// When the path is a directory without an
+    /// [`index_file`](FileOptions::index_file), list its contents
+    /// instead of responding with 404. The listing is HTML for
+    /// browsers and JSON otherwise, leaves out hidden files, and can
+    /// be sorted with the `sort` query parameter (`name`, `size`, or
+    /// `modified`) and `order=desc`. The default is `false`.
+    pub fn listing(mut self, listing: bool) -> FileOptions {
+        self.listing = listing;
+        self
+    }
+
+    /// Look for precompressed copies of the file next to it, named
+    /// with a `.br` or `.gz` suffix, and send one of those instead if
+    /// the client accepts its encoding. The response gets the
+    /// matching `Content-Encoding`, and the `Content-Type` of the
+    /// original file. The default is `false`.
+    pub fn precompressed(mut self, precompressed: bool) -> FileOptions {
+        self.precompressed = precompressed;
+        self
+    }
+
+    /// Resolve and open the path to serve, or return `None` if it is
+    /// rejected or doesn't exist.
+    #[throws(io::Error)]
+    fn open(&self, path: &Path) -> Option<(PathBuf, File, Metadata)> {
+        let result = self.resolve(path).and_then(|full| match full {
+            Some(full) => {
+                let file = File::open(&full)?;
+                let metadata = file.metadata()?;
+                Ok(Some((full, file, metadata)))
+            }
+            None => Ok(None),
+        });
+        match result {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            result => result?,
+        }
+    }
+
+    /// Resolve the path to serve, or `None` if it is rejected.
+    #[throws(io::Error)]
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        if path.components().any(|c| c == Component::ParentDir) {
+            return None;
+        }
+        let full = if let Some(root) = &self.root {
+            let relative: PathBuf = path
+                .components()
+                .filter(|c| matches!(c, Component::Normal(_)))
+                .collect();
+            root.join(relative)
+        } else {
+            path.to_path_buf()
+        };
+
+        if !self.follow_symlinks
+            && fs::symlink_metadata(&full)?.file_type().is_symlink()
+        {
+            return None;
+        }
+        if let Some(root) = &self.root {
+            if !fs::canonicalize(&full)?.starts_with(fs::canonicalize(root)?) {
+                return None;
+            }
+        }
+        Some(full)
+    }
+}
+
+/// Guess the content type of a file from its first bytes, leaving it
+/// positioned at the start.
+#[throws(io::Error)]
+fn sniff_file(file: &mut File) -> &'static str {
+    let mut start = Vec::with_capacity(512);
+    (&mut *file).take(512).read_to_end(&mut start)?;
+    file.seek(SeekFrom::Start(0))?;
+    mime::sniff(&start).unwrap_or(mime::OCTET_STREAM)
+}
+
+/// Build a `Content-Disposition` value for a download.
+fn content_disposition(name: &str) -> String {
+    let ascii: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if ascii == name {
+        format!("attachment; filename=\"{}\"", name)
+    } else {
+        let encoded = percent_encoding::utf8_percent_encode(
+            name,
+            percent_encoding::NON_ALPHANUMERIC,
+        );
+        format!(
+            "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+            ascii, encoded
+        )
+    }
+}
+
+impl Request {
+    /// Send a file as the response body. See
+    /// [`write_file_with_options`](Request::write_file_with_options).
+    #[throws]
+    pub fn write_file(&mut self, path: impl AsRef<Path>) {
+        self.write_file_with_options(path, &FileOptions::default())?
+    }
+
+    /// Send a file as the response body. The file is not read into
+    /// memory up front; it is copied to the connection when the
+    /// response is written.
+    ///
+    /// This sets `Content-Type` with [`mime::guess`], from the file
+    /// extension or else the start of the file, and `Last-Modified`
+    /// from the file's modification time. If the file hasn't changed
+    /// since `If-Modified-Since`, the response is set to 304 (not
+    /// modified) with [`Request::check_last_modified`].
+    ///
+    /// Paths containing `..` are always rejected, as are symlinks
+    /// unless [`FileOptions::follow_symlinks`] is set. If the file is
+    /// rejected, doesn't exist, or isn't a regular file, the response
+    /// is set to 404 (not found) and `Ok` is returned. Directories
+    /// can be served with [`FileOptions::index_file`] and
+    /// [`FileOptions::listing`].
+    ///
+    /// `Range` requests are supported, including multiple ranges,
+    /// which are sent as `multipart/byteranges`. Other IO errors
+    /// are returned as errors.
+    #[throws]
+    pub fn write_file_with_options(
+        &mut self,
+        path: impl AsRef<Path>,
+        options: &FileOptions,
+    ) {
+        let path = path.as_ref();
+        let not_found = |req: &mut Request| {
+            req.set_not_found();
+            req.write_text("not found");
+        };
+
+        let mut path = path.to_path_buf();
+        let (mut full, mut file, mut metadata) = match options.open(&path)? {
+            Some(found) => found,
+            None => return not_found(self),
+        };
+        if metadata.is_dir() {
+            let index_path = options.index_file.as_ref().map(|n| path.join(n));
+            let index = match &index_path {
+                Some(index_path) => options.open(index_path)?,
+                None => None,
+            };
+            match (index_path, index) {
+                (Some(index_path), Some(index)) if index.2.is_file() => {
+                    (full, file, metadata) = index;
+                    path = index_path;
+                }
+                _ if options.listing => {
+                    return dir_listing::write_listing(
+                        self,
+                        &full,
+                        options.follow_symlinks,
+                    )?;
+                }
+                _ => return not_found(self),
+            }
+        }
+        if !metadata.is_file() {
+            return not_found(self);
+        }
+
+        let content_type = match mime::from_path(&full) {
+            Some(content_type) => content_type,
+            None => sniff_file(&mut file)?,
+        };
+        self.set_content_type(content_type);
+        let mut len = metadata.len();
+        if options.precompressed {
+            if let Some((encoding, compressed, compressed_len)) =
+                self.find_precompressed(&path, options)?
+            {
+                self.set_header("Content-Encoding", encoding);
+                file = compressed;
+                len = compressed_len;
+            }
+        }
+        if let Ok(modified) = metadata.modified() {
+            if self.check_last_modified(modified) {
+                return;
+            }
+        }
+        if options.attachment {
+            if let Some(name) = full.file_name().and_then(|n| n.to_str()) {
+                self.set_header(
+                    "Content-Disposition",
+                    &content_disposition(name),
+                );
+            }
+        }
+        self.resp_body = ResponseBody::File { file, len };
+        self.apply_range()?;
+    }
+
+    /// Open the precompressed copy of `path` to send, if any, along
+    /// with its encoding and length.
+    #[throws(io::Error)]
+    fn find_precompressed(
+        &mut self,
+        path: &Path,
+        options: &FileOptions,
+    ) -> Option<(&'static str, File, u64)> {
+        let mut found = Vec::new();
+        for &(encoding, suffix) in &[("br", ".br"), ("gzip", ".gz")] {
+            let mut name = OsString::from(path);
+            name.push(suffix);
+            if let Some((_, file, metadata)) = options.open(Path::new(&name))? {
+                if metadata.is_file() {
+                    found.push((encoding, file, metadata.len()));
+                }
+            }
+        }
+        if found.is_empty() {
+            return None;
+        }
+
+        // Which file is sent depends on Accept-Encoding
+        let vary = match self.resp_header("Vary") {
+            Some(vary) => format!("{}, Accept-Encoding", vary),
+            None => "Accept-Encoding".into(),
+        };
+        self.set_header("Vary", &vary);
+        let available = found.iter().map(|f| f.0).collect::<Vec<_>>();
+        let encoding = self
+            .req_headers
+            .get(&HeaderName::new("Accept-Encoding".into()))
+            .and_then(|accept| choose_encoding(accept, &available));
+        found.into_iter().find(|f| Some(f.0) == encoding)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StatusCode;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("a.txt"),
+            "attachment; filename=\"a.txt\""
+        );
+        assert_eq!(
+            content_disposition("é.txt"),
+            "attachment; filename=\"_.txt\"; filename*=UTF-8''%C3%A9%2Etxt"
+        );
+    }
+
+    #[test]
+    fn test_sniff_file() {
+        let mut file = tempfile::tempfile().unwrap();
+        io::Write::write_all(&mut file, b"%PDF-1.7 ...").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(sniff_file(&mut file).unwrap(), "application/pdf");
+        assert_eq!(file.stream_position().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("sub/a.txt"), "a").unwrap();
+        fs::write(dir.path().join("index.html"), "<p>index</p>").unwrap();
+
+        let write = |path: &str, options: &FileOptions| {
+            let mut req = crate::test_req("GET", path, &[]);
+            req.write_file_with_options(path, options).unwrap();
+            req
+        };
+        let options = FileOptions::default().root(dir.path());
+        assert_eq!(write("/", &options).status, StatusCode::NotFound);
+
+        let options = options.index_file(Some("index.html"));
+        let req = write("/", &options);
+        assert_eq!(req.status, StatusCode::Ok);
+        assert_eq!(req.resp_body.peek(100).unwrap(), b"<p>index</p>");
+        assert_eq!(write("/sub", &options).status, StatusCode::NotFound);
+
+        let options = options.listing(true);
+        let req = write("/sub/", &options);
+        assert_eq!(req.status, StatusCode::Ok);
+        let listing: serde_json::Value =
+            serde_json::from_slice(&req.resp_body.peek(1000).unwrap()).unwrap();
+        assert_eq!(listing[0]["name"], "a.txt");
+        assert_eq!(listing[0]["size"], 1);
+        assert_eq!(req.resp_header("Vary"), Some("Accept"));
+
+        let mut req =
+            crate::test_req("GET", "/sub/", &[("Accept", "text/html")]);
+        req.write_file_with_options("/sub/", &options).unwrap();
+        assert!(req
+            .resp_header("Content-Type")
+            .unwrap()
+            .starts_with("text/html"));
+        assert_eq!(req.resp_header("Vary"), Some("Accept"));
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        let modified = fs::metadata(dir.path().join("a.txt"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        let options = FileOptions::default().root(dir.path());
+        let write = |since: SystemTime| {
+            let since = httpdate::fmt_http_date(since);
+            let mut req = crate::test_req(
+                "GET",
+                "/a.txt",
+                &[("If-Modified-Since", &since)],
+            );
+            req.write_file_with_options("/a.txt", &options).unwrap();
+            req
+        };
+
+        let req = write(modified);
+        assert_eq!(req.status, StatusCode::NotModified);
+        assert_eq!(req.resp_body.len(), 0);
+        assert!(req.resp_header("Last-Modified").is_some());
+
+        let req = write(modified - Duration::from_secs(10));
+        assert_eq!(req.status, StatusCode::Ok);
+        assert_eq!(req.resp_body.peek(100).unwrap(), b"a");
+    }
+
+    #[test]
+    fn test_precompressed() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("app.js"), "plain").unwrap();
+        fs::write(dir.path().join("app.js.gz"), "gzipped").unwrap();
+        fs::write(dir.path().join("app.js.br"), "brotli").unwrap();
+        fs::write(dir.path().join("other.js"), "other").unwrap();
+
+        let options =
+            FileOptions::default().root(dir.path()).precompressed(true);
+        let write = |path: &str, accept: Option<&str>| {
+            let headers: Vec<_> = accept
+                .iter()
+                .map(|accept| ("Accept-Encoding", *accept))
+                .collect();
+            let mut req = crate::test_req("GET", path, &headers);
+            req.write_file_with_options(path, &options).unwrap();
+            let body = req.resp_body.peek(100).unwrap();
+            let encoding =
+                req.resp_header("Content-Encoding").map(String::from);
+            let vary = req.resp_header("Vary").map(String::from);
+            (String::from_utf8(body).unwrap(), encoding, vary)
+        };
+        assert_eq!(
+            write("/app.js", Some("gzip, br")),
+            (
+                "brotli".into(),
+                Some("br".into()),
+                Some("Accept-Encoding".into())
+            )
+        );
+        assert_eq!(
+            write("/app.js", Some("gzip")),
+            (
+                "gzipped".into(),
+                Some("gzip".into()),
+                Some("Accept-Encoding".into())
+            )
+        );
+        assert_eq!(
+            write("/app.js", None),
+            ("plain".into(), None, Some("Accept-Encoding".into()))
+        );
+        assert_eq!(
+            write("/other.js", Some("gzip")),
+            ("other".into(), None, None)
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(dir.path().join("secret"), "s").unwrap();
+
+        let options = FileOptions::default().root(&root);
+        let resolve = |p: &str| options.resolve(Path::new(p)).unwrap();
+        assert_eq!(resolve("a.txt"), Some(root.join("a.txt")));
+        assert_eq!(resolve("/a.txt"), Some(root.join("a.txt")));
+        assert_eq!(resolve("../secret"), None);
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(
+                dir.path().join("secret"),
+                root.join("link"),
+            )
+            .unwrap();
+            assert_eq!(resolve("link"), None);
+            let options = options.clone().follow_symlinks(true);
+            assert_eq!(options.resolve(Path::new("link")).unwrap(), None);
+        }
+    }
+}
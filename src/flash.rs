@@ -0,0 +1,62 @@
+//! One-shot messages passed from one request to the next through a
+//! signed cookie, typically to show a notice after a redirect.
+
+use crate::{Cookie, Request};
+use anyhow::Error;
+use fehler::throws;
+
+const FLASH_COOKIE: &str = "shs-flash";
+
+/// Flash state for the current request, kept in the request's
+/// extensions.
+#[derive(Default)]
+struct FlashState {
+    /// Messages added during this request, for the next one.
+    outgoing: Vec<String>,
+    /// Whether the incoming messages have been taken.
+    taken: bool,
+}
+
+fn flash_cookie(value: &str) -> Cookie {
+    Cookie::new(FLASH_COOKIE, value).path(Some("/"))
+}
+
+impl Request {
+    /// Add a message to show on the next request from this client,
+    /// for example `req.flash("Saved!")` before redirecting. Messages
+    /// are stored in a cookie signed with the server's [`CookieJar`],
+    /// so this fails if no jar was set.
+    ///
+    /// [`CookieJar`]: crate::CookieJar
+    #[throws]
+    pub fn flash(&mut self, message: &str) {
+        let mut state =
+            self.extensions.remove::<FlashState>().unwrap_or_default();
+        state.outgoing.push(message.into());
+        let value = serde_json::to_string(&state.outgoing)?;
+        self.extensions.insert(state);
+        self.set_signed_cookie(flash_cookie(&value))?;
+    }
+
+    /// Get the messages added with [`Request::flash`] during the
+    /// previous request. The messages are cleared, so they are only
+    /// returned once. Invalid or tampered flash cookies are ignored.
+    pub fn take_flashes(&mut self) -> Vec<String> {
+        let mut state =
+            self.extensions.remove::<FlashState>().unwrap_or_default();
+        let messages = if state.taken {
+            Vec::new()
+        } else {
+            self.signed_cookie(FLASH_COOKIE)
+                .and_then(|value| serde_json::from_str(&value).ok())
+                .unwrap_or_default()
+        };
+        state.taken = true;
+        // Messages added in this request replace the cookie anyway
+        if state.outgoing.is_empty() && self.cookie(FLASH_COOKIE).is_some() {
+            self.remove_cookie(flash_cookie(""));
+        }
+        self.extensions.insert(state);
+        messages
+    }
+}
@@ -0,0 +1,261 @@
+use crate::HeaderName;
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// Forwarding header that trusted proxies set, chosen with
+/// [`Server::set_forwarded_header`]. Only this header is read, since
+/// a proxy passes the other one through from the client unchanged.
+///
+/// [`Server::set_forwarded_header`]: crate::Server::set_forwarded_header
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ForwardedHeader {
+    /// `X-Forwarded-For`, with the scheme from `X-Forwarded-Proto`.
+    /// This is what most proxies send.
+    #[default]
+    XForwardedFor,
+    /// The RFC 7239 `Forwarded` header.
+    Forwarded,
+}
+
+/// One hop from a `Forwarded` or `X-Forwarded-For` header.
+#[derive(Debug, Default, PartialEq)]
+struct Hop {
+    ip: Option<IpAddr>,
+    proto: Option<String>,
+}
+
+/// Client identity after taking trusted proxies into account.
+#[derive(Debug, PartialEq)]
+pub(crate) struct ClientInfo {
+    pub(crate) ip: Option<IpAddr>,
+    pub(crate) scheme: String,
+}
+
+fn strip_quotes(s: &str) -> &str {
+    s.trim()
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or_else(|| s.trim())
+}
+
+/// Parse a node from either header. This may be a bare address, an
+/// IPv4 address with a port, or a bracketed IPv6 address with an
+/// optional port. Obfuscated identifiers and "unknown" yield `None`.
+fn parse_node(s: &str) -> Option<IpAddr> {
+    let s = strip_quotes(s);
+    if let Some(rest) = s.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    if let Ok(ip) = s.parse() {
+        return Some(ip);
+    }
+    s.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+/// Parse an RFC 7239 `Forwarded` header.
+fn parse_forwarded(value: &str) -> Vec<Hop> {
+    value
+        .split(',')
+        .map(|element| {
+            let mut hop = Hop::default();
+            for pair in element.split(';') {
+                let mut iter = pair.splitn(2, '=');
+                let key = iter.next().unwrap_or("").trim();
+                let value = iter.next().unwrap_or("");
+                if key.eq_ignore_ascii_case("for") {
+                    hop.ip = parse_node(value);
+                } else if key.eq_ignore_ascii_case("proto") {
+                    hop.proto = Some(strip_quotes(value).to_lowercase());
+                }
+            }
+            hop
+        })
+        .collect()
+}
+
+/// Parse `X-Forwarded-For` and `X-Forwarded-Proto`. The proto header
+/// normally just has one value set by the outermost proxy, so that
+/// value is applied to every hop.
+fn parse_x_forwarded(xff: &str, proto: Option<&String>) -> Vec<Hop> {
+    let proto = proto
+        .and_then(|p| p.split(',').next())
+        .map(|p| p.trim().to_lowercase());
+    xff.split(',')
+        .map(|node| Hop {
+            ip: parse_node(node),
+            proto: proto.clone(),
+        })
+        .collect()
+}
+
+fn is_trusted(ip: IpAddr, trusted: &[IpNet]) -> bool {
+    trusted.iter().any(|net| net.contains(&ip))
+}
+
+/// Determine the client IP and scheme for a request.
+///
+/// If the connection comes from a trusted proxy, the forwarding
+/// headers are walked from the end (the hop closest to us) until an
+/// address that isn't a trusted proxy is found; that is the
+/// client. Otherwise the socket address is used and the headers are
+/// ignored, since anyone can send them.
+pub(crate) fn resolve_client(
+    peer_addr: Option<SocketAddr>,
+    headers: &HashMap<HeaderName, String>,
+    trusted: &[IpNet],
+    header: ForwardedHeader,
+) -> ClientInfo {
+    let direct = ClientInfo {
+        ip: peer_addr.map(|addr| addr.ip()),
+        scheme: "http".into(),
+    };
+    let peer_ip = match direct.ip {
+        Some(ip) if is_trusted(ip, trusted) => ip,
+        _ => return direct,
+    };
+
+    let get = |name: &str| headers.get(&HeaderName::new(name.into()));
+    let hops = match header {
+        ForwardedHeader::Forwarded => match get("Forwarded") {
+            Some(forwarded) => parse_forwarded(forwarded),
+            None => return direct,
+        },
+        ForwardedHeader::XForwardedFor => {
+            let proto = get("X-Forwarded-Proto");
+            match (get("X-Forwarded-For"), proto) {
+                (Some(xff), _) => parse_x_forwarded(xff, proto),
+                (None, Some(_)) => {
+                    parse_x_forwarded(&peer_ip.to_string(), proto)
+                }
+                (None, None) => return direct,
+            }
+        }
+    };
+
+    let mut client = None;
+    for hop in hops.iter().rev() {
+        client = Some(hop);
+        match hop.ip {
+            Some(ip) if is_trusted(ip, trusted) => continue,
+            _ => break,
+        }
+    }
+    match client {
+        Some(hop) => ClientInfo {
+            ip: hop.ip,
+            // The scheme ends up in Request::url, so only accept the
+            // two that make sense there
+            scheme: hop
+                .proto
+                .clone()
+                .filter(|proto| proto == "http" || proto == "https")
+                .unwrap_or(direct.scheme),
+        },
+        None => direct,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve_with(
+        header: ForwardedHeader,
+        peer: &str,
+        headers: &[(&str, &str)],
+    ) -> ClientInfo {
+        let trusted = vec!["10.0.0.0/8".parse().unwrap()];
+        let headers = headers
+            .iter()
+            .map(|(k, v)| (HeaderName::new(k.to_string()), v.to_string()))
+            .collect();
+        resolve_client(Some(peer.parse().unwrap()), &headers, &trusted, header)
+    }
+
+    fn resolve(peer: &str, headers: &[(&str, &str)]) -> ClientInfo {
+        resolve_with(ForwardedHeader::XForwardedFor, peer, headers)
+    }
+
+    fn info(ip: &str, scheme: &str) -> ClientInfo {
+        ClientInfo {
+            ip: Some(ip.parse().unwrap()),
+            scheme: scheme.into(),
+        }
+    }
+
+    #[test]
+    fn test_untrusted_peer() {
+        assert_eq!(
+            resolve("1.2.3.4:80", &[("X-Forwarded-For", "5.6.7.8")]),
+            info("1.2.3.4", "http")
+        );
+    }
+
+    #[test]
+    fn test_x_forwarded() {
+        assert_eq!(
+            resolve(
+                "10.0.0.1:80",
+                &[
+                    ("X-Forwarded-For", "6.6.6.6, 5.6.7.8, 10.0.0.2"),
+                    ("X-Forwarded-Proto", "https"),
+                ]
+            ),
+            info("5.6.7.8", "https")
+        );
+    }
+
+    #[test]
+    fn test_forwarded() {
+        assert_eq!(
+            resolve_with(
+                ForwardedHeader::Forwarded,
+                "10.0.0.1:80",
+                &[(
+                    "Forwarded",
+                    r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.3"#
+                )]
+            ),
+            info("2001:db8::1", "https")
+        );
+    }
+
+    #[test]
+    fn test_forged_header() {
+        // The proxy appends to X-Forwarded-For and passes a
+        // client-supplied Forwarded header through
+        let headers = [
+            ("Forwarded", "for=6.6.6.6;proto=https"),
+            ("X-Forwarded-For", "5.6.7.8"),
+        ];
+        assert_eq!(resolve("10.0.0.1:80", &headers), info("5.6.7.8", "http"));
+
+        // And the other way around
+        let headers = [
+            ("Forwarded", "for=5.6.7.8"),
+            ("X-Forwarded-For", "6.6.6.6"),
+            ("X-Forwarded-Proto", "https"),
+        ];
+        assert_eq!(
+            resolve_with(ForwardedHeader::Forwarded, "10.0.0.1:80", &headers),
+            info("5.6.7.8", "http")
+        );
+        assert_eq!(
+            resolve_with(
+                ForwardedHeader::Forwarded,
+                "10.0.0.1:80",
+                &[("X-Forwarded-For", "6.6.6.6")]
+            ),
+            info("10.0.0.1", "http")
+        );
+    }
+
+    #[test]
+    fn test_unknown_proto() {
+        assert_eq!(
+            resolve("10.0.0.1:80", &[("X-Forwarded-Proto", "javascript")]),
+            info("10.0.0.1", "http")
+        );
+    }
+}
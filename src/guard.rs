@@ -0,0 +1,42 @@
+use crate::{HeaderName, Request};
+
+/// Predicate that decides whether a route handles a request. Add one
+/// with [`RouteHandle::guard`].
+///
+/// [`RouteHandle::guard`]: crate::RouteHandle::guard
+pub type Guard = dyn Fn(&Request) -> bool + Send + Sync;
+
+fn get_header<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(&HeaderName::new(name.into()))
+        .map(|value| value.as_str())
+}
+
+/// Strip parameters from a media type, e.g. `"text/html; q=0.9"`
+/// becomes `"text/html"`.
+fn essence(media_type: &str) -> &str {
+    media_type.split(';').next().unwrap_or("").trim()
+}
+
+pub(crate) fn header(name: &str, value: &str) -> impl Fn(&Request) -> bool {
+    let (name, value) = (name.to_string(), value.to_string());
+    move |req| get_header(req, &name).map(str::trim) == Some(value.as_str())
+}
+
+pub(crate) fn content_type(media_type: &str) -> impl Fn(&Request) -> bool {
+    let media_type = media_type.to_string();
+    move |req| {
+        get_header(req, "Content-Type")
+            .is_some_and(|v| essence(v).eq_ignore_ascii_case(&media_type))
+    }
+}
+
+pub(crate) fn accept(media_type: &str) -> impl Fn(&Request) -> bool {
+    let media_type = media_type.to_string();
+    move |req| {
+        get_header(req, "Accept").is_some_and(|v| {
+            v.split(',')
+                .any(|item| essence(item).eq_ignore_ascii_case(&media_type))
+        })
+    }
+}
@@ -0,0 +1,234 @@
+//! Handing the listening socket to a new process for restarts that
+//! don't drop connections (Unix only).
+//!
+//! The running server starts the new binary with
+//! [`BackgroundServer::hand_over`], which passes it a copy of the
+//! listener in the `SHS_LISTEN_FD` environment variable along with a
+//! socket in `SHS_READY_FD` for it to report back on. The new process
+//! adopts the listener with [`ServerBuilder::inherited`] and reports
+//! ready once it's accepting connections. Until then the old server
+//! keeps serving, and connections that arrive in between wait in the
+//! shared backlog, so none are refused. The old server can then be
+//! stopped, which lets its requests in progress finish.
+
+use crate::{BackgroundServer, Server, ServerBuilder};
+use anyhow::{anyhow, bail, Context, Error};
+use fehler::throws;
+use socket2::SockRef;
+use std::env;
+use std::fmt::{Debug, Display};
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::process::{Child, Command};
+use std::time::Duration;
+
+/// Environment variable holding the inherited listener's descriptor.
+pub const LISTEN_FD_ENV: &str = "SHS_LISTEN_FD";
+
+/// Environment variable holding the descriptor of the socket the new
+/// process reports ready on.
+pub const READY_FD_ENV: &str = "SHS_READY_FD";
+
+#[throws]
+fn take_fd(name: &str) -> Option<RawFd> {
+    let value = match env::var(name) {
+        Ok(value) => value,
+        Err(env::VarError::NotPresent) => return None,
+        Err(err) => bail!("invalid {}: {}", name, err),
+    };
+    // Processes started by this one shouldn't try to adopt it too
+    env::remove_var(name);
+    let fd = value
+        .parse::<RawFd>()
+        .ok()
+        .filter(|fd| *fd > 2)
+        .ok_or_else(|| anyhow!("invalid {}: {}", name, value))?;
+    Some(fd)
+}
+
+/// Tell the old process that this one is serving. Failures are only
+/// logged, since the listener works either way.
+pub(crate) fn notify_ready(mut ready: UnixStream) {
+    if let Err(err) = ready.write_all(b"1") {
+        log::warn!("failed to report ready to the old process: {}", err);
+    }
+}
+
+impl<E: Debug + Display + 'static> Server<E> {
+    /// Create a Server that accepts connections on the listening
+    /// socket `fd`, such as one passed down by a process manager. It's
+    /// the same as [`Server::from_listener`] once the descriptor is
+    /// wrapped.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open, listening TCP socket that nothing else in
+    /// the process owns. It's closed when the server stops.
+    pub unsafe fn from_raw_fd(fd: RawFd) -> Server<E> {
+        Server::from_listener(TcpListener::from_raw_fd(fd))
+    }
+}
+
+impl ServerBuilder {
+    /// Create a builder for a server that adopts the listener handed
+    /// over by [`BackgroundServer::hand_over`], or return `None` if
+    /// this process wasn't started that way. The old process is told
+    /// the new one is ready once the server launches.
+    ///
+    /// ```no_run
+    /// use shs::ServerBuilder;
+    ///
+    /// let builder = match ServerBuilder::inherited()? {
+    ///     Some(builder) => builder,
+    ///     None => ServerBuilder::new("0.0.0.0:8080"),
+    /// };
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    #[throws]
+    pub fn inherited() -> Option<ServerBuilder> {
+        let listen_fd = match take_fd(LISTEN_FD_ENV)? {
+            Some(fd) => fd,
+            None => return None,
+        };
+        // Safety: the old process opened this descriptor for us and
+        // nothing else in this process owns it
+        let listener = unsafe { TcpListener::from_raw_fd(listen_fd) };
+        listener
+            .local_addr()
+            .with_context(|| format!("{} is not a listener", LISTEN_FD_ENV))?;
+        SockRef::from(&listener).set_cloexec(true)?;
+        let mut builder = ServerBuilder::from_listener(listener);
+
+        if let Some(ready_fd) = take_fd(READY_FD_ENV)? {
+            // Safety: as above
+            let ready = unsafe { UnixStream::from_raw_fd(ready_fd) };
+            SockRef::from(&ready).set_cloexec(true)?;
+            builder.handover_ready = Some(ready);
+        }
+        Some(builder)
+    }
+}
+
+impl BackgroundServer {
+    /// Start `command`, normally a new version of this program, and
+    /// hand it a copy of the listening socket. Waits up to `timeout`
+    /// for the new process to launch a server built with
+    /// [`ServerBuilder::inherited`], and fails if it doesn't. This
+    /// server keeps accepting connections until it's stopped, which
+    /// should be done once this returns successfully.
+    ///
+    /// ```no_run
+    /// # use shs::Server;
+    /// # use std::process::Command;
+    /// # use std::time::Duration;
+    /// # let server: Server<anyhow::Error> = Server::new("0.0.0.0:8080")?;
+    /// let running = server.launch_in_background()?;
+    /// // ... later, for example on SIGHUP:
+    /// let exe = std::env::current_exe()?;
+    /// running.hand_over(Command::new(exe), Duration::from_secs(30))?;
+    /// running.stop()?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    #[throws]
+    pub fn hand_over(&self, mut command: Command, timeout: Duration) -> Child {
+        let listener = self
+            .listener
+            .as_ref()
+            .ok_or_else(|| anyhow!("the listener can't be handed over"))?;
+        // Copies for the child, which has to inherit them. Ours are
+        // closed once it has started.
+        let child_listener = listener.try_clone()?;
+        SockRef::from(&child_listener).set_cloexec(false)?;
+        let (mut ready, child_ready) = UnixStream::pair()?;
+        SockRef::from(&child_ready).set_cloexec(false)?;
+
+        command
+            .env(LISTEN_FD_ENV, child_listener.as_raw_fd().to_string())
+            .env(READY_FD_ENV, child_ready.as_raw_fd().to_string());
+        let mut child =
+            command.spawn().context("failed to start new process")?;
+        drop(child_listener);
+        drop(child_ready);
+
+        ready.set_read_timeout(Some(timeout))?;
+        let mut byte = [0];
+        match ready.read(&mut byte) {
+            Ok(1) => child,
+            result => {
+                let reason = match result {
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            ErrorKind::WouldBlock | ErrorKind::TimedOut
+                        ) =>
+                    {
+                        "timed out".to_string()
+                    }
+                    Err(err) => err.to_string(),
+                    _ => "exited before it was ready".into(),
+                };
+                // It may be stuck; don't leave two servers running
+                let _ = child.kill();
+                let _ = child.wait();
+                bail!("new process didn't take over: {}", reason)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_fd() {
+        env::set_var("SHS_TEST_FD", "7");
+        assert_eq!(take_fd("SHS_TEST_FD").unwrap(), Some(7));
+        assert!(env::var("SHS_TEST_FD").is_err());
+        assert_eq!(take_fd("SHS_TEST_FD").unwrap(), None);
+
+        for value in &["x", "-1", "1"] {
+            env::set_var("SHS_TEST_FD", value);
+            assert!(take_fd("SHS_TEST_FD").is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_hand_over_failure() {
+        let server: crate::Server<Error> =
+            crate::Server::new("127.0.0.1:0").unwrap();
+        let running = server.launch_in_background().unwrap();
+        // The command exits without taking over
+        let err = running
+            .hand_over(Command::new("true"), Duration::from_secs(10))
+            .unwrap_err();
+        assert!(err.to_string().contains("exited before it was ready"));
+        // The old server is still running
+        assert!(std::net::TcpStream::connect(running.local_addr()).is_ok());
+        running.stop().unwrap();
+    }
+
+    #[test]
+    fn test_inherited() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (mut old, new) = UnixStream::pair().unwrap();
+        env::set_var(LISTEN_FD_ENV, listener.as_raw_fd().to_string());
+        env::set_var(READY_FD_ENV, new.as_raw_fd().to_string());
+        // The builder takes ownership of both descriptors
+        std::mem::forget(listener);
+        std::mem::forget(new);
+
+        let builder = ServerBuilder::inherited().unwrap().unwrap();
+        assert!(env::var(LISTEN_FD_ENV).is_err());
+        let server: crate::Server<Error> = builder.build().unwrap();
+        let running = server.launch_in_background().unwrap();
+        assert_eq!(running.local_addr(), addr);
+        let mut byte = [0];
+        old.read_exact(&mut byte).unwrap();
+        assert_eq!(&byte, b"1");
+        running.stop().unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+//! Response headers kept in the order they were set.
+
+/// Well-known response headers, spelled the way they are sent.
+const CANONICAL_NAMES: &[&str] = &[
+    "Accept-Ranges",
+    "Access-Control-Allow-Credentials",
+    "Access-Control-Allow-Headers",
+    "Access-Control-Allow-Methods",
+    "Access-Control-Allow-Origin",
+    "Access-Control-Expose-Headers",
+    "Access-Control-Max-Age",
+    "Age",
+    "Allow",
+    "Cache-Control",
+    "Connection",
+    "Content-Disposition",
+    "Content-Encoding",
+    "Content-Language",
+    "Content-Length",
+    "Content-Location",
+    "Content-Range",
+    "Content-Security-Policy",
+    "Content-Type",
+    "Cross-Origin-Opener-Policy",
+    "Cross-Origin-Resource-Policy",
+    "Date",
+    "ETag",
+    "Expires",
+    "Last-Modified",
+    "Link",
+    "Location",
+    "Permissions-Policy",
+    "Referrer-Policy",
+    "Retry-After",
+    "Server",
+    "Set-Cookie",
+    "Strict-Transport-Security",
+    "Transfer-Encoding",
+    "Vary",
+    "WWW-Authenticate",
+    "X-Content-Type-Options",
+    "X-Frame-Options",
+];
+
+/// Get the canonical spelling of a well-known header name, or the
+/// name as given for other headers.
+fn canonical_name(name: &str) -> &str {
+    CANONICAL_NAMES
+        .iter()
+        .find(|canonical| canonical.eq_ignore_ascii_case(name))
+        .copied()
+        .unwrap_or(name)
+}
+
+/// Response headers in insertion order, so responses are serialized
+/// the same way every time.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct ResponseHeaders(Vec<(String, String)>);
+
+impl ResponseHeaders {
+    /// Set a header, replacing the value of a header with the same name
+    /// in place or else adding it at the end. Names are compared
+    /// case-insensitively, and well-known names are stored with their
+    /// canonical casing.
+    pub(crate) fn insert(&mut self, name: &str, value: &str) {
+        match self
+            .0
+            .iter_mut()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        {
+            Some((_, v)) => *v = value.into(),
+            None => self.0.push((canonical_name(name).into(), value.into())),
+        }
+    }
+
+    /// Get a header value. The name is case-insensitive.
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Set every header in `other`.
+    pub(crate) fn extend(&mut self, other: &ResponseHeaders) {
+        for (name, value) in other.iter() {
+            self.insert(name, value);
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert() {
+        let mut headers = ResponseHeaders::default();
+        headers.insert("B", "1");
+        headers.insert("A", "2");
+        headers.insert("B", "3");
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [("B", "3"), ("A", "2")]
+        );
+        assert_eq!(headers.get("b"), Some("3"));
+        assert_eq!(headers.get("C"), None);
+    }
+
+    #[test]
+    fn test_casing() {
+        let mut headers = ResponseHeaders::default();
+        headers.insert("content-type", "text/plain");
+        headers.insert("CONTENT-TYPE", "text/html");
+        headers.insert("x-custom", "1");
+        headers.insert("etag", "\"a\"");
+        assert_eq!(
+            headers.iter().collect::<Vec<_>>(),
+            [
+                ("Content-Type", "text/html"),
+                ("x-custom", "1"),
+                ("ETag", "\"a\"")
+            ]
+        );
+    }
+}
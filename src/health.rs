@@ -0,0 +1,226 @@
+//! Liveness and readiness endpoints, enabled with
+//! [`Server::enable_health`].
+
+use crate::{Request, Server, StatusCode};
+use anyhow::Error;
+use serde::Serialize;
+use std::fmt::{Debug, Display};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+type CheckFn = dyn Fn() -> Result<(), Error> + Send + Sync;
+
+struct Check {
+    name: String,
+    timeout: Duration,
+    check: Arc<CheckFn>,
+}
+
+/// Paths and readiness checks set up with [`Server::enable_health`]
+/// and [`Server::add_readiness_check`].
+#[derive(Default)]
+pub(crate) struct Health {
+    live_path: Option<String>,
+    ready_path: Option<String>,
+    checks: Vec<Check>,
+}
+
+#[derive(Debug, Serialize)]
+struct CheckResult {
+    name: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    duration_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    status: &'static str,
+    checks: Vec<CheckResult>,
+}
+
+impl Health {
+    /// Run every check at once, giving each until its own timeout.
+    /// A check that times out is still left running on its thread,
+    /// since there's no way to stop it.
+    fn run_checks(&self) -> Vec<CheckResult> {
+        let start = Instant::now();
+        let mut results: Vec<Option<(Result<(), String>, Duration)>> =
+            self.checks.iter().map(|_| None).collect();
+        let (sender, receiver) = mpsc::channel();
+        for (index, check) in self.checks.iter().enumerate() {
+            let sender = sender.clone();
+            let f = check.check.clone();
+            let spawned = thread::Builder::new()
+                .name("shs-health".into())
+                .spawn(move || {
+                    let result = f().map_err(|err| err.to_string());
+                    let _ = sender.send((index, result, start.elapsed()));
+                });
+            if let Err(err) = spawned {
+                let error = format!("failed to spawn thread: {}", err);
+                results[index] = Some((Err(error), Duration::ZERO));
+            }
+        }
+        drop(sender);
+
+        // Wait until every check has either finished or run out of time
+        loop {
+            let deadline = self
+                .checks
+                .iter()
+                .zip(&results)
+                .filter(|(_, result)| result.is_none())
+                .map(|(check, _)| check.timeout)
+                .max();
+            let remaining =
+                match deadline.and_then(|d| d.checked_sub(start.elapsed())) {
+                    Some(remaining) => remaining,
+                    None => break,
+                };
+            match receiver.recv_timeout(remaining) {
+                Ok((index, result, elapsed)) => {
+                    // A result that came in after its own timeout still
+                    // counts as a timeout
+                    if elapsed <= self.checks[index].timeout {
+                        results[index] = Some((result, elapsed));
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+
+        self.checks
+            .iter()
+            .zip(results)
+            .map(|(check, result)| {
+                let (result, elapsed) = result.unwrap_or_else(|| {
+                    (Err("timed out".into()), check.timeout)
+                });
+                CheckResult {
+                    name: check.name.clone(),
+                    ok: result.is_ok(),
+                    error: result.err(),
+                    duration_ms: elapsed.as_secs_f64() * 1000.0,
+                }
+            })
+            .collect()
+    }
+
+    /// Respond if `path` is one of the health endpoints. Returns false
+    /// otherwise so the request is routed normally.
+    pub(crate) fn handle(&self, path: &str, req: &mut Request) -> bool {
+        if req.method != "GET" && req.method != "HEAD" {
+            return false;
+        }
+        let checks = if self.live_path.as_deref() == Some(path) {
+            Vec::new()
+        } else if self.ready_path.as_deref() == Some(path) {
+            self.run_checks()
+        } else {
+            return false;
+        };
+        let ok = checks.iter().all(|check| check.ok);
+        if !ok {
+            log::warn!(
+                "readiness check failed: {}",
+                checks
+                    .iter()
+                    .filter(|check| !check.ok)
+                    .map(|check| check.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            req.set_status(StatusCode::ServiceUnavailable);
+        }
+        // Serializing plain structs can't fail
+        let _ = req.write_json(&Report {
+            status: if ok { "ok" } else { "unavailable" },
+            checks,
+        });
+        req.set_header("Cache-Control", "no-store");
+        true
+    }
+}
+
+impl<E: Debug + Display> Server<E> {
+    /// Serve health endpoints for load balancers and orchestrators such
+    /// as Kubernetes. `live_path` always responds `200 OK` while the
+    /// server is running. `ready_path` runs the checks added with
+    /// [`Server::add_readiness_check`] and responds `200 OK` if they
+    /// all pass or `503 Service Unavailable` if any fail, with a JSON
+    /// breakdown of each check. Like [`Server::enable_debug_routes`],
+    /// these endpoints bypass middleware.
+    pub fn enable_health(&mut self, live_path: &str, ready_path: &str) {
+        self.routes.health.live_path = Some(live_path.into());
+        self.routes.health.ready_path = Some(ready_path.into());
+    }
+
+    /// Add a check run on each readiness request, such as pinging a
+    /// database or checking free disk space. A check that fails or
+    /// doesn't finish within `timeout` makes the server not ready.
+    /// Checks run in parallel, each on its own thread.
+    pub fn add_readiness_check(
+        &mut self,
+        name: &str,
+        timeout: Duration,
+        check: impl Fn() -> Result<(), Error> + Send + Sync + 'static,
+    ) {
+        self.routes.health.checks.push(Check {
+            name: name.into(),
+            timeout,
+            check: Arc::new(check),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestRequest;
+    use anyhow::anyhow;
+    use serde_json::Value;
+
+    #[test]
+    fn test_health() {
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        let get = |server: &Server<Error>, path: &str| {
+            server.test_request(
+                &TestRequest::new(&format!("GET {}", path)).unwrap(),
+            )
+        };
+        assert!(get(&server, "/healthz").is_err());
+
+        server.enable_health("/healthz", "/readyz");
+        let resp = get(&server, "/healthz").unwrap();
+        assert_eq!(resp.status, StatusCode::Ok);
+        let resp = get(&server, "/readyz").unwrap();
+        assert_eq!(resp.status, StatusCode::Ok);
+        assert_eq!(resp.json::<Value>().unwrap()["status"], "ok");
+
+        server.add_readiness_check("db", Duration::from_secs(5), || Ok(()));
+        server.add_readiness_check("disk", Duration::from_secs(5), || {
+            Err(anyhow!("disk full"))
+        });
+        server.add_readiness_check("slow", Duration::from_millis(20), || {
+            thread::sleep(Duration::from_millis(500));
+            Ok(())
+        });
+        let start = Instant::now();
+        let resp = get(&server, "/readyz").unwrap();
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(resp.status, StatusCode::ServiceUnavailable);
+        let report: Value = resp.json().unwrap();
+        assert_eq!(report["status"], "unavailable");
+        let checks = report["checks"].as_array().unwrap();
+        assert_eq!(checks[0]["name"], "db");
+        assert_eq!(checks[0]["ok"], true);
+        assert_eq!(checks[1]["error"], "disk full");
+        assert_eq!(checks[2]["error"], "timed out");
+
+        // Liveness doesn't depend on the checks
+        assert_eq!(get(&server, "/healthz").unwrap().status, StatusCode::Ok);
+    }
+}
@@ -0,0 +1,75 @@
+/// Split a `Host` header value into host name and optional port.
+/// Handles bracketed IPv6 addresses like `[::1]:8080`.
+fn split_host(value: &str) -> (&str, Option<&str>) {
+    if let Some(rest) = value.strip_prefix('[') {
+        return match rest.split_once(']') {
+            Some((host, port)) => (host, port.strip_prefix(':')),
+            None => (value, None),
+        };
+    }
+    match value.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (value, None),
+    }
+}
+
+/// Check a `Host` header value against an allowlist entry. An entry
+/// without a port matches any port. An entry starting with `*.`
+/// matches any subdomain, but not the domain itself.
+fn matches(pattern: &str, host: &str) -> bool {
+    let (pattern_name, pattern_port) = split_host(pattern);
+    let (name, port) = split_host(host);
+    if pattern_port.is_some() && pattern_port != port {
+        return false;
+    }
+    match pattern_name.strip_prefix("*.") {
+        Some(domain) => name
+            .len()
+            .checked_sub(domain.len() + 1)
+            .map(|i| {
+                name.as_bytes()[i] == b'.'
+                    && name[i + 1..].eq_ignore_ascii_case(domain)
+            })
+            .unwrap_or(false),
+        None => name.eq_ignore_ascii_case(pattern_name),
+    }
+}
+
+/// Check if `host` is allowed. An empty allowlist allows everything.
+pub(crate) fn is_allowed(allowed: &[String], host: &str) -> bool {
+    allowed.is_empty() || allowed.iter().any(|pattern| matches(pattern, host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed() {
+        let allowed = ["example.com", "*.example.org", "[::1]:8080"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        for host in &[
+            "example.com",
+            "EXAMPLE.com:1234",
+            "a.example.org",
+            "a.b.example.org:80",
+            "[::1]:8080",
+        ] {
+            assert!(is_allowed(&allowed, host), "{}", host);
+        }
+        for host in &[
+            "evil.com",
+            "example.com.evil.com",
+            "notexample.com",
+            "example.org",
+            "aexample.org",
+            "[::1]:8081",
+            "[::1]",
+        ] {
+            assert!(!is_allowed(&allowed, host), "{}", host);
+        }
+        assert!(is_allowed(&[], "anything"));
+    }
+}
@@ -0,0 +1,280 @@
+//! Conversions to and from the types of the [`http`] crate, so that
+//! code written against them can be used with shs. Requires the
+//! `http` feature.
+//!
+//! - [`Request`] converts to `http::Request<Vec<u8>>`, and a handler
+//!   can answer with an `http::Response<Vec<u8>>` using
+//!   [`Request::write_http_response`].
+//! - [`TestRequest`] converts from `http::Request<Vec<u8>>`, and
+//!   [`TestResponse`] converts to and from `http::Response<Vec<u8>>`.
+//!
+//! The shs types keep one value per header, so repeated headers are
+//! joined with commas, except for `Set-Cookie`, which is kept as
+//! separate cookies.
+
+use crate::body::ResponseBody;
+use crate::cookie::parse_set_cookie;
+use crate::{HeaderName, Request, StatusCode, TestRequest, TestResponse};
+use anyhow::{anyhow, Error};
+use fehler::{throw, throws};
+use http::header::{self, HeaderMap};
+use std::convert::TryFrom;
+use std::io::Read;
+use url::Url;
+
+/// Headers the server sets itself when it sends the response.
+const FRAMING_HEADERS: &[header::HeaderName] =
+    &[header::CONTENT_LENGTH, header::TRANSFER_ENCODING];
+
+/// Get the headers with one value per name.
+#[throws]
+fn joined_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    let mut joined = Vec::new();
+    for name in headers.keys() {
+        let values = headers
+            .get_all(name)
+            .iter()
+            .map(|value| {
+                std::str::from_utf8(value.as_bytes()).map_err(|_| {
+                    anyhow!("value of header {} is not valid UTF-8", name)
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        joined.push((name.as_str().to_string(), values.join(", ")));
+    }
+    joined
+}
+
+fn set_cookie_headers(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(header::SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+}
+
+impl TryFrom<&Request> for http::Request<Vec<u8>> {
+    type Error = Error;
+
+    /// Copy the method, URL, version, headers, and body, reading the
+    /// body from its file if it was spooled.
+    #[throws]
+    fn try_from(req: &Request) -> http::Request<Vec<u8>> {
+        let mut body = Vec::new();
+        req.body_reader()?.read_to_end(&mut body)?;
+        let version = if req.is_http10() {
+            http::Version::HTTP_10
+        } else {
+            http::Version::HTTP_11
+        };
+        let mut builder = http::Request::builder()
+            .method(req.method())
+            .uri(req.url().as_str())
+            .version(version);
+        for (name, value) in req.headers() {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        builder.body(body)?
+    }
+}
+
+impl Request {
+    /// Use `resp` as the response: its status, headers, and body
+    /// replace what was set so far, and its `Set-Cookie` headers are
+    /// added as cookies. `Content-Length` and `Transfer-Encoding` are
+    /// ignored, since the server sets those itself. Requires the
+    /// `http` feature.
+    ///
+    /// Example usage:
+    /// ```
+    /// use anyhow::Error;
+    /// use fehler::throws;
+    /// use shs::Request;
+    ///
+    /// #[throws]
+    /// fn handler(req: &mut Request) {
+    ///     let resp = http::Response::builder()
+    ///         .status(201)
+    ///         .header("Location", "/items/1")
+    ///         .body(b"created".to_vec())?;
+    ///     req.write_http_response(resp)?;
+    /// }
+    /// ```
+    #[throws]
+    pub fn write_http_response(&mut self, resp: http::Response<Vec<u8>>) {
+        let (parts, body) = resp.into_parts();
+        let code = parts.status.as_u16();
+        match StatusCode::try_from(code) {
+            Ok(status) => self.set_status(status),
+            Err(_) => self.set_status_with_reason(
+                code,
+                parts.status.canonical_reason().unwrap_or("Unknown"),
+            )?,
+        }
+        for (name, value) in joined_headers(&parts.headers)? {
+            let skip = name.eq_ignore_ascii_case(header::SET_COOKIE.as_str())
+                || FRAMING_HEADERS
+                    .iter()
+                    .any(|framing| name.eq_ignore_ascii_case(framing.as_str()));
+            if !skip {
+                self.try_set_header(&name, &value)?;
+            }
+        }
+        for value in set_cookie_headers(&parts.headers) {
+            match parse_set_cookie(value) {
+                Some(cookie) => self.set_cookie(cookie),
+                None => {
+                    throw!(anyhow!("invalid Set-Cookie header {:?}", value))
+                }
+            }
+        }
+        self.resp_body = ResponseBody::Bytes(body);
+    }
+}
+
+impl TryFrom<http::Request<Vec<u8>>> for TestRequest {
+    type Error = Error;
+
+    /// A URI without a host gets it from the `Host` header, or
+    /// `example.com` if there's none, like [`TestRequest::new`].
+    #[throws]
+    fn try_from(req: http::Request<Vec<u8>>) -> TestRequest {
+        let (parts, body) = req.into_parts();
+        let url = if parts.uri.scheme().is_some() {
+            Url::parse(&parts.uri.to_string())?
+        } else {
+            let host = parts
+                .headers
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .unwrap_or("example.com");
+            let path =
+                parts.uri.path_and_query().map_or("/", |path| path.as_str());
+            Url::parse(&format!("http://{}{}", host, path))?
+        };
+        TestRequest {
+            body,
+            method: parts.method.as_str().into(),
+            url,
+            headers: joined_headers(&parts.headers)?.into_iter().collect(),
+            peer_addr: None,
+        }
+    }
+}
+
+impl TryFrom<TestResponse> for http::Response<Vec<u8>> {
+    type Error = Error;
+
+    #[throws]
+    fn try_from(resp: TestResponse) -> http::Response<Vec<u8>> {
+        let mut builder =
+            http::Response::builder().status(u16::from(resp.status));
+        for (name, value) in &resp.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        for cookie in &resp.cookies {
+            builder = builder.header(header::SET_COOKIE, cookie.as_str());
+        }
+        builder.body(resp.body)?
+    }
+}
+
+impl TryFrom<http::Response<Vec<u8>>> for TestResponse {
+    type Error = Error;
+
+    /// Fails if the status isn't one [`StatusCode`] knows.
+    #[throws]
+    fn try_from(resp: http::Response<Vec<u8>>) -> TestResponse {
+        let (parts, body) = resp.into_parts();
+        TestResponse {
+            status: StatusCode::try_from(parts.status.as_u16())?,
+            headers: joined_headers(&parts.headers)?
+                .into_iter()
+                .filter(|(name, _)| name != header::SET_COOKIE.as_str())
+                .map(|(name, value)| (HeaderName::new(name), value))
+                .collect(),
+            cookies: set_cookie_headers(&parts.headers)
+                .map(Into::into)
+                .collect(),
+            body,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+
+    #[throws]
+    fn echo(req: &mut Request) {
+        let http_req = http::Request::<Vec<u8>>::try_from(&*req)?;
+        assert_eq!(http_req.method(), "POST");
+        assert_eq!(http_req.uri(), "http://localhost/echo?x=1");
+        assert_eq!(http_req.version(), http::Version::HTTP_11);
+        let accept = http_req.headers()["accept"].clone();
+        let resp = http::Response::builder()
+            .status(202)
+            .header("X-Accept", accept)
+            .header("Content-Length", "999")
+            .header("Set-Cookie", "a=1; Path=/")
+            .header("Set-Cookie", "b=2")
+            .body(http_req.into_body())?;
+        req.write_http_response(resp)?;
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        server.route("POST /echo", &echo).unwrap();
+
+        let req = http::Request::builder()
+            .method("POST")
+            .uri("/echo?x=1")
+            .header("Host", "localhost")
+            .header("Accept", "text/plain")
+            .header("Accept", "text/html")
+            .body(b"hello".to_vec())
+            .unwrap();
+        let req = TestRequest::try_from(req).unwrap();
+        assert_eq!(req.url.as_str(), "http://localhost/echo?x=1");
+        assert_eq!(req.headers["accept"], "text/plain, text/html");
+
+        let resp = server.test_request(&req).unwrap();
+        assert_eq!(resp.status, StatusCode::Accepted);
+        assert_eq!(resp.body, b"hello");
+        assert_eq!(
+            resp.headers[&HeaderName::new("X-Accept".into())],
+            "text/plain, text/html"
+        );
+        // The server sets its own framing headers
+        assert!(!resp
+            .headers
+            .contains_key(&HeaderName::new("Content-Length".into())));
+        assert_eq!(resp.cookies, ["a=1; Path=/", "b=2"]);
+
+        let resp = http::Response::try_from(resp).unwrap();
+        assert_eq!(resp.status(), 202);
+        assert_eq!(resp.headers().get_all("set-cookie").iter().count(), 2);
+        let resp = TestResponse::try_from(resp).unwrap();
+        assert_eq!(resp.status, StatusCode::Accepted);
+        assert_eq!(resp.cookies, ["a=1; Path=/", "b=2"]);
+        assert_eq!(resp.body, b"hello");
+    }
+
+    #[test]
+    fn test_write_http_response_errors() {
+        let mut req = crate::test_req("GET", "/", &[]);
+        let resp = http::Response::builder()
+            .status(599)
+            .body(Vec::new())
+            .unwrap();
+        req.write_http_response(resp).unwrap();
+        assert_eq!(req.status_code(), 599);
+
+        let resp = http::Response::builder()
+            .header("Set-Cookie", "novalue")
+            .body(Vec::new())
+            .unwrap();
+        assert!(req.write_http_response(resp).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+//! Interim (1xx) responses sent before the final response.
+
+use crate::{parse, Request, StatusCode};
+use anyhow::{anyhow, Error};
+use fehler::{throw, throws};
+use std::io::Write;
+
+impl Request {
+    /// Send an informational response, such as `103 Early Hints`,
+    /// before the final response. It's written to the client
+    /// immediately, and may be sent several times. Informational
+    /// responses are skipped for [`Server::test_request`], and for
+    /// HTTP/1.0 clients, which can't receive them (RFC 7231 §6.2).
+    ///
+    /// `101 Switching Protocols` isn't allowed, since the connection
+    /// would no longer speak HTTP. Like [`Request::try_set_header`],
+    /// this fails if a header name isn't a valid token or a value
+    /// contains control characters other than tab.
+    ///
+    /// [`Server::test_request`]: crate::Server::test_request
+    #[throws]
+    pub fn send_informational(
+        &mut self,
+        status: StatusCode,
+        headers: &[(&str, &str)],
+    ) {
+        if !status.is_informational()
+            || status == StatusCode::SwitchingProtocols
+        {
+            throw!(anyhow!("{} is not a valid interim status", status));
+        }
+        if self.streamed {
+            throw!(anyhow!("the response was already sent"));
+        }
+        for (name, value) in headers {
+            if !parse::is_token(name) {
+                throw!(anyhow!("invalid interim header name {:?}", name));
+            }
+            if !parse::is_valid_header_value(value) {
+                throw!(anyhow!("invalid value for interim header {}", name));
+            }
+        }
+        if self.is_http10() {
+            return;
+        }
+        let stream = match &mut self.connection {
+            Some(stream) => stream,
+            None => return,
+        };
+        let mut head =
+            format!("HTTP/1.1 {} {}\r\n", status, status.canonical_reason());
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+    }
+
+    /// Send `103 Early Hints` with a `Link` header for each of
+    /// `links`, so the browser can start loading assets while the
+    /// final response is being produced.
+    ///
+    /// Example usage:
+    /// ```
+    /// use anyhow::Error;
+    /// use fehler::throws;
+    /// use shs::Request;
+    ///
+    /// #[throws]
+    /// fn page(req: &mut Request) {
+    ///     req.send_early_hints(&["</style.css>; rel=preload; as=style"])?;
+    ///     req.write_text("...");
+    /// }
+    /// ```
+    #[throws]
+    pub fn send_early_hints(&mut self, links: &[&str]) {
+        let headers: Vec<_> =
+            links.iter().map(|link| ("Link", *link)).collect();
+        self.send_informational(StatusCode::EarlyHints, &headers)?;
+    }
+}
@@ -0,0 +1,73 @@
+//! Allowing or denying connections by the peer's IP address.
+
+use anyhow::{Context, Error};
+use fehler::throws;
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Parse IP addresses and CIDR ranges such as `"10.0.0.0/8"`. A bare
+/// address is treated as a range containing just that address.
+#[throws]
+pub(crate) fn parse_nets(entries: &[&str]) -> Vec<IpNet> {
+    entries
+        .iter()
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .with_context(|| format!("invalid IP address {}", entry))
+        })
+        .collect::<Result<_, _>>()?
+}
+
+/// Peers allowed to connect, set with [`Server::set_ip_allowlist`]
+/// and [`Server::set_ip_denylist`].
+///
+/// [`Server::set_ip_allowlist`]: crate::Server::set_ip_allowlist
+/// [`Server::set_ip_denylist`]: crate::Server::set_ip_denylist
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IpFilter {
+    /// If not empty, only these peers are allowed.
+    pub(crate) allow: Vec<IpNet>,
+    /// These peers are never allowed, even if on the allowlist.
+    pub(crate) deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub(crate) fn is_allowed(&self, ip: IpAddr) -> bool {
+        // Compare IPv4-mapped IPv6 peers, as seen on dual-stack
+        // listeners, as plain IPv4
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        assert!(IpFilter::default().is_allowed(ip("192.0.2.1")));
+
+        let filter = IpFilter {
+            allow: parse_nets(&["10.0.0.0/8", "::1"]).unwrap(),
+            deny: parse_nets(&["10.0.0.13"]).unwrap(),
+        };
+        assert!(filter.is_allowed(ip("10.1.2.3")));
+        assert!(filter.is_allowed(ip("::ffff:10.1.2.3")));
+        assert!(filter.is_allowed(ip("::1")));
+        assert!(!filter.is_allowed(ip("10.0.0.13")));
+        assert!(!filter.is_allowed(ip("192.0.2.1")));
+
+        assert!(parse_nets(&["10.0.0.0/33"]).is_err());
+    }
+}
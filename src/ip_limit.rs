@@ -0,0 +1,229 @@
+//! Limits on how much of the server a single client IP can use.
+
+use crate::stream::Connection;
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{IpAddr, Shutdown};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What to do with a connection from a client that is over its limit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LimitAction {
+    /// Respond with `429 Too Many Requests` and a `Retry-After`
+    /// header, then close the connection.
+    TooManyRequests,
+    /// Close the connection without responding.
+    Close,
+}
+
+/// Per-client-IP limits, set with [`ServerBuilder::ip_limits`]. This
+/// is a cheap defense against a single client tying up all the
+/// connection threads.
+///
+/// Limits apply to the address of the peer, before the request is
+/// read, so behind a proxy every client shares the proxy's address.
+///
+/// Example usage:
+/// ```
+/// use shs::{IpLimits, LimitAction};
+/// use std::time::Duration;
+///
+/// let limits = IpLimits::new()
+///     .max_connections(Some(8))
+///     .max_requests(Some((100, Duration::from_secs(60))))
+///     .action(LimitAction::Close);
+/// ```
+///
+/// [`ServerBuilder::ip_limits`]: crate::ServerBuilder::ip_limits
+#[derive(Clone, Debug)]
+pub struct IpLimits {
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) max_requests: Option<(u32, Duration)>,
+    action: LimitAction,
+}
+
+impl Default for IpLimits {
+    fn default() -> IpLimits {
+        IpLimits {
+            max_connections: None,
+            max_requests: None,
+            action: LimitAction::TooManyRequests,
+        }
+    }
+}
+
+impl IpLimits {
+    /// Create limits that allow everything.
+    pub fn new() -> IpLimits {
+        IpLimits::default()
+    }
+
+    /// Set the maximum number of connections one IP can have open at
+    /// once.
+    pub fn max_connections(mut self, max: Option<usize>) -> IpLimits {
+        self.max_connections = max;
+        self
+    }
+
+    /// Set the maximum number of requests one IP can make within each
+    /// window of the given length.
+    pub fn max_requests(mut self, max: Option<(u32, Duration)>) -> IpLimits {
+        self.max_requests = max;
+        self
+    }
+
+    /// Set what happens to connections over the limit. The default is
+    /// [`LimitAction::TooManyRequests`].
+    pub fn action(mut self, action: LimitAction) -> IpLimits {
+        self.action = action;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct ClientState {
+    connections: usize,
+    window_start: Instant,
+    requests: u32,
+}
+
+/// Entries are only purged once there are this many, so that the
+/// common case doesn't scan the map.
+const PURGE_THRESHOLD: usize = 1024;
+
+/// Tracks connections and requests per client IP.
+pub(crate) struct IpTracker {
+    limits: IpLimits,
+    clients: Mutex<HashMap<IpAddr, ClientState>>,
+}
+
+impl IpTracker {
+    pub(crate) fn new(limits: IpLimits) -> IpTracker {
+        IpTracker {
+            limits,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count a new connection from `ip`. Returns how long the client
+    /// should wait if it's over a limit.
+    fn check(self: &Arc<Self>, ip: IpAddr) -> Result<IpGuard, Duration> {
+        let now = Instant::now();
+        let window = self.limits.max_requests.map(|(_, window)| window);
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= PURGE_THRESHOLD {
+            clients.retain(|_, client| {
+                client.connections > 0
+                    || window.is_some_and(|w| now - client.window_start < w)
+            });
+        }
+        let client = clients.entry(ip).or_insert(ClientState {
+            connections: 0,
+            window_start: now,
+            requests: 0,
+        });
+        if let Some(max) = self.limits.max_connections {
+            if client.connections >= max {
+                return Err(Duration::from_secs(1));
+            }
+        }
+        if let Some((max, window)) = self.limits.max_requests {
+            let elapsed = now - client.window_start;
+            if elapsed >= window {
+                client.window_start = now;
+                client.requests = 0;
+            } else if client.requests >= max {
+                return Err(window - elapsed);
+            }
+            client.requests += 1;
+        }
+        client.connections += 1;
+        Ok(IpGuard {
+            tracker: self.clone(),
+            ip,
+        })
+    }
+
+    /// Check `stream` against the limits, rejecting it if it's over.
+    /// Returns the stream and a guard that keeps the connection
+    /// counted if it should be handled.
+    pub(crate) fn admit(
+        self: &Arc<Self>,
+        mut stream: Connection,
+        ip: IpAddr,
+    ) -> Option<(Connection, IpGuard)> {
+        match self.check(ip) {
+            Ok(guard) => Some((stream, guard)),
+            Err(retry_after) => {
+                log::warn!("rejecting connection from {}: over limit", ip);
+                if self.limits.action == LimitAction::TooManyRequests {
+                    let secs = retry_after.as_secs_f64().ceil().max(1.0);
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 429 Too Many Requests\r\n\
+                         Retry-After: {}\r\nContent-Length: 0\r\n\
+                         Connection: close\r\n\r\n",
+                        secs
+                    );
+                    let _ = stream.shutdown(Shutdown::Write);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Keeps a connection counted for its client IP.
+pub(crate) struct IpGuard {
+    tracker: Arc<IpTracker>,
+    ip: IpAddr,
+}
+
+impl Drop for IpGuard {
+    fn drop(&mut self) {
+        let mut clients = self.tracker.clients.lock().unwrap();
+        if let Some(client) = clients.get_mut(&self.ip) {
+            client.connections -= 1;
+            if client.connections == 0
+                && self.tracker.limits.max_requests.is_none()
+            {
+                clients.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_connections() {
+        let tracker =
+            Arc::new(IpTracker::new(IpLimits::new().max_connections(Some(2))));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let first = tracker.check(a).unwrap();
+        let _second = tracker.check(a).unwrap();
+        assert!(tracker.check(a).is_err());
+        let _other = tracker.check(b).unwrap();
+        drop(first);
+        let _third = tracker.check(a).unwrap();
+    }
+
+    #[test]
+    fn test_max_requests() {
+        let window = Duration::from_millis(50);
+        let tracker = Arc::new(IpTracker::new(
+            IpLimits::new().max_requests(Some((2, window))),
+        ));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        drop(tracker.check(ip).unwrap());
+        drop(tracker.check(ip).unwrap());
+        let retry_after = tracker.check(ip).err().unwrap();
+        assert!(retry_after <= window);
+        std::thread::sleep(window);
+        drop(tracker.check(ip).unwrap());
+    }
+}
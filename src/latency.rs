@@ -0,0 +1,178 @@
+//! Handler latency per route, and logging of slow requests.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds of the histogram buckets, in milliseconds. Durations
+/// above the last bound go in an extra overflow bucket.
+const BUCKET_BOUNDS_MS: [u64; 13] =
+    [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Histogram of handler durations for one route.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Histogram {
+    counts: [u64; BUCKET_BOUNDS_MS.len() + 1],
+    total: Duration,
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration) {
+        let index = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|&bound| elapsed <= Duration::from_millis(bound))
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.counts[index] += 1;
+        self.total += elapsed;
+    }
+
+    /// Number of requests recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Total time spent in the handler.
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    /// Cumulative bucket counts: for each upper bound, the number of
+    /// requests that took at most that long. Requests slower than the
+    /// last bound are only included in [`Histogram::count`].
+    pub fn buckets(&self) -> Vec<(Duration, u64)> {
+        let mut cumulative = 0;
+        BUCKET_BOUNDS_MS
+            .iter()
+            .zip(&self.counts)
+            .map(|(&bound, &count)| {
+                cumulative += count;
+                (Duration::from_millis(bound), cumulative)
+            })
+            .collect()
+    }
+
+    /// Estimate a quantile such as 0.99, as the upper bound of the
+    /// bucket it falls in. Returns `None` if nothing was recorded or
+    /// the quantile is in the overflow bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        self.buckets()
+            .into_iter()
+            .find(|&(_, cumulative)| cumulative >= rank)
+            .map(|(bound, _)| bound)
+    }
+}
+
+/// Latency of one route, returned by [`LatencyMonitor::routes`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RouteLatency {
+    /// Request method, e.g. `"GET"`.
+    pub method: String,
+    /// Path pattern of the route.
+    pub pattern: String,
+    /// Handler durations.
+    pub histogram: Histogram,
+}
+
+type Histograms = Arc<Mutex<HashMap<(String, String), Histogram>>>;
+
+/// Records handler durations, and logs slow requests.
+#[derive(Clone, Default)]
+pub(crate) struct LatencyRecorder {
+    histograms: Histograms,
+    pub(crate) slow_threshold: Option<Duration>,
+}
+
+impl LatencyRecorder {
+    pub(crate) fn record(
+        &self,
+        method: &str,
+        pattern: &str,
+        path: &str,
+        elapsed: Duration,
+    ) {
+        if self.slow_threshold.is_some_and(|t| elapsed > t) {
+            log::warn!(
+                "slow request: {} {} took {:?} (route {})",
+                method,
+                path,
+                elapsed,
+                pattern
+            );
+        }
+        self.histograms
+            .lock()
+            .unwrap()
+            .entry((method.into(), pattern.into()))
+            .or_default()
+            .record(elapsed);
+    }
+
+    pub(crate) fn monitor(&self) -> LatencyMonitor {
+        LatencyMonitor {
+            histograms: self.histograms.clone(),
+        }
+    }
+}
+
+/// Read-only view of the handler latency of each route, returned by
+/// [`Server::latency_monitor`]. It can be kept after the server is
+/// launched, for example to export metrics.
+///
+/// The time covers middleware and the handler, not reading the
+/// request or writing the response.
+///
+/// [`Server::latency_monitor`]: crate::Server::latency_monitor
+#[derive(Clone)]
+pub struct LatencyMonitor {
+    histograms: Histograms,
+}
+
+impl LatencyMonitor {
+    /// Get the latency of every route that has handled a request,
+    /// sorted by method and pattern.
+    pub fn routes(&self) -> Vec<RouteLatency> {
+        let mut routes = self
+            .histograms
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((method, pattern), histogram)| RouteLatency {
+                method: method.clone(),
+                pattern: pattern.clone(),
+                histogram: histogram.clone(),
+            })
+            .collect::<Vec<_>>();
+        routes.sort_by(|a, b| {
+            (&a.method, &a.pattern).cmp(&(&b.method, &b.pattern))
+        });
+        routes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+        for ms in [0, 3, 3, 40, 20_000] {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.total(), Duration::from_millis(20_046));
+        let buckets = histogram.buckets();
+        assert_eq!(buckets[0], (Duration::from_millis(1), 1));
+        assert_eq!(buckets[2], (Duration::from_millis(5), 3));
+        assert_eq!(buckets[12], (Duration::from_millis(10000), 4));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_millis(5)));
+        assert_eq!(histogram.quantile(0.8), Some(Duration::from_millis(50)));
+        assert_eq!(histogram.quantile(1.0), None);
+    }
+}
@@ -2,65 +2,405 @@
 
 //! Easy-to-use non-async HTTP 1.1 server.
 
+mod acceptor;
+mod access_log;
+mod api_key;
+mod audit;
+mod background;
+mod body;
+mod builder;
+mod cache_control;
+mod cgi;
+#[cfg(feature = "checksum")]
+mod checksum;
+mod chunked;
+mod compression;
+#[cfg(feature = "config")]
+mod config;
+mod connection;
+mod cookie;
+#[cfg(feature = "secure-cookies")]
+mod cookie_jar;
+mod deadline;
+mod debug_routes;
+#[cfg(feature = "digest-auth")]
+mod digest_auth;
+mod dir_listing;
+mod embed;
+mod error_page;
+mod extensions;
+mod extract;
+mod file;
+#[cfg(feature = "secure-cookies")]
+mod flash;
+mod forwarded;
+mod guard;
+#[cfg(unix)]
+pub mod handover;
+mod headers;
+mod health;
+mod host;
+mod html;
+#[cfg(feature = "http")]
+mod http_interop;
+mod informational;
+mod ip_filter;
+mod ip_limit;
+mod latency;
+mod load;
+mod method_override;
+mod middleware;
+pub mod mime;
+mod multipart;
+#[cfg(windows)]
+mod named_pipe;
+mod ndjson;
+mod otel;
+pub mod parse;
+mod pattern;
+mod precondition;
+mod problem;
+mod range;
+mod recording;
+mod response_cache;
+mod route;
+mod router;
+mod schedule;
+mod security_headers;
+#[cfg(feature = "tower")]
+mod service;
+mod session;
+mod shutdown;
+mod spool;
 mod status_code;
+mod stream;
+#[cfg(feature = "templates")]
+pub mod template;
+mod test_session;
+mod timeout;
+mod token_auth;
+mod trace;
+mod tunnel;
 
+pub use acceptor::Acceptor;
+pub use access_log::AccessLogFormat;
 use anyhow::{anyhow, Context, Error};
+pub use api_key::{ApiKeyAuth, ApiKeyIdentity};
+pub use audit::{AuditRecord, AuditSink};
+pub use background::BackgroundServer;
+use body::ResponseBody;
 use bufstream::BufStream;
+pub use builder::{ConfigError, ServerBuilder};
+use builder::{Listen, SocketOptions, ThreadOptions};
+pub use cache_control::CacheControl;
+pub use cgi::{Cgi, FastCgi};
+#[cfg(feature = "checksum")]
+pub use checksum::{BodyChecksum, ChecksumAlgorithm};
+use chunked::ChunkedReader;
+pub use compression::Compression;
+use compression::DEFAULT_MAX_DECOMPRESSED_SIZE;
+#[cfg(feature = "config")]
+pub use config::ServerConfig;
+pub use connection::ClientDisconnected;
+pub use cookie::{Cookie, SameSite};
+#[cfg(feature = "secure-cookies")]
+pub use cookie_jar::{CookieJar, Key};
+use deadline::DeadlineStream;
+#[cfg(feature = "digest-auth")]
+pub use digest_auth::{
+    DigestAlgorithm, DigestAuth, DigestCredential, DigestUser,
+};
+pub use embed::EmbeddedAssets;
+pub use extensions::Extensions;
+use extract::StateMap;
+pub use extract::{
+    FromRequest, IntoResponse, Json, PathParam, PathParams, Rejection, State,
+    TypedHandler,
+};
 use fehler::{throw, throws};
-use log::error;
+pub use file::FileOptions;
+use forwarded::resolve_client;
+pub use forwarded::ForwardedHeader;
+pub use guard::Guard;
+use headers::ResponseHeaders;
+use ip_filter::IpFilter;
+use ip_limit::IpTracker;
+pub use ip_limit::{IpLimits, LimitAction};
+use ipnet::IpNet;
+pub use latency::{Histogram, LatencyMonitor, RouteLatency};
+pub use load::LoadMonitor;
+use load::{ConnectionCount, LoadOptions};
+use log::{error, info};
+pub use middleware::{AfterHook, Middleware, Next};
+pub use multipart::{Multipart, Part, SavedPart};
+pub use ndjson::NdjsonSender;
+pub use parse::TargetForm;
+use parse::{BodyLength, ParseOptions};
+use pattern::Pattern;
+pub use precondition::IfMatch;
+pub use problem::Problem;
+pub use response_cache::{CacheHandle, ResponseCache};
+pub use route::{RouteGroup, RouteHandle, RouteInfo};
+use route::{RouteOptions, RouteTable, Routes};
+pub use router::{LinearRouter, RouteMatch, Router, TrieRouter};
+pub use security_headers::SecurityHeaders;
 use serde::{Deserialize, Serialize};
+pub use session::{
+    FileStore, MemoryStore, Session, SessionData, SessionStore, Sessions,
+};
+use shutdown::Shutdown;
+use spool::DEFAULT_MAX_BODY_SIZE;
+pub use spool::{Body, UnknownLengthPolicy};
 pub use status_code::StatusCode;
+use std::any::{Any, TypeId};
 use std::collections::HashMap;
-use std::convert::Infallible;
+use std::convert::{Infallible, TryFrom};
 use std::fmt::{Debug, Display};
-use std::io::{BufRead, Read, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::io::{self, Write};
+use std::mem;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::panic::{self, AssertUnwindSafe};
 use std::str::FromStr;
-use std::sync::{Arc, RwLock};
-use std::thread;
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+use stream::Listener;
+pub use test_session::TestSession;
+pub use token_auth::{
+    AuthError, AuthProvider, Claims, MemoryAuthProvider, TokenAuth,
+};
+pub use tunnel::ConnectFilter;
 use url::Url;
 
 type HeaderName = unicase::UniCase<String>;
 
-/// Requirements for the Server Error type parameter.
-//pub trait ErrorTrait: Debug + Display + 'static {}
+const DEFAULT_SERVER_HEADER: &str = concat!("shs/", env!("CARGO_PKG_VERSION"));
 
 /// Request type passed to handlers. It provides both the input
 /// request and the output response, as well as access to state shared
 /// across requests.
 pub struct Request {
     method: String,
+    version: String,
     path_params: HashMap<String, String>,
+    route_pattern: Option<String>,
     req_headers: HashMap<HeaderName, String>,
-    req_body: Vec<u8>,
+    req_body: Body,
     url: Url,
+    peer_addr: Option<SocketAddr>,
+    client_ip: Option<IpAddr>,
+    scheme: String,
+    extensions: Extensions,
+    route_names: Arc<HashMap<String, Pattern>>,
+    state: Arc<StateMap>,
+    #[cfg(feature = "templates")]
+    templates: Option<Arc<template::Templates>>,
 
     status: StatusCode,
-    resp_body: Vec<u8>,
-    resp_headers: HashMap<String, String>,
+    /// Code and reason phrase set with
+    /// [`Request::set_status_with_reason`].
+    custom_status: Option<(u16, String)>,
+    resp_body: ResponseBody,
+    resp_headers: ResponseHeaders,
+    resp_cookies: Vec<Cookie>,
+    target_form: TargetForm,
+    connection: Option<stream::Connection>,
+    server_header: Option<String>,
+    /// Whether the response was already sent while the handler ran.
+    streamed: bool,
+    /// Set while a handler with a timeout runs.
+    watchdog: Option<timeout::Watchdog>,
+    /// Opt-outs of the route that matched.
+    route_options: RouteOptions,
+    /// Set with [`BodyChecksum::respond_with`].
+    #[cfg(feature = "checksum")]
+    content_digest: Option<ChecksumAlgorithm>,
 }
 
 impl Request {
-    /// Get the request URL.
+    fn new(
+        method: String,
+        mut url: Url,
+        req_headers: HashMap<HeaderName, String>,
+        req_body: Body,
+        peer_addr: Option<SocketAddr>,
+        settings: &Settings,
+    ) -> Request {
+        let client = resolve_client(
+            peer_addr,
+            &req_headers,
+            &settings.trusted_proxies,
+            settings.forwarded_header,
+        );
+        // Connections are always plain HTTP, but a trusted proxy may
+        // have terminated TLS
+        if url.scheme() != client.scheme {
+            // Switching between http and https can't fail
+            let _ = url.set_scheme(&client.scheme);
+        }
+        let mut req = Request {
+            method,
+            version: "HTTP/1.1".into(),
+            path_params: HashMap::new(),
+            route_pattern: None,
+            req_headers,
+            req_body,
+            url,
+            peer_addr,
+            client_ip: client.ip,
+            scheme: client.scheme,
+            extensions: Extensions::new(),
+            route_names: settings.route_names.clone(),
+            state: settings.state.clone(),
+            #[cfg(feature = "templates")]
+            templates: settings.templates.clone(),
+
+            resp_body: ResponseBody::default(),
+            status: StatusCode::Ok,
+            custom_status: None,
+            resp_headers: ResponseHeaders::default(),
+            resp_cookies: Vec::new(),
+            target_form: TargetForm::Origin,
+            connection: None,
+            server_header: settings.server_header.clone(),
+            streamed: false,
+            watchdog: None,
+            route_options: RouteOptions::default(),
+            #[cfg(feature = "checksum")]
+            content_digest: None,
+        };
+        if let Some(security_headers) = &settings.security_headers {
+            for (name, value) in security_headers.iter() {
+                req.set_header(name, value);
+            }
+        }
+        req
+    }
+    /// Get the request URL. The scheme is the one returned by
+    /// [`Request::scheme`].
     pub fn url(&self) -> &Url {
         &self.url
     }
 
+    /// Get the address of the client at the other end of the
+    /// connection. This is `None` for test requests that don't set an
+    /// address with [`TestRequest::set_peer_addr`].
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// Get the IP address of the client. If the connection comes from
+    /// a proxy listed in [`Server::set_trusted_proxies`], this is
+    /// taken from the header chosen with
+    /// [`Server::set_forwarded_header`]; otherwise it's the address of [`Request::peer_addr`].
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.client_ip
+    }
+
+    /// Add the `Date` and `Server` headers unless the handler already
+    /// set them.
+    fn add_standard_headers(&mut self) {
+        if self.resp_header("Date").is_none() {
+            self.set_header(
+                "Date",
+                &httpdate::fmt_http_date(SystemTime::now()),
+            );
+        }
+        if let Some(server_header) = self.server_header.clone() {
+            if self.resp_header("Server").is_none() {
+                self.set_header("Server", &server_header);
+            }
+        }
+    }
+
+    /// Drop the response body if the response must not have one, and
+    /// return how the remaining body should be framed (RFC 7230
+    /// §3.3). Responses to `HEAD` keep the `Content-Length` their body
+    /// would have had.
+    fn body_framing(&mut self) -> Framing {
+        let status = self.status;
+        if status.is_informational()
+            || status == StatusCode::NoContent
+            || status == StatusCode::NotModified
+        {
+            self.resp_body = ResponseBody::default();
+            Framing::None
+        } else if self.method == "HEAD" {
+            let len = self.resp_body.len();
+            self.resp_body = ResponseBody::default();
+            Framing::Length(len)
+        } else {
+            Framing::Length(self.resp_body.len())
+        }
+    }
+
+    /// Check whether the client is still connected, so that slow
+    /// handlers can give up once nobody is waiting for the response.
+    /// This doesn't block. Always true for [`Server::test_request`].
+    pub fn is_client_connected(&self) -> bool {
+        self.connection
+            .as_ref()
+            .is_none_or(|connection| connection.is_connected())
+    }
+
+    /// Get the scheme (`"http"` or `"https"`) the client used. This is
+    /// always `"http"` unless the connection comes from a trusted
+    /// proxy that sets `Forwarded` or `X-Forwarded-Proto`.
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    /// Get the form of the request target. This is
+    /// [`TargetForm::Origin`] except for requests meant for a proxy,
+    /// or `OPTIONS *`.
+    pub fn target_form(&self) -> TargetForm {
+        self.target_form
+    }
+
     /// Get the request headers.
     pub fn headers(&self) -> &HashMap<HeaderName, String> {
         &self.req_headers
     }
 
+    /// Get the raw request body. This is empty if the body was
+    /// spooled to a file; see [`Request::body_reader`].
+    pub fn body(&self) -> &[u8] {
+        self.req_body.bytes().unwrap_or_default()
+    }
+
+    /// Get the body if it's in memory, or fail if it was spooled.
+    #[throws]
+    fn body_in_memory(&self) -> &[u8] {
+        match self.req_body.bytes() {
+            Some(bytes) => bytes,
+            None => throw!(anyhow!(
+                "request body was spooled to a file; use Request::body_reader"
+            )),
+        }
+    }
+
+    /// Get the request body as text. Fails if the body is not valid
+    /// UTF-8.
+    #[throws]
+    pub fn body_text(&self) -> &str {
+        std::str::from_utf8(self.body_in_memory()?).map_err(|err| {
+            anyhow!(
+                "request body is not valid UTF-8 (invalid byte at offset {})",
+                err.valid_up_to()
+            )
+        })?
+    }
+
     /// Deserialize the body as JSON.
     #[throws]
     pub fn read_json<'a, D: Deserialize<'a>>(&'a self) -> D {
-        serde_json::from_slice(&self.req_body)?
+        serde_json::from_slice(self.body_in_memory()?)?
     }
 
     /// Write the input as the response body. This also sets the
     /// `Content-Type` to `application/octet-stream`.
     pub fn write_bytes(&mut self, body: &[u8]) {
-        self.resp_body = body.to_vec();
+        self.resp_body = ResponseBody::Bytes(body.to_vec());
         self.set_content_type("application/octet-stream");
     }
 
@@ -68,20 +408,60 @@ impl Request {
     /// `Content-Type` to `application/json`.
     #[throws]
     pub fn write_json<S: Serialize>(&mut self, body: &S) {
-        self.resp_body = serde_json::to_vec(body)?;
+        self.resp_body = ResponseBody::Bytes(serde_json::to_vec(body)?);
         self.set_content_type("application/json");
     }
 
     /// Write the input as the response body with utf-8 encoding. This
     /// also sets the `Content-Type` to `text/plain; charset=UTF-8`.
     pub fn write_text(&mut self, body: &str) {
-        self.resp_body = body.as_bytes().to_vec();
+        self.resp_body = ResponseBody::Bytes(body.as_bytes().to_vec());
         self.set_content_type("text/plain; charset=UTF-8");
     }
 
     /// Set the response status code.
     pub fn set_status(&mut self, status: StatusCode) {
         self.status = status;
+        self.custom_status = None;
+    }
+
+    /// Set the response status line to a code and reason phrase of
+    /// your choosing, for gateways that pass on nonstandard statuses:
+    ///
+    /// ```
+    /// # use anyhow::Error;
+    /// # use fehler::throws;
+    /// # use shs::Request;
+    /// #[throws]
+    /// fn upstream_timeout(req: &mut Request) {
+    ///     req.set_status_with_reason(599, "Network Timeout")?;
+    /// }
+    /// ```
+    ///
+    /// The code has to be in the range 100 to 599, and the reason
+    /// can't contain control characters. A code that [`StatusCode`]
+    /// doesn't know is handled everywhere else as the generic code of
+    /// its class, so 599 is treated as 500 by error handling and in
+    /// [`TestResponse::status`].
+    #[throws]
+    pub fn set_status_with_reason(&mut self, code: u16, reason: &str) {
+        if !(100..600).contains(&code) {
+            throw!(anyhow!("invalid status code {}", code));
+        }
+        if !parse::is_valid_header_value(reason) {
+            throw!(anyhow!("invalid reason phrase {:?}", reason));
+        }
+        self.status = StatusCode::try_from(code)
+            .or_else(|_| StatusCode::try_from(code / 100 * 100))?;
+        self.custom_status = Some((code, reason.into()));
+    }
+
+    /// The status code actually sent, including a custom one.
+    pub(crate) fn status_code(&self) -> u16 {
+        match &self.custom_status {
+            Some((code, _)) => *code,
+            None => self.status.into(),
+        }
     }
 
     /// Set the response status code to 404 (not found).
@@ -89,9 +469,112 @@ impl Request {
         self.set_status(StatusCode::NotFound);
     }
 
-    /// Set a response header.
+    /// Set a response header. A header with an invalid name, or a
+    /// value containing control characters such as `\r` or `\n`, is
+    /// logged and not set, so that untrusted input can't inject
+    /// headers. Use [`Request::try_set_header`] to handle that case.
     pub fn set_header(&mut self, name: &str, value: &str) {
-        self.resp_headers.insert(name.into(), value.into());
+        if let Err(err) = self.try_set_header(name, value) {
+            error!("{}", err);
+        }
+    }
+
+    /// Set a response header, failing if the name isn't a valid token
+    /// or the value contains control characters other than tab.
+    #[throws]
+    pub fn try_set_header(&mut self, name: &str, value: &str) {
+        if !parse::is_token(name) {
+            throw!(anyhow!("invalid response header name {:?}", name));
+        }
+        if !parse::is_valid_header_value(value) {
+            throw!(anyhow!("invalid value for response header {}", name));
+        }
+        self.resp_headers.insert(name, value);
+    }
+
+    /// Get the value of a cookie sent by the client.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        let header = self.req_headers.get(&HeaderName::new("Cookie".into()))?;
+        cookie::find_cookie(header, name)
+    }
+
+    /// Send a cookie to the client with a `Set-Cookie` header. This
+    /// replaces any cookie with the same name set earlier in the same
+    /// response. Like [`Request::set_header`], a cookie containing
+    /// control characters is logged and not set.
+    pub fn set_cookie(&mut self, cookie: Cookie) {
+        if !parse::is_valid_header_value(&cookie.to_string()) {
+            error!("invalid cookie {:?}", cookie.name());
+            return;
+        }
+        self.resp_cookies.retain(|c| c.name() != cookie.name());
+        self.resp_cookies.push(cookie);
+    }
+
+    /// Tell the client to delete a cookie. The path and domain must
+    /// match the ones the cookie was set with, so pass the original
+    /// cookie or one built the same way; its value is ignored.
+    pub fn remove_cookie(&mut self, cookie: Cookie) {
+        self.set_cookie(cookie.into_removal());
+    }
+
+    /// Get the [`CookieJar`] set with [`Server::set_cookie_jar`].
+    #[cfg(feature = "secure-cookies")]
+    #[throws]
+    fn cookie_jar(&self) -> Arc<CookieJar> {
+        self.state::<CookieJar>()
+            .ok_or_else(|| anyhow!("no cookie jar set on the server"))?
+    }
+
+    /// Get the value of a cookie set with
+    /// [`Request::set_signed_cookie`]. Returns `None` if the cookie is
+    /// missing or its signature doesn't match, which means the client
+    /// changed it.
+    #[cfg(feature = "secure-cookies")]
+    pub fn signed_cookie(&self, name: &str) -> Option<String> {
+        self.cookie_jar().ok()?.verify(name, self.cookie(name)?)
+    }
+
+    /// Send a cookie whose value is signed with the server's
+    /// [`CookieJar`]. Fails if no jar was set.
+    #[cfg(feature = "secure-cookies")]
+    #[throws]
+    pub fn set_signed_cookie(&mut self, mut cookie: Cookie) {
+        let value = self.cookie_jar()?.sign(cookie.name(), cookie.value());
+        cookie.set_value(&value);
+        self.set_cookie(cookie);
+    }
+
+    /// Get the value of a cookie set with
+    /// [`Request::set_private_cookie`]. Returns `None` if the cookie is
+    /// missing or can't be decrypted.
+    #[cfg(feature = "secure-cookies")]
+    pub fn private_cookie(&self, name: &str) -> Option<String> {
+        self.cookie_jar().ok()?.decrypt(name, self.cookie(name)?)
+    }
+
+    /// Send a cookie whose value is encrypted with the server's
+    /// [`CookieJar`]. Fails if no jar was set.
+    #[cfg(feature = "secure-cookies")]
+    #[throws]
+    pub fn set_private_cookie(&mut self, mut cookie: Cookie) {
+        let value = self.cookie_jar()?.encrypt(cookie.name(), cookie.value());
+        cookie.set_value(&value);
+        self.set_cookie(cookie);
+    }
+
+    /// Set the status along with a short plain-text body naming it,
+    /// e.g. "payload too large". Used for errors detected by the
+    /// server itself rather than a handler.
+    fn write_status_body(&mut self, status: StatusCode) {
+        self.set_status(status);
+        self.write_text(&status.canonical_reason().to_lowercase());
+    }
+
+    /// Get a response header that has already been set. The name is
+    /// case-insensitive.
+    fn resp_header(&self, name: &str) -> Option<&str> {
+        self.resp_headers.get(name)
     }
 
     /// Set the `Content-Type` response header.
@@ -99,6 +582,41 @@ impl Request {
         self.set_header("Content-Type", value);
     }
 
+    /// Store a value in the request's extensions, replacing any
+    /// existing value of the same type. This is how middleware passes
+    /// data such as the authenticated user to handlers.
+    pub fn insert_ext<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.extensions.insert(value)
+    }
+
+    /// Get a value of type `T` from the request's extensions.
+    pub fn get_ext<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.extensions.get()
+    }
+
+    /// Get all of the request's extensions.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Get all of the request's extensions mutably.
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// Build a path to a named route. See [`Server::url_for`].
+    #[throws]
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> String {
+        url_for(&self.route_names, name, params)?
+    }
+
+    /// Get shared state of type `T` added with [`Server::add_state`].
+    pub fn state<T: Any + Send + Sync>(&self) -> Option<Arc<T>> {
+        self.state
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.clone().downcast().ok())
+    }
+
     /// Get a path parameter. For example, if an input route
     /// "/resource/:key" is defined, the handler can get the ":key"
     /// portion by calling `path_param("key")`. The returned type can
@@ -117,6 +635,75 @@ impl Request {
             .parse()
             .with_context(|| format!("failed to parse path param {}", name))?
     }
+
+    /// Get all path parameters of the matched route, keyed by name
+    /// without the leading colon or asterisk.
+    pub fn path_params(&self) -> &HashMap<String, String> {
+        &self.path_params
+    }
+
+    /// Get the request method, for example `"GET"`.
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// Get the protocol version from the request line, for example
+    /// `"HTTP/1.0"`. Responses always use HTTP/1.1, which HTTP/1.0
+    /// clients accept, but avoid features those clients don't
+    /// understand. Test requests are HTTP/1.1.
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// Whether the client only speaks HTTP/1.0, and so can't receive
+    /// chunked responses.
+    pub(crate) fn is_http10(&self) -> bool {
+        parse::is_http10(&self.version)
+    }
+
+    /// Get the pattern of the route that matched the request, for
+    /// example `"/resource/:key"`. This is `None` if no route matched,
+    /// so error handlers can tell a 404 apart from a failing route.
+    pub fn route_pattern(&self) -> Option<&str> {
+        self.route_pattern.as_deref()
+    }
+}
+
+/// Build a request for unit tests that call `Request` methods
+/// directly rather than going through a server. `target` is a path
+/// with an optional query, such as `"/a?b=c"`.
+#[cfg(test)]
+pub(crate) fn test_req(
+    method: &str,
+    target: &str,
+    headers: &[(&str, &str)],
+) -> Request {
+    Request::new(
+        method.into(),
+        Url::parse(&format!("http://example.com{}", target)).unwrap(),
+        headers
+            .iter()
+            .map(|(name, value)| {
+                (HeaderName::new(name.to_string()), value.to_string())
+            })
+            .collect(),
+        Body::default(),
+        None,
+        &Settings::default(),
+    )
+}
+
+#[throws]
+fn url_for(
+    route_names: &HashMap<String, Pattern>,
+    name: &str,
+    params: &[(&str, &str)],
+) -> String {
+    route_names
+        .get(name)
+        .ok_or_else(|| anyhow!("no route named {}", name))?
+        .format(params)
+        .with_context(|| format!("failed to build URL for route {}", name))?
 }
 
 /// Handler function for a route.
@@ -125,30 +712,13 @@ pub type Handler<E> = dyn Fn(&mut Request) -> Result<(), E> + Send + Sync;
 /// Error handler function.
 pub type ErrorHandler<E> = dyn Fn(&mut Request, &RequestError<E>) + Send + Sync;
 
-type ErrorHandlerArc<E> = Arc<RwLock<ErrorHandler<E>>>;
+type ErrorHandlerArc<E> = Arc<ErrorHandler<E>>;
 
 #[derive(Clone)]
 struct Path {
     parts: Vec<String>,
 }
 
-fn match_path(
-    path: &Path,
-    route_path: &Path,
-) -> Option<HashMap<String, String>> {
-    let mut map = HashMap::new();
-    for (left, right) in path.parts.iter().zip(route_path.parts.iter()) {
-        let is_placeholder = right.starts_with(':');
-        if !is_placeholder && left != right {
-            return None;
-        }
-        if is_placeholder {
-            map.insert(right[1..].to_string(), left.to_string());
-        }
-    }
-    Some(map)
-}
-
 impl FromStr for Path {
     type Err = Infallible;
 
@@ -160,14 +730,6 @@ impl FromStr for Path {
     }
 }
 
-struct Route<E> {
-    method: String,
-    path: Path,
-    handler: Box<Handler<E>>,
-}
-
-type Routes<E> = Arc<RwLock<Vec<Route<E>>>>;
-
 /// Errors that can occur when dispatching an error to a handler.
 #[derive(Debug, thiserror::Error)]
 pub enum RequestError<E: Debug + Display> {
@@ -178,9 +740,14 @@ pub enum RequestError<E: Debug + Display> {
     /// Customer error returned by a handler.
     #[error("custom: {0}")]
     Custom(E),
+
+    /// The handler panicked. Contains the panic message, if it was a
+    /// string.
+    #[error("handler panicked: {0}")]
+    Panic(String),
 }
 
-fn default_error_handler<E: Debug + Display>(
+fn default_error_handler<E: Debug + Display + 'static>(
     req: &mut Request,
     error: &RequestError<E>,
 ) {
@@ -188,120 +755,411 @@ fn default_error_handler<E: Debug + Display>(
         RequestError::NotFound => {
             error!("not found: {}", req.url().path());
             req.set_status(StatusCode::NotFound);
-            req.write_text("not found");
         }
         RequestError::Custom(err) => {
-            error!("error handling {}: {}", req.url().path(), err);
+            if let Some(rejection) = Rejection::find(err) {
+                req.set_status(rejection.status);
+                req.write_text(&rejection.message);
+                return;
+            }
+            error!(
+                "error handling {} {}: {}",
+                req.method(),
+                req.url().path(),
+                err
+            );
             req.set_status(StatusCode::InternalServerError);
-            req.write_text("internal server error");
         }
+        RequestError::Panic(_) => {
+            // The panic was already logged by dispatch_request
+            req.set_status(StatusCode::InternalServerError);
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "<non-string panic payload>".into()
     }
 }
 
 fn dispatch_request<E: Debug + Display>(
-    routes: Routes<E>,
-    path: &Path,
+    table: &RouteTable<E>,
+    path: &str,
     req: &mut Request,
 ) -> Result<(), RequestError<E>> {
-    for route in &*routes.read().unwrap() {
-        if req.method != route.method {
-            continue;
+    if req.method == "GET" && table.debug_path.as_deref() == Some(path) {
+        debug_routes::write_listing(req, &table.infos());
+        return Ok(());
+    }
+    if table.health.handle(path, req) {
+        return Ok(());
+    }
+    if table.method_override {
+        method_override::apply(req);
+    }
+    let route = table.lookup(path, req).ok_or(RequestError::NotFound)?;
+    req.route_pattern = Some(route.pattern.clone());
+    req.route_options = route.options;
+    // The cache goes last so that middleware such as authentication
+    // runs even when the response is served from the cache
+    let cached = |req: &mut Request| match &route.cache {
+        Some(cache) => cache.run(req, Next::new(&[], &[], &*route.handler)),
+        None => (route.handler)(req),
+    };
+    let next = Next::new(&table.middleware, &route.middleware, &cached);
+    let start = Instant::now();
+    req.watchdog = route.timeout.map(|timeout| {
+        let connection =
+            req.connection.as_ref().and_then(|c| c.try_clone().ok());
+        let name = format!("{} {}", route.method, route.pattern);
+        timeout::Watchdog::start(timeout, connection, name)
+    });
+    let result = panic::catch_unwind(AssertUnwindSafe(|| next.run(req)));
+    if req.watchdog.take().is_some_and(|w| w.finish()) {
+        // Without a connection nothing was sent yet, so the response
+        // is replaced. Otherwise the watchdog already responded.
+        req.set_status(StatusCode::GatewayTimeout);
+        req.resp_body = ResponseBody::default();
+        if req.connection.is_some() {
+            req.streamed = true;
         }
-
-        if let Some(path_params) = match_path(path, &route.path) {
-            req.path_params = path_params;
-            (route.handler)(req).map_err(RequestError::Custom)?;
-            return Ok(());
+        return Ok(());
+    }
+    table
+        .latency
+        .record(&route.method, &route.pattern, path, start.elapsed());
+    if route.options.no_cache && req.resp_header("Cache-Control").is_none() {
+        req.set_header("Cache-Control", "no-store");
+    }
+    match result {
+        Ok(result) => result.map_err(RequestError::Custom),
+        Err(payload) => {
+            let msg = panic_message(&*payload);
+            error!(
+                "handler for {} {} panicked: {}",
+                route.method, route.pattern, msg
+            );
+            Err(RequestError::Panic(msg))
         }
     }
-
-    Err(RequestError::NotFound)
 }
 
 #[throws]
 fn handle_connection<E: Debug + Display>(
-    stream: TcpStream,
-    routes: Routes<E>,
-    error_handler: ErrorHandlerArc<E>,
+    stream: stream::Connection,
+    peer_addr: Option<SocketAddr>,
+    routes: &RouteTable<E>,
+    error_handler: &ErrorHandler<E>,
+    settings: Arc<Settings>,
 ) {
-    let mut stream = BufStream::new(stream);
-    let mut line = String::new();
+    // This applies to every handle to the connection, so it also
+    // covers streamed responses and tunnels
+    stream.set_write_timeout(settings.write_timeout)?;
+    let mut stream =
+        BufStream::new(DeadlineStream::new(stream, settings.read_timeout));
     stream
-        .read_line(&mut line)
-        .context("missing request header")?;
-    let parts = line.split_whitespace().take(3).collect::<Vec<_>>();
-    if parts.len() != 3 {
-        throw!(anyhow!("invalid request: {}", line));
+        .get_mut()
+        .set_deadline(settings.header_timeout.map(|t| Instant::now() + t));
+    let head = match parse::read_head(&mut stream, &settings.parse_options) {
+        Ok(head) => head,
+        Err(err) => {
+            // Best effort; the client may already be gone. The rest of
+            // an oversized head is left unread, so drain it before
+            // closing or the client may get a reset instead of the
+            // response.
+            let _ = write_status_only(&mut stream, err.status);
+            stream.get_mut().get_mut().close();
+            throw!(err);
+        }
+    };
+    stream.get_mut().set_deadline(None);
+    let start = Instant::now();
+    let body_length = head.body_length()?;
+    let parse::RequestHead {
+        method,
+        target,
+        version,
+        headers,
+    } = head;
+    let target = match parse::parse_target(&method, &target) {
+        Ok(target) => target,
+        Err(err) => {
+            let _ = write_status_only(&mut stream, err.status);
+            throw!(err);
+        }
+    };
+
+    // The rest of a body that's too large is left unread, so close
+    // the connection gracefully after refusing it
+    let too_large = |stream: &mut BufStream<DeadlineStream>| {
+        let _ = write_status_only(stream, StatusCode::PayloadTooLarge);
+        stream.get_mut().get_mut().close();
+    };
+    let req_body = match body_length {
+        BodyLength::Length(len) => {
+            if settings.max_body_size.is_some_and(|max| len > max) {
+                return too_large(&mut stream);
+            }
+            Body::read_from(&mut stream, len, settings.spool_threshold)?
+        }
+        BodyLength::Chunked => match Body::read_to_eof_limited(
+            &mut ChunkedReader::new(&mut stream),
+            settings.spool_threshold,
+            settings.max_body_size,
+        ) {
+            Ok(Some(body)) => body,
+            Ok(None) => return too_large(&mut stream),
+            Err(err) => {
+                let _ = write_status_only(&mut stream, StatusCode::BadRequest);
+                throw!(err);
+            }
+        },
+        BodyLength::Unknown
+            if matches!(method.as_str(), "POST" | "PUT" | "PATCH") =>
+        {
+            match settings.unknown_length {
+                UnknownLengthPolicy::Empty => Body::default(),
+                UnknownLengthPolicy::LengthRequired => {
+                    write_status_only(
+                        stream.get_mut().get_mut(),
+                        StatusCode::LengthRequired,
+                    )?;
+                    return;
+                }
+                UnknownLengthPolicy::ReadToEof
+                    if parse::is_http10(&version) =>
+                {
+                    match Body::read_to_eof_limited(
+                        &mut stream,
+                        settings.spool_threshold,
+                        settings.max_body_size,
+                    )? {
+                        Some(body) => body,
+                        None => return too_large(&mut stream),
+                    }
+                }
+                UnknownLengthPolicy::ReadToEof => Body::default(),
+            }
+        }
+        BodyLength::Unknown => Body::default(),
+    };
+
+    // The host in an absolute-form target takes precedence over the
+    // Host header (RFC 7230 §5.4)
+    let host = match &target.authority {
+        Some(authority) => authority,
+        None => headers
+            .get(&HeaderName::new("host".into()))
+            .ok_or_else(|| anyhow!("missing host header"))?,
+    };
+    if !host::is_allowed(&settings.allowed_hosts, host) {
+        // Refuse before the host can end up in generated URLs
+        log::warn!("rejecting request for unexpected host {}", host);
+        write_status_only(
+            stream.get_mut().get_mut(),
+            StatusCode::MisdirectedRequest,
+        )?;
+        return;
+    }
+    let mut url = Url::parse(&format!("http://{}", host))
+        .with_context(|| format!("failed to parse host {}", host))?;
+    if target.form != TargetForm::Authority
+        && target.form != TargetForm::Asterisk
+    {
+        url.set_path(&target.path);
     }
-    let method = parts[0];
-    let raw_path = parts[1];
-    let path = raw_path.parse::<Path>()?;
+    url.set_query(target.query.as_deref());
+    let raw_path = target.path;
 
-    // Parse headers
-    // TODO: do duplicate headers accumulate? should be Vec value if so
-    let mut headers: HashMap<HeaderName, String> = HashMap::new();
-    loop {
-        let mut line = String::new();
-        stream.read_line(&mut line).context("failed to read line")?;
+    let mut req =
+        Request::new(method, url, headers, req_body, peer_addr, &settings);
+    req.version = version;
+    req.target_form = target.form;
+    req.connection = stream.get_mut().get_mut().try_clone().ok();
 
-        let mut parts = line.splitn(2, ':');
-        if let Some(name) = parts.next() {
-            let value = parts.next().unwrap_or("");
-            headers.insert(name.into(), value.trim().to_string());
+    if let (Some(filter), Some(authority)) =
+        (settings.connect_filter, &target.authority)
+    {
+        if target.form == TargetForm::Authority {
+            tunnel::run(stream, &req, authority, filter)?;
+            return;
         }
+    }
 
-        if line.trim().is_empty() {
-            break;
-        }
+    if let Some(recorder) = &settings.recorder {
+        recorder.record(&req);
     }
+    let audit_record = settings.audit.as_ref().map(|a| a.start(&req));
+    let otel_span = otel::ServerSpan::start(&req);
+    let span = trace::RequestSpan::new(&req.method, &raw_path);
+    otel_span.in_scope(|| {
+        span.in_scope(|| {
+            if let Err(status) =
+                req.decompress_body(settings.max_decompressed_size)
+            {
+                req.write_status_body(status);
+            } else if let Err(err) =
+                dispatch_request(routes, &raw_path, &mut req)
+            {
+                if !matches!(err, RequestError::NotFound) {
+                    trace::handler_error(&err);
+                }
+                error_handler(&mut req, &err);
+            }
+        })
+    });
+    req.apply_error_page(&settings.error_pages);
+    span.finish(req.status);
+    otel_span.finish(&req);
 
-    let mut req_body = Vec::new();
-    if let Some(len) = headers.get(&HeaderName::new("Content-Length".into())) {
-        if let Ok(len) = len.parse::<usize>() {
-            req_body.resize(len, 0);
-            stream.read_exact(&mut req_body)?;
+    req.finish_session();
+    for hook in &settings.after_hooks {
+        hook(&mut req);
+    }
+    if req.streamed {
+        if let (Some(auditor), Some(record)) = (&settings.audit, audit_record) {
+            auditor.finish(record, &req, start.elapsed());
+        }
+        if let Some(format) = settings.access_log {
+            access_log::log(format, &req, None, start.elapsed());
+        }
+        return;
+    }
+    if let Some(compression) = &settings.compression {
+        if let Err(err) = req.compress_response(compression) {
+            error!("failed to compress response: {}", err);
         }
     }
+    #[cfg(feature = "checksum")]
+    req.add_content_digest();
 
-    let host = headers
-        .get(&HeaderName::new("host".into()))
-        .ok_or_else(|| anyhow!("missing host header"))?;
-    let mut url = Url::parse(&format!("http://{}", host))
-        .with_context(|| format!("failed to parse host {}", host))?;
-    url.set_path(raw_path);
-
-    let mut req = Request {
-        method: method.into(),
-        path_params: HashMap::new(),
-        req_headers: headers,
-        req_body,
-        url,
-
-        resp_body: Vec::new(),
-        status: StatusCode::Ok,
-        resp_headers: HashMap::new(),
-    };
+    req.add_standard_headers();
 
-    if let Err(err) = dispatch_request(routes, &path, &mut req) {
-        (error_handler.read().unwrap())(&mut req, &err);
+    // Nothing has been written through the buffered stream, so it's
+    // safe to write to the connection directly. That way the response
+    // goes out in as few system calls as possible.
+    let framing = req.body_framing();
+    let head = serialize_head(&req, framing);
+    let bytes = req.resp_body.len();
+    if let (Some(auditor), Some(record)) = (&settings.audit, audit_record) {
+        auditor.finish(record, &req, start.elapsed());
+    }
+    // If the write fails, for example because a file turned out to be
+    // shorter than its Content-Length, the connection is dropped
+    // rather than closed gracefully
+    mem::take(&mut req.resp_body)
+        .write_with_head(head, stream.get_mut().get_mut())?;
+    if let Some(format) = settings.access_log {
+        access_log::log(format, &req, Some(bytes), start.elapsed());
     }
+    stream.get_mut().get_mut().close();
+}
 
-    stream.write_all(
-        format!(
-            "HTTP/1.1 {} {}\n",
+/// How the length of a response body is indicated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Framing {
+    /// Send a `Content-Length` header.
+    Length(u64),
+    /// Send the body with chunked encoding.
+    Chunked,
+    /// The body ends when the connection is closed. This is how
+    /// HTTP/1.0 clients receive streamed responses.
+    CloseDelimited,
+    /// The response can't have a body, so send neither.
+    None,
+}
+
+/// Serialize the status line and headers, including the blank line
+/// that ends them, into a single buffer.
+fn serialize_head(req: &Request, framing: Framing) -> Vec<u8> {
+    // Room for the status line, Content-Length, and the separators
+    let size = 64
+        + req
+            .resp_headers
+            .iter()
+            .map(|(name, value)| name.len() + value.len() + 4)
+            .sum::<usize>();
+    let mut head = Vec::with_capacity(size);
+    // Writing to a Vec can't fail
+    let _ = match &req.custom_status {
+        Some((code, reason)) => {
+            write!(head, "HTTP/1.1 {} {}\r\n", code, reason)
+        }
+        None => write!(
+            head,
+            "HTTP/1.1 {} {}\r\n",
             req.status,
-            req.status.canonical_reason(),
+            req.status.canonical_reason()
+        ),
+    };
+    // Date and Server always come first, then the other headers in
+    // the order they were set, then cookies and the framing headers
+    let is_standard = |name: &str| {
+        name.eq_ignore_ascii_case("Date") || name.eq_ignore_ascii_case("Server")
+    };
+    let standard = ["Date", "Server"]
+        .iter()
+        .filter_map(|&name| Some((name, req.resp_headers.get(name)?)));
+    let others = req
+        .resp_headers
+        .iter()
+        .filter(|(name, _)| !is_standard(name));
+    for (name, value) in standard.chain(others) {
+        head.extend_from_slice(name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    for cookie in &req.resp_cookies {
+        let _ = write!(head, "Set-Cookie: {}\r\n", cookie);
+    }
+    match framing {
+        Framing::Length(len) => {
+            let _ = write!(head, "Content-Length: {}\r\n", len);
+        }
+        Framing::Chunked => {
+            head.extend_from_slice(b"Transfer-Encoding: chunked\r\n")
+        }
+        Framing::None | Framing::CloseDelimited => {}
+    }
+    // Only one request is handled per connection, so tell the client
+    // not to send, or pipeline, any more on it
+    if req.resp_headers.get("Connection").is_none() {
+        head.extend_from_slice(b"Connection: close\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+/// Write a response with just a status line and an empty body, for
+/// requests that are rejected before they can be handled.
+#[throws]
+fn write_status_only(stream: &mut impl Write, status: StatusCode) {
+    let reason = status.canonical_reason();
+    // Errors get the reason as a minimal body, so that a browser shows
+    // more than a blank page
+    let head = if status.is_client_error() || status.is_server_error() {
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+            status,
+            reason,
+            reason.len() + 1,
+            reason,
+        )
+    } else {
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            status, reason,
         )
-        .as_bytes(),
-    )?;
-    for (name, value) in req.resp_headers {
-        stream.write_all(format!("{}: {}\n", name, value).as_bytes())?;
-    }
-    stream.write_all(
-        format!("Content-Length: {}\n", req.resp_body.len()).as_bytes(),
-    )?;
-    stream.write_all(b"\n")?;
-    stream.write_all(&req.resp_body)?;
+    };
+    stream.write_all(head.as_bytes())?;
+    stream.flush()?;
 }
 
 /// Test request for calling Server::test_request.
@@ -311,6 +1169,7 @@ pub struct TestRequest {
     method: String,
     url: Url,
     headers: HashMap<String, String>,
+    peer_addr: Option<SocketAddr>,
 }
 
 impl TestRequest {
@@ -327,6 +1186,7 @@ impl TestRequest {
             method: parts[0].into(),
             url: Url::parse(&format!("http://example.com{}", parts[1]))?,
             headers: HashMap::new(),
+            peer_addr: None,
         }
     }
 
@@ -343,6 +1203,7 @@ impl TestRequest {
             method: parts[0].into(),
             url: Url::parse(&format!("http://example.com{}", parts[1]))?,
             headers: HashMap::new(),
+            peer_addr: None,
         }
     }
 
@@ -356,9 +1217,41 @@ impl TestRequest {
         Self::new_with_body(s, &Vec::new())?
     }
 
-    #[throws]
-    fn path(&self) -> Path {
-        self.url.path().parse()?
+    /// Set a request header.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.insert(name.into(), value.into());
+    }
+
+    /// Set the client address returned by [`Request::peer_addr`].
+    pub fn set_peer_addr(&mut self, addr: SocketAddr) {
+        self.peer_addr = Some(addr);
+    }
+
+    /// Send a cookie, as returned by [`Request::cookie`]. Each call adds
+    /// another cookie to the `Cookie` header.
+    pub fn cookie(&mut self, name: &str, value: &str) {
+        let key = self
+            .headers
+            .keys()
+            .find(|key| key.eq_ignore_ascii_case("Cookie"))
+            .cloned()
+            .unwrap_or_else(|| "Cookie".into());
+        let header = self.headers.entry(key).or_default();
+        if !header.is_empty() {
+            header.push_str("; ");
+        }
+        header.push_str(name);
+        header.push('=');
+        header.push_str(value);
+    }
+
+    /// Get the value of a cookie set with [`TestRequest::cookie`].
+    fn find_cookie(&self, name: &str) -> Option<&str> {
+        let (_, header) = self
+            .headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case("Cookie"))?;
+        cookie::find_cookie(header, name)
     }
 }
 
@@ -373,6 +1266,10 @@ pub struct TestResponse {
 
     /// Response headers.
     pub headers: HashMap<HeaderName, String>,
+
+    /// Values of the `Set-Cookie` headers. These are kept separate
+    /// from `headers` since there can be more than one.
+    pub cookies: Vec<String>,
 }
 
 impl TestResponse {
@@ -381,6 +1278,24 @@ impl TestResponse {
     pub fn json<'a, D: Deserialize<'a>>(&'a self) -> D {
         serde_json::from_slice(&self.body)?
     }
+
+    /// Parse the `Set-Cookie` headers in the `cookies` field. Invalid
+    /// ones are skipped.
+    pub fn cookies(&self) -> Vec<Cookie> {
+        self.cookies
+            .iter()
+            .filter_map(|header| cookie::parse_set_cookie(header))
+            .collect()
+    }
+
+    /// Get the cookie set with `name`, if any. If there's more than one,
+    /// the last is returned.
+    pub fn cookie(&self, name: &str) -> Option<Cookie> {
+        self.cookies()
+            .into_iter()
+            .rev()
+            .find(|cookie| cookie.name() == name)
+    }
 }
 
 fn convert_header_map_to_unicase(
@@ -410,118 +1325,3003 @@ fn convert_header_map_to_unicase(
 /// # Ok::<(), Error>(())
 /// ```
 pub struct Server<E: Debug + Display> {
-    address: SocketAddr,
-
-    // The Routes and ErrorHandlerArc types puts the contents behind
-    // an Arc<RwLock>. For the non-test case, the launch() function
-    // consumes self, so we could just move a regular Vec<Route> into
-    // the Arc with no RwLock needed. But test_request does not
-    // consume self, since you want to be able to call test_request
-    // multiple times, so a RwLock is needed.
-    routes: Routes<E>,
+    listen: Listen,
+    socket_options: SocketOptions,
+    thread_options: ThreadOptions,
+    load_options: LoadOptions,
+    connections: ConnectionCount,
+    ip_tracker: Option<Arc<IpTracker>>,
+    ip_filter: IpFilter,
+    jobs: Vec<schedule::Job>,
+    /// Where to report ready after taking over from an old process.
+    #[cfg(unix)]
+    handover_ready: Option<std::os::unix::net::UnixStream>,
+
+    // Launching consumes self, so the route table is moved into an Arc
+    // then and shared by the connection threads without a lock.
+    routes: RouteTable<E>,
     error_handler: ErrorHandlerArc<E>,
+    settings: Settings,
+}
+
+/// Per-server settings that are shared with every connection.
+#[derive(Clone)]
+struct Settings {
+    server_header: Option<String>,
+    trusted_proxies: Vec<IpNet>,
+    forwarded_header: ForwardedHeader,
+    security_headers: Option<SecurityHeaders>,
+    route_names: Arc<HashMap<String, Pattern>>,
+    compression: Option<Compression>,
+    max_decompressed_size: usize,
+    max_body_size: Option<usize>,
+    spool_threshold: Option<usize>,
+    parse_options: ParseOptions,
+    read_timeout: Option<Duration>,
+    header_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    after_hooks: Vec<&'static AfterHook>,
+    state: Arc<StateMap>,
+    allowed_hosts: Vec<String>,
+    connect_filter: Option<&'static ConnectFilter>,
+    access_log: Option<AccessLogFormat>,
+    audit: Option<audit::Auditor>,
+    /// Set with [`Server::record_requests`].
+    recorder: Option<recording::Recorder>,
+    unknown_length: UnknownLengthPolicy,
+    error_pages: HashMap<StatusCode, String>,
+    #[cfg(feature = "templates")]
+    templates: Option<Arc<template::Templates>>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            server_header: Some(DEFAULT_SERVER_HEADER.into()),
+            trusted_proxies: Vec::new(),
+            forwarded_header: ForwardedHeader::default(),
+            security_headers: None,
+            route_names: Arc::new(HashMap::new()),
+            compression: None,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+            max_body_size: Some(DEFAULT_MAX_BODY_SIZE),
+            spool_threshold: None,
+            parse_options: ParseOptions::default(),
+            read_timeout: None,
+            header_timeout: Some(Duration::from_secs(30)),
+            write_timeout: Some(Duration::from_secs(30)),
+            after_hooks: Vec::new(),
+            state: Arc::new(HashMap::new()),
+            allowed_hosts: Vec::new(),
+            connect_filter: None,
+            access_log: None,
+            audit: None,
+            recorder: None,
+            unknown_length: UnknownLengthPolicy::Empty,
+            error_pages: error_page::default_pages(),
+            #[cfg(feature = "templates")]
+            templates: None,
+        }
+    }
 }
 
 impl<E: Debug + Display + 'static> Server<E> {
-    /// Create a new Server.
+    /// Create a new Server. Use [`Server::builder`] to customize socket
+    /// settings, timeouts, and limits.
     #[throws]
     pub fn new(address: &str) -> Server<E> {
-        Server {
-            address: address.parse::<SocketAddr>()?,
-            routes: Arc::new(RwLock::new(Vec::new())),
-            error_handler: Arc::new(RwLock::new(Box::new(
-                default_error_handler,
-            ))),
-        }
+        ServerBuilder::new(address).build()?
+    }
+
+    /// Create a [`ServerBuilder`] for a server that will listen on
+    /// `address`. This is the same as [`ServerBuilder::new`], which
+    /// is often more convenient since the error type is chosen by
+    /// [`ServerBuilder::build`] rather than here.
+    pub fn builder(address: &str) -> ServerBuilder {
+        ServerBuilder::new(address)
+    }
+
+    /// Create a Server that accepts connections from an existing
+    /// listener instead of binding its own. This is useful when the
+    /// listener comes from systemd socket activation or `listenfd`, or
+    /// when a test needs to bind to port 0 and learn the real address
+    /// before launching.
+    pub fn from_listener(listener: TcpListener) -> Server<E> {
+        ServerBuilder::from_listener(listener)
+            .build()
+            .expect("building from a listener can't fail")
     }
 
     /// Add a new route. The basic format is `"METHOD /path"`. The
     /// path can contain parameters that start with a colon, for
     /// example `"/resource/:key"`; these parameters act as wild cards
-    /// that can match any single path segment.
-    #[throws]
-    pub fn route(&mut self, route: &str, handler: &'static Handler<E>) {
-        let mut iter = route.split_whitespace();
-        let method = iter.next().ok_or_else(|| anyhow!("missing method"))?;
-        let path = iter.next().ok_or_else(|| anyhow!("missing path"))?;
-        let mut routes = self.routes.write().unwrap();
-        routes.push(Route {
-            method: method.into(),
-            path: path.parse()?,
-            handler: Box::new(handler),
-        });
+    /// that can match any single path segment. The last segment can
+    /// also be a wildcard that starts with an asterisk, for example
+    /// `"/static/*path"`, which matches all remaining segments.
+    ///
+    /// When more than one route matches a request, the most specific
+    /// one wins regardless of the order the routes were added in:
+    /// segments are compared left to right, with static segments
+    /// beating parameters and parameters beating wildcards. An error
+    /// is returned if the new route matches exactly the same paths as
+    /// an existing route with the same method.
+    ///
+    /// The returned [`RouteHandle`] can be used to configure the route
+    /// further, for example to give it a name.
+    #[throws]
+    pub fn route(
+        &mut self,
+        route: &str,
+        handler: &'static Handler<E>,
+    ) -> RouteHandle<'_, E> {
+        let id = self.routes.add(route, Box::new(handler))?;
+        RouteHandle { server: self, id }
     }
 
-    /// Set a custom error handler.
+    /// Add a route whose handler returns a different error type than
+    /// the server's, converting errors with [`From`]. This allows
+    /// handlers from different modules or crates to share a server.
     ///
-    /// The default error handler:
-    /// - Logs the error
-    /// - If the error is NotFound, sets the status to NotFound and
-    ///   the body to "not found"
-    /// - If the error is Custom, sets the status to
-    ///   InternalServerError and the body to "internal server error"
-    pub fn set_error_handler(
+    /// ```
+    /// # use anyhow::Error;
+    /// # use shs::{Request, Server};
+    /// fn parse(req: &mut Request) -> Result<(), std::num::ParseIntError> {
+    ///     let num: u32 = "42".parse()?;
+    ///     req.write_text(&num.to_string());
+    ///     Ok(())
+    /// }
+    ///
+    /// let mut server = Server::<Error>::new("127.0.0.1:1234")?;
+    /// server.route_with_error("GET /parse", &parse)?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    #[throws]
+    pub fn route_with_error<F: 'static>(
         &mut self,
-        error_handler: &'static ErrorHandler<E>,
-    ) {
-        self.error_handler = Arc::new(RwLock::new(Box::new(error_handler)));
+        route: &str,
+        handler: &'static Handler<F>,
+    ) -> RouteHandle<'_, E>
+    where
+        E: From<F>,
+    {
+        let handler = move |req: &mut Request| handler(req).map_err(E::from);
+        let id = self.routes.add(route, Box::new(handler))?;
+        RouteHandle { server: self, id }
     }
 
-    /// Start the server.
-    pub fn launch(self) -> Result<(), Error> {
-        let listener = TcpListener::bind(self.address)?;
-        loop {
-            let (tcp_stream, _addr) = listener.accept()?;
-            let routes = self.routes.clone();
-            let error_handler = self.error_handler.clone();
-
-            // Handle the request in a new thread
-            if let Err(err) = thread::Builder::new()
-                .name("shs-handler".into())
-                .spawn(move || {
-                    if let Err(err) =
-                        handle_connection(tcp_stream, routes, error_handler)
-                    {
-                        error!("{}", err);
-                    }
-                })
-            {
-                error!("failed to spawn thread: {}", err);
-            }
-        }
+    /// Add a route whose handler takes arguments extracted from the
+    /// request and returns a value to write as the response, instead
+    /// of working with the [`Request`] directly. Arguments can be any
+    /// type that implements [`FromRequest`], and the return type any
+    /// that implements [`IntoResponse`]. If an argument can't be
+    /// extracted, the handler isn't called and the [`Rejection`] is
+    /// sent as the response.
+    ///
+    /// ```
+    /// # use anyhow::Error;
+    /// # use serde::{Deserialize, Serialize};
+    /// # use shs::{Json, PathParam, Server, State};
+    /// # use std::sync::Mutex;
+    /// #[derive(Deserialize)]
+    /// struct Update {
+    ///     name: String,
+    /// }
+    ///
+    /// #[derive(Serialize)]
+    /// struct User {
+    ///     id: u64,
+    ///     name: String,
+    /// }
+    ///
+    /// fn update_user(
+    ///     PathParam(id): PathParam<u64>,
+    ///     Json(update): Json<Update>,
+    ///     db: State<Mutex<Vec<String>>>,
+    /// ) -> Result<Json<User>, Error> {
+    ///     db.lock().unwrap().push(update.name.clone());
+    ///     Ok(Json(User { id, name: update.name }))
+    /// }
+    ///
+    /// let mut server = Server::new("127.0.0.1:1234")?;
+    /// server.add_state(Mutex::new(Vec::<String>::new()));
+    /// server.typed_route("PUT /users/:id", update_user)?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    #[throws]
+    pub fn typed_route<Args>(
+        &mut self,
+        route: &str,
+        handler: impl TypedHandler<Args, E>,
+    ) -> RouteHandle<'_, E> {
+        let handler = move |req: &mut Request| handler.call(req);
+        let id = self.routes.add(route, Box::new(handler))?;
+        RouteHandle { server: self, id }
     }
 
-    /// Send a fake request for testing.
-    pub fn test_request(
-        &self,
-        input: &TestRequest,
-    ) -> Result<TestResponse, RequestError<E>> {
-        let mut req = Request {
-            method: input.method.clone(),
-            path_params: HashMap::new(),
-            req_headers: convert_header_map_to_unicase(&input.headers),
-            req_body: input.body.clone(),
-            url: input.url.clone(),
+    /// Set the keys used to sign and encrypt cookies with
+    /// [`Request::set_signed_cookie`] and
+    /// [`Request::set_private_cookie`].
+    #[cfg(feature = "secure-cookies")]
+    pub fn set_cookie_jar(&mut self, jar: CookieJar) {
+        self.add_state(jar);
+    }
 
-            resp_body: Vec::new(),
-            status: StatusCode::Ok,
-            resp_headers: HashMap::new(),
-        };
-        let path = input.path().unwrap();
-        dispatch_request(self.routes.clone(), &path, &mut req)?;
+    /// Enable sessions, which handlers use with [`Request::session`].
+    pub fn set_sessions(&mut self, sessions: Sessions) {
+        self.add_state(sessions);
+    }
 
-        Ok(TestResponse {
-            status: req.status,
-            body: req.resp_body,
-            headers: convert_header_map_to_unicase(&req.resp_headers),
-        })
+    /// Add shared state that handlers can get with [`Request::state`]
+    /// or the [`State`] extractor. There's one value per type; adding
+    /// a second value of the same type replaces the first.
+    pub fn add_state<T: Any + Send + Sync>(&mut self, value: T) {
+        Arc::make_mut(&mut self.settings.state)
+            .insert(TypeId::of::<T>(), Arc::new(value));
     }
-}
+
+    /// Create a group of routes that share a path prefix and
+    /// middleware. Pass an empty prefix to only share middleware.
+    pub fn group(&mut self, prefix: &str) -> RouteGroup<'_, E> {
+        RouteGroup {
+            server: self,
+            prefix: prefix.trim_end_matches('/').into(),
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Add middleware that runs for every matched route, before the
+    /// route's own middleware. Middleware runs in the order it was
+    /// added. See [`Middleware`] for an example.
+    pub fn add_middleware(&mut self, middleware: &'static Middleware<E>) {
+        self.routes.middleware.push(Box::new(middleware));
+    }
+
+    /// Log each request after its response is sent, in the given
+    /// format. See [`AccessLogFormat`] for details. The default is
+    /// `None`, which logs nothing.
+    pub fn set_access_log(&mut self, format: Option<AccessLogFormat>) {
+        self.settings.access_log = format;
+    }
+
+    /// Pass a copy of each request and its response to `sink`, keeping
+    /// at most `body_limit` bytes of each body. The sink runs on its
+    /// own thread; see [`AuditSink`].
+    pub fn set_audit_sink(&mut self, sink: impl AuditSink, body_limit: usize) {
+        self.settings.audit = Some(audit::Auditor::spawn(sink, body_limit));
+    }
+
+    /// Add a hook that runs after every request is handled. Hooks run
+    /// in the order they were added, before the response is compressed
+    /// and before the `Date` and `Server` headers are filled in.
+    pub fn add_after_hook(&mut self, hook: &'static AfterHook) {
+        self.settings.after_hooks.push(hook);
+    }
+
+    /// Accept `CONNECT` requests, opening a tunnel to the requested
+    /// host and port when `filter` returns true and responding with
+    /// 403 otherwise. Destinations must also pass
+    /// [`Server::set_allowed_hosts`]. Without a filter, `CONNECT`
+    /// requests are routed like any other request and so normally get
+    /// a 404.
+    pub fn set_connect_filter(&mut self, filter: &'static ConnectFilter) {
+        self.settings.connect_filter = Some(filter);
+    }
+
+    /// Set the router used to match requests to routes. Routes that
+    /// were already added are moved to the new router; this fails if
+    /// the new router rejects any of them.
+    ///
+    /// The default is [`LinearRouter`]. [`TrieRouter`] is faster for
+    /// large route tables.
+    #[throws]
+    pub fn set_router(&mut self, router: impl Router + 'static) {
+        self.routes.set_router(Box::new(router))?;
+    }
+
+    /// Get metadata for all routes, in the order they are matched
+    /// against requests.
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        self.routes.infos()
+    }
+
+    /// Serve a listing of all routes at `path`, as returned by
+    /// [`Server::routes`]. Browsers get an HTML table and other
+    /// clients JSON. This is meant for development; the listing isn't
+    /// protected by middleware, so don't enable it in production.
+    pub fn enable_debug_routes(&mut self, path: &str) {
+        self.routes.debug_path = Some(path.into());
+    }
+
+    /// Build a path to the route named `name` (see
+    /// [`RouteHandle::name`]), filling in its path parameters. For
+    /// example, if the route `"GET /users/:id"` is named `"user"`,
+    /// then `url_for("user", &[("id", "42")])` returns `"/users/42"`.
+    #[throws]
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> String {
+        url_for(&self.settings.route_names, name, params)?
+    }
+
+    /// Set a custom error handler.
+    ///
+    /// The default error handler:
+    /// - Logs the error
+    /// - If the error is NotFound, sets the status to NotFound
+    /// - If the error is Custom or Panic, sets the status to
+    ///   InternalServerError
+    ///
+    /// It leaves the body empty, so the error page for the status is
+    /// sent. See [`Server::set_error_page`].
+    pub fn set_error_handler(
+        &mut self,
+        error_handler: &'static ErrorHandler<E>,
+    ) {
+        self.error_handler = Arc::new(error_handler);
+    }
+
+    /// Replace the error handler with one that writes errors as RFC
+    /// 7807 `application/problem+json` responses, which suits JSON APIs
+    /// better than plain text. The `detail` member holds the error or
+    /// panic message if `include_details` is true; leave it false in
+    /// production so internal details aren't leaked to clients.
+    pub fn set_problem_error_handler(&mut self, include_details: bool)
+    where
+        E: 'static,
+    {
+        self.error_handler =
+            Arc::new(move |req: &mut Request, err: &RequestError<E>| {
+                problem::problem_error_handler(req, err, include_details)
+            });
+    }
+
+    /// Set the `Server` response header. The default is
+    /// `shs/<version>`. Pass `None` to leave the header out of
+    /// responses entirely.
+    ///
+    /// A `Date` header is always added to responses. Either header
+    /// can still be overridden by a handler with
+    /// [`Request::set_header`].
+    pub fn set_server_header(&mut self, value: Option<&str>) {
+        self.settings.server_header = value.map(|v| v.into());
+    }
+
+    /// Add a set of security headers to every response. Use
+    /// `SecurityHeaders::default()` for a reasonable starting point.
+    pub fn set_security_headers(&mut self, headers: SecurityHeaders) {
+        self.settings.security_headers = Some(headers);
+    }
+
+    /// Compress response bodies when the client supports it. See
+    /// [`Compression`] for details. Compression is off by default.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.settings.compression = Some(compression);
+    }
+
+    /// Set the largest request body, in bytes, that a compressed
+    /// request is allowed to expand to.
+    ///
+    /// Request bodies with a `Content-Encoding` of `gzip` or
+    /// `deflate` (or `br` with the `brotli` feature) are decompressed
+    /// before the handler sees them. Bodies that would expand past
+    /// this limit get a 413 response instead, and unsupported
+    /// encodings get 415. The default is 16 MiB.
+    pub fn set_max_decompressed_size(&mut self, max: usize) {
+        self.settings.max_decompressed_size = max;
+    }
+
+    /// Set the largest request body, in bytes, that is read from a
+    /// connection. Larger bodies get a 413 response without being
+    /// read, whether they have a `Content-Length` or are chunked. This
+    /// applies to spooled bodies too, so raise it for servers that
+    /// take large uploads. The default is 16 MiB; `None` removes the
+    /// limit.
+    pub fn set_max_body_size(&mut self, max: Option<usize>) {
+        self.settings.max_body_size = max;
+    }
+
+    /// Spool request bodies larger than `threshold` bytes to a
+    /// temporary file instead of reading them into memory. Handlers
+    /// read spooled bodies with [`Request::body_reader`] or keep them
+    /// with [`Request::take_body`]; [`Request::body`] is empty for
+    /// them. Spooled bodies are not decompressed. The default is
+    /// `None`, keeping every body in memory.
+    pub fn set_spool_threshold(&mut self, threshold: Option<usize>) {
+        self.settings.spool_threshold = threshold;
+    }
+
+    /// Set how `POST`, `PUT`, and `PATCH` requests without
+    /// `Content-Length` or `Transfer-Encoding` are handled. See
+    /// [`UnknownLengthPolicy`].
+    pub fn set_unknown_length_policy(&mut self, policy: UnknownLengthPolicy) {
+        self.settings.unknown_length = policy;
+    }
+
+    /// Enable or disable strict request parsing. The default is
+    /// disabled.
+    ///
+    /// In strict mode, requests must use CRLF line endings, a request
+    /// line of exactly `METHOD SP target SP HTTP/x.y`, and a colon
+    /// after every header name. Requests that don't are rejected with
+    /// 400 rather than interpreted leniently. Header names that aren't
+    /// valid tokens, including ones with whitespace before the colon,
+    /// are rejected in either mode.
+    pub fn set_strict_parsing(&mut self, strict: bool) {
+        self.settings.parse_options.strict = strict;
+    }
+
+    /// Set the maximum length in bytes of the request line (method,
+    /// target, and version). Longer requests are rejected with 414.
+    /// The default is 8 KiB.
+    pub fn set_max_request_line_len(&mut self, max: usize) {
+        self.settings.parse_options.max_request_line = max;
+    }
+
+    /// Set the maximum total size in bytes of the request headers.
+    /// Larger requests are rejected with 431. The default is 64 KiB.
+    pub fn set_max_header_bytes(&mut self, max: usize) {
+        self.settings.parse_options.max_header_bytes = max;
+    }
+
+    /// Set the maximum number of request headers. Requests with more
+    /// are rejected with 431. The default is 100.
+    pub fn set_max_header_count(&mut self, max: usize) {
+        self.settings.parse_options.max_headers = max;
+    }
+
+    /// Only accept requests whose `Host` header matches one of
+    /// `hosts`. Other requests get `421 Misdirected Request`, so that an
+    /// attacker-controlled host never ends up in [`Request::url`] and
+    /// the links and redirects built from it.
+    ///
+    /// An entry without a port, like `"example.com"`, matches any port;
+    /// one like `"example.com:8080"` only matches that port. An entry
+    /// like `"*.example.com"` matches any subdomain of `example.com`.
+    /// By default every host is allowed.
+    pub fn set_allowed_hosts(&mut self, hosts: &[&str]) {
+        self.settings.allowed_hosts =
+            hosts.iter().map(|host| host.to_string()).collect();
+    }
+
+    /// Set the proxies whose forwarding headers are trusted. Each
+    /// entry is an IP address or a CIDR range such as
+    /// `"10.0.0.0/8"`. This affects [`Request::client_ip`] and
+    /// [`Request::scheme`]. By default no proxies are trusted.
+    #[throws]
+    pub fn set_trusted_proxies(&mut self, proxies: &[&str]) {
+        self.settings.trusted_proxies =
+            ip_filter::parse_nets(proxies).context("invalid trusted proxy")?;
+    }
+
+    /// Set which forwarding header the trusted proxies set. The default
+    /// is [`ForwardedHeader::XForwardedFor`]. The other header is
+    /// ignored, so a client can't choose its own address by sending it
+    /// through a proxy that doesn't strip it.
+    pub fn set_forwarded_header(&mut self, header: ForwardedHeader) {
+        self.settings.forwarded_header = header;
+    }
+
+    /// Only accept connections from these peers. Each entry is an IP
+    /// address or a CIDR range such as `"10.0.0.0/8"`. Other peers are
+    /// disconnected before their request is read. An empty list, the
+    /// default, allows every peer.
+    ///
+    /// This checks the socket address, not [`Request::client_ip`],
+    /// since forwarding headers haven't been read yet.
+    #[throws]
+    pub fn set_ip_allowlist(&mut self, nets: &[&str]) {
+        self.ip_filter.allow = ip_filter::parse_nets(nets)?;
+    }
+
+    /// Disconnect peers in these ranges before their request is read,
+    /// even if they are in the allowlist. Entries are written as in
+    /// [`Server::set_ip_allowlist`].
+    #[throws]
+    pub fn set_ip_denylist(&mut self, nets: &[&str]) {
+        self.ip_filter.deny = ip_filter::parse_nets(nets)?;
+    }
+
+    /// Log a warning for requests whose middleware and handler take
+    /// longer than `threshold`, including the method, path, and time
+    /// taken. The default is `None`, which logs nothing.
+    pub fn set_slow_request_threshold(&mut self, threshold: Option<Duration>) {
+        self.routes.latency.slow_threshold = threshold;
+    }
+
+    /// Get a handle for reading the handler latency of each route. It
+    /// can be kept after the server is launched.
+    pub fn latency_monitor(&self) -> LatencyMonitor {
+        self.routes.latency.monitor()
+    }
+
+    /// Get a handle for watching the number of connections being
+    /// handled and how many were shed, for example to export as
+    /// metrics. Get it before launching the server.
+    pub fn load_monitor(&self) -> LoadMonitor {
+        LoadMonitor {
+            count: self.connections.clone(),
+            options: self.load_options.clone(),
+        }
+    }
+
+    /// Start the server.
+    pub fn launch(self) -> Result<(), Error> {
+        self.serve(|_| Ok(None))
+    }
+
+    /// Start the server on a new thread and return without blocking.
+    /// The server runs until the returned [`BackgroundServer`] is
+    /// stopped or dropped.
+    ///
+    /// This fails if the listener can't be bound.
+    ///
+    /// Example usage:
+    /// ```
+    /// use anyhow::Error;
+    /// use shs::Server;
+    ///
+    /// let server: Server<Error> = Server::new("127.0.0.1:0")?;
+    /// let running = server.launch_in_background()?;
+    /// println!("listening on {}", running.local_addr());
+    /// running.stop()?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    #[throws]
+    pub fn launch_in_background(self) -> BackgroundServer {
+        let (sender, receiver) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("shs-server".into())
+            .spawn(move || {
+                self.serve(|listener| {
+                    let shutdown = Shutdown::new(listener)?;
+                    let tcp = listener.as_tcp();
+                    let local_addr =
+                        tcp.map(TcpListener::local_addr).transpose()?;
+                    let copy = tcp.and_then(|l| l.try_clone().ok());
+                    // The receiver only goes away if launch_in_background
+                    // has already returned
+                    let _ = sender.send((shutdown.clone(), local_addr, copy));
+                    Ok(Some(shutdown))
+                })
+            })?;
+        match receiver.recv() {
+            #[cfg_attr(not(unix), allow(unused_variables))]
+            Ok((shutdown, local_addr, listener)) => BackgroundServer {
+                shutdown,
+                local_addr,
+                thread: Some(thread),
+                #[cfg(unix)]
+                listener,
+            },
+            // The server failed before it was ready
+            Err(_) => match thread.join() {
+                Ok(Err(err)) => throw!(err),
+                _ => throw!(anyhow!("server thread exited unexpectedly")),
+            },
+        }
+    }
+
+    /// Start the server and run it until the process receives SIGINT
+    /// or SIGTERM. Once a signal arrives the server stops accepting
+    /// connections, waits for the requests already in progress to
+    /// finish, and returns.
+    ///
+    /// Requires the `signals` feature, and is only available on Unix.
+    #[cfg(all(feature = "signals", unix))]
+    pub fn launch_until_signal(self) -> Result<(), Error> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        use signal_hook::iterator::Signals;
+
+        // Register before binding so that no signal can arrive while
+        // the server is reachable but the handlers aren't installed
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let signals_handle = signals.handle();
+        let mut signal_thread = None;
+        let result = self.serve(|listener| {
+            let shutdown = Shutdown::new(listener)?;
+            let trigger = shutdown.clone();
+            signal_thread = Some(std::thread::spawn(move || {
+                if let Some(signal) = signals.forever().next() {
+                    log::info!("received signal {}, shutting down", signal);
+                    trigger.trigger();
+                }
+            }));
+            Ok(Some(shutdown))
+        });
+        signals_handle.close();
+        if let Some(signal_thread) = signal_thread {
+            let _ = signal_thread.join();
+        }
+        result
+    }
+
+    /// Bind the listener and accept connections. `on_bind` is called
+    /// once the listener is ready and can return a [`Shutdown`] that
+    /// stops the server; after that the open connections are waited
+    /// for. Without a [`Shutdown`] this never returns unless accepting
+    /// fails.
+    fn serve(
+        #[cfg_attr(not(unix), allow(unused_mut))] mut self,
+        on_bind: impl FnOnce(&Listener) -> io::Result<Option<Shutdown>>,
+    ) -> Result<(), Error> {
+        let mut listener = self.listen.into_listener(&self.socket_options)?;
+        let shutdown = on_bind(&listener)?;
+        #[cfg(unix)]
+        if let Some(ready) = self.handover_ready.take() {
+            handover::notify_ready(ready);
+        }
+        // Dropped last, so jobs keep running while connections finish
+        let _scheduler = schedule::Scheduler::start(&self.jobs);
+        let settings = Arc::new(self.settings);
+        let routes: Routes<E> = Arc::new(self.routes);
+        let mut next_id: usize = 0;
+        let mut threads: Vec<JoinHandle<()>> = Vec::new();
+        let connections = self.connections;
+        loop {
+            if let Some(max) = self.load_options.max_connections {
+                connections.wait_below(max);
+            }
+            let accepted = listener.accept();
+            if shutdown
+                .as_ref()
+                .map(Shutdown::is_triggered)
+                .unwrap_or(false)
+            {
+                break;
+            }
+            let (stream, peer_addr) = accepted?;
+            if let Some(peer_addr) = peer_addr {
+                if !self.ip_filter.is_allowed(peer_addr.ip()) {
+                    info!(
+                        "rejecting connection from {}: not allowed",
+                        peer_addr
+                    );
+                    continue;
+                }
+            }
+            let stream = match connections.shed(stream, &self.load_options) {
+                Some(stream) => stream,
+                None => continue,
+            };
+            let (stream, ip_guard) = match (&self.ip_tracker, peer_addr) {
+                (Some(tracker), Some(peer_addr)) => {
+                    match tracker.admit(stream, peer_addr.ip()) {
+                        Some((stream, guard)) => (stream, Some(guard)),
+                        None => continue,
+                    }
+                }
+                _ => (stream, None),
+            };
+            if let Some(tcp_stream) = stream.as_tcp() {
+                if let Err(err) =
+                    self.socket_options.configure_stream(tcp_stream)
+                {
+                    error!("failed to configure stream: {}", err);
+                }
+            }
+            let routes = routes.clone();
+            let error_handler = self.error_handler.clone();
+            let settings = settings.clone();
+            let id = next_id;
+            next_id = next_id.wrapping_add(1);
+            let connection = connections.add();
+
+            // Handle the request in a new thread
+            match self.thread_options.spawn(id, move || {
+                let _connection = connection;
+                let _ip_guard = ip_guard;
+                if let Err(err) = handle_connection(
+                    stream,
+                    peer_addr,
+                    &routes,
+                    &*error_handler,
+                    settings,
+                ) {
+                    trace::connection_error(&err);
+                    error!("{}", err);
+                }
+            }) {
+                Ok(thread) => threads.push(thread),
+                Err(err) => error!("failed to spawn thread: {}", err),
+            }
+            threads.retain(|thread| !thread.is_finished());
+        }
+
+        drop(listener);
+        for thread in threads {
+            // Panics are already caught and logged in dispatch_request
+            let _ = thread.join();
+        }
+        Ok(())
+    }
+
+    /// Send a fake request for testing. If a file response body can't
+    /// be read, the response is a 500 with an empty body.
+    pub fn test_request(
+        &self,
+        input: &TestRequest,
+    ) -> Result<TestResponse, RequestError<E>> {
+        let host =
+            &input.url[url::Position::BeforeHost..url::Position::AfterPort];
+        if !host::is_allowed(&self.settings.allowed_hosts, host) {
+            return Ok(TestResponse {
+                status: StatusCode::MisdirectedRequest,
+                body: Vec::new(),
+                headers: HashMap::new(),
+                cookies: Vec::new(),
+            });
+        }
+        let mut req = Request::new(
+            input.method.clone(),
+            input.url.clone(),
+            convert_header_map_to_unicase(&input.headers),
+            Body::InMemory(input.body.clone()),
+            input.peer_addr,
+            &self.settings,
+        );
+        let path = input.url.path();
+        if let Err(status) =
+            req.decompress_body(self.settings.max_decompressed_size)
+        {
+            req.write_status_body(status);
+        } else {
+            dispatch_request(&self.routes, path, &mut req)?;
+        }
+        req.apply_error_page(&self.settings.error_pages);
+        req.finish_session();
+        for hook in &self.settings.after_hooks {
+            hook(&mut req);
+        }
+        if let Some(compression) = &self.settings.compression {
+            if let Err(err) = req.compress_response(compression) {
+                error!("failed to compress response: {}", err);
+            }
+        }
+        #[cfg(feature = "checksum")]
+        req.add_content_digest();
+
+        req.body_framing();
+        let (status, body) = match req.resp_body.into_bytes() {
+            Ok(body) => (req.status, body),
+            Err(err) => {
+                // On a real connection this would cut the response
+                // short, so report it as a server error
+                error!("failed to read response body: {}", err);
+                (StatusCode::InternalServerError, Vec::new())
+            }
+        };
+        Ok(TestResponse {
+            status,
+            body,
+            headers: req
+                .resp_headers
+                .iter()
+                .map(|(name, value)| {
+                    (HeaderName::new(name.into()), value.into())
+                })
+                .collect(),
+            cookies: req.resp_cookies.iter().map(|c| c.to_string()).collect(),
+        })
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpStream;
+    use std::thread;
+
+    /// Send raw request bytes through `handle_connection` and return
+    /// the raw response.
+    fn send_raw(server: &Server<Error>, input: &[u8]) -> String {
+        let (result, output) = send_raw_result(server, input);
+        result.unwrap();
+        output
+    }
+
+    /// Like `send_raw`, but also returns the connection's result
+    /// instead of requiring it to succeed.
+    fn send_raw_result(
+        server: &Server<Error>,
+        input: &[u8],
+    ) -> (Result<(), Error>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(input).unwrap();
+        let (stream, peer_addr) = listener.accept().unwrap();
+        let result = handle_connection(
+            Box::new(stream),
+            Some(peer_addr),
+            &server.routes,
+            &*server.error_handler,
+            Arc::new(server.settings.clone()),
+        );
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        (result, output)
+    }
+
+    #[throws]
+    fn hello(req: &mut Request) {
+        req.write_text("hello");
+    }
+
+    fn panics(_req: &mut Request) -> Result<(), Error> {
+        panic!("oh no");
+    }
+
+    #[throws]
+    fn peer(req: &mut Request) {
+        let addr = req.peer_addr().ok_or_else(|| anyhow!("no peer"))?;
+        req.write_text(&addr.ip().to_string());
+    }
+
+    #[throws]
+    fn echo(req: &mut Request) {
+        let text = req.body_text()?.to_uppercase();
+        req.write_text(&text);
+    }
+
+    #[throws]
+    fn me(req: &mut Request) {
+        req.write_text("me");
+    }
+
+    #[throws]
+    fn user(req: &mut Request) {
+        let id: String = req.path_param("id")?;
+        req.write_text(&id);
+    }
+
+    #[throws]
+    fn cached(req: &mut Request) {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        if !req.check_last_modified(modified) {
+            req.write_text("fresh");
+        }
+    }
+
+    #[throws]
+    fn long_text(req: &mut Request) {
+        req.write_text(&"hello ".repeat(100));
+    }
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_date_and_server_headers() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /hello", &hello).unwrap();
+        let input = b"GET /hello HTTP/1.1\nHost: example.com\n\n";
+
+        let output = send_raw(&server, input);
+        assert!(output.contains("\r\nDate: "));
+        assert!(output.contains(DEFAULT_SERVER_HEADER));
+
+        server.set_server_header(Some("custom"));
+        assert!(send_raw(&server, input).contains("\r\nServer: custom\r\n"));
+
+        server.set_server_header(None);
+        assert!(!send_raw(&server, input).contains("\r\nServer:"));
+    }
+
+    #[test]
+    fn test_handler_panic() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /panic", &panics).unwrap();
+
+        let err = server
+            .test_request(&TestRequest::new("GET /panic").unwrap())
+            .unwrap_err();
+        assert!(matches!(err, RequestError::Panic(msg) if msg == "oh no"));
+
+        let output =
+            send_raw(&server, b"GET /panic HTTP/1.1\nHost: example.com\n\n");
+        assert!(output.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+    }
+
+    #[test]
+    fn test_peer_addr() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /peer", &peer).unwrap();
+
+        let output =
+            send_raw(&server, b"GET /peer HTTP/1.1\nHost: example.com\n\n");
+        assert!(output.ends_with("\r\n\r\n127.0.0.1"));
+
+        let mut req = TestRequest::new("GET /peer").unwrap();
+        req.set_peer_addr("10.0.0.1:5000".parse().unwrap());
+        let resp = server.test_request(&req).unwrap();
+        assert_eq!(resp.body, b"10.0.0.1");
+    }
+
+    #[test]
+    fn test_security_headers() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /hello", &hello).unwrap();
+        server.set_security_headers(
+            SecurityHeaders::default()
+                .frame_options(None)
+                .content_security_policy(Some("default-src 'self'")),
+        );
+
+        let resp = server
+            .test_request(&TestRequest::new("GET /hello").unwrap())
+            .unwrap();
+        let get = |name: &str| resp.headers.get(&HeaderName::new(name.into()));
+        assert_eq!(get("X-Content-Type-Options").unwrap(), "nosniff");
+        assert_eq!(
+            get("Content-Security-Policy").unwrap(),
+            "default-src 'self'"
+        );
+        assert!(get("X-Frame-Options").is_none());
+    }
+
+    #[test]
+    fn test_body_text() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("POST /echo", &echo).unwrap();
+
+        let req = TestRequest::new_with_body("POST /echo", b"hi").unwrap();
+        assert_eq!(server.test_request(&req).unwrap().body, b"HI");
+
+        let req = TestRequest::new_with_body("POST /echo", b"ok\xff").unwrap();
+        let err = server.test_request(&req).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "custom: request body is not valid UTF-8 \
+             (invalid byte at offset 2)"
+        );
+    }
+
+    #[test]
+    fn test_route_specificity() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /users/:id", &user).unwrap();
+        server.route("GET /users/me", &me).unwrap();
+        assert!(server.route("GET /users/:name", &user).is_err());
+        server.route("POST /users/:name", &user).unwrap();
+
+        let get = |path: &str| {
+            server
+                .test_request(&TestRequest::new(path).unwrap())
+                .map(|resp| resp.body)
+        };
+        assert_eq!(get("GET /users/me").unwrap(), b"me");
+        assert_eq!(get("GET /users/bob").unwrap(), b"bob");
+        assert!(get("GET /users/bob/extra").is_err());
+    }
+
+    #[test]
+    fn test_named_routes() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server
+            .route("GET /users/:id", &user)
+            .unwrap()
+            .name("user")
+            .unwrap();
+        server.route("GET /users/me", &me).unwrap();
+        assert!(server.route("GET /me", &me).unwrap().name("user").is_err());
+
+        assert_eq!(
+            server.url_for("user", &[("id", "a b")]).unwrap(),
+            "/users/a%20b"
+        );
+        assert!(server.url_for("missing", &[]).is_err());
+
+        let routes = server.routes();
+        let patterns = routes.iter().map(|r| &r.pattern).collect::<Vec<_>>();
+        assert_eq!(patterns, ["/me", "/users/me", "/users/:id"]);
+        assert_eq!(routes[2].name.as_deref(), Some("user"));
+    }
+
+    #[test]
+    fn test_check_last_modified() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /cached", &cached).unwrap();
+
+        let mut req = TestRequest::new("GET /cached").unwrap();
+        let resp = server.test_request(&req).unwrap();
+        assert_eq!(resp.status, StatusCode::Ok);
+        assert_eq!(resp.body, b"fresh");
+
+        let since = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        req.set_header("If-Modified-Since", &httpdate::fmt_http_date(since));
+        let resp = server.test_request(&req).unwrap();
+        assert_eq!(resp.status, StatusCode::NotModified);
+        assert!(resp.body.is_empty());
+    }
+
+    #[test]
+    fn test_compression() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /long", &long_text).unwrap();
+        server.set_compression(Compression::default().min_size(100));
+
+        let mut req = TestRequest::new("GET /long").unwrap();
+        let resp = server.test_request(&req).unwrap();
+        assert_eq!(resp.body.len(), 600);
+
+        req.set_header("Accept-Encoding", "gzip");
+        let resp = server.test_request(&req).unwrap();
+        let get = |name: &str| resp.headers.get(&HeaderName::new(name.into()));
+        assert_eq!(get("Content-Encoding").unwrap(), "gzip");
+        assert_eq!(get("Vary").unwrap(), "Accept-Encoding");
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(&resp.body[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "hello ".repeat(100));
+    }
+
+    #[test]
+    fn test_request_decompression() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("POST /echo", &echo).unwrap();
+
+        let body = compression::compress("gzip", b"hi").unwrap();
+        let mut req = TestRequest::new_with_body("POST /echo", &body).unwrap();
+        req.set_header("Content-Encoding", "gzip");
+        assert_eq!(server.test_request(&req).unwrap().body, b"HI");
+
+        req.set_header("Content-Encoding", "compress");
+        let resp = server.test_request(&req).unwrap();
+        assert_eq!(resp.status, StatusCode::UnsupportedMediaType);
+    }
+
+    #[test]
+    fn test_reject_ambiguous_framing() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("POST /echo", &echo).unwrap();
+
+        let (result, output) = send_raw_result(
+            &server,
+            b"POST /echo HTTP/1.1\nHost: example.com\nContent-Length: 2\n\
+              Transfer-Encoding: chunked\n\nhi",
+        );
+        assert!(result.is_err());
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn test_chunked_request() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("POST /echo", &echo).unwrap();
+
+        let output = send_raw(
+            &server,
+            b"POST /echo HTTP/1.1\r\nHost: example.com\r\n\
+              Transfer-Encoding: chunked\r\n\r\n\
+              3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("\r\n\r\nABCDE"));
+
+        let (result, output) = send_raw_result(
+            &server,
+            b"POST /echo HTTP/1.1\r\nHost: example.com\r\n\
+              Transfer-Encoding: chunked\r\n\r\nzz\r\n",
+        );
+        assert!(result.is_err());
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let (result, output) = send_raw_result(
+            &server,
+            b"POST /echo HTTP/1.1\r\nHost: example.com\r\n\
+              Transfer-Encoding: gzip, chunked\r\n\r\n",
+        );
+        assert!(result.unwrap_err().to_string().contains("gzip"));
+        assert!(output.starts_with("HTTP/1.1 501 Not Implemented\r\n"));
+    }
+
+    #[test]
+    fn test_strict_parsing() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /hello", &hello).unwrap();
+        server.set_strict_parsing(true);
+
+        let output = send_raw(
+            &server,
+            b"GET /hello HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let (result, output) = send_raw_result(
+            &server,
+            b"GET /hello HTTP/1.1\nHost: example.com\n\n",
+        );
+        assert!(result.is_err());
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn test_header_limits() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /hello", &hello).unwrap();
+        server.set_max_header_count(1);
+
+        let (result, output) = send_raw_result(
+            &server,
+            b"GET /hello HTTP/1.1\nHost: example.com\nA: b\n\n",
+        );
+        assert!(result.is_err());
+        assert!(output
+            .starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[test]
+    fn test_oversized_head() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /hello", &hello).unwrap();
+        server.set_max_request_line_len(100);
+        server.set_max_header_bytes(100);
+
+        let input = format!(
+            "GET /{} HTTP/1.1\nHost: example.com\n\n",
+            "a".repeat(1000)
+        );
+        let (result, output) = send_raw_result(&server, input.as_bytes());
+        assert_eq!(result.unwrap_err().to_string(), "request line too long");
+        assert!(output.starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+        assert!(output.contains("\r\nConnection: close\r\n"));
+        assert!(output.ends_with("\r\n\r\nURI Too Long\n"));
+
+        let input = format!(
+            "GET /hello HTTP/1.1\nHost: example.com\nA: {}\n\n",
+            "b".repeat(1000)
+        );
+        let (result, output) = send_raw_result(&server, input.as_bytes());
+        assert!(result.is_err());
+        assert!(output
+            .starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        assert!(output.ends_with("\r\n\r\nRequest Header Fields Too Large\n"));
+    }
+
+    #[test]
+    fn test_header_timeout() {
+        let mut server: Server<Error> = ServerBuilder::new("127.0.0.1:1234")
+            .header_timeout(Some(Duration::from_millis(50)))
+            .build()
+            .unwrap();
+        server.route("GET /hello", &hello).unwrap();
+
+        // The headers never end, so the deadline expires
+        let (result, output) =
+            send_raw_result(&server, b"GET /hello HTTP/1.1\nHost: a\n");
+        assert!(result.is_err());
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn test_write_timeout() {
+        let mut server: Server<Error> = ServerBuilder::new("127.0.0.1:1234")
+            .write_timeout(Some(Duration::from_millis(100)))
+            .build()
+            .unwrap();
+        let large = |req: &mut Request| -> Result<(), Error> {
+            req.write_bytes(&vec![b'x'; 32 * 1024 * 1024]);
+            Ok(())
+        };
+        server.routes.add("GET /large", Box::new(large)).unwrap();
+
+        // The client never reads the response, so a write times out
+        // once the socket buffers are full
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .write_all(b"GET /large HTTP/1.1\r\nHost: a\r\n\r\n")
+            .unwrap();
+        let (stream, peer_addr) = listener.accept().unwrap();
+        let result = handle_connection(
+            Box::new(stream),
+            Some(peer_addr),
+            &server.routes,
+            &*server.error_handler,
+            Arc::new(server.settings.clone()),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_from_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut server: Server<Error> = Server::from_listener(listener);
+        server.route("GET /hello", &hello).unwrap();
+        thread::spawn(move || server.launch());
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert!(output.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_serve_until_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown =
+            Shutdown::new(&Listener::Tcp(listener.try_clone().unwrap()))
+                .unwrap();
+        let mut server: Server<Error> = Server::from_listener(listener);
+        server.route("GET /hello", &hello).unwrap();
+        let server_thread = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || server.serve(|_| Ok(Some(shutdown))))
+        };
+
+        // Start a request, stop the server, then finish the request.
+        // The server waits for it before returning.
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /hello HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        shutdown.trigger();
+        client.write_all(b"Host: example.com\r\n\r\n").unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert!(output.ends_with("\r\n\r\nhello"));
+
+        server_thread.join().unwrap().unwrap();
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[cfg(all(feature = "signals", unix))]
+    #[test]
+    fn test_launch_until_signal() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server: Server<Error> = Server::from_listener(listener);
+        let server_thread = thread::spawn(move || server.launch_until_signal());
+
+        // Wait for the server to be ready, which also means the signal
+        // handlers are installed
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n")
+            .unwrap();
+        client.read_to_end(&mut Vec::new()).unwrap();
+
+        signal_hook::low_level::raise(signal_hook::consts::SIGTERM).unwrap();
+        server_thread.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_launch_in_background() {
+        let mut server: Server<Error> = Server::new("127.0.0.1:0").unwrap();
+        server.route("GET /hello", &hello).unwrap();
+        let running = server.launch_in_background().unwrap();
+        let address = running.local_addr();
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .write_all(b"GET /hello HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert!(output.ends_with("\r\n\r\nhello"));
+
+        running.stop().unwrap();
+        assert!(TcpStream::connect(address).is_err());
+
+        // Binding to the same port in use fails up front
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken = listener.local_addr().unwrap().to_string();
+        let server: Server<Error> = ServerBuilder::new(&taken)
+            .reuse_address(false)
+            .build()
+            .unwrap();
+        assert!(server.launch_in_background().is_err());
+    }
+
+    #[test]
+    fn test_trie_router() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /users/:id", &user).unwrap();
+        server.set_router(TrieRouter::new()).unwrap();
+        server.route("GET /users/me", &me).unwrap();
+        assert!(server.route("GET /users/:name", &user).is_err());
+
+        let get = |path: &str| {
+            server
+                .test_request(&TestRequest::new(path).unwrap())
+                .map(|resp| resp.body)
+        };
+        assert_eq!(get("GET /users/me").unwrap(), b"me");
+        assert_eq!(get("GET /users/bob").unwrap(), b"bob");
+        assert!(get("GET /users/bob/extra").is_err());
+    }
+
+    #[test]
+    fn test_middleware() {
+        /// Record the order middleware runs in.
+        fn trace(req: &mut Request, name: &str) {
+            let mut seen = req.get_ext::<String>().cloned().unwrap_or_default();
+            seen.push_str(name);
+            req.insert_ext(seen);
+        }
+
+        #[throws]
+        fn handler(req: &mut Request) {
+            trace(req, "handler");
+            let seen = req.get_ext::<String>().unwrap().clone();
+            req.write_text(&seen);
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.add_middleware(&|req, next| {
+            trace(req, "global,");
+            next.run(req)
+        });
+        server.route("GET /open", &handler).unwrap();
+        server
+            .route("GET /closed", &handler)
+            .unwrap()
+            .with(&|req, _next| {
+                req.set_status(StatusCode::Forbidden);
+                Ok(())
+            });
+        {
+            let mut group = server.group("/admin/").with(&|req, next| {
+                trace(req, "group,");
+                next.run(req)
+            });
+            group.route("GET /", &handler).unwrap();
+            group
+                .route("GET /users", &handler)
+                .unwrap()
+                .with(&|req, next| {
+                    trace(req, "route,");
+                    next.run(req)
+                });
+        }
+
+        let get = |path: &str| {
+            server
+                .test_request(&TestRequest::new(path).unwrap())
+                .unwrap()
+        };
+        assert_eq!(get("GET /open").body, b"global,handler");
+        let resp = get("GET /closed");
+        assert_eq!(resp.status, StatusCode::Forbidden);
+        assert!(resp.body.is_empty());
+        assert_eq!(get("GET /admin").body, b"global,group,handler");
+        assert_eq!(get("GET /admin/users").body, b"global,group,route,handler");
+    }
+
+    #[test]
+    fn test_after_hook() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /hello", &hello).unwrap();
+        server.add_after_hook(&|req| {
+            let len = req.resp_body.len().to_string();
+            req.set_header("X-Body-Size", &len);
+        });
+        server.add_after_hook(&|req| {
+            if req.status == StatusCode::NotFound {
+                req.write_text("nothing here");
+            }
+        });
+
+        let resp = server
+            .test_request(&TestRequest::new("GET /hello").unwrap())
+            .unwrap();
+        assert_eq!(resp.headers[&HeaderName::new("X-Body-Size".into())], "5");
+
+        // Hooks also see responses from the error handler
+        let output = send_raw(
+            &server,
+            b"GET /missing HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        );
+        let page_len = error_page::default_pages()[&StatusCode::NotFound].len();
+        assert!(output.contains(&format!("X-Body-Size: {}\r\n", page_len)));
+        assert!(output.ends_with("\r\n\r\nnothing here"));
+    }
+
+    #[test]
+    fn test_typed_route() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Deserialize)]
+        struct Add {
+            amount: usize,
+        }
+
+        fn add(
+            PathParam(name): PathParam<String>,
+            Json(add): Json<Add>,
+            counter: State<AtomicUsize>,
+        ) -> Result<Json<(String, usize)>, Error> {
+            let total = counter.fetch_add(add.amount, Ordering::SeqCst);
+            Ok(Json((name, total + add.amount)))
+        }
+
+        fn square(PathParam(n): PathParam<u32>) -> Result<String, Error> {
+            Ok((n * n).to_string())
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.add_state(AtomicUsize::new(1));
+        server.typed_route("POST /add/:name", add).unwrap();
+        server.typed_route("GET /square/:n", square).unwrap();
+        server
+            .typed_route("GET /teapot", || Ok((StatusCode::ImATeapot, "tea")))
+            .unwrap();
+
+        let post = |path: &str, body: &str| {
+            let req =
+                TestRequest::new_with_body(path, body.as_bytes()).unwrap();
+            server.test_request(&req).unwrap()
+        };
+        let get = |path: &str| {
+            server
+                .test_request(&TestRequest::new(path).unwrap())
+                .unwrap()
+        };
+
+        let resp = post("POST /add/x", r#"{"amount": 2}"#);
+        assert_eq!(resp.json::<(String, usize)>().unwrap(), ("x".into(), 3));
+        let resp = post("POST /add/x", "not json");
+        assert_eq!(resp.status, StatusCode::BadRequest);
+
+        assert_eq!(get("GET /square/12").body, b"144");
+        assert_eq!(get("GET /square/x").status, StatusCode::BadRequest);
+
+        let resp = get("GET /teapot");
+        assert_eq!(resp.status, StatusCode::ImATeapot);
+        assert_eq!(resp.body, b"tea");
+    }
+
+    #[test]
+    fn test_cookies() {
+        #[throws]
+        fn handler(req: &mut Request) {
+            let visits: u32 = req
+                .cookie("visits")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0);
+            req.set_cookie(Cookie::new("visits", &(visits + 1).to_string()));
+            req.set_cookie(Cookie::new("theme", "light"));
+            req.set_cookie(Cookie::new("theme", "dark").path(Some("/")));
+            req.remove_cookie(Cookie::new("old", ""));
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /", &handler).unwrap();
+        let output = send_raw(
+            &server,
+            b"GET / HTTP/1.1\r\nHost: example.com\r\n\
+              Cookie: a=b; visits=2\r\n\r\n",
+        );
+        let cookies = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("Set-Cookie: "))
+            .collect::<Vec<_>>();
+        assert_eq!(cookies.len(), 3);
+        assert_eq!(cookies[0], "visits=3; HttpOnly");
+        assert_eq!(cookies[1], "theme=dark; Path=/; HttpOnly");
+        assert!(cookies[2].starts_with("old=; Max-Age=0"));
+    }
+
+    #[cfg(feature = "secure-cookies")]
+    #[test]
+    fn test_secure_cookies() {
+        #[throws]
+        fn set(req: &mut Request) {
+            req.set_signed_cookie(Cookie::new("user", "alice"))?;
+            req.set_private_cookie(Cookie::new("secret", "s3cret"))?;
+        }
+
+        #[throws]
+        fn get(req: &mut Request) {
+            let user = req.signed_cookie("user").unwrap_or_default();
+            let secret = req.private_cookie("secret").unwrap_or_default();
+            req.write_text(&format!("{} {}", user, secret));
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /set", &set).unwrap();
+        server.route("GET /get", &get).unwrap();
+
+        // Fails without a jar
+        assert!(server
+            .test_request(&TestRequest::new("GET /set").unwrap())
+            .is_err());
+
+        server.set_cookie_jar(CookieJar::new(Key::generate()));
+        let resp = server
+            .test_request(&TestRequest::new("GET /set").unwrap())
+            .unwrap();
+        assert!(!resp.cookies[1].contains("s3cret"));
+        let header = resp
+            .cookies
+            .iter()
+            .map(|c| c.split(';').next().unwrap())
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        let get_with = |cookie: &str| {
+            let mut req = TestRequest::new("GET /get").unwrap();
+            req.set_header("Cookie", cookie);
+            server.test_request(&req).unwrap().body
+        };
+        assert_eq!(get_with(&header), b"alice s3cret");
+        assert_eq!(get_with("user=alice; secret=s3cret"), b" ");
+    }
+
+    #[cfg(feature = "secure-cookies")]
+    #[test]
+    fn test_flash() {
+        #[throws]
+        fn save(req: &mut Request) {
+            req.flash("Saved!")?;
+            req.flash("Really")?;
+        }
+
+        #[throws]
+        fn show(req: &mut Request) {
+            let first = req.take_flashes();
+            // Only returned once
+            assert!(req.take_flashes().is_empty());
+            req.write_json(&first)?;
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.set_cookie_jar(CookieJar::new(Key::generate()));
+        server.route("POST /save", &save).unwrap();
+        server.route("GET /show", &show).unwrap();
+
+        let resp = server
+            .test_request(&TestRequest::new("POST /save").unwrap())
+            .unwrap();
+        assert_eq!(resp.cookies.len(), 1);
+        let cookie = resp.cookies[0].split(';').next().unwrap();
+
+        let mut req = TestRequest::new("GET /show").unwrap();
+        req.set_header("Cookie", cookie);
+        let resp = server.test_request(&req).unwrap();
+        assert_eq!(resp.json::<Vec<String>>().unwrap(), ["Saved!", "Really"]);
+        // The cookie is cleared
+        assert!(resp.cookies[0].starts_with("shs-flash=; Path=/; Max-Age=0"));
+
+        // Nothing to show and nothing to clear
+        let resp = server
+            .test_request(&TestRequest::new("GET /show").unwrap())
+            .unwrap();
+        assert_eq!(resp.json::<Vec<String>>().unwrap(), Vec::<String>::new());
+        assert!(resp.cookies.is_empty());
+    }
+
+    #[test]
+    fn test_sessions() {
+        #[throws]
+        fn login(req: &mut Request) {
+            req.session()?.insert("user", &"alice")?;
+        }
+
+        #[throws]
+        fn whoami(req: &mut Request) {
+            let user: Option<String> = req.session()?.get("user");
+            req.write_text(&user.unwrap_or_default());
+        }
+
+        fn send(
+            server: &Server<Error>,
+            path: &str,
+            cookie: Option<&str>,
+        ) -> Result<TestResponse, RequestError<Error>> {
+            let mut req = TestRequest::new(path).unwrap();
+            if let Some(cookie) = cookie {
+                req.set_header("Cookie", cookie);
+            }
+            server.test_request(&req)
+        }
+
+        #[throws]
+        fn logout(req: &mut Request) {
+            req.session()?.destroy();
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("POST /login", &login).unwrap();
+        server.route("GET /whoami", &whoami).unwrap();
+        server.route("POST /logout", &logout).unwrap();
+
+        // Not enabled yet
+        assert!(send(&server, "POST /login", None).is_err());
+        server
+            .set_sessions(Sessions::new(MemoryStore::new()).cookie_name("sid"));
+
+        let resp = send(&server, "POST /login", None).unwrap();
+        assert!(resp.cookies[0].starts_with("sid="));
+        let cookie = resp.cookies[0].split(';').next().unwrap().to_string();
+
+        let resp = send(&server, "GET /whoami", Some(&cookie)).unwrap();
+        assert_eq!(resp.body, b"alice");
+        // Unchanged sessions don't resend the cookie
+        assert!(resp.cookies.is_empty());
+
+        // An unknown ID gets a new empty session rather than being
+        // adopted
+        let resp = send(&server, "GET /whoami", Some("sid=abcd")).unwrap();
+        assert!(resp.body.is_empty());
+
+        let resp = send(&server, "POST /logout", Some(&cookie)).unwrap();
+        assert!(resp.cookies[0].starts_with("sid=; Path=/; Max-Age=0"));
+        let resp = send(&server, "GET /whoami", Some(&cookie)).unwrap();
+        assert!(resp.body.is_empty());
+    }
+
+    #[test]
+    fn test_guards() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server
+            .route("GET /data", &|req| {
+                req.write_json(&"json")?;
+                Ok(())
+            })
+            .unwrap()
+            .require_accept("application/json");
+        server
+            .route("GET /data", &|req| {
+                req.write_text("v2");
+                Ok(())
+            })
+            .unwrap()
+            .require_header("X-Api-Version", "2");
+        server
+            .route("GET /data", &|req| {
+                req.write_text("text");
+                Ok(())
+            })
+            .unwrap();
+        // No more routes can be added after the fallback
+        assert!(server.route("GET /data", &hello).is_err());
+
+        server
+            .route("POST /upload/:name", &hello)
+            .unwrap()
+            .require_content_type("image/png")
+            .guard(|req| req.path_param::<String>("name").is_ok());
+
+        let get = |path: &str, headers: &[(&str, &str)]| {
+            let mut req = TestRequest::new(path).unwrap();
+            for (name, value) in headers {
+                req.set_header(name, value);
+            }
+            server.test_request(&req).map(|resp| resp.body)
+        };
+        assert_eq!(
+            get("GET /data", &[("Accept", "text/html, application/json")])
+                .unwrap(),
+            b"\"json\""
+        );
+        assert_eq!(get("GET /data", &[("Accept", "*/*")]).unwrap(), b"text");
+        assert_eq!(get("GET /data", &[("X-Api-Version", "2")]).unwrap(), b"v2");
+        assert_eq!(get("GET /data", &[]).unwrap(), b"text");
+
+        let ct = "Content-Type";
+        assert!(get("POST /upload/a", &[(ct, "image/png")]).is_ok());
+        assert!(get("POST /upload/a", &[(ct, "Image/PNG; x=y")]).is_ok());
+        assert!(matches!(
+            get("POST /upload/a", &[(ct, "text/plain")]),
+            Err(RequestError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_allowed_hosts() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /hello", &hello).unwrap();
+        server.set_allowed_hosts(&["example.com", "localhost:8080"]);
+
+        let output = send_raw(
+            &server,
+            b"GET /hello HTTP/1.1\r\nHost: example.com:80\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        let output =
+            send_raw(&server, b"GET /hello HTTP/1.1\r\nHost: evil.com\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 421 Misdirected Request\r\n"));
+
+        let resp = server
+            .test_request(&TestRequest::new("GET /hello").unwrap())
+            .unwrap();
+        assert_eq!(resp.status, StatusCode::Ok);
+        server.set_allowed_hosts(&["localhost:8080"]);
+        let resp = server
+            .test_request(&TestRequest::new("GET /hello").unwrap())
+            .unwrap();
+        assert_eq!(resp.status, StatusCode::MisdirectedRequest);
+    }
+
+    #[test]
+    fn test_url_scheme() {
+        #[throws]
+        fn url(req: &mut Request) {
+            let url = req.url().to_string();
+            req.write_text(&url);
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /url", &url).unwrap();
+        server.set_trusted_proxies(&["10.0.0.1"]).unwrap();
+
+        let get = |proto: &str| {
+            let mut req = TestRequest::new("GET /url").unwrap();
+            req.set_peer_addr("10.0.0.1:1234".parse().unwrap());
+            req.set_header("X-Forwarded-Proto", proto);
+            server.test_request(&req).unwrap().body
+        };
+        assert_eq!(get("https"), b"https://example.com/url");
+        assert_eq!(get("http"), b"http://example.com/url");
+        assert_eq!(get("ftp"), b"http://example.com/url");
+    }
+
+    #[test]
+    fn test_request_target() {
+        #[throws]
+        fn target(req: &mut Request) {
+            let text = format!("{:?} {}", req.target_form(), req.url());
+            req.write_text(&text);
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /target", &target).unwrap();
+        server.set_allowed_hosts(&["example.com", "proxied.com"]);
+
+        let output = send_raw(
+            &server,
+            b"GET /target?a=1 HTTP/1.1\nHost: example.com\n\n",
+        );
+        assert!(output.ends_with("Origin http://example.com/target?a=1"));
+
+        let output = send_raw(
+            &server,
+            b"GET http://proxied.com/target HTTP/1.1\nHost: other\n\n",
+        );
+        assert!(output.ends_with("Absolute http://proxied.com/target"));
+
+        let output = send_raw(
+            &server,
+            b"GET http://evil.com/target HTTP/1.1\nHost: example.com\n\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 421"));
+
+        let (result, output) = send_raw_result(
+            &server,
+            b"GET ftp://example.com/ HTTP/1.1\nHost: example.com\n\n",
+        );
+        assert!(result.is_err());
+        assert!(output.starts_with("HTTP/1.1 400"));
+    }
+
+    #[test]
+    fn test_connect() {
+        // Upstream that echoes everything back
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = upstream.accept().unwrap();
+            let mut reader = stream.try_clone().unwrap();
+            io::copy(&mut reader, &mut stream).unwrap();
+        });
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.set_connect_filter(&|req| {
+            req.url().host_str() == Some("127.0.0.1")
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        write!(
+            client,
+            "CONNECT {0} HTTP/1.1\nHost: {0}\n\nping",
+            upstream_addr
+        )
+        .unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let (stream, peer_addr) = listener.accept().unwrap();
+        handle_connection(
+            Box::new(stream),
+            Some(peer_addr),
+            &server.routes,
+            &*server.error_handler,
+            Arc::new(server.settings.clone()),
+        )
+        .unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "HTTP/1.1 200 Connection Established\r\n\r\nping");
+
+        let output = send_raw(
+            &server,
+            b"CONNECT localhost:443 HTTP/1.1\nHost: localhost\n\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 403"));
+    }
+
+    #[test]
+    fn test_client_connected() {
+        #[throws]
+        fn connected(req: &mut Request) {
+            let connected = req.is_client_connected().to_string();
+            req.write_text(&connected);
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /connected", &connected).unwrap();
+        let output = send_raw(
+            &server,
+            b"GET /connected HTTP/1.1\nHost: example.com\n\n",
+        );
+        assert!(output.ends_with("\r\n\r\ntrue"));
+        let resp = server
+            .test_request(&TestRequest::new("GET /connected").unwrap())
+            .unwrap();
+        assert_eq!(resp.body, b"true");
+    }
+
+    #[test]
+    fn test_ndjson() {
+        #[throws]
+        fn progress(req: &mut Request) {
+            req.set_header("X-Test", "yes");
+            let mut sender = req.start_ndjson()?;
+            sender.send(&serde_json::json!({"done": 1}))?;
+            sender.send(&"two")?;
+            sender.finish()?;
+            req.write_text("ignored");
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /progress", &progress).unwrap();
+        let output =
+            send_raw(&server, b"GET /progress HTTP/1.1\nHost: example.com\n\n");
+        let (head, body) = output.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("\r\nContent-Type: application/x-ndjson\r\n"));
+        assert!(head.contains("\r\nTransfer-Encoding: chunked"));
+        assert!(head.contains("\r\nX-Test: yes\r\n"));
+        assert!(head.contains("\r\nDate: "));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(body, "b\r\n{\"done\":1}\n\r\n6\r\n\"two\"\n\r\n0\r\n\r\n");
+
+        assert!(server
+            .test_request(&TestRequest::new("GET /progress").unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_ndjson_trailers() {
+        #[throws]
+        fn checksum(req: &mut Request) {
+            assert!(req
+                .start_ndjson_with_trailers(&["Content-Length"])
+                .is_err());
+            let mut sender = req.start_ndjson_with_trailers(&["X-Checksum"])?;
+            sender.send(&1)?;
+            assert!(sender.set_trailer("X-Other", "1").is_err());
+            assert!(sender.set_trailer("X-Checksum", "a\r\nb").is_err());
+            sender.set_trailer("X-Checksum", "abc")?;
+            sender.finish()?;
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /checksum", &checksum).unwrap();
+        let output =
+            send_raw(&server, b"GET /checksum HTTP/1.1\nHost: example.com\n\n");
+        let (head, body) = output.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("\r\nTrailer: X-Checksum\r\n"));
+        assert_eq!(body, "2\r\n1\n\r\n0\r\nX-Checksum: abc\r\n\r\n");
+    }
+
+    #[test]
+    fn test_response_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        #[throws]
+        fn counter(req: &mut Request) {
+            let calls = CALLS.fetch_add(1, Ordering::SeqCst) + 1;
+            req.write_text(&calls.to_string());
+        }
+
+        #[throws]
+        fn uncacheable(req: &mut Request) {
+            req.set_header("Cache-Control", "no-store");
+            counter(req)?;
+        }
+
+        let cache =
+            ResponseCache::new(Duration::from_secs(60)).vary("Accept-Language");
+        let handle = cache.handle();
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /count", &counter).unwrap().cache(cache);
+        server
+            .route("GET /uncacheable", &uncacheable)
+            .unwrap()
+            .cache(ResponseCache::new(Duration::from_secs(60)));
+        server
+            .route("GET /opted-out", &counter)
+            .unwrap()
+            .no_cache()
+            .cache(ResponseCache::new(Duration::from_secs(60)));
+
+        let get = |path: &str, lang: &str| {
+            let mut req = TestRequest::new(&format!("GET {}", path)).unwrap();
+            req.set_header("Accept-Language", lang);
+            let resp = server.test_request(&req).unwrap();
+            assert_eq!(
+                resp.headers.get(&"Content-Type".into()).unwrap(),
+                "text/plain; charset=UTF-8"
+            );
+            String::from_utf8(resp.body).unwrap()
+        };
+        assert_eq!(get("/count", "en"), "1");
+        assert_eq!(get("/count", "en"), "1");
+        assert_eq!(get("/count?page=2", "en"), "2");
+        assert_eq!(get("/count", "de"), "3");
+        handle.invalidate("/count");
+        assert_eq!(get("/count", "en"), "4");
+        assert_eq!(get("/count", "en"), "4");
+        handle.clear();
+        assert_eq!(get("/count", "en"), "5");
+
+        assert_eq!(get("/uncacheable", "en"), "6");
+        assert_eq!(get("/uncacheable", "en"), "7");
+        assert_eq!(get("/opted-out", "en"), "8");
+        assert_eq!(get("/opted-out", "en"), "9");
+
+        // Each virtual host gets its own entry
+        let mut req = TestRequest::new("GET /count").unwrap();
+        req.set_header("Accept-Language", "en");
+        assert_eq!(server.test_request(&req).unwrap().body, b"5");
+        req.url.set_host(Some("other.example.com")).unwrap();
+        assert_eq!(server.test_request(&req).unwrap().body, b"10");
+        assert_eq!(server.test_request(&req).unwrap().body, b"10");
+        req.url.set_port(Some(8080)).unwrap();
+        assert_eq!(server.test_request(&req).unwrap().body, b"11");
+    }
+
+    #[test]
+    fn test_response_cache_per_client() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        #[throws]
+        fn login(req: &mut Request) {
+            let calls = CALLS.fetch_add(1, Ordering::SeqCst) + 1;
+            req.session()?.insert("user", &calls)?;
+            req.write_text(&calls.to_string());
+        }
+
+        #[throws]
+        fn whoami(req: &mut Request) {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            let user: Option<usize> = req.session()?.get("user");
+            req.write_text(&user.unwrap_or_default().to_string());
+        }
+
+        #[throws]
+        fn raw_cookie(req: &mut Request) {
+            let calls = CALLS.fetch_add(1, Ordering::SeqCst) + 1;
+            req.set_header("Set-Cookie", &format!("id={}", calls));
+            req.write_text(&calls.to_string());
+        }
+
+        let cache = || ResponseCache::new(Duration::from_secs(60));
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.set_sessions(Sessions::new(MemoryStore::new()));
+        server.route("GET /login", &login).unwrap().cache(cache());
+        server.route("GET /whoami", &whoami).unwrap().cache(cache());
+        server
+            .route("GET /raw", &raw_cookie)
+            .unwrap()
+            .cache(cache());
+
+        let get = |path: &str| {
+            let req = TestRequest::new(&format!("GET {}", path)).unwrap();
+            server.test_request(&req).unwrap()
+        };
+        let first = get("/login");
+        assert_eq!(first.cookies.len(), 1);
+        let second = get("/login");
+        assert_eq!(second.body, b"2");
+        assert_ne!(first.cookies, second.cookies);
+
+        let mut session = server.test_session();
+        session
+            .request(&TestRequest::new("GET /login").unwrap())
+            .unwrap();
+        let whoami = TestRequest::new("GET /whoami").unwrap();
+        assert_eq!(session.request(&whoami).unwrap().body, b"3");
+        assert_eq!(get("/whoami").body, b"0");
+
+        assert_eq!(get("/raw").body, b"6");
+        assert_eq!(get("/raw").body, b"7");
+    }
+
+    #[test]
+    fn test_response_cache_authenticated() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        #[throws]
+        fn whoami(req: &mut Request) {
+            let calls = CALLS.fetch_add(1, Ordering::SeqCst) + 1;
+            let user = req
+                .headers()
+                .get(&"Authorization".into())
+                .cloned()
+                .or_else(|| {
+                    req.get_ext::<ApiKeyIdentity>().map(|id| id.0.clone())
+                })
+                .unwrap_or_else(|| "anonymous".into());
+            req.write_text(&format!("{} {}", calls, user));
+        }
+
+        let cache = || ResponseCache::new(Duration::from_secs(60));
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /whoami", &whoami).unwrap().cache(cache());
+        // The cache is added first, but still runs after the auth
+        server
+            .route("GET /secret", &whoami)
+            .unwrap()
+            .cache(cache())
+            .api_key_auth(ApiKeyAuth::new(|key| {
+                (key == "key").then(|| "bot".to_string())
+            }));
+
+        let get = |path: &str, header: Option<(&str, &str)>| {
+            let mut req = TestRequest::new(&format!("GET {}", path)).unwrap();
+            if let Some((name, value)) = header {
+                req.set_header(name, value);
+            }
+            server.test_request(&req).unwrap()
+        };
+        let alice = Some(("Authorization", "Basic YWxpY2U6cHc="));
+        assert_eq!(get("/whoami", alice).body, b"1 Basic YWxpY2U6cHc=");
+        assert_eq!(get("/whoami", None).body, b"2 anonymous");
+        assert_eq!(get("/whoami", None).body, b"2 anonymous");
+        assert_eq!(get("/whoami", alice).body, b"3 Basic YWxpY2U6cHc=");
+
+        let key = Some(("X-Api-Key", "key"));
+        assert_eq!(get("/secret", key).body, b"4 bot");
+        assert_eq!(get("/secret", key).body, b"5 bot");
+        assert_eq!(get("/secret", None).status, StatusCode::Unauthorized);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 5);
+    }
+
+    #[test]
+    fn test_body_suppression() {
+        #[throws]
+        fn status(req: &mut Request) {
+            let status: u16 = req.path_param("status")?;
+            req.set_status(std::convert::TryFrom::try_from(status)?);
+            req.write_text("body");
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /status/:status", &status).unwrap();
+        server.route("HEAD /status/:status", &status).unwrap();
+
+        let send = |request: &str| {
+            let input = format!("{} HTTP/1.1\nHost: example.com\n\n", request);
+            send_raw(&server, input.as_bytes())
+        };
+        let output = send("GET /status/200");
+        assert!(output.contains("\r\nContent-Length: 4\r\n"));
+        assert!(output.ends_with("\r\n\r\nbody"));
+
+        let output = send("HEAD /status/200");
+        assert!(output.contains("\r\nContent-Length: 4\r\n"));
+        assert!(output.ends_with("\r\n\r\n"));
+
+        for request in &["GET /status/204", "GET /status/304"] {
+            let output = send(request);
+            assert!(!output.contains("Content-Length"), "{}", request);
+            assert!(output.ends_with("\r\n\r\n"), "{}", request);
+        }
+
+        let resp = server
+            .test_request(&TestRequest::new("HEAD /status/200").unwrap())
+            .unwrap();
+        assert!(resp.body.is_empty());
+    }
+
+    #[test]
+    fn test_early_hints() {
+        #[throws]
+        fn page(req: &mut Request) {
+            req.send_early_hints(&["</a.css>; rel=preload", "</b.js>"])?;
+            assert!(req.send_informational(StatusCode::Ok, &[]).is_err());
+            req.write_text("page");
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /page", &page).unwrap();
+        let output =
+            send_raw(&server, b"GET /page HTTP/1.1\nHost: example.com\n\n");
+        assert!(output.starts_with(
+            "HTTP/1.1 103 Early Hints\r\n\
+             Link: </a.css>; rel=preload\r\n\
+             Link: </b.js>\r\n\r\n\
+             HTTP/1.1 200 OK\r\n"
+        ));
+        assert!(output.ends_with("\r\n\r\npage"));
+
+        let resp = server
+            .test_request(&TestRequest::new("GET /page").unwrap())
+            .unwrap();
+        assert_eq!(resp.body, b"page");
+
+        // HTTP/1.0 clients don't understand interim responses
+        let output =
+            send_raw(&server, b"GET /page HTTP/1.0\nHost: example.com\n\n");
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_request_unreadable_body() {
+        #[throws]
+        fn unreadable(req: &mut Request) {
+            // Reading a directory fails with EISDIR
+            let file = std::fs::File::open(std::env::temp_dir())?;
+            req.resp_body = ResponseBody::File { file, len: 10 };
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /", &unreadable).unwrap();
+        let resp = server
+            .test_request(&TestRequest::new("GET /").unwrap())
+            .unwrap();
+        assert_eq!(resp.status, StatusCode::InternalServerError);
+        assert!(resp.body.is_empty());
+    }
+
+    #[test]
+    fn test_informational_header_injection() {
+        #[throws]
+        fn page(req: &mut Request) {
+            for (name, value) in &[
+                ("Link", "</a.css>\r\nSet-Cookie: a=1"),
+                ("Link", "</a.css>\nX: 1"),
+                ("Bad Name", "x"),
+                ("X\r\nY", "x"),
+            ] {
+                let headers = [(*name, *value)];
+                assert!(req
+                    .send_informational(StatusCode::EarlyHints, &headers)
+                    .is_err());
+            }
+            assert!(req.send_early_hints(&["</a>\r\n\r\nHTTP/1.1"]).is_err());
+            req.write_text("page");
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /page", &page).unwrap();
+        let output =
+            send_raw(&server, b"GET /page HTTP/1.1\nHost: example.com\n\n");
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+        assert!(!output.contains("Set-Cookie"));
+    }
+
+    #[test]
+    fn test_route_with_error() {
+        fn parse(req: &mut Request) -> Result<(), std::num::ParseIntError> {
+            let num: u32 = req.url().query().unwrap_or("").parse()?;
+            req.write_text(&num.to_string());
+            Ok(())
+        }
+
+        let mut server = Server::<Error>::new("127.0.0.1:1234").unwrap();
+        server.route_with_error("GET /parse", &parse).unwrap();
+        let get = |path| server.test_request(&TestRequest::new(path).unwrap());
+        assert_eq!(get("GET /parse?12").unwrap().body, b"12");
+        match get("GET /parse?x") {
+            Err(RequestError::Custom(err)) => {
+                assert!(err.is::<std::num::ParseIntError>());
+            }
+            other => panic!("unexpected result: {:?}", other.map(|r| r.status)),
+        }
+    }
+
+    #[test]
+    fn test_error_handler_context() {
+        fn fails(_req: &mut Request) -> Result<(), Error> {
+            Err(anyhow!("oh no"))
+        }
+
+        fn error_handler(req: &mut Request, _err: &RequestError<Error>) {
+            let text = format!(
+                "{} {:?} {:?}",
+                req.method(),
+                req.route_pattern(),
+                req.path_params().get("id"),
+            );
+            req.write_text(&text);
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("POST /items/:id", &fails).unwrap();
+        server.set_error_handler(&error_handler);
+        let send = |request: &str| {
+            let input = format!("{} HTTP/1.1\nHost: example.com\n\n", request);
+            send_raw(&server, input.as_bytes())
+        };
+        assert!(send("POST /items/7")
+            .ends_with(r#"POST Some("/items/:id") Some("7")"#));
+        assert!(send("GET /missing").ends_with("GET None None"));
+    }
+
+    #[test]
+    fn test_problem_errors() {
+        fn fails(_req: &mut Request) -> Result<(), Error> {
+            Err(anyhow!("database is down"))
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /fails", &fails).unwrap();
+        let send = |server: &Server<Error>, path: &str| {
+            let input = format!("GET {} HTTP/1.1\nHost: example.com\n\n", path);
+            let output = send_raw(server, input.as_bytes());
+            assert!(output
+                .contains("\r\nContent-Type: application/problem+json\r\n"));
+            let body = output.split_once("\r\n\r\n").unwrap().1;
+            serde_json::from_str::<serde_json::Value>(body).unwrap()
+        };
+
+        server.set_problem_error_handler(false);
+        assert_eq!(
+            send(&server, "/missing"),
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Not Found",
+                "status": 404,
+                "instance": "/missing",
+            })
+        );
+        assert!(send(&server, "/fails").get("detail").is_none());
+
+        server.set_problem_error_handler(true);
+        assert_eq!(send(&server, "/fails")["detail"], "database is down");
+    }
+
+    #[test]
+    fn test_debug_routes() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server
+            .route("GET /hello", &hello)
+            .unwrap()
+            .name("hello")
+            .unwrap();
+        server.enable_debug_routes("/_routes");
+
+        let resp = server
+            .test_request(&TestRequest::new("GET /_routes").unwrap())
+            .unwrap();
+        let routes: serde_json::Value =
+            serde_json::from_slice(&resp.body).unwrap();
+        assert_eq!(
+            routes,
+            serde_json::json!([{
+                "method": "GET",
+                "pattern": "/hello",
+                "name": "hello",
+                "middleware": 0,
+                "guards": 0,
+            }])
+        );
+
+        let mut req = TestRequest::new("GET /_routes").unwrap();
+        req.set_header("Accept", "text/html,*/*;q=0.8");
+        let resp = server.test_request(&req).unwrap();
+        assert_eq!(
+            resp.headers.get(&"Content-Type".into()).unwrap(),
+            "text/html; charset=UTF-8"
+        );
+        assert!(String::from_utf8(resp.body).unwrap().contains("/hello"));
+    }
+
+    #[test]
+    fn test_shed_load() {
+        #[throws]
+        fn slow(req: &mut Request) {
+            thread::sleep(Duration::from_millis(200));
+            req.write_text("done");
+        }
+
+        let mut server: Server<Error> = ServerBuilder::new("127.0.0.1:0")
+            .shed_load(Some(1), Duration::from_secs(3))
+            .build()
+            .unwrap();
+        server.route("GET /slow", &slow).unwrap();
+        let monitor = server.load_monitor();
+        assert_eq!(monitor.shed_threshold(), Some(1));
+        let running = server.launch_in_background().unwrap();
+
+        let request = b"GET /slow HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let mut first = TcpStream::connect(running.local_addr()).unwrap();
+        first.write_all(request).unwrap();
+        while monitor.in_flight() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let mut second = TcpStream::connect(running.local_addr()).unwrap();
+        let mut output = String::new();
+        second.read_to_string(&mut output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(output.contains("\r\nRetry-After: 3\r\n"));
+        assert_eq!(monitor.shed_total(), 1);
+
+        output.clear();
+        first.read_to_string(&mut output).unwrap();
+        assert!(output.ends_with("\r\n\r\ndone"));
+        running.stop().unwrap();
+    }
+
+    #[test]
+    fn test_ip_limits() {
+        let mut server: Server<Error> = ServerBuilder::new("127.0.0.1:0")
+            .ip_limits(
+                IpLimits::new()
+                    .max_requests(Some((1, Duration::from_secs(60)))),
+            )
+            .build()
+            .unwrap();
+        server.route("GET /hello", &hello).unwrap();
+        let running = server.launch_in_background().unwrap();
+
+        let get = || {
+            let mut client = TcpStream::connect(running.local_addr()).unwrap();
+            client
+                .write_all(b"GET /hello HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .unwrap();
+            let mut output = String::new();
+            client.read_to_string(&mut output).unwrap();
+            output
+        };
+        assert!(get().ends_with("\r\n\r\nhello"));
+        let output = get();
+        assert!(output.starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+        assert!(output.contains("\r\nRetry-After: "));
+        running.stop().unwrap();
+    }
+
+    #[test]
+    fn test_ip_filter() {
+        let get = |server: Server<Error>| {
+            let running = server.launch_in_background().unwrap();
+            let mut client = TcpStream::connect(running.local_addr()).unwrap();
+            // The server may close before reading the request
+            let _ = client
+                .write_all(b"GET /hello HTTP/1.1\r\nHost: example.com\r\n\r\n");
+            let mut output = String::new();
+            let _ = client.read_to_string(&mut output);
+            running.stop().unwrap();
+            output
+        };
+        let new_server = || {
+            let mut server: Server<Error> = Server::new("127.0.0.1:0").unwrap();
+            server.route("GET /hello", &hello).unwrap();
+            server
+        };
+
+        let mut server = new_server();
+        server.set_ip_allowlist(&["127.0.0.0/8"]).unwrap();
+        assert!(get(server).ends_with("\r\n\r\nhello"));
+
+        let mut server = new_server();
+        server.set_ip_allowlist(&["10.0.0.0/8"]).unwrap();
+        assert_eq!(get(server), "");
+
+        let mut server = new_server();
+        server.set_ip_denylist(&["127.0.0.1"]).unwrap();
+        assert_eq!(get(server), "");
+
+        assert!(new_server().set_ip_denylist(&["nope"]).is_err());
+    }
+
+    #[test]
+    fn test_spool_threshold() {
+        #[throws]
+        fn upload(req: &mut Request) {
+            let spooled = matches!(req.spooled_body(), Body::File(_));
+            let mut body = String::new();
+            req.body_reader()?.read_to_string(&mut body)?;
+            req.write_text(&format!("{} {}", spooled, body));
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.set_spool_threshold(Some(4));
+        server.route("POST /upload", &upload).unwrap();
+        let output = send_raw(
+            &server,
+            b"POST /upload HTTP/1.1\nHost: example.com\nContent-Length: 4\n\n\
+              abcd",
+        );
+        assert!(output.ends_with("\r\n\r\nfalse abcd"));
+        let output = send_raw(
+            &server,
+            b"POST /upload HTTP/1.1\nHost: example.com\nContent-Length: 5\n\n\
+              abcde",
+        );
+        assert!(output.ends_with("\r\n\r\ntrue abcde"));
+    }
+
+    #[test]
+    fn test_max_body_size() {
+        #[throws]
+        fn upload(req: &mut Request) {
+            let mut body = String::new();
+            req.body_reader()?.read_to_string(&mut body)?;
+            req.write_text(&body);
+        }
+
+        for threshold in &[None, Some(2)] {
+            let mut server = Server::new("127.0.0.1:1234").unwrap();
+            server.set_max_body_size(Some(4));
+            server.set_spool_threshold(*threshold);
+            server.route("POST /upload", &upload).unwrap();
+            let send = |headers: &str, body: &str| {
+                let input = format!(
+                    "POST /upload HTTP/1.1\r\nHost: example.com\r\n{}\r\n{}",
+                    headers, body
+                );
+                send_raw(&server, input.as_bytes())
+            };
+
+            let output = send("Content-Length: 4\r\n", "abcd");
+            assert!(output.ends_with("\r\n\r\nabcd"), "{}", output);
+            let output = send("Content-Length: 5\r\n", "abcde");
+            assert!(output.starts_with("HTTP/1.1 413 "), "{}", output);
+
+            let chunked = "Transfer-Encoding: chunked\r\n";
+            let output = send(chunked, "2\r\nab\r\n2\r\ncd\r\n0\r\n\r\n");
+            assert!(output.ends_with("\r\n\r\nabcd"), "{}", output);
+            let output = send(chunked, "2\r\nab\r\n3\r\ncde\r\n0\r\n\r\n");
+            assert!(output.starts_with("HTTP/1.1 413 "), "{}", output);
+        }
+    }
+
+    #[test]
+    fn test_multipart_upload() {
+        #[throws]
+        fn upload(req: &mut Request) {
+            let dir = tempfile::tempdir()?;
+            let saved = req.multipart()?.save_to_dir(dir.path())?;
+            let summary = saved
+                .iter()
+                .map(|part| match part {
+                    SavedPart::Field { value, .. } => value.clone(),
+                    SavedPart::File { path, .. } => {
+                        std::fs::read_to_string(path).unwrap()
+                    }
+                })
+                .collect::<Vec<_>>();
+            req.write_text(&summary.join(","));
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.set_spool_threshold(Some(16));
+        server.route("POST /upload", &upload).unwrap();
+        let body =
+            "--b\r\nContent-Disposition: form-data; name=\"x\"\r\n\r\n1\r\n\
+                    --b\r\nContent-Disposition: form-data; name=\"f\"; \
+                    filename=\"f.txt\"\r\n\r\ncontents\r\n--b--\r\n";
+        let request = format!(
+            "POST /upload HTTP/1.1\r\nHost: example.com\r\n\
+             Content-Type: multipart/form-data; boundary=b\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let output = send_raw(&server, request.as_bytes());
+        assert!(output.ends_with("\r\n\r\n1,contents"), "{}", output);
+
+        // A malformed body is the client's fault
+        let request = request.replace("--b--", "--c--");
+        let output = send_raw(&server, request.as_bytes());
+        assert!(output.starts_with("HTTP/1.1 400 "), "{}", output);
+        assert!(output.ends_with("closing boundary"), "{}", output);
+    }
+
+    #[test]
+    fn test_header_injection() {
+        #[throws]
+        fn inject(req: &mut Request) {
+            req.set_header("X-Name", "a\r\nSet-Cookie: pwn=1");
+            req.set_header("Bad Name", "a");
+            req.set_cookie(Cookie::new("c", "1\r\nX-Pwn: 1"));
+            assert!(req.try_set_header("X-Ok", "a\nb").is_err());
+            req.try_set_header("X-Ok", "fine")?;
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /inject", &inject).unwrap();
+        let output = send_raw(
+            &server,
+            b"GET /inject HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        );
+        assert!(output.contains("\r\nX-Ok: fine\r\n"));
+        assert!(!output.contains("pwn"), "{}", output);
+        assert!(!output.contains("X-Pwn"), "{}", output);
+        assert!(!output.contains("X-Name"), "{}", output);
+        assert!(!output.contains("Bad Name"), "{}", output);
+    }
+
+    #[test]
+    fn test_header_order() {
+        #[throws]
+        fn ordered(req: &mut Request) {
+            req.set_header("X-B", "1");
+            req.set_header("Date", "Thu, 01 Jan 1970 00:00:00 GMT");
+            req.set_header("X-A", "2");
+            req.set_header("X-B", "3");
+            req.set_cookie(Cookie::new("c", "1"));
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.set_server_header(Some("test"));
+        server.route("GET /ordered", &ordered).unwrap();
+        let output = send_raw(
+            &server,
+            b"GET /ordered HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        );
+        assert_eq!(
+            output,
+            "HTTP/1.1 200 OK\r\n\
+             Date: Thu, 01 Jan 1970 00:00:00 GMT\r\n\
+             Server: test\r\n\
+             X-B: 3\r\n\
+             X-A: 2\r\n\
+             Set-Cookie: c=1; HttpOnly\r\n\
+             Content-Length: 0\r\n\
+             Connection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_custom_status() {
+        #[throws]
+        fn gateway(req: &mut Request) {
+            req.set_status_with_reason(599, "Network Timeout")?;
+            assert!(req.set_status_with_reason(600, "Too High").is_err());
+            assert!(req.set_status_with_reason(500, "Bad\r\nX: y").is_err());
+        }
+
+        #[throws]
+        fn teapot(req: &mut Request) {
+            req.set_status_with_reason(418, "Short And Stout")?;
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /gateway", &gateway).unwrap();
+        server.route("GET /teapot", &teapot).unwrap();
+        let output = send_raw(
+            &server,
+            b"GET /gateway HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 599 Network Timeout\r\n"));
+        let output = send_raw(
+            &server,
+            b"GET /teapot HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        );
+        assert!(output.starts_with("HTTP/1.1 418 Short And Stout\r\n"));
+
+        let resp = server
+            .test_request(&TestRequest::new("GET /gateway").unwrap())
+            .unwrap();
+        assert_eq!(resp.status, StatusCode::InternalServerError);
+    }
+
+    #[test]
+    fn test_header_casing() {
+        #[throws]
+        fn lowercase(req: &mut Request) {
+            req.write_text("hi");
+            req.set_header("content-type", "text/html");
+            req.set_header("cache-control", "no-cache");
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /lowercase", &lowercase).unwrap();
+        let output = send_raw(
+            &server,
+            b"GET /lowercase HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        );
+        assert!(output.contains("\r\nContent-Type: text/html\r\n"));
+        assert!(output.contains("\r\nCache-Control: no-cache\r\n"));
+        assert!(!output.to_lowercase().contains("charset"), "{}", output);
+    }
+
+    #[test]
+    fn test_latency_monitor() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.set_slow_request_threshold(Some(Duration::from_secs(60)));
+        server.route("GET /hello", &hello).unwrap();
+        let monitor = server.latency_monitor();
+        assert!(monitor.routes().is_empty());
+        for _ in 0..3 {
+            server
+                .test_request(&TestRequest::new("GET /hello").unwrap())
+                .unwrap();
+        }
+        let routes = monitor.routes();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].method, "GET");
+        assert_eq!(routes[0].pattern, "/hello");
+        assert_eq!(routes[0].histogram.count(), 3);
+    }
+
+    #[test]
+    fn test_audit_sink() {
+        #[throws]
+        fn echo(req: &mut Request) {
+            let body = req.body_text()?.to_owned();
+            req.set_cookie(Cookie::new("a", "b"));
+            req.write_text(&body);
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.set_audit_sink(move |record| sender.send(record).unwrap(), 3);
+        server.route("POST /echo", &echo).unwrap();
+        let output = send_raw(
+            &server,
+            b"POST /echo?x=1 HTTP/1.1\nHost: example.com\n\
+              Content-Length: 5\n\nhello",
+        );
+        assert!(output.ends_with("\r\n\r\nhello"));
+
+        let record = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(record.method, "POST");
+        assert_eq!(record.url.as_str(), "http://example.com/echo?x=1");
+        assert_eq!(
+            record.request_headers[&HeaderName::new("host".into())],
+            "example.com"
+        );
+        assert_eq!(record.request_body, b"hel");
+        assert_eq!(record.request_body_len, 5);
+        assert_eq!(record.status, StatusCode::Ok);
+        assert_eq!(
+            record.response_headers[&HeaderName::new("content-type".into())],
+            "text/plain; charset=UTF-8"
+        );
+        assert_eq!(record.response_cookies, ["a=b; HttpOnly"]);
+        assert_eq!(record.response_body, b"hel");
+        assert_eq!(record.response_body_len, Some(5));
+    }
+
+    #[test]
+    fn test_record_requests() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.record_requests(file.path()).unwrap();
+        server.route("POST /echo", &echo).unwrap();
+        let input = b"POST /echo?x=1 HTTP/1.1\nHost: example.com\n\
+                      Content-Length: 5\n\nhello";
+        send_raw(&server, input);
+        send_raw(&server, input);
+
+        let requests = TestRequest::read_recording(file.path()).unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].url.as_str(), "http://example.com/echo?x=1");
+        assert_eq!(requests[0].headers["Content-Length"], "5");
+        assert_eq!(requests[0].body, b"hello");
+        assert!(requests[0].peer_addr.is_some());
+        for result in server.replay(file.path()).unwrap() {
+            assert_eq!(result.unwrap().body, b"HELLO");
+        }
+    }
+
+    #[test]
+    fn test_route_opt_outs() {
+        let (sender, receiver) = mpsc::channel();
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.set_compression(Compression::default().min_size(100));
+        server.set_audit_sink(move |record| sender.send(record).unwrap(), 100);
+        server.route("GET /long", &long_text).unwrap();
+        server
+            .route("POST /secret", &long_text)
+            .unwrap()
+            .no_compress()
+            .no_cache()
+            .no_log_body();
+
+        let mut req = TestRequest::new("GET /long").unwrap();
+        req.set_header("Accept-Encoding", "gzip");
+        let resp = server.test_request(&req).unwrap();
+        let get = |resp: &TestResponse, name: &str| {
+            resp.headers.get(&HeaderName::new(name.into())).cloned()
+        };
+        assert_eq!(get(&resp, "Content-Encoding").unwrap(), "gzip");
+        assert!(get(&resp, "Cache-Control").is_none());
+
+        let mut req =
+            TestRequest::new_with_body("POST /secret", b"password").unwrap();
+        req.set_header("Accept-Encoding", "gzip");
+        let resp = server.test_request(&req).unwrap();
+        assert!(get(&resp, "Content-Encoding").is_none());
+        assert_eq!(get(&resp, "Cache-Control").unwrap(), "no-store");
+        assert_eq!(resp.body.len(), 600);
+
+        let output = send_raw(
+            &server,
+            b"POST /secret HTTP/1.1\nHost: example.com\n\
+              Content-Length: 8\n\npassword",
+        );
+        assert!(output.contains("\r\nCache-Control: no-store\r\n"));
+        let record = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(record.request_body.is_empty());
+        assert_eq!(record.request_body_len, 8);
+        assert!(record.response_body.is_empty());
+        assert_eq!(record.response_body_len, Some(600));
+    }
+
+    #[test]
+    fn test_range() {
+        #[throws]
+        fn manifest(req: &mut Request) {
+            req.write_file("Cargo.toml")?;
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /manifest", &manifest).unwrap();
+        let contents = std::fs::read_to_string("Cargo.toml").unwrap();
+        let len = contents.len();
+        let request = |range: &str| {
+            send_raw(
+                &server,
+                format!(
+                    "GET /manifest HTTP/1.1\nHost: example.com\nRange: {}\n\n",
+                    range
+                )
+                .as_bytes(),
+            )
+        };
+
+        let output = request("bytes=1-4");
+        assert!(output.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(output
+            .contains(&format!("\r\nContent-Range: bytes 1-4/{}\r\n", len)));
+        assert!(output.contains("\r\nContent-Length: 4\r\n"));
+        assert!(output.ends_with(&format!("\r\n\r\n{}", &contents[1..5])));
+
+        let output = request("bytes=0-1, -2");
+        assert!(output.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        let boundary = output
+            .split("multipart/byteranges; boundary=")
+            .nth(1)
+            .and_then(|rest| rest.split("\r\n").next())
+            .unwrap();
+        let body = output.split_once("\r\n\r\n").unwrap().1;
+        assert_eq!(
+            body,
+            format!(
+                "\r\n--{0}\r\nContent-Type: text/plain; charset=UTF-8\r\n\
+                 Content-Range: bytes 0-1/{1}\r\n\r\n{2}\
+                 \r\n--{0}\r\nContent-Type: text/plain; charset=UTF-8\r\n\
+                 Content-Range: bytes {3}-{4}/{1}\r\n\r\n{5}\
+                 \r\n--{0}--\r\n",
+                boundary,
+                len,
+                &contents[..2],
+                len - 2,
+                len - 1,
+                &contents[len - 2..]
+            )
+        );
+
+        let output = request(&format!("bytes={}-", len));
+        assert!(output.starts_with("HTTP/1.1 416 "));
+        assert!(
+            output.contains(&format!("\r\nContent-Range: bytes */{}\r\n", len))
+        );
+    }
+
+    #[test]
+    fn test_pipelined_requests() {
+        #[throws]
+        fn echo(req: &mut Request) {
+            let body = req.body_text()?.to_owned();
+            req.write_text(&body);
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("POST /echo", &echo).unwrap();
+        // Only the first request is answered, and the client is told
+        // to send the rest again on a new connection
+        let output = send_raw(
+            &server,
+            b"POST /echo HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\n\
+              firstPOST /echo HTTP/1.1\r\nHost: example.com\r\n\
+              Content-Length: 6\r\n\r\nsecond",
+        );
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.ends_with("\r\nConnection: close\r\n\r\nfirst"));
+        assert_eq!(output.matches("HTTP/1.1").count(), 1);
+    }
+
+    #[test]
+    fn test_unknown_length_policy() {
+        #[throws]
+        fn echo(req: &mut Request) {
+            let body = req.body_text()?.to_owned();
+            req.write_text(&body);
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("POST /echo", &echo).unwrap();
+        let http10 = b"POST /echo HTTP/1.0\r\nHost: example.com\r\n\r\nbody";
+        let http11 = b"POST /echo HTTP/1.1\r\nHost: example.com\r\n\r\nbody";
+        assert!(send_raw(&server, http10).ends_with("\r\n\r\n"));
+
+        server.set_unknown_length_policy(UnknownLengthPolicy::LengthRequired);
+        assert!(send_raw(&server, http11).starts_with("HTTP/1.1 411 "));
+
+        // send_raw doesn't close the client's side, so read from a
+        // connection that has been shut down for writing
+        server.set_unknown_length_policy(UnknownLengthPolicy::ReadToEof);
+        let send_and_close = |input: &[u8]| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client =
+                TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            client.write_all(input).unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            let (stream, peer_addr) = listener.accept().unwrap();
+            handle_connection(
+                Box::new(stream),
+                Some(peer_addr),
+                &server.routes,
+                &*server.error_handler,
+                Arc::new(server.settings.clone()),
+            )
+            .unwrap();
+            let mut output = String::new();
+            client.read_to_string(&mut output).unwrap();
+            output
+        };
+        assert!(send_and_close(http10).ends_with("\r\n\r\nbody"));
+        // The version is matched case-insensitively, as in is_http10
+        let lowercase = b"POST /echo http/1.0\r\nHost: example.com\r\n\r\nbody";
+        assert!(send_and_close(lowercase).ends_with("\r\n\r\nbody"));
+        assert!(send_raw(&server, http11).ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_spool_read_to_eof() {
+        let body = Body::read_to_eof(&mut &b"abcdef"[..], Some(3)).unwrap();
+        assert!(matches!(body, Body::File(_)));
+        let mut contents = String::new();
+        body.reader()
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "abcdef");
+        let body = Body::read_to_eof(&mut &b"abc"[..], Some(3)).unwrap();
+        assert_eq!(body.bytes(), Some(&b"abc"[..]));
+    }
+
+    #[test]
+    fn test_http10_streaming() {
+        #[throws]
+        fn progress(req: &mut Request) {
+            assert_eq!(req.version(), "HTTP/1.0");
+            let mut sender = req.start_ndjson_with_trailers(&["X-Count"])?;
+            sender.send(&1)?;
+            sender.send(&2)?;
+            sender.set_trailer("X-Count", "2")?;
+            sender.finish()?;
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /progress", &progress).unwrap();
+        let output =
+            send_raw(&server, b"GET /progress HTTP/1.0\nHost: example.com\n\n");
+        let (head, body) = output.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("\r\nConnection: close"));
+        assert!(!head.contains("Transfer-Encoding"));
+        assert!(!head.contains("Trailer"));
+        assert_eq!(body, "1\n2\n");
+    }
+
+    #[test]
+    fn test_error_pages() {
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /hello", &hello).unwrap();
+        server.route("GET /panic", &panics).unwrap();
+
+        let output =
+            send_raw(&server, b"GET /missing HTTP/1.1\nHost: example.com\n\n");
+        assert!(output.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(output.contains("\r\nContent-Type: text/html; charset=utf-8"));
+        assert!(output.contains("<h1>404 Not Found</h1>"));
+
+        server.set_error_page(StatusCode::NotFound, "<p>Lost?</p>");
+        server.set_error_page(StatusCode::Forbidden, "<p>Go away</p>");
+        let output =
+            send_raw(&server, b"GET /missing HTTP/1.1\nHost: example.com\n\n");
+        assert!(output.ends_with("\r\n\r\n<p>Lost?</p>"));
+
+        let output =
+            send_raw(&server, b"GET /panic HTTP/1.1\nHost: example.com\n\n");
+        assert!(output.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(output.contains("<h1>500 Internal Server Error</h1>"));
+
+        server.remove_error_page(StatusCode::InternalServerError);
+        let output =
+            send_raw(&server, b"GET /panic HTTP/1.1\nHost: example.com\n\n");
+        assert!(
+            output.ends_with("Content-Length: 0\r\nConnection: close\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn test_route_timeout() {
+        #[throws]
+        fn slow(req: &mut Request) {
+            assert!(req.remaining_time().unwrap() <= Duration::from_millis(50));
+            std::thread::sleep(Duration::from_millis(200));
+            req.write_text("too late");
+        }
+
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server
+            .route("GET /slow", &slow)
+            .unwrap()
+            .timeout(Duration::from_millis(50));
+        server
+            .route("GET /hello", &hello)
+            .unwrap()
+            .timeout(Duration::from_secs(10));
+
+        let output =
+            send_raw(&server, b"GET /slow HTTP/1.1\nHost: example.com\n\n");
+        assert!(output.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+        assert!(!output.contains("too late"));
+        let output =
+            send_raw(&server, b"GET /hello HTTP/1.1\nHost: example.com\n\n");
+        assert!(output.ends_with("\r\n\r\nhello"));
+
+        let resp = server
+            .test_request(&TestRequest::new("GET /slow").unwrap())
+            .unwrap();
+        assert_eq!(resp.status, StatusCode::GatewayTimeout);
+        assert!(resp.body.is_empty());
+    }
+
+    #[test]
+    fn test_scheduled_jobs_stop_with_server() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let mut server: Server<Error> = Server::new("127.0.0.1:0").unwrap();
+        server.schedule(Duration::from_millis(10), move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        let running = server.launch_in_background().unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        running.stop().unwrap();
+        let runs = count.load(Ordering::SeqCst);
+        assert!(runs > 0);
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(count.load(Ordering::SeqCst), runs);
+    }
 }
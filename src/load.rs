@@ -0,0 +1,177 @@
+//! Tracking how many connections are being handled, and limiting it.
+
+use crate::stream::Connection;
+use std::io::Write;
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Limits on the number of connections handled at once.
+#[derive(Clone, Debug)]
+pub(crate) struct LoadOptions {
+    /// Wait for a connection to finish before accepting another.
+    pub(crate) max_connections: Option<usize>,
+    /// Reject new connections with 503 instead of handling them.
+    pub(crate) shed_above: Option<usize>,
+    /// Value of the `Retry-After` header for rejected connections.
+    pub(crate) retry_after: Duration,
+}
+
+impl Default for LoadOptions {
+    fn default() -> LoadOptions {
+        LoadOptions {
+            max_connections: None,
+            shed_above: None,
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    in_flight: Mutex<usize>,
+    cvar: Condvar,
+    shed: AtomicU64,
+}
+
+/// Number of connections being handled, so that the accept loop can
+/// wait for a free slot or shed load.
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionCount(Arc<State>);
+
+impl ConnectionCount {
+    /// Block until fewer than `max` connections are being handled.
+    pub(crate) fn wait_below(&self, max: usize) {
+        let mut in_flight = self.0.in_flight.lock().unwrap();
+        while *in_flight >= max {
+            in_flight = self.0.cvar.wait(in_flight).unwrap();
+        }
+    }
+
+    /// Count a new connection until the returned guard is dropped.
+    pub(crate) fn add(&self) -> ConnectionGuard {
+        *self.0.in_flight.lock().unwrap() += 1;
+        ConnectionGuard(self.clone())
+    }
+
+    fn in_flight(&self) -> usize {
+        *self.0.in_flight.lock().unwrap()
+    }
+
+    /// Reject `stream` with 503 if there are already `options.shed_above`
+    /// connections. Returns the stream if it should be handled.
+    pub(crate) fn shed(
+        &self,
+        mut stream: Connection,
+        options: &LoadOptions,
+    ) -> Option<Connection> {
+        match options.shed_above {
+            Some(max) if self.in_flight() >= max => {}
+            _ => return Some(stream),
+        }
+        self.0.shed.fetch_add(1, Ordering::Relaxed);
+        // Respond without reading the request, so this stays cheap;
+        // the client may not see it if it's still sending
+        let _ = write!(
+            stream,
+            "HTTP/1.1 503 Service Unavailable\r\n\
+             Retry-After: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            options.retry_after.as_secs().max(1)
+        );
+        let _ = stream.shutdown(Shutdown::Write);
+        None
+    }
+}
+
+/// Keeps a connection counted in a [`ConnectionCount`].
+pub(crate) struct ConnectionGuard(ConnectionCount);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        *(self.0).0.in_flight.lock().unwrap() -= 1;
+        (self.0).0.cvar.notify_one();
+    }
+}
+
+/// Read-only view of a server's load, returned by
+/// [`Server::load_monitor`]. It can be kept after the server is
+/// launched, for example to report metrics.
+///
+/// [`Server::load_monitor`]: crate::Server::load_monitor
+#[derive(Clone)]
+pub struct LoadMonitor {
+    pub(crate) count: ConnectionCount,
+    pub(crate) options: LoadOptions,
+}
+
+impl LoadMonitor {
+    /// Number of connections being handled right now.
+    pub fn in_flight(&self) -> usize {
+        self.count.in_flight()
+    }
+
+    /// Total number of connections rejected with 503 since the server
+    /// was created.
+    pub fn shed_total(&self) -> u64 {
+        self.count.0.shed.load(Ordering::Relaxed)
+    }
+
+    /// Limit set with [`ServerBuilder::max_connections`].
+    ///
+    /// [`ServerBuilder::max_connections`]: crate::ServerBuilder::max_connections
+    pub fn max_connections(&self) -> Option<usize> {
+        self.options.max_connections
+    }
+
+    /// Threshold set with [`ServerBuilder::shed_load`].
+    ///
+    /// [`ServerBuilder::shed_load`]: crate::ServerBuilder::shed_load
+    pub fn shed_threshold(&self) -> Option<usize> {
+        self.options.shed_above
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn test_connection_count() {
+        let count = ConnectionCount::default();
+        let guard = count.add();
+        let count2 = count.clone();
+        let waiter = thread::spawn(move || count2.wait_below(1));
+        thread::sleep(Duration::from_millis(20));
+        assert!(!waiter.is_finished());
+        drop(guard);
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn test_shed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client =
+            TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _addr) = listener.accept().unwrap();
+
+        let count = ConnectionCount::default();
+        let options = LoadOptions {
+            shed_above: Some(1),
+            retry_after: Duration::from_secs(5),
+            ..LoadOptions::default()
+        };
+        let stream = count.shed(Box::new(stream), &options).unwrap();
+        let _guard = count.add();
+        assert!(count.shed(stream, &options).is_none());
+        assert_eq!(count.0.shed.load(Ordering::Relaxed), 1);
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(output.contains("\r\nRetry-After: 5\r\n"));
+    }
+}
@@ -0,0 +1,127 @@
+//! Letting `POST` requests stand in for other methods, enabled with
+//! [`Server::set_method_override`].
+
+use crate::{HeaderName, Request, Server};
+use std::fmt::{Debug, Display};
+
+/// Methods a request can be rewritten to. Overriding to a safe method
+/// such as `GET` is refused, since a form could then reach it in ways
+/// its route doesn't expect.
+const ALLOWED: &[&str] = &["PUT", "PATCH", "DELETE"];
+
+/// The method requested by the `X-HTTP-Method-Override` header, or
+/// else by the `_method` field of a URL-encoded form body.
+fn requested(req: &Request) -> Option<String> {
+    let headers = req.headers();
+    if let Some(method) =
+        headers.get(&HeaderName::new("X-HTTP-Method-Override".into()))
+    {
+        return Some(method.trim().to_string());
+    }
+    let content_type = headers.get(&HeaderName::new("Content-Type".into()))?;
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    if !essence.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        return None;
+    }
+    // A form large enough to be spooled is an upload, not one that just
+    // picks a method
+    let body = req.spooled_body().bytes()?;
+    url::form_urlencoded::parse(body)
+        .find(|(name, _)| name == "_method")
+        .map(|(_, value)| value.trim().to_string())
+}
+
+/// Rewrite the method of a `POST` request that asks for an override.
+/// Overrides to methods that aren't allowed are ignored.
+pub(crate) fn apply(req: &mut Request) {
+    if req.method != "POST" {
+        return;
+    }
+    if let Some(method) = requested(req) {
+        let method = method.to_ascii_uppercase();
+        if ALLOWED.contains(&method.as_str()) {
+            req.method = method;
+        }
+    }
+}
+
+impl<E: Debug + Display + 'static> Server<E> {
+    /// Enable or disable method overrides. The default is disabled.
+    ///
+    /// When enabled, a `POST` request with an `X-HTTP-Method-Override`
+    /// header, or a URL-encoded form body with a `_method` field, is
+    /// routed as if it used that method instead, so that HTML forms
+    /// and clients limited to `GET` and `POST` can reach `PUT`,
+    /// `PATCH`, and `DELETE` routes. Other methods can't be requested
+    /// this way. The header takes precedence over the form field, and
+    /// [`Request::method`] returns the rewritten method.
+    ///
+    /// ```html
+    /// <form method="post" action="/posts/42">
+    ///   <input type="hidden" name="_method" value="DELETE">
+    ///   <button>Delete</button>
+    /// </form>
+    /// ```
+    pub fn set_method_override(&mut self, enabled: bool) {
+        self.routes.method_override = enabled;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{StatusCode, TestRequest};
+    use anyhow::Error;
+    use fehler::throws;
+
+    #[throws]
+    fn method(req: &mut Request) {
+        let method = req.method().to_string();
+        req.write_text(&method);
+    }
+
+    #[test]
+    fn test_method_override() {
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        server.route("POST /posts/:id", &method).unwrap();
+        server.route("DELETE /posts/:id", &method).unwrap();
+        server.route("PUT /posts/:id", &method).unwrap();
+        server.route("GET /posts/:id", &method).unwrap();
+
+        let form = |body: &[u8]| {
+            let mut req =
+                TestRequest::new_with_body("POST /posts/1", body).unwrap();
+            req.set_header(
+                "Content-Type",
+                "application/x-www-form-urlencoded; charset=UTF-8",
+            );
+            req
+        };
+        let mut with_header = TestRequest::new("POST /posts/1").unwrap();
+        with_header.set_header("X-HTTP-Method-Override", "put");
+
+        // Disabled by default
+        let resp = server.test_request(&form(b"_method=DELETE")).unwrap();
+        assert_eq!(resp.body, b"POST");
+
+        server.set_method_override(true);
+        let resp = server.test_request(&form(b"a=1&_method=DELETE")).unwrap();
+        assert_eq!(resp.body, b"DELETE");
+        let resp = server.test_request(&with_header).unwrap();
+        assert_eq!(resp.status, StatusCode::Ok);
+        assert_eq!(resp.body, b"PUT");
+
+        // Safe methods and non-form bodies aren't overridden
+        let resp = server.test_request(&form(b"_method=GET")).unwrap();
+        assert_eq!(resp.body, b"POST");
+        let req =
+            TestRequest::new_with_body("POST /posts/1", b"_method=DELETE")
+                .unwrap();
+        assert_eq!(server.test_request(&req).unwrap().body, b"POST");
+
+        // Only POST requests are rewritten
+        let mut req = TestRequest::new("GET /posts/1").unwrap();
+        req.set_header("X-HTTP-Method-Override", "DELETE");
+        assert_eq!(server.test_request(&req).unwrap().body, b"GET");
+    }
+}
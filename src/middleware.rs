@@ -0,0 +1,77 @@
+use crate::Request;
+
+/// Middleware function. It receives the request and the rest of the
+/// chain; call [`Next::run`] to continue on to the route's handler,
+/// or return without calling it to stop early, for example after
+/// writing an error response.
+///
+/// Middleware can be added globally with [`Server::add_middleware`],
+/// or to a single route with [`RouteHandle::with`].
+///
+/// ```
+/// use shs::{Next, Request, StatusCode};
+///
+/// // Only allow requests from this machine
+/// fn local_only(
+///     req: &mut Request,
+///     next: Next<'_, anyhow::Error>,
+/// ) -> Result<(), anyhow::Error> {
+///     if req.client_ip().map_or(false, |ip| ip.is_loopback()) {
+///         next.run(req)
+///     } else {
+///         req.set_status(StatusCode::Forbidden);
+///         Ok(())
+///     }
+/// }
+/// ```
+///
+/// [`Server::add_middleware`]: crate::Server::add_middleware
+/// [`RouteHandle::with`]: crate::RouteHandle::with
+pub type Middleware<E> =
+    dyn Fn(&mut Request, Next<'_, E>) -> Result<(), E> + Send + Sync;
+
+/// The rest of a middleware chain, ending in the route's handler.
+pub struct Next<'a, E> {
+    global: &'a [Box<Middleware<E>>],
+    route: &'a [Box<Middleware<E>>],
+    handler: &'a (dyn Fn(&mut Request) -> Result<(), E> + Send + Sync + 'a),
+}
+
+impl<'a, E> Next<'a, E> {
+    /// Create a chain that runs the `global` middleware, then the
+    /// `route` middleware, then `handler`.
+    pub(crate) fn new(
+        global: &'a [Box<Middleware<E>>],
+        route: &'a [Box<Middleware<E>>],
+        handler: &'a (dyn Fn(&mut Request) -> Result<(), E> + Send + Sync + 'a),
+    ) -> Next<'a, E> {
+        Next {
+            global,
+            route,
+            handler,
+        }
+    }
+
+    /// Run the next middleware in the chain, or the handler if there
+    /// is no more middleware.
+    pub fn run(self, req: &mut Request) -> Result<(), E> {
+        if let Some((first, global)) = self.global.split_first() {
+            first(req, Next { global, ..self })
+        } else if let Some((first, route)) = self.route.split_first() {
+            first(req, Next { route, ..self })
+        } else {
+            (self.handler)(req)
+        }
+    }
+}
+
+/// Function that runs after a request has been handled, once the
+/// status, headers, and body are set but before the response is
+/// written. Add one with [`Server::add_after_hook`].
+///
+/// Hooks run for every response, including ones produced by the error
+/// handler, so they are a good place for cross-cutting changes such as
+/// adding headers or recording response sizes.
+///
+/// [`Server::add_after_hook`]: crate::Server::add_after_hook
+pub type AfterHook = dyn Fn(&mut Request) + Send + Sync;
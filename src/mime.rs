@@ -0,0 +1,176 @@
+//! Guessing content types from file names and contents.
+//!
+//! This is what [`Request::write_file`] and [`Server::serve_embedded`]
+//! use, and it's available for handlers that build responses
+//! themselves:
+//! ```
+//! use shs::mime;
+//!
+//! assert_eq!(mime::from_extension("png"), Some("image/png"));
+//! assert_eq!(mime::guess("notes", b"plain text"), "text/plain; charset=UTF-8");
+//! ```
+//!
+//! [`Request::write_file`]: crate::Request::write_file
+//! [`Server::serve_embedded`]: crate::Server::serve_embedded
+
+use std::path::Path;
+
+/// Content type for data that couldn't be identified.
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Content types for common file extensions.
+const TYPES: &[(&str, &str)] = &[
+    ("css", "text/css; charset=UTF-8"),
+    ("csv", "text/csv; charset=UTF-8"),
+    ("gif", "image/gif"),
+    ("gz", "application/gzip"),
+    ("htm", "text/html; charset=UTF-8"),
+    ("html", "text/html; charset=UTF-8"),
+    ("ico", "image/x-icon"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript; charset=UTF-8"),
+    ("json", "application/json"),
+    ("md", "text/markdown; charset=UTF-8"),
+    ("mjs", "text/javascript; charset=UTF-8"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("svg", "image/svg+xml"),
+    ("tar", "application/x-tar"),
+    ("ttf", "font/ttf"),
+    ("txt", "text/plain; charset=UTF-8"),
+    ("wasm", "application/wasm"),
+    ("webm", "video/webm"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xml", "application/xml"),
+    ("zip", "application/zip"),
+];
+
+/// Get the content type for a file extension (without the leading
+/// dot). The comparison is case-insensitive.
+pub fn from_extension(ext: &str) -> Option<&'static str> {
+    TYPES
+        .iter()
+        .find(|(e, _)| e.eq_ignore_ascii_case(ext))
+        .map(|(_, t)| *t)
+}
+
+/// Get the content type for a path from its extension.
+pub fn from_path(path: impl AsRef<Path>) -> Option<&'static str> {
+    path.as_ref()
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(from_extension)
+}
+
+/// Signatures at the start of common binary formats.
+const MAGIC: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x00asm", "application/wasm"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+];
+
+/// Guess the content type from the first bytes of the data, for files
+/// without a known extension. Only a few common binary formats are
+/// recognized, along with HTML, XML, and JSON. Other valid UTF-8 is
+/// treated as plain text.
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    if let Some((_, content_type)) =
+        MAGIC.iter().find(|(magic, _)| data.starts_with(magic))
+    {
+        return Some(content_type);
+    }
+    if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+
+    // The data may be cut off in the middle of a character
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&data[..err.valid_up_to()]).ok()?
+        }
+        Err(_) => return None,
+    };
+    if text.contains('\0') {
+        return None;
+    }
+    let start = text.trim_start().to_ascii_lowercase();
+    let content_type =
+        if start.starts_with("<!doctype html") || start.starts_with("<html") {
+            "text/html; charset=UTF-8"
+        } else if start.starts_with("<?xml") {
+            "application/xml"
+        } else if start.starts_with("<svg") {
+            "image/svg+xml"
+        } else if (start.starts_with('{') || start.starts_with('['))
+            && serde_json::from_str::<serde_json::Value>(text).is_ok()
+        {
+            "application/json"
+        } else {
+            "text/plain; charset=UTF-8"
+        };
+    Some(content_type)
+}
+
+/// Guess the content type from the path's extension, falling back to
+/// sniffing `data` and then to [`OCTET_STREAM`]. `data` only needs to
+/// be the start of the contents; 512 bytes is enough.
+pub fn guess(path: impl AsRef<Path>, data: &[u8]) -> &'static str {
+    from_path(path)
+        .or_else(|| sniff(data))
+        .unwrap_or(OCTET_STREAM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(from_path("a/b.CSS"), Some("text/css; charset=UTF-8"));
+        assert_eq!(from_path("a/b"), None);
+        assert_eq!(from_path("a/b.unknown"), None);
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n...."), Some("image/png"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(
+            sniff(b"  <!DOCTYPE html><html>"),
+            Some("text/html; charset=UTF-8")
+        );
+        assert_eq!(sniff(br#"{"a": [1]}"#), Some("application/json"));
+        // Truncated JSON is just text
+        assert_eq!(sniff(br#"{"a": "#), Some("text/plain; charset=UTF-8"));
+        // A multi-byte character cut off at the end
+        assert_eq!(
+            sniff("h\u{e9}".as_bytes()[..2].as_ref()),
+            Some("text/plain; charset=UTF-8")
+        );
+        assert_eq!(sniff(b"\x00\x01\x02"), None);
+        assert_eq!(sniff(b"\xfe\xff\x00"), None);
+    }
+
+    #[test]
+    fn test_guess() {
+        assert_eq!(guess("x.png", b"text"), "image/png");
+        assert_eq!(guess("x", b"%PDF-1.7"), "application/pdf");
+        assert_eq!(guess("x", b"\x00\x01"), OCTET_STREAM);
+    }
+}
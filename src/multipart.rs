@@ -0,0 +1,562 @@
+//! Streaming `multipart/form-data` request bodies.
+
+use crate::{HeaderName, Rejection, Request, StatusCode};
+use anyhow::Error;
+use fehler::{throw, throws};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Limit on the size of one part's headers.
+const MAX_PART_HEADER_BYTES: usize = 8 * 1024;
+
+/// Limit on the size of a text field read by [`Multipart::save_to_dir`].
+const MAX_FIELD_LEN: usize = 64 * 1024;
+
+/// Splits a stream at each occurrence of the part delimiter, keeping
+/// only enough data buffered to recognize it.
+struct Scanner<R> {
+    reader: R,
+    buf: Vec<u8>,
+    /// `\r\n--boundary`
+    delimiter: Vec<u8>,
+    eof: bool,
+    /// Whether the current part has been read up to the delimiter.
+    at_delimiter: bool,
+}
+
+impl<R: Read> Scanner<R> {
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = [0; 8192];
+        let n = loop {
+            match self.reader.read(&mut chunk) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                result => break result?,
+            }
+        };
+        if n == 0 {
+            self.eof = true;
+        }
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    fn unexpected_eof() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "multipart body ended before the closing boundary",
+        )
+    }
+
+    /// Read the current part's body, up to the delimiter.
+    fn read_body(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.at_delimiter {
+                return Ok(0);
+            }
+            let available = match find(&self.buf, &self.delimiter) {
+                Some(0) => {
+                    self.buf.drain(..self.delimiter.len());
+                    self.at_delimiter = true;
+                    return Ok(0);
+                }
+                Some(i) => i,
+                // The end of the buffer could be the start of the
+                // delimiter
+                None => self.buf.len().saturating_sub(self.delimiter.len() - 1),
+            };
+            if available > 0 {
+                let n = available.min(out.len());
+                out[..n].copy_from_slice(&self.buf[..n]);
+                self.buf.drain(..n);
+                return Ok(n);
+            }
+            if self.eof {
+                return Err(Scanner::<R>::unexpected_eof());
+            }
+            self.fill()?;
+        }
+    }
+
+    /// Read a CRLF-terminated line, without the CRLF.
+    fn read_line(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(i) = find(&self.buf, b"\r\n") {
+                let line = self.buf[..i].to_vec();
+                self.buf.drain(..i + 2);
+                return Ok(line);
+            }
+            if self.buf.len() > MAX_PART_HEADER_BYTES {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "multipart part headers are too large",
+                ));
+            }
+            if self.eof {
+                return Err(Scanner::<R>::unexpected_eof());
+            }
+            self.fill()?;
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Error for a malformed body, sent to the client as a 400 by the
+/// built-in error handlers.
+fn bad_request(message: impl Into<String>) -> Error {
+    Rejection::new(StatusCode::BadRequest, message).into()
+}
+
+/// Convert an error from reading the body. The scanner reports a
+/// malformed body as invalid data or an unexpected end; anything else,
+/// such as failing to read a spooled body, is the server's problem.
+fn body_error(err: io::Error) -> Error {
+    match err.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+            bad_request(err.to_string())
+        }
+        _ => err.into(),
+    }
+}
+
+/// Split a header value at each `;` that isn't inside a quoted
+/// string, so that `filename="a;b.txt"` stays in one piece.
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    params.push(&value[start..]);
+    params
+}
+
+/// Get a parameter such as `boundary` or `name` from a header value
+/// like `form-data; name="file"`, unquoting it if needed.
+fn header_param(value: &str, param: &str) -> Option<String> {
+    split_params(value).into_iter().skip(1).find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case(param) {
+            return None;
+        }
+        let value = value.trim();
+        match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(quoted) => {
+                let mut unquoted = String::with_capacity(quoted.len());
+                let mut chars = quoted.chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => unquoted.extend(chars.next()),
+                        c => unquoted.push(c),
+                    }
+                }
+                Some(unquoted)
+            }
+            None => Some(value.to_string()),
+        }
+    })
+}
+
+/// Streaming reader for a `multipart/form-data` request body, created
+/// with [`Request::multipart`]. Parts are read one at a time, so files
+/// are never held in memory in full.
+///
+/// Example usage:
+/// ```
+/// use anyhow::Error;
+/// use fehler::throws;
+/// use shs::Request;
+/// use std::io;
+///
+/// #[throws]
+/// fn upload(req: &mut Request) {
+///     let mut multipart = req.multipart()?;
+///     while let Some(mut part) = multipart.next_part()? {
+///         let len = io::copy(&mut part, &mut io::sink())?;
+///         println!("{:?}: {} bytes", part.name(), len);
+///     }
+/// }
+/// ```
+pub struct Multipart<'a> {
+    scanner: Scanner<Box<dyn Read + 'a>>,
+    finished: bool,
+}
+
+impl<'a> Multipart<'a> {
+    fn new(reader: Box<dyn Read + 'a>, boundary: &str) -> Multipart<'a> {
+        Multipart {
+            scanner: Scanner {
+                reader,
+                // So that a boundary at the very start matches the
+                // delimiter too
+                buf: b"\r\n".to_vec(),
+                delimiter: format!("\r\n--{}", boundary).into_bytes(),
+                eof: false,
+                at_delimiter: false,
+            },
+            finished: false,
+        }
+    }
+
+    /// Get the next part, skipping whatever is left of the previous
+    /// one. Returns `None` after the last part. A malformed body is a
+    /// [`Rejection`] with status 400.
+    #[throws]
+    pub fn next_part(&mut self) -> Option<Part<'_, 'a>> {
+        if self.finished {
+            return None;
+        }
+        let mut skipped = [0; 8192];
+        while self.scanner.read_body(&mut skipped).map_err(body_error)? > 0 {}
+
+        // The rest of the boundary line is `--` after the last part
+        let line = self.scanner.read_line().map_err(body_error)?;
+        if line.starts_with(b"--") {
+            self.finished = true;
+            return None;
+        }
+
+        let mut part = Part {
+            name: None,
+            filename: None,
+            content_type: None,
+            scanner: &mut self.scanner,
+        };
+        let mut header_bytes = 0;
+        loop {
+            let line = part.scanner.read_line().map_err(body_error)?;
+            if line.is_empty() {
+                break;
+            }
+            header_bytes += line.len();
+            if header_bytes > MAX_PART_HEADER_BYTES {
+                throw!(bad_request("multipart part headers are too large"));
+            }
+            let line = String::from_utf8_lossy(&line);
+            let (name, value) = match line.split_once(':') {
+                Some(header) => header,
+                None => throw!(bad_request(format!(
+                    "invalid multipart header: {}",
+                    line
+                ))),
+            };
+            let name = name.trim();
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("Content-Disposition") {
+                part.name = header_param(value, "name");
+                part.filename = header_param(value, "filename");
+            } else if name.eq_ignore_ascii_case("Content-Type") {
+                part.content_type = Some(value.to_string());
+            }
+        }
+        part.scanner.at_delimiter = false;
+        Some(part)
+    }
+
+    /// Read every part, writing each file to a new file in `dir` and
+    /// keeping text fields in memory. Files get generated names rather
+    /// than the client's filename, which can't be trusted as a path.
+    /// Fields over 64 KiB are an error. If any part fails, the files
+    /// already saved are deleted.
+    #[throws]
+    pub fn save_to_dir(&mut self, dir: &Path) -> Vec<SavedPart> {
+        let mut saved = Vec::new();
+        if let Err(err) = self.save_parts(dir, &mut saved) {
+            for part in &saved {
+                if let SavedPart::File { path, .. } = part {
+                    if let Err(err) = fs::remove_file(path) {
+                        log::error!(
+                            "failed to remove {}: {}",
+                            path.display(),
+                            err
+                        );
+                    }
+                }
+            }
+            throw!(err);
+        }
+        saved
+    }
+
+    #[throws]
+    fn save_parts(&mut self, dir: &Path, saved: &mut Vec<SavedPart>) {
+        while let Some(mut part) = self.next_part()? {
+            if part.filename.is_none() {
+                let mut value = Vec::new();
+                (&mut part)
+                    .take(MAX_FIELD_LEN as u64 + 1)
+                    .read_to_end(&mut value)
+                    .map_err(body_error)?;
+                if value.len() > MAX_FIELD_LEN {
+                    throw!(Rejection::new(
+                        StatusCode::PayloadTooLarge,
+                        "multipart field is too large"
+                    ));
+                }
+                let value = String::from_utf8(value).map_err(|_| {
+                    bad_request("multipart field is not valid UTF-8")
+                })?;
+                saved.push(SavedPart::Field {
+                    name: part.name.take(),
+                    value,
+                });
+                continue;
+            }
+            // The file is deleted on drop unless it's kept below
+            let mut file = tempfile::Builder::new()
+                .prefix("upload-")
+                .tempfile_in(dir)?;
+            let len = io::copy(&mut part, &mut file).map_err(body_error)?;
+            file.flush()?;
+            let (_, path) = file.keep()?;
+            saved.push(SavedPart::File {
+                name: part.name.take(),
+                filename: part.filename.take(),
+                content_type: part.content_type.take(),
+                path,
+                len,
+            });
+        }
+    }
+}
+
+/// One part of a multipart body. Reading from it reads the part's
+/// body.
+pub struct Part<'m, 'a> {
+    name: Option<String>,
+    filename: Option<String>,
+    content_type: Option<String>,
+    scanner: &'m mut Scanner<Box<dyn Read + 'a>>,
+}
+
+impl Part<'_, '_> {
+    /// Get the form field name from `Content-Disposition`.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Get the client's filename from `Content-Disposition`, if the
+    /// part is a file.
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_deref()
+    }
+
+    /// Get the part's `Content-Type`.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+}
+
+impl Read for Part<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.scanner.read_body(buf)
+    }
+}
+
+/// Part saved by [`Multipart::save_to_dir`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SavedPart {
+    /// Text field.
+    Field {
+        /// Form field name.
+        name: Option<String>,
+        /// Field value.
+        value: String,
+    },
+    /// File written to disk.
+    File {
+        /// Form field name.
+        name: Option<String>,
+        /// Filename sent by the client.
+        filename: Option<String>,
+        /// Content type sent by the client.
+        content_type: Option<String>,
+        /// Where the file was saved.
+        path: PathBuf,
+        /// Size of the file in bytes.
+        len: u64,
+    },
+}
+
+impl Request {
+    /// Read the body as `multipart/form-data`. Fails with a
+    /// [`Rejection`] with status 400 if the `Content-Type` isn't
+    /// multipart or has no boundary.
+    ///
+    /// This works for bodies spooled to a file as well as in-memory
+    /// ones; see [`Server::set_spool_threshold`].
+    ///
+    /// [`Server::set_spool_threshold`]: crate::Server::set_spool_threshold
+    #[throws]
+    pub fn multipart(&self) -> Multipart<'_> {
+        let content_type = self
+            .req_headers
+            .get(&HeaderName::new("Content-Type".into()))
+            .ok_or_else(|| bad_request("missing content type"))?;
+        if !content_type
+            .trim_start()
+            .to_ascii_lowercase()
+            .starts_with("multipart/")
+        {
+            throw!(bad_request(format!(
+                "not a multipart body: {}",
+                content_type
+            )));
+        }
+        let boundary = match header_param(content_type, "boundary") {
+            Some(boundary) if !boundary.is_empty() => boundary,
+            _ => throw!(bad_request("missing multipart boundary")),
+        };
+        Multipart::new(self.body_reader()?, &boundary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+        hello\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"file\"; \
+        filename=\"a \\\"b\\\".txt\"\r\n\
+        Content-Type: text/plain\r\n\r\n\
+        line one\r\n--Xy\r\nline two\r\n--XyZ--\r\n";
+
+    #[test]
+    fn test_next_part() {
+        // A tiny reader makes the delimiter span reads
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = buf.len().min(self.0.len()).min(3);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let mut multipart = Multipart::new(Box::new(Trickle(BODY)), "XyZ");
+        let part = multipart.next_part().unwrap().unwrap();
+        assert_eq!(part.name(), Some("title"));
+        assert_eq!(part.filename(), None);
+
+        let mut part = multipart.next_part().unwrap().unwrap();
+        assert_eq!(part.name(), Some("file"));
+        assert_eq!(part.filename(), Some("a \"b\".txt"));
+        assert_eq!(part.content_type(), Some("text/plain"));
+        let mut contents = String::new();
+        part.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "line one\r\n--Xy\r\nline two");
+
+        assert!(multipart.next_part().unwrap().is_none());
+        assert!(multipart.next_part().unwrap().is_none());
+
+        let mut truncated =
+            Multipart::new(Box::new(&BODY[..BODY.len() - 12]), "XyZ");
+        truncated.next_part().unwrap();
+        let mut part = truncated.next_part().unwrap().unwrap();
+        assert!(part.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_header_param() {
+        let value = r#"form-data; name="f"; filename="a;b \";c.txt""#;
+        assert_eq!(header_param(value, "name").as_deref(), Some("f"));
+        assert_eq!(
+            header_param(value, "filename").as_deref(),
+            Some("a;b \";c.txt")
+        );
+        assert_eq!(header_param(value, "other"), None);
+        assert_eq!(
+            header_param("multipart/form-data; boundary=x;y", "boundary")
+                .as_deref(),
+            Some("x")
+        );
+    }
+
+    #[test]
+    fn test_malformed() {
+        let status = |err: Error| err.downcast::<Rejection>().unwrap().status;
+        let mut truncated =
+            Multipart::new(Box::new(&BODY[..BODY.len() - 12]), "XyZ");
+        truncated.next_part().unwrap();
+        truncated.next_part().unwrap();
+        let err = truncated.next_part().err().unwrap();
+        assert_eq!(status(err), StatusCode::BadRequest);
+
+        let mut invalid = Multipart::new(
+            Box::new(&b"--XyZ\r\nno colon\r\n\r\n\r\n--XyZ--\r\n"[..]),
+            "XyZ",
+        );
+        let err = invalid.next_part().err().unwrap();
+        assert_eq!(status(err), StatusCode::BadRequest);
+    }
+
+    #[test]
+    fn test_save_to_dir_cleanup() {
+        // The file part is saved before the body turns out to be
+        // truncated
+        let body = b"--XyZ\r\n\
+            Content-Disposition: form-data; name=\"f\"; filename=\"a\"\r\n\r\n\
+            contents\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"x\"\r\n\r\n\
+            no closing boundary";
+        let dir = tempfile::tempdir().unwrap();
+        let mut multipart = Multipart::new(Box::new(&body[..]), "XyZ");
+        let err = multipart.save_to_dir(dir.path()).unwrap_err();
+        assert!(err.downcast_ref::<Rejection>().is_some(), "{}", err);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_save_to_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut multipart = Multipart::new(Box::new(BODY), "XyZ");
+        let saved = multipart.save_to_dir(dir.path()).unwrap();
+        assert_eq!(saved.len(), 2);
+        assert_eq!(
+            saved[0],
+            SavedPart::Field {
+                name: Some("title".into()),
+                value: "hello".into()
+            }
+        );
+        match &saved[1] {
+            SavedPart::File {
+                filename,
+                path,
+                len,
+                ..
+            } => {
+                assert_eq!(filename.as_deref(), Some("a \"b\".txt"));
+                assert!(path.starts_with(dir.path()));
+                assert_eq!(*len, 24);
+                assert_eq!(
+                    std::fs::read(path).unwrap(),
+                    b"line one\r\n--Xy\r\nline two"
+                );
+            }
+            part => panic!("unexpected part {:?}", part),
+        }
+    }
+}
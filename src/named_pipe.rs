@@ -0,0 +1,262 @@
+//! Serving on a Windows named pipe, set up with
+//! [`Server::new_named_pipe`], for servers that should only be
+//! reachable from the same machine.
+
+use crate::body::SendFile;
+use crate::builder::Listen;
+use crate::stream::{Connection, Stream};
+use crate::{Server, ServerBuilder};
+use anyhow::Error;
+use fehler::{throw, throws};
+use std::ffi::OsStr;
+use std::fmt::{Debug, Display};
+use std::fs::{File, OpenOptions};
+use std::io::{self, IoSlice, Read, Write};
+use std::net::Shutdown;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::{AsRawHandle, FromRawHandle};
+use std::ptr;
+use std::time::Duration;
+use windows_sys::Win32::Foundation::{
+    ERROR_PIPE_CONNECTED, HANDLE, INVALID_HANDLE_VALUE,
+};
+use windows_sys::Win32::Storage::FileSystem::{
+    FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX,
+};
+use windows_sys::Win32::System::Pipes::{
+    ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe,
+    PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE,
+    PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+};
+
+/// Size of the pipe's buffer in each direction.
+const BUFFER_SIZE: u32 = 64 * 1024;
+
+/// Accepts clients on a named pipe. Each client gets its own instance
+/// of the pipe, and the next instance is created as soon as one is
+/// taken, so that clients don't find the pipe busy in between.
+pub(crate) struct PipeListener {
+    name: String,
+    /// The name as a null-terminated wide string, for the API.
+    wide_name: Vec<u16>,
+    /// The instance waiting for the next client.
+    next: File,
+}
+
+impl PipeListener {
+    #[throws(io::Error)]
+    pub(crate) fn bind(name: &str) -> PipeListener {
+        let wide_name: Vec<u16> =
+            OsStr::new(name).encode_wide().chain(Some(0)).collect();
+        // The first instance fails if another process already owns the
+        // name, rather than sharing it
+        let next = create_instance(&wide_name, FILE_FLAG_FIRST_PIPE_INSTANCE)?;
+        PipeListener {
+            name: name.into(),
+            wide_name,
+            next,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Wait for a client to connect to the waiting instance.
+    #[throws(io::Error)]
+    pub(crate) fn accept(&mut self) -> Connection {
+        // Safety: the handle belongs to `self.next`, and no overlapped
+        // structure is needed since it was opened for synchronous I/O
+        let ok =
+            unsafe { ConnectNamedPipe(handle(&self.next), ptr::null_mut()) };
+        if ok == 0 {
+            let err = io::Error::last_os_error();
+            // The client connected between creating the instance and
+            // the call, which is fine
+            if err.raw_os_error() != Some(ERROR_PIPE_CONNECTED as i32) {
+                throw!(err);
+            }
+        }
+        let next = create_instance(&self.wide_name, 0)?;
+        let connected = std::mem::replace(&mut self.next, next);
+        Box::new(PipeStream(connected)) as Connection
+    }
+
+    /// Connect to the pipe, so that a blocked [`PipeListener::accept`]
+    /// returns.
+    pub(crate) fn wake(name: &str) {
+        let _ = OpenOptions::new().read(true).write(true).open(name);
+    }
+}
+
+fn handle(file: &File) -> HANDLE {
+    file.as_raw_handle() as HANDLE
+}
+
+#[throws(io::Error)]
+fn create_instance(wide_name: &[u16], flags: u32) -> File {
+    // Safety: the name is null-terminated and outlives the call, and
+    // the default security attributes are used
+    let handle = unsafe {
+        CreateNamedPipeW(
+            wide_name.as_ptr(),
+            PIPE_ACCESS_DUPLEX | flags,
+            PIPE_TYPE_BYTE
+                | PIPE_READMODE_BYTE
+                | PIPE_WAIT
+                | PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_UNLIMITED_INSTANCES,
+            BUFFER_SIZE,
+            BUFFER_SIZE,
+            0,
+            ptr::null(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        throw!(io::Error::last_os_error());
+    }
+    // Safety: the handle was just created and nothing else owns it
+    unsafe { File::from_raw_handle(handle as _) }
+}
+
+/// One client's instance of the pipe.
+struct PipeStream(File);
+
+impl Read for PipeStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for PipeStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.0.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl SendFile for PipeStream {}
+
+impl Stream for PipeStream {
+    fn try_clone(&self) -> io::Result<Connection> {
+        Ok(Box::new(PipeStream(self.0.try_clone()?)))
+    }
+
+    /// Reads on a synchronous pipe can't time out, so the read and
+    /// header timeouts don't apply.
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Nor can writes, so the write timeout doesn't apply either.
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// A pipe can't be closed in just one direction, so only
+    /// `Shutdown::Both` does anything. It discards any data the client
+    /// hasn't read.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how == Shutdown::Both {
+            // Safety: the handle belongs to `self.0`
+            if unsafe { DisconnectNamedPipe(handle(&self.0)) } == 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        // There's no way to peek at a pipe without blocking that also
+        // notices the client leaving, so assume it's still there
+        true
+    }
+
+    fn close(&self) {
+        // Wait until the client has read the whole response. The pipe
+        // closes once the last handle to it is dropped.
+        let _ = self.0.sync_all();
+    }
+}
+
+impl ServerBuilder {
+    /// Create a builder for a server that accepts connections on the
+    /// named pipe `name`, such as `r"\\.\pipe\myapp"`. Clients on
+    /// other machines are rejected, so this suits servers that only
+    /// the local machine should reach. Launching fails if another
+    /// process already has a pipe with the same name.
+    ///
+    /// Requests on a named pipe have no [`Request::peer_addr`], so IP
+    /// filters and limits don't apply, and neither do the TCP socket
+    /// options or read timeouts.
+    ///
+    /// [`Request::peer_addr`]: crate::Request::peer_addr
+    pub fn named_pipe(name: &str) -> ServerBuilder {
+        ServerBuilder::with_listen(Ok(Listen::NamedPipe(name.into())))
+    }
+}
+
+impl<E: Debug + Display + 'static> Server<E> {
+    /// Create a Server that accepts connections on the named pipe
+    /// `name`. See [`ServerBuilder::named_pipe`].
+    ///
+    /// ```no_run
+    /// use shs::Server;
+    ///
+    /// let server: Server<anyhow::Error> =
+    ///     Server::new_named_pipe(r"\\.\pipe\myapp")?;
+    /// server.launch()?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    #[throws]
+    pub fn new_named_pipe(name: &str) -> Server<E> {
+        ServerBuilder::named_pipe(name).build()?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Request;
+
+    #[throws]
+    fn hello(req: &mut Request) {
+        assert!(req.peer_addr().is_none());
+        req.write_text("hello");
+    }
+
+    #[test]
+    fn test_named_pipe() {
+        let name = format!(r"\\.\pipe\shs-test-{}", std::process::id());
+        let mut server: Server<Error> = Server::new_named_pipe(&name).unwrap();
+        server.route("GET /hello", &hello).unwrap();
+        let running = server.launch_in_background().unwrap();
+
+        // Another server can't take the same name
+        let other: Server<Error> = Server::new_named_pipe(&name).unwrap();
+        assert!(other.launch_in_background().is_err());
+
+        for _ in 0..2 {
+            let mut client = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&name)
+                .unwrap();
+            client
+                .write_all(b"GET /hello HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.ends_with("\r\n\r\nhello"));
+        }
+        running.stop().unwrap();
+    }
+}
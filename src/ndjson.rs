@@ -0,0 +1,223 @@
+//! Streaming responses in the newline-delimited JSON format, one
+//! value per line.
+
+use crate::connection::{self, ClientDisconnected};
+use crate::stream::Connection;
+use crate::{parse, serialize_head, Framing, Request};
+use anyhow::{anyhow, Error};
+use fehler::{throw, throws};
+use serde::Serialize;
+use std::io::{self, Write};
+
+/// Writer for a streaming NDJSON response, returned by
+/// [`Request::start_ndjson`]. Each value is sent to the client as
+/// soon as it's written. The response ends when the sender is
+/// finished or dropped.
+pub struct NdjsonSender {
+    stream: Connection,
+    finished: bool,
+    /// False for HTTP/1.0 clients, which get the lines as they are
+    /// and no trailers.
+    chunked: bool,
+    /// Names declared with [`Request::start_ndjson_with_trailers`].
+    declared: Vec<String>,
+    /// Values set with [`NdjsonSender::set_trailer`].
+    trailers: Vec<(String, String)>,
+}
+
+/// Headers that can't be trailers, since the client needs them before
+/// the body (RFC 7230 §4.1.2).
+const FORBIDDEN_TRAILERS: &[&str] = &[
+    "Cache-Control",
+    "Content-Encoding",
+    "Content-Length",
+    "Content-Range",
+    "Content-Type",
+    "Host",
+    "Set-Cookie",
+    "Trailer",
+    "Transfer-Encoding",
+];
+
+fn write_error(err: io::Error) -> Error {
+    if connection::is_disconnect(&err) {
+        ClientDisconnected.into()
+    } else {
+        err.into()
+    }
+}
+
+impl NdjsonSender {
+    /// Write `value` as one line of JSON. Fails with
+    /// [`ClientDisconnected`] if the client went away.
+    #[throws]
+    pub fn send(&mut self, value: &impl Serialize) {
+        let mut json = serde_json::to_vec(value)?;
+        json.push(b'\n');
+        if self.chunked {
+            // Chunk size, then the line and its newline
+            let mut chunk = format!("{:x}\r\n", json.len()).into_bytes();
+            chunk.extend_from_slice(&json);
+            chunk.extend_from_slice(b"\r\n");
+            json = chunk;
+        }
+        self.stream.write_all(&json).map_err(write_error)?;
+    }
+
+    /// Set the value of a trailer declared with
+    /// [`Request::start_ndjson_with_trailers`], such as a checksum of
+    /// the values sent. Trailers are sent after the last value, when
+    /// the response ends. Setting a trailer again replaces its value.
+    #[throws]
+    pub fn set_trailer(&mut self, name: &str, value: &str) {
+        if !self.declared.iter().any(|d| d.eq_ignore_ascii_case(name)) {
+            throw!(anyhow!("trailer {} was not declared", name));
+        }
+        if !parse::is_valid_header_value(value) {
+            throw!(anyhow!("invalid value for trailer {}", name));
+        }
+        self.trailers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.trailers.push((name.into(), value.into()));
+    }
+
+    /// The last chunk, followed by the trailers.
+    fn last_chunk(&self) -> Vec<u8> {
+        if !self.chunked {
+            return Vec::new();
+        }
+        let mut chunk = b"0\r\n".to_vec();
+        for (name, value) in &self.trailers {
+            chunk.extend_from_slice(
+                format!("{}: {}\r\n", name, value).as_bytes(),
+            );
+        }
+        chunk.extend_from_slice(b"\r\n");
+        chunk
+    }
+
+    /// End the response. This is done automatically when the sender
+    /// is dropped, but calling it directly reports errors.
+    #[throws]
+    pub fn finish(mut self) {
+        self.finished = true;
+        let chunk = self.last_chunk();
+        self.stream.write_all(&chunk).map_err(write_error)?;
+    }
+}
+
+impl Drop for NdjsonSender {
+    fn drop(&mut self) {
+        if !self.finished {
+            let chunk = self.last_chunk();
+            let _ = self.stream.write_all(&chunk);
+        }
+    }
+}
+
+impl Request {
+    /// Start a streaming response with the `application/x-ndjson`
+    /// content type, for progress updates, log tailing, and the like.
+    /// The status line and headers are sent immediately, so set the
+    /// status, headers, and cookies first; anything written to the
+    /// request afterwards is ignored. Streaming responses are sent
+    /// with chunked encoding, or for HTTP/1.0 clients end when the
+    /// connection closes, and aren't compressed.
+    ///
+    /// Not supported for [`Server::test_request`].
+    ///
+    /// Example usage:
+    /// ```
+    /// use anyhow::Error;
+    /// use fehler::throws;
+    /// use shs::Request;
+    ///
+    /// #[throws]
+    /// fn progress(req: &mut Request) {
+    ///     let mut sender = req.start_ndjson()?;
+    ///     for percent in (0..=100).step_by(10) {
+    ///         sender.send(&percent)?;
+    ///     }
+    ///     sender.finish()?;
+    /// }
+    /// ```
+    ///
+    /// [`Server::test_request`]: crate::Server::test_request
+    #[throws]
+    pub fn start_ndjson(&mut self) -> NdjsonSender {
+        self.start_ndjson_with_trailers(&[])?
+    }
+
+    /// Like [`Request::start_ndjson`], but declare trailers whose
+    /// values are set with [`NdjsonSender::set_trailer`] while
+    /// streaming and sent after the last value. They're listed in the
+    /// `Trailer` response header so that clients know to expect them.
+    /// Headers the client needs up front, such as `Content-Type`,
+    /// can't be trailers. HTTP/1.0 clients don't get trailers, since
+    /// they can't receive chunked responses.
+    ///
+    /// ```
+    /// # use anyhow::Error;
+    /// # use fehler::throws;
+    /// # use shs::Request;
+    /// #[throws]
+    /// fn numbers(req: &mut Request) {
+    ///     let mut sender = req.start_ndjson_with_trailers(&["X-Count"])?;
+    ///     for n in 0..3 {
+    ///         sender.send(&n)?;
+    ///     }
+    ///     sender.set_trailer("X-Count", "3")?;
+    ///     sender.finish()?;
+    /// }
+    /// ```
+    #[throws]
+    pub fn start_ndjson_with_trailers(
+        &mut self,
+        trailers: &[&str],
+    ) -> NdjsonSender {
+        for name in trailers {
+            if !parse::is_token(name)
+                || FORBIDDEN_TRAILERS
+                    .iter()
+                    .any(|f| f.eq_ignore_ascii_case(name))
+            {
+                throw!(anyhow!("{:?} can't be a trailer", name));
+            }
+        }
+        if self.streamed {
+            throw!(anyhow!("the response was already sent"));
+        }
+        if let Some(watchdog) = &self.watchdog {
+            if !watchdog.start_streaming() {
+                throw!(anyhow!("the handler timed out"));
+            }
+        }
+        let mut stream = self
+            .connection
+            .as_ref()
+            .ok_or_else(|| anyhow!("streaming requires a real connection"))?
+            .try_clone()?;
+        // The session cookie has to go out with the headers
+        self.finish_session();
+        self.set_header("Content-Type", "application/x-ndjson");
+        let chunked = !self.is_http10();
+        if chunked && !trailers.is_empty() {
+            self.set_header("Trailer", &trailers.join(", "));
+        }
+        self.add_standard_headers();
+        let framing = if chunked {
+            Framing::Chunked
+        } else {
+            Framing::CloseDelimited
+        };
+        let head = serialize_head(self, framing);
+        stream.write_all(&head).map_err(write_error)?;
+        self.streamed = true;
+        NdjsonSender {
+            stream,
+            finished: false,
+            chunked,
+            declared: trailers.iter().map(|&name| name.into()).collect(),
+            trailers: Vec::new(),
+        }
+    }
+}
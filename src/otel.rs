@@ -0,0 +1,181 @@
+//! Optional OpenTelemetry spans and metrics. Without the
+//! `opentelemetry` feature all of these are no-ops.
+//!
+//! Only the OpenTelemetry API is used; the application installs an SDK
+//! with an exporter, such as OTLP, as the global tracer and meter
+//! providers. Attribute names follow the HTTP semantic conventions.
+
+use crate::Request;
+#[cfg(feature = "opentelemetry")]
+use opentelemetry::{
+    global,
+    metrics::Histogram,
+    propagation::Extractor,
+    trace::{SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+#[cfg(feature = "opentelemetry")]
+use std::sync::OnceLock;
+#[cfg(feature = "opentelemetry")]
+use std::time::Instant;
+
+#[cfg(feature = "opentelemetry")]
+const SCOPE: &str = "shs";
+
+/// Lets the global propagator read trace context, such as
+/// `traceparent`, from the request headers.
+#[cfg(feature = "opentelemetry")]
+struct HeaderExtractor<'a>(&'a Request);
+
+#[cfg(feature = "opentelemetry")]
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .headers()
+            .get(&crate::HeaderName::new(key.into()))
+            .map(String::as_str)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.headers().keys().map(|key| key.as_str()).collect()
+    }
+}
+
+#[cfg(feature = "opentelemetry")]
+fn duration_histogram() -> &'static Histogram<f64> {
+    static HISTOGRAM: OnceLock<Histogram<f64>> = OnceLock::new();
+    HISTOGRAM.get_or_init(|| {
+        global::meter(SCOPE)
+            .f64_histogram("http.server.request.duration")
+            .with_unit("s")
+            .with_description("Duration of HTTP server requests.")
+            .build()
+    })
+}
+
+/// Attributes known when the request arrives.
+#[cfg(feature = "opentelemetry")]
+fn request_attributes(req: &Request) -> Vec<KeyValue> {
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", req.method.clone()),
+        KeyValue::new("url.path", req.url().path().to_string()),
+        KeyValue::new("url.scheme", req.scheme().to_string()),
+        KeyValue::new("network.protocol.version", "1.1"),
+    ];
+    if let Some(query) = req.url().query() {
+        attributes.push(KeyValue::new("url.query", query.to_string()));
+    }
+    if let Some(host) = req.url().host_str() {
+        attributes.push(KeyValue::new("server.address", host.to_string()));
+    }
+    if let Some(ip) = req.client_ip() {
+        attributes.push(KeyValue::new("client.address", ip.to_string()));
+    }
+    if let Some(agent) = HeaderExtractor(req).get("User-Agent") {
+        attributes
+            .push(KeyValue::new("user_agent.original", agent.to_string()));
+    }
+    attributes
+}
+
+/// Server span for one request, continuing the client's trace if the
+/// request carries trace context.
+pub(crate) struct ServerSpan {
+    #[cfg(feature = "opentelemetry")]
+    cx: Context,
+    #[cfg(feature = "opentelemetry")]
+    start: Instant,
+}
+
+impl ServerSpan {
+    #[cfg_attr(not(feature = "opentelemetry"), allow(unused_variables))]
+    pub(crate) fn start(req: &Request) -> ServerSpan {
+        #[cfg(feature = "opentelemetry")]
+        {
+            let parent = global::get_text_map_propagator(|propagator| {
+                propagator.extract(&HeaderExtractor(req))
+            });
+            let tracer = global::tracer(SCOPE);
+            // Renamed to include the route once it's known
+            let span = tracer
+                .span_builder(req.method.clone())
+                .with_kind(SpanKind::Server)
+                .with_attributes(request_attributes(req))
+                .start_with_context(&tracer, &parent);
+            ServerSpan {
+                cx: parent.with_span(span),
+                start: Instant::now(),
+            }
+        }
+        #[cfg(not(feature = "opentelemetry"))]
+        ServerSpan {}
+    }
+
+    /// Run `f` with the span's context as the current context, so
+    /// spans created by handlers become its children.
+    pub(crate) fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "opentelemetry")]
+        let _guard = self.cx.clone().attach();
+        f()
+    }
+
+    /// End the span and record the request duration.
+    #[cfg_attr(not(feature = "opentelemetry"), allow(unused_variables))]
+    pub(crate) fn finish(self, req: &Request) {
+        #[cfg(feature = "opentelemetry")]
+        {
+            let status = i64::from(req.status_code());
+            let mut attributes = vec![
+                KeyValue::new("http.request.method", req.method.clone()),
+                KeyValue::new("http.response.status_code", status),
+                KeyValue::new("url.scheme", req.scheme().to_string()),
+            ];
+            let span = self.cx.span();
+            span.set_attribute(KeyValue::new(
+                "http.response.status_code",
+                status,
+            ));
+            if let Some(route) = &req.route_pattern {
+                span.update_name(format!("{} {}", req.method, route));
+                span.set_attribute(KeyValue::new("http.route", route.clone()));
+                attributes.push(KeyValue::new("http.route", route.clone()));
+            }
+            // Client errors aren't server span errors
+            if status >= 500 {
+                span.set_status(Status::error(""));
+            }
+            span.end();
+            duration_histogram()
+                .record(self.start.elapsed().as_secs_f64(), &attributes);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "opentelemetry"))]
+mod tests {
+    use super::*;
+    use crate::test_req;
+
+    #[test]
+    fn test_request_attributes() {
+        let mut req = test_req(
+            "GET",
+            "/a?b=c",
+            &[("User-Agent", "curl/8"), ("traceparent", "00-x")],
+        );
+        req.client_ip = Some("10.0.0.1".parse().unwrap());
+        let attributes = request_attributes(&req);
+        let get = |key: &str| {
+            attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.to_string())
+        };
+        assert_eq!(get("http.request.method").as_deref(), Some("GET"));
+        assert_eq!(get("url.path").as_deref(), Some("/a"));
+        assert_eq!(get("url.query").as_deref(), Some("b=c"));
+        assert_eq!(get("client.address").as_deref(), Some("10.0.0.1"));
+        assert_eq!(get("user_agent.original").as_deref(), Some("curl/8"));
+        assert_eq!(HeaderExtractor(&req).get("TRACEPARENT"), Some("00-x"));
+    }
+}
@@ -0,0 +1,820 @@
+//! Parsing HTTP/1.x requests.
+//!
+//! The server reads requests from the connection with the same code
+//! that [`parse_request`] runs on a buffer, so the parser can be
+//! tested and fuzzed without a socket:
+//!
+//! ```
+//! use shs::parse::{parse_request, TargetForm};
+//! use shs::StatusCode;
+//!
+//! let req = parse_request(b"GET /a?b HTTP/1.1\r\nHost: x\r\n\r\n")?;
+//! assert_eq!(req.target.form, TargetForm::Origin);
+//! assert_eq!(req.target.query.as_deref(), Some("b"));
+//!
+//! let err = parse_request(b"GET /a HTTP/2.0\r\n\r\n").unwrap_err();
+//! assert_eq!(err.status(), StatusCode::HttpVersionNotSupported);
+//! # Ok::<(), shs::parse::ParseError>(())
+//! ```
+
+use crate::chunked::ChunkedReader;
+use crate::{HeaderName, StatusCode};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read};
+
+/// Error parsing a request. The connection can't be used for a normal
+/// response, but [`ParseError::status`] tells what to send back
+/// before closing it.
+#[derive(Debug, thiserror::Error)]
+#[error("{message}")]
+pub struct ParseError {
+    pub(crate) status: StatusCode,
+    message: String,
+}
+
+impl ParseError {
+    fn new(status: StatusCode, message: impl Into<String>) -> ParseError {
+        ParseError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request(message: impl Into<String>) -> ParseError {
+        ParseError::new(StatusCode::BadRequest, message)
+    }
+
+    /// Status to respond with.
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(err: io::Error) -> ParseError {
+        let status = match err.kind() {
+            // Read timeouts show up as WouldBlock on Unix
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+                StatusCode::RequestTimeout
+            }
+            _ => StatusCode::BadRequest,
+        };
+        ParseError::new(status, format!("failed to read request: {}", err))
+    }
+}
+
+/// Options controlling how requests are parsed.
+#[derive(Clone, Debug)]
+pub(crate) struct ParseOptions {
+    /// Reject requests that don't follow the RFC 7230 grammar
+    /// instead of making a best guess at what they mean.
+    pub(crate) strict: bool,
+
+    /// Maximum length of the request line in bytes, including the
+    /// line terminator.
+    pub(crate) max_request_line: usize,
+
+    /// Maximum total size in bytes of the header lines, including the
+    /// blank line that ends them.
+    pub(crate) max_header_bytes: usize,
+
+    /// Maximum number of header lines.
+    pub(crate) max_headers: usize,
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            strict: false,
+            max_request_line: 8 * 1024,
+            max_header_bytes: 64 * 1024,
+            max_headers: 100,
+        }
+    }
+}
+
+/// Read one line into `line`, consuming at most `limit` bytes. Returns
+/// `false` if the line didn't end within the limit.
+fn read_line_limited(
+    stream: &mut impl BufRead,
+    limit: usize,
+    line: &mut String,
+) -> Result<bool, ParseError> {
+    stream.by_ref().take(limit as u64).read_line(line)?;
+    Ok(line.ends_with('\n') || line.len() < limit)
+}
+
+/// Request line and headers.
+#[derive(Debug)]
+pub(crate) struct RequestHead {
+    pub(crate) method: String,
+    pub(crate) target: String,
+    /// Protocol version, such as `HTTP/1.1`.
+    pub(crate) version: String,
+    pub(crate) headers: HashMap<HeaderName, String>,
+}
+
+/// Check if `c` is allowed in a token, such as a method or header
+/// name (RFC 7230 §3.2.6).
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+pub(crate) fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(is_tchar)
+}
+
+/// Check that a header value has no control characters other than
+/// tab. In particular a CR or LF could end the header early.
+pub(crate) fn is_valid_header_value(s: &str) -> bool {
+    !s.chars().any(|c| c.is_ascii_control() && c != '\t')
+}
+
+/// Check for an `HTTP/x.y` version.
+fn is_http_version(s: &str) -> bool {
+    match s.strip_prefix("HTTP/").map(|v| v.as_bytes()) {
+        Some([major, b'.', minor]) => {
+            major.is_ascii_digit() && minor.is_ascii_digit()
+        }
+        _ => false,
+    }
+}
+
+/// Check for HTTP/1.0. Versions aren't validated in lenient mode, so
+/// this ignores case.
+pub(crate) fn is_http10(version: &str) -> bool {
+    version.eq_ignore_ascii_case("HTTP/1.0")
+}
+
+/// Remove the line terminator. In strict mode it must be CRLF;
+/// otherwise a bare LF is accepted too.
+fn strip_line_end<'a>(
+    line: &'a str,
+    options: &ParseOptions,
+) -> Result<&'a str, ParseError> {
+    if let Some(line) = line.strip_suffix("\r\n") {
+        Ok(line)
+    } else if options.strict {
+        Err(ParseError::bad_request("line not terminated by CRLF"))
+    } else {
+        Ok(line.strip_suffix('\n').unwrap_or(line))
+    }
+}
+
+/// Parse the request line into method, target, and version.
+fn parse_request_line<'a>(
+    line: &'a str,
+    options: &ParseOptions,
+) -> Result<(&'a str, &'a str, &'a str), ParseError> {
+    let invalid =
+        || ParseError::bad_request(format!("invalid request: {}", line));
+    if options.strict {
+        let line = strip_line_end(line, options)?;
+        let parts = line.split(' ').collect::<Vec<_>>();
+        match parts[..] {
+            [method, target, version]
+                if is_token(method)
+                    && !target.is_empty()
+                    && !target.chars().any(|c| c.is_ascii_control())
+                    && is_http_version(version) =>
+            {
+                Ok((method, target, version))
+            }
+            _ => Err(invalid()),
+        }
+    } else {
+        let parts = line.split_whitespace().take(3).collect::<Vec<_>>();
+        if parts.len() != 3 {
+            return Err(invalid());
+        }
+        Ok((parts[0], parts[1], parts[2]))
+    }
+}
+
+/// Read the request line and headers, stopping after the blank line
+/// that ends the head.
+///
+/// Repeated headers are combined into one comma-separated value as
+/// described in RFC 7230 §3.2.2. Obsolete line folding is rejected
+/// rather than unfolded, since proxies disagree on how to handle it.
+pub(crate) fn read_head(
+    stream: &mut impl BufRead,
+    options: &ParseOptions,
+) -> Result<RequestHead, ParseError> {
+    // One buffer is reused for every line
+    let mut line = String::with_capacity(256);
+    if !read_line_limited(stream, options.max_request_line, &mut line)? {
+        return Err(ParseError::new(
+            StatusCode::UriTooLong,
+            "request line too long",
+        ));
+    }
+    let (method, target, version) = parse_request_line(&line, options)?;
+    // Only HTTP/1.x is supported. This also rejects the connection
+    // preface ("PRI * HTTP/2.0") sent by HTTP/2 clients with prior
+    // knowledge. An "Upgrade: h2c" header is simply ignored, which
+    // RFC 7540 §3.2 allows.
+    if is_http_version(version) && !version.starts_with("HTTP/1.") {
+        return Err(ParseError::new(
+            StatusCode::HttpVersionNotSupported,
+            format!("unsupported version: {}", version),
+        ));
+    }
+    let (method, target, version) =
+        (method.to_string(), target.to_string(), version.to_string());
+
+    let mut headers: HashMap<HeaderName, String> = HashMap::new();
+    let mut header_bytes = 0;
+    let mut header_count = 0;
+    loop {
+        let too_large = |message| {
+            ParseError::new(StatusCode::RequestHeaderFieldsTooLarge, message)
+        };
+        line.clear();
+        let remaining = options.max_header_bytes - header_bytes;
+        if !read_line_limited(stream, remaining, &mut line)? {
+            return Err(too_large("headers too large"));
+        }
+        if line.is_empty() {
+            return Err(ParseError::bad_request("incomplete request head"));
+        }
+        header_bytes += line.len();
+        // Only an empty line ends the head. One with just whitespace is
+        // rejected below, as obsolete line folding or an empty name.
+        if matches!(line.as_str(), "\n" | "\r\n") {
+            break;
+        }
+        header_count += 1;
+        if header_count > options.max_headers {
+            return Err(too_large("too many headers"));
+        }
+        if line.starts_with(' ') || line.starts_with('\t') {
+            return Err(ParseError::bad_request("obsolete line folding"));
+        }
+
+        let line = strip_line_end(&line, options)?;
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next();
+        // Whitespace before the colon would make a name such as
+        // "Transfer-Encoding " that the framing checks don't see, while
+        // a proxy may trim it and frame the request differently
+        if !is_token(name) || (options.strict && value.is_none()) {
+            return Err(ParseError::bad_request(format!(
+                "invalid header: {}",
+                line
+            )));
+        }
+        // A bare CR or NUL could be treated as a line end by a proxy,
+        // letting a client smuggle headers past it
+        if !is_valid_header_value(line) {
+            return Err(ParseError::bad_request("control character in header"));
+        }
+        let value = value.unwrap_or("").trim();
+        headers
+            .entry(name.into())
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+
+    check_framing(&headers)?;
+    Ok(RequestHead {
+        method,
+        target,
+        version,
+        headers,
+    })
+}
+
+/// Form of the request target, as described in RFC 7230 §5.3.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TargetForm {
+    /// A path with an optional query, e.g. `/search?q=shs`. This is
+    /// what clients send to origin servers.
+    Origin,
+    /// A full URL, e.g. `http://example.com/search?q=shs`. This is
+    /// what clients send to proxies.
+    Absolute,
+    /// Just a host and port, e.g. `example.com:443`. Only used with
+    /// `CONNECT`.
+    Authority,
+    /// `*`, used with `OPTIONS` to refer to the whole server.
+    Asterisk,
+}
+
+/// Request target split into its parts.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Target {
+    /// Which of the four forms the target takes.
+    pub form: TargetForm,
+    /// Host and optional port from an absolute-form or authority-form
+    /// target. These take precedence over the `Host` header.
+    pub authority: Option<String>,
+    /// Path as sent, without percent-decoding.
+    pub path: String,
+    /// Query after the `?`, if any.
+    pub query: Option<String>,
+}
+
+/// Parse the request target. The path of an authority-form or
+/// asterisk-form target is `*`, which no route can match.
+pub(crate) fn parse_target(
+    method: &str,
+    target: &str,
+) -> Result<Target, ParseError> {
+    let invalid =
+        || ParseError::bad_request(format!("invalid target: {}", target));
+    let split_query = |s: &str| match s.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (s.to_string(), None),
+    };
+    if target.starts_with('/') {
+        let (path, query) = split_query(target);
+        return Ok(Target {
+            form: TargetForm::Origin,
+            authority: None,
+            path,
+            query,
+        });
+    }
+    if target == "*" {
+        return Ok(Target {
+            form: TargetForm::Asterisk,
+            authority: None,
+            path: "*".into(),
+            query: None,
+        });
+    }
+    if method == "CONNECT" {
+        // host:port, where the port is required
+        let url = url::Url::parse(&format!("http://{}", target))
+            .map_err(|_| invalid())?;
+        let contains_port = target
+            .rsplit_once(':')
+            .is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+        if url.path() != "/" || url.query().is_some() || !contains_port {
+            return Err(invalid());
+        }
+        return Ok(Target {
+            form: TargetForm::Authority,
+            authority: Some(target.into()),
+            path: "*".into(),
+            query: None,
+        });
+    }
+    let (scheme, rest) = target.split_once("://").ok_or_else(invalid)?;
+    if !scheme.eq_ignore_ascii_case("http")
+        && !scheme.eq_ignore_ascii_case("https")
+    {
+        return Err(invalid());
+    }
+    // Keep the path exactly as sent rather than letting Url
+    // normalize it
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() || authority.contains('@') {
+        return Err(invalid());
+    }
+    let (path, query) = split_query(path);
+    Ok(Target {
+        form: TargetForm::Absolute,
+        authority: Some(authority.into()),
+        path: if path.is_empty() { "/".into() } else { path },
+        query,
+    })
+}
+
+/// Reject header combinations that different servers could
+/// interpret as different body lengths, per RFC 7230 §3.3.3.
+fn check_framing(
+    headers: &HashMap<HeaderName, String>,
+) -> Result<(), ParseError> {
+    if headers.contains_key(&HeaderName::new("Transfer-Encoding".into()))
+        && headers.contains_key(&HeaderName::new("Content-Length".into()))
+    {
+        return Err(ParseError::bad_request(
+            "both Content-Length and Transfer-Encoding",
+        ));
+    }
+    if let Some(value) =
+        headers.get(&HeaderName::new("Transfer-Encoding".into()))
+    {
+        check_transfer_encoding(value)?;
+    }
+    content_length(headers)?;
+    Ok(())
+}
+
+/// Only `chunked` is supported as a transfer coding. In a request it
+/// has to come last, and only once, or the body length can't be
+/// determined (RFC 7230 §3.3.3). Other codings can't be decoded, so
+/// they're rejected with `501 Not Implemented` rather than passed on
+/// to handlers still encoded.
+fn check_transfer_encoding(value: &str) -> Result<(), ParseError> {
+    let codings: Vec<String> = value
+        .split(',')
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty())
+        .collect();
+    if codings.last().map(String::as_str) != Some("chunked")
+        || codings.iter().filter(|c| *c == "chunked").count() > 1
+    {
+        return Err(ParseError::bad_request(format!(
+            "chunked must be the final transfer coding: {}",
+            value
+        )));
+    }
+    if codings.len() > 1 {
+        return Err(ParseError::new(
+            StatusCode::NotImplemented,
+            format!("unsupported transfer coding: {}", value),
+        ));
+    }
+    Ok(())
+}
+
+/// How the length of a request body is determined.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum BodyLength {
+    /// `Content-Length`.
+    Length(usize),
+    /// `Transfer-Encoding: chunked`. [`read_head`] rejects any other
+    /// transfer coding.
+    Chunked,
+    /// Neither header, which for most requests means there's no body.
+    Unknown,
+}
+
+impl RequestHead {
+    pub(crate) fn body_length(&self) -> Result<BodyLength, ParseError> {
+        if let Some(len) = content_length(&self.headers)? {
+            Ok(BodyLength::Length(len))
+        } else if self
+            .headers
+            .contains_key(&HeaderName::new("Transfer-Encoding".into()))
+        {
+            Ok(BodyLength::Chunked)
+        } else {
+            Ok(BodyLength::Unknown)
+        }
+    }
+}
+
+/// Get the request's `Content-Length`. A repeated header is allowed only
+/// if every value is the same.
+fn content_length(
+    headers: &HashMap<HeaderName, String>,
+) -> Result<Option<usize>, ParseError> {
+    let value = match headers.get(&HeaderName::new("Content-Length".into())) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let mut len = None;
+    for part in value.split(',') {
+        let part = part.trim();
+        let parsed = part
+            .parse::<usize>()
+            .ok()
+            // parse accepts a leading '+', which isn't valid here
+            .filter(|_| part.bytes().all(|b| b.is_ascii_digit()))
+            .ok_or_else(|| {
+                ParseError::bad_request(format!(
+                    "invalid Content-Length: {}",
+                    value
+                ))
+            })?;
+        if len.map(|len| len != parsed).unwrap_or(false) {
+            return Err(ParseError::bad_request(format!(
+                "conflicting Content-Length: {}",
+                value
+            )));
+        }
+        len = Some(parsed);
+    }
+    Ok(len)
+}
+
+/// Request parsed by [`parse_request`].
+#[derive(Debug)]
+pub struct ParsedRequest {
+    /// Method, such as `GET`.
+    pub method: String,
+    /// Parsed request target.
+    pub target: Target,
+    /// Protocol version, such as `HTTP/1.1`.
+    pub version: String,
+    /// Headers, with repeated headers combined into one
+    /// comma-separated value.
+    pub headers: HashMap<HeaderName, String>,
+    /// Body, with any chunked transfer coding removed.
+    pub body: Vec<u8>,
+}
+
+/// Parse one request from the start of `input`, with the server's
+/// default parse options. Anything after the request, such as a
+/// pipelined second request, is ignored. A body without a length is
+/// treated as empty, as it is for most requests by the server.
+pub fn parse_request(mut input: &[u8]) -> Result<ParsedRequest, ParseError> {
+    let head = read_head(&mut input, &ParseOptions::default())?;
+    let target = parse_target(&head.method, &head.target)?;
+    let body = match head.body_length()? {
+        BodyLength::Length(len) => input
+            .get(..len)
+            .ok_or_else(|| {
+                ParseError::bad_request("body shorter than Content-Length")
+            })?
+            .to_vec(),
+        BodyLength::Chunked => {
+            let mut body = Vec::new();
+            ChunkedReader::new(&mut input).read_to_end(&mut body)?;
+            body
+        }
+        BodyLength::Unknown => Vec::new(),
+    };
+    Ok(ParsedRequest {
+        method: head.method,
+        target,
+        version: head.version,
+        headers: head.headers,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<RequestHead, ParseError> {
+        read_head(&mut input.as_bytes(), &ParseOptions::default())
+    }
+
+    fn parse_strict(input: &str) -> Result<RequestHead, ParseError> {
+        let options = ParseOptions {
+            strict: true,
+            ..ParseOptions::default()
+        };
+        read_head(&mut input.as_bytes(), &options)
+    }
+
+    #[test]
+    fn test_pipelined() {
+        // Reading one request leaves the next one untouched
+        let mut input: &[u8] = b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\n\
+                                 abcGET /b HTTP/1.1\r\n\r\n";
+        let first = read_head(&mut input, &ParseOptions::default()).unwrap();
+        assert_eq!(first.target, "/a");
+        let mut body = [0; 3];
+        input.read_exact(&mut body).unwrap();
+        assert_eq!(&body, b"abc");
+        let second = read_head(&mut input, &ParseOptions::default()).unwrap();
+        assert_eq!(second.method, "GET");
+        assert_eq!(second.target, "/b");
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn test_read_head() {
+        let head = parse("GET /a HTTP/1.1\r\nHost: x\r\nA: 1\r\na: 2\r\n\r\n")
+            .unwrap();
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.target, "/a");
+        assert_eq!(head.headers[&HeaderName::new("A".into())], "1, 2");
+        assert!(parse("GET /a\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_incomplete_head() {
+        for input in &[
+            "GET / HTTP/1.1\r\n",
+            "GET / HTTP/1.1\r\nHost: x\r\n",
+            "GET / HTTP/1.1\r\nHost: x",
+        ] {
+            assert_eq!(
+                parse(input).unwrap_err().status,
+                StatusCode::BadRequest,
+                "{:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_version() {
+        assert!(parse("GET / HTTP/1.0\r\n\r\n").is_ok());
+        for input in
+            &["PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n", "GET / HTTP/3.0\r\n\r\n"]
+        {
+            assert_eq!(
+                parse(input).unwrap_err().status,
+                StatusCode::HttpVersionNotSupported
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_target() {
+        let target = |method, s| parse_target(method, s).unwrap();
+        let t = target("GET", "/a/b?x=1");
+        assert_eq!(t.form, TargetForm::Origin);
+        assert_eq!(
+            (t.path.as_str(), t.query.as_deref()),
+            ("/a/b", Some("x=1"))
+        );
+
+        let t = target("GET", "http://example.com:8080/a?x");
+        assert_eq!(t.form, TargetForm::Absolute);
+        assert_eq!(t.authority.as_deref(), Some("example.com:8080"));
+        assert_eq!((t.path.as_str(), t.query.as_deref()), ("/a", Some("x")));
+        assert_eq!(target("GET", "HTTPS://example.com").path, "/");
+        assert_eq!(target("GET", "http://example.com?x").path, "/");
+
+        let t = target("CONNECT", "example.com:443");
+        assert_eq!(t.form, TargetForm::Authority);
+        assert_eq!(t.authority.as_deref(), Some("example.com:443"));
+        assert_eq!(target("OPTIONS", "*").form, TargetForm::Asterisk);
+
+        for (method, s) in &[
+            ("GET", "example.com"),
+            ("GET", "ftp://example.com/"),
+            ("GET", "http:///a"),
+            ("GET", "http://user@example.com/"),
+            ("CONNECT", "example.com"),
+            ("CONNECT", "example.com:443/a"),
+        ] {
+            assert!(parse_target(method, s).is_err(), "{} {}", method, s);
+        }
+    }
+
+    #[test]
+    fn test_smuggling() {
+        let err = |s: &str| parse(s).unwrap_err().status;
+        assert_eq!(
+            err("POST / HTTP/1.1\r\nContent-Length: 1\r\n\
+                 Transfer-Encoding: chunked\r\n\r\n"),
+            StatusCode::BadRequest
+        );
+        assert_eq!(
+            err("POST / HTTP/1.1\r\nContent-Length: 1\r\n\
+                 Content-Length: 2\r\n\r\n"),
+            StatusCode::BadRequest
+        );
+        assert_eq!(
+            err("POST / HTTP/1.1\r\nContent-Length: +1\r\n\r\n"),
+            StatusCode::BadRequest
+        );
+        assert_eq!(
+            err("GET / HTTP/1.1\r\nA: 1\r\n  continued\r\n\r\n"),
+            StatusCode::BadRequest
+        );
+
+        for input in &[
+            "POST / HTTP/1.1\r\nTransfer-Encoding : chunked\r\n\
+             Content-Length: 3\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\
+             Content-Length : 3\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length\t: 3\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost x\r\n\r\n",
+            "GET / HTTP/1.1\r\n(Host): x\r\n\r\n",
+        ] {
+            assert_eq!(err(input), StatusCode::BadRequest, "{:?}", input);
+        }
+
+        for value in &["gzip", "chunked, gzip", "chunked, chunked", ","] {
+            let head = format!(
+                "POST / HTTP/1.1\r\nTransfer-Encoding: {}\r\n\r\n",
+                value
+            );
+            assert_eq!(err(&head), StatusCode::BadRequest, "{}", value);
+        }
+        assert_eq!(
+            err("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"),
+            StatusCode::NotImplemented
+        );
+        assert!(
+            parse("POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n")
+                .is_ok()
+        );
+
+        let head = parse(
+            "POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(content_length(&head.headers).unwrap(), Some(3));
+    }
+
+    #[test]
+    fn test_strict() {
+        assert!(parse_strict("GET / HTTP/1.1\r\nHost: x\r\n\r\n").is_ok());
+
+        // Accepted by the lenient parser, but not in strict mode
+        for input in &[
+            "GET / HTTP/1.1\nHost: x\n\n",
+            "GET  / HTTP/1.1\r\n\r\n",
+            "GET / HTTP/1\r\n\r\n",
+            "GET / FTP/1.1\r\n\r\n",
+            "G(T / HTTP/1.1\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost\r\n\r\n",
+        ] {
+            assert!(parse(input).is_ok(), "{}", input);
+            assert!(parse_strict(input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_empty_header_name() {
+        for input in &[
+            "GET / HTTP/1.1\r\n: x\r\n\r\n",
+            "GET / HTTP/1.1\r\n\r: x\r\n\r\n",
+            "GET / HTTP/1.1\r\n \r\n\r\n",
+            "GET / HTTP/1.1\r\n\t\r\n\r\n",
+            "GET / HTTP/1.1\r\n\r\r\n\r\n",
+        ] {
+            assert_eq!(
+                parse(input).unwrap_err().status,
+                StatusCode::BadRequest,
+                "{:?}",
+                input
+            );
+            assert!(parse_request(input.as_bytes()).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_control_characters() {
+        assert!(parse("GET / HTTP/1.1\r\nX: a\tb\r\n\r\n").is_ok());
+        for input in &[
+            "GET / HTTP/1.1\r\nX: a\rY: b\r\n\r\n",
+            "GET / HTTP/1.1\r\nX: a\0b\r\n\r\n",
+            "GET / HTTP/1.1\r\nX\x7f: a\r\n\r\n",
+        ] {
+            assert!(parse(input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let options = ParseOptions {
+            max_request_line: 20,
+            max_header_bytes: 30,
+            max_headers: 2,
+            ..ParseOptions::default()
+        };
+        let err = |s: &str| read_head(&mut s.as_bytes(), &options).unwrap_err();
+
+        assert!(
+            read_head(&mut "GET / HTTP/1.1\n\n".as_bytes(), &options).is_ok()
+        );
+        assert_eq!(
+            err("GET /aaaaaaaaaaaaaaaa HTTP/1.1\n\n").status,
+            StatusCode::UriTooLong
+        );
+        assert_eq!(
+            err("GET / HTTP/1.1\nA: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\n\n")
+                .status,
+            StatusCode::RequestHeaderFieldsTooLarge
+        );
+        assert_eq!(
+            err("GET / HTTP/1.1\nA: 1\nB: 2\nC: 3\n\n").status,
+            StatusCode::RequestHeaderFieldsTooLarge
+        );
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let req = parse_request(
+            b"POST http://example.com/a?b HTTP/1.1\r\n\
+              Transfer-Encoding: chunked\r\n\r\n\
+              3\r\nabc\r\n0\r\n\r\nGET /next HTTP/1.1\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.target.form, TargetForm::Absolute);
+        assert_eq!(req.target.authority.as_deref(), Some("example.com"));
+        assert_eq!(req.target.path, "/a");
+        assert_eq!(req.version, "HTTP/1.1");
+        assert_eq!(req.body, b"abc");
+
+        let req =
+            parse_request(b"PUT / HTTP/1.1\r\nContent-Length: 2\r\n\r\nabc")
+                .unwrap();
+        assert_eq!(req.body, b"ab");
+
+        for input in [
+            &b"PUT / HTTP/1.1\r\nContent-Length: 4\r\n\r\nabc"[..],
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nz\r\n",
+            b"GET example.com HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: \xff\r\n\r\n",
+            b"",
+        ]
+        .iter()
+        {
+            let err = parse_request(input).unwrap_err();
+            assert_eq!(err.status(), StatusCode::BadRequest, "{:?}", input);
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use crate::Path;
+use anyhow::{anyhow, bail, Error};
+use fehler::throws;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Characters that must be escaped in a path segment.
+pub(crate) const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// One segment of a route pattern.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Segment {
+    /// Must match the path segment exactly.
+    Static(String),
+
+    /// `:name`, matches any single path segment.
+    Param(String),
+
+    /// `*name`, matches all remaining path segments. Only allowed at
+    /// the end of a pattern.
+    Wildcard(String),
+}
+
+impl Segment {
+    /// Rank used to compare specificity; lower is more specific.
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        }
+    }
+}
+
+/// Parsed route path such as `/resource/:key` or `/static/*path`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Pattern {
+    pub(crate) segments: Vec<Segment>,
+}
+
+impl FromStr for Pattern {
+    type Err = Error;
+
+    #[throws]
+    fn from_str(s: &str) -> Pattern {
+        let parts = s.split('/').collect::<Vec<_>>();
+        let mut segments = Vec::with_capacity(parts.len());
+        for (index, part) in parts.iter().enumerate() {
+            let segment = if let Some(name) = part.strip_prefix(':') {
+                Segment::Param(name.into())
+            } else if let Some(name) = part.strip_prefix('*') {
+                if index != parts.len() - 1 {
+                    bail!("wildcard must be the last segment: {}", s);
+                }
+                Segment::Wildcard(name.into())
+            } else {
+                Segment::Static(part.to_string())
+            };
+            if let Segment::Param(name) | Segment::Wildcard(name) = &segment {
+                if name.is_empty() {
+                    bail!("parameter without a name: {}", s);
+                }
+            }
+            segments.push(segment);
+        }
+        Pattern { segments }
+    }
+}
+
+impl Pattern {
+    /// Match a request path against the pattern, returning the path
+    /// parameters if it matches.
+    pub(crate) fn matches(
+        &self,
+        path: &Path,
+    ) -> Option<HashMap<String, String>> {
+        let mut params = HashMap::new();
+        for (index, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Static(s) => {
+                    if path.parts.get(index) != Some(s) {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    params.insert(name.clone(), path.parts.get(index)?.clone());
+                }
+                Segment::Wildcard(name) => {
+                    if index >= path.parts.len() {
+                        return None;
+                    }
+                    let rest = &path.parts[index..];
+                    params.insert(name.clone(), rest.join("/"));
+                    return Some(params);
+                }
+            }
+        }
+        if path.parts.len() == self.segments.len() {
+            Some(params)
+        } else {
+            None
+        }
+    }
+
+    /// Order patterns so that more specific ones come first. Segments
+    /// are compared left to right: static segments beat parameters,
+    /// which beat wildcards.
+    pub(crate) fn cmp_specificity(&self, other: &Pattern) -> Ordering {
+        let ranks = |p: &Pattern| {
+            p.segments.iter().map(Segment::rank).collect::<Vec<_>>()
+        };
+        ranks(self).cmp(&ranks(other))
+    }
+
+    /// Build a path from the pattern by filling in the parameters. Every
+    /// parameter in the pattern must be given a value, and every value
+    /// must correspond to a parameter. Values are percent-encoded; for
+    /// wildcards, slashes are kept as segment separators.
+    #[throws]
+    pub(crate) fn format(&self, params: &[(&str, &str)]) -> String {
+        if let Some((name, _)) =
+            params.iter().find(|(name, _)| !self.has_param(name))
+        {
+            bail!("unknown parameter {}", name);
+        }
+        let lookup = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| *value)
+                .ok_or_else(|| anyhow!("missing parameter {}", name))
+        };
+        let encode = |s: &str| utf8_percent_encode(s, SEGMENT).to_string();
+        let mut parts = Vec::with_capacity(self.segments.len());
+        for segment in &self.segments {
+            match segment {
+                Segment::Static(s) => parts.push(s.clone()),
+                Segment::Param(name) => parts.push(encode(lookup(name)?)),
+                Segment::Wildcard(name) => {
+                    parts.extend(lookup(name)?.split('/').map(encode))
+                }
+            }
+        }
+        parts.join("/")
+    }
+
+    fn has_param(&self, name: &str) -> bool {
+        self.segments.iter().any(|segment| match segment {
+            Segment::Param(n) | Segment::Wildcard(n) => n == name,
+            Segment::Static(_) => false,
+        })
+    }
+
+    /// Check if the two patterns match exactly the same set of
+    /// paths, differing at most in parameter names.
+    pub(crate) fn is_ambiguous_with(&self, other: &Pattern) -> bool {
+        self.segments.len() == other.segments.len()
+            && self
+                .segments
+                .iter()
+                .zip(other.segments.iter())
+                .all(|(a, b)| match (a, b) {
+                    (Segment::Static(a), Segment::Static(b)) => a == b,
+                    (a, b) => a.rank() == b.rank(),
+                })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+        let pattern: Pattern = pattern.parse().unwrap();
+        let mut params = pattern
+            .matches(&path.parse().unwrap())?
+            .into_iter()
+            .collect::<Vec<_>>();
+        params.sort();
+        Some(params)
+    }
+
+    fn pair(k: &str, v: &str) -> (String, String) {
+        (k.into(), v.into())
+    }
+
+    #[test]
+    fn test_matches() {
+        assert_eq!(params("/a/b", "/a/b"), Some(vec![]));
+        assert_eq!(params("/a/b", "/a"), None);
+        assert_eq!(params("/a/:x", "/a/b"), Some(vec![pair("x", "b")]));
+        assert_eq!(params("/a/:x", "/a"), None);
+        assert_eq!(params("/a/:x", "/a/b/c"), None);
+        assert_eq!(params("/a/*x", "/a/b/c"), Some(vec![pair("x", "b/c")]));
+        assert_eq!(params("/a/*x", "/a"), None);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!("/a/:".parse::<Pattern>().is_err());
+        assert!("/a/*x/b".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        let p = |s: &str| s.parse::<Pattern>().unwrap();
+        assert_eq!(p("/a/:x").format(&[("x", "b c")]).unwrap(), "/a/b%20c");
+        assert_eq!(p("/a/*x").format(&[("x", "b/c")]).unwrap(), "/a/b/c");
+        assert!(p("/a/:x").format(&[]).is_err());
+        assert!(p("/a/:x").format(&[("x", "b"), ("y", "c")]).is_err());
+    }
+
+    #[test]
+    fn test_specificity() {
+        let p = |s: &str| s.parse::<Pattern>().unwrap();
+        assert_eq!(p("/u/me").cmp_specificity(&p("/u/:id")), Ordering::Less);
+        assert_eq!(p("/u/:id").cmp_specificity(&p("/u/*x")), Ordering::Less);
+        assert!(p("/u/:id").is_ambiguous_with(&p("/u/:name")));
+        assert!(!p("/u/:id").is_ambiguous_with(&p("/u/me")));
+        assert!(!p("/u/a").is_ambiguous_with(&p("/u/b")));
+    }
+}
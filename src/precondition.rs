@@ -0,0 +1,203 @@
+//! `If-Match` and `If-Unmodified-Since`, for handlers that only apply
+//! a change when the client saw the current version of a resource.
+
+use crate::cache_control::to_secs;
+use crate::{HeaderName, Request, StatusCode};
+use std::time::SystemTime;
+
+/// Parsed `If-Match` request header, returned by [`Request::if_match`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IfMatch {
+    /// `*`: the resource must exist, whatever its version.
+    Any,
+    /// Entity tags as sent, including the quotes and any `W/` prefix,
+    /// for example `"\"v2\""`.
+    Tags(Vec<String>),
+}
+
+impl IfMatch {
+    /// Check whether `etag`, the current entity tag of the resource or
+    /// `None` if it doesn't exist, satisfies the condition. Tags are
+    /// compared strongly, as `If-Match` requires (RFC 9110 §13.1.1),
+    /// so weak tags never match.
+    pub fn matches(&self, etag: Option<&str>) -> bool {
+        match (self, etag) {
+            (_, None) => false,
+            (IfMatch::Any, Some(_)) => true,
+            (IfMatch::Tags(tags), Some(etag)) => {
+                !etag.starts_with("W/") && tags.iter().any(|tag| tag == etag)
+            }
+        }
+    }
+}
+
+/// Parse a comma-separated list of entity tags. Quoted tags may
+/// themselves contain commas, so this can't just split the list.
+fn parse_tags(value: &str) -> Option<Vec<String>> {
+    let mut tags = Vec::new();
+    let mut rest = value;
+    loop {
+        rest = rest.trim_start_matches([',', ' ', '\t']);
+        if rest.is_empty() {
+            return Some(tags);
+        }
+        let weak = rest.starts_with("W/");
+        let quoted = if weak { &rest[2..] } else { rest };
+        let end = quoted.strip_prefix('"')?.find('"')? + 2;
+        let len = end + if weak { 2 } else { 0 };
+        tags.push(rest[..len].to_string());
+        rest = &rest[len..];
+    }
+}
+
+impl Request {
+    /// Get the `If-Match` request header, if present and valid.
+    pub fn if_match(&self) -> Option<IfMatch> {
+        let value = self
+            .req_headers
+            .get(&HeaderName::new("If-Match".into()))?
+            .trim();
+        if value == "*" {
+            Some(IfMatch::Any)
+        } else {
+            parse_tags(value).map(IfMatch::Tags)
+        }
+    }
+
+    /// Get the `If-Unmodified-Since` request header, if present and
+    /// valid.
+    pub fn if_unmodified_since(&self) -> Option<SystemTime> {
+        self.req_headers
+            .get(&HeaderName::new("If-Unmodified-Since".into()))
+            .and_then(|value| httpdate::parse_http_date(value).ok())
+    }
+
+    /// Set the status to 412 (precondition failed), for a request
+    /// whose conditions don't hold for the current resource.
+    pub fn precondition_failed(&mut self) {
+        self.write_status_body(StatusCode::PreconditionFailed);
+    }
+
+    /// Evaluate `If-Match`, or `If-Unmodified-Since` if there's no
+    /// `If-Match` (RFC 9110 §13.2.2), against the current state of the
+    /// resource: its entity tag and modification time, or `None` for
+    /// each if it doesn't exist or doesn't have one.
+    ///
+    /// If the conditions don't hold, the status is set to 412 with
+    /// [`Request::precondition_failed`] and `true` is returned; the
+    /// handler should then leave the resource unchanged. Requests
+    /// without either header always pass.
+    ///
+    /// ```
+    /// # use anyhow::Error;
+    /// # use fehler::throws;
+    /// # use shs::Request;
+    /// # struct Doc { version: u64, text: String }
+    /// # fn load() -> Doc { Doc { version: 1, text: String::new() } }
+    /// # fn save(doc: &Doc) {}
+    /// #[throws]
+    /// fn update(req: &mut Request) {
+    ///     let mut doc = load();
+    ///     let etag = format!("\"{}\"", doc.version);
+    ///     if req.check_preconditions(Some(&etag), None) {
+    ///         return;
+    ///     }
+    ///     doc.text = req.body_text()?.into();
+    ///     doc.version += 1;
+    ///     save(&doc);
+    ///     req.set_header("ETag", &format!("\"{}\"", doc.version));
+    /// }
+    /// ```
+    pub fn check_preconditions(
+        &mut self,
+        etag: Option<&str>,
+        last_modified: Option<SystemTime>,
+    ) -> bool {
+        let holds = match self.if_match() {
+            Some(if_match) => if_match.matches(etag),
+            None => match (self.if_unmodified_since(), last_modified) {
+                (Some(since), Some(modified)) => {
+                    to_secs(modified) <= to_secs(since)
+                }
+                // Without a modification time the condition can't be
+                // evaluated, so it's ignored
+                _ => true,
+            },
+        };
+        if !holds {
+            self.precondition_failed();
+        }
+        !holds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Server, TestRequest};
+    use anyhow::Error;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_tags() {
+        assert_eq!(
+            parse_tags(r#""a", W/"b" ,"c,d""#).unwrap(),
+            [r#""a""#, r#"W/"b""#, r#""c,d""#]
+        );
+        assert_eq!(parse_tags("").unwrap(), Vec::<String>::new());
+        assert!(parse_tags("a").is_none());
+        assert!(parse_tags(r#""unterminated"#).is_none());
+    }
+
+    #[test]
+    fn test_if_match() {
+        let tags = IfMatch::Tags(vec![r#""a""#.into(), r#"W/"b""#.into()]);
+        assert!(tags.matches(Some(r#""a""#)));
+        assert!(!tags.matches(Some(r#"W/"b""#)));
+        assert!(!tags.matches(Some(r#""c""#)));
+        assert!(!tags.matches(None));
+        assert!(IfMatch::Any.matches(Some(r#""c""#)));
+        assert!(!IfMatch::Any.matches(None));
+    }
+
+    #[test]
+    fn test_check_preconditions() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let handler = move |req: &mut Request| -> Result<(), Error> {
+            if !req.check_preconditions(Some(r#""v2""#), Some(modified)) {
+                req.write_text("updated");
+            }
+            Ok(())
+        };
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        server.routes.add("PUT /doc", Box::new(handler)).unwrap();
+        let put = |name: Option<(&str, &str)>| {
+            let mut req = TestRequest::new("PUT /doc").unwrap();
+            if let Some((name, value)) = name {
+                req.set_header(name, value);
+            }
+            server.test_request(&req).unwrap()
+        };
+
+        assert_eq!(put(None).status, StatusCode::Ok);
+        let resp = put(Some(("If-Match", r#""v1", "v2""#)));
+        assert_eq!(resp.status, StatusCode::Ok);
+        assert_eq!(resp.body, b"updated");
+        let resp = put(Some(("If-Match", r#""v1""#)));
+        assert_eq!(resp.status, StatusCode::PreconditionFailed);
+        assert_eq!(resp.body, b"precondition failed");
+        assert_eq!(put(Some(("If-Match", "*"))).status, StatusCode::Ok);
+
+        let date = |secs| {
+            httpdate::fmt_http_date(
+                SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            )
+        };
+        let since = date(1000);
+        let resp = put(Some(("If-Unmodified-Since", &since)));
+        assert_eq!(resp.status, StatusCode::Ok);
+        let since = date(999);
+        let resp = put(Some(("If-Unmodified-Since", &since)));
+        assert_eq!(resp.status, StatusCode::PreconditionFailed);
+    }
+}
@@ -0,0 +1,131 @@
+//! Error responses in the RFC 7807 `application/problem+json` format.
+
+use crate::{Rejection, Request, RequestError, StatusCode};
+use log::error;
+use serde::Serialize;
+use std::fmt::{Debug, Display};
+
+/// Problem details for an HTTP API error response, as described in
+/// RFC 7807. Write one with [`Request::write_problem`].
+///
+/// Example usage:
+/// ```
+/// use shs::{Problem, StatusCode};
+///
+/// let problem = Problem::new(StatusCode::Conflict)
+///     .detail("the item was changed by someone else");
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Problem {
+    /// URI identifying the problem type. Defaults to `about:blank`,
+    /// meaning the problem is described by the status code alone.
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// Short summary of the problem type.
+    pub title: String,
+    /// HTTP status code.
+    pub status: u16,
+    /// Explanation specific to this occurrence of the problem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// URI reference identifying this occurrence of the problem.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+}
+
+impl Problem {
+    /// Create a problem for `status`, titled with the status's
+    /// canonical reason.
+    pub fn new(status: StatusCode) -> Problem {
+        Problem {
+            problem_type: "about:blank".into(),
+            title: status.canonical_reason().into(),
+            status: status.into(),
+            detail: None,
+            instance: None,
+        }
+    }
+
+    /// Set the problem type URI. Set the title too, since the default
+    /// title only makes sense for `about:blank`.
+    pub fn problem_type(mut self, uri: &str) -> Problem {
+        self.problem_type = uri.into();
+        self
+    }
+
+    /// Set the title.
+    pub fn title(mut self, title: &str) -> Problem {
+        self.title = title.into();
+        self
+    }
+
+    /// Set the detail.
+    pub fn detail(mut self, detail: &str) -> Problem {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// Set the instance.
+    pub fn instance(mut self, instance: &str) -> Problem {
+        self.instance = Some(instance.into());
+        self
+    }
+}
+
+impl Request {
+    /// Write `problem` as the response, setting the status to match
+    /// and the `Content-Type` to `application/problem+json`.
+    pub fn write_problem(&mut self, problem: &Problem) {
+        if let Ok(status) = std::convert::TryFrom::try_from(problem.status) {
+            self.set_status(status);
+        }
+        // Serializing strings and numbers can't fail
+        let _ = self.write_json(problem);
+        self.set_content_type("application/problem+json");
+    }
+}
+
+/// Error handler set by [`Server::set_problem_error_handler`]. Errors
+/// are logged like the default error handler does.
+///
+/// [`Server::set_problem_error_handler`]: crate::Server::set_problem_error_handler
+pub(crate) fn problem_error_handler<E: Debug + Display + 'static>(
+    req: &mut Request,
+    err: &RequestError<E>,
+    include_details: bool,
+) {
+    // A rejection is the client's fault, so its message is always
+    // included
+    if let RequestError::Custom(err) = err {
+        if let Some(rejection) = Rejection::find(err) {
+            let problem = Problem::new(rejection.status)
+                .instance(req.url().path())
+                .detail(&rejection.message);
+            return req.write_problem(&problem);
+        }
+    }
+    let (status, detail) = match err {
+        RequestError::NotFound => {
+            error!("not found: {}", req.url().path());
+            (StatusCode::NotFound, None)
+        }
+        RequestError::Custom(err) => {
+            error!(
+                "error handling {} {}: {}",
+                req.method(),
+                req.url().path(),
+                err
+            );
+            (StatusCode::InternalServerError, Some(err.to_string()))
+        }
+        // The panic was already logged by dispatch_request
+        RequestError::Panic(_) => {
+            (StatusCode::InternalServerError, Some(err.to_string()))
+        }
+    };
+    let mut problem = Problem::new(status).instance(req.url().path());
+    if include_details {
+        problem.detail = detail;
+    }
+    req.write_problem(&problem);
+}
@@ -0,0 +1,206 @@
+//! `Range` requests for files sent with [`Request::write_file`].
+//!
+//! [`Request::write_file`]: crate::Request::write_file
+
+use crate::body::ResponseBody;
+use crate::{HeaderName, Request, StatusCode};
+use anyhow::{anyhow, Error};
+use fehler::throws;
+use std::fmt::Write as _;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Requests with more ranges than this get the whole file, to limit
+/// the cost of odd requests.
+const MAX_RANGES: usize = 16;
+
+/// Multiple ranges are assembled in memory. If they add up to more
+/// than this, the whole file is sent instead.
+const MAX_MULTIPART_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Result of parsing a `Range` header.
+#[derive(Debug, Eq, PartialEq)]
+enum Ranges {
+    /// Inclusive `(start, end)` byte offsets, sorted and with
+    /// overlapping or adjacent ranges merged.
+    Satisfiable(Vec<(u64, u64)>),
+    /// None of the ranges overlap the body.
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header for a body of `len` bytes. Returns `None`
+/// if the header is malformed or has too many ranges, in which case
+/// it should be ignored.
+fn parse(header: &str, len: u64) -> Option<Ranges> {
+    let (unit, specs) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let mut ranges = Vec::new();
+    for (index, spec) in specs.split(',').enumerate() {
+        if index == MAX_RANGES {
+            return None;
+        }
+        let (start, end) = spec.trim().split_once('-')?;
+        let range = if start.is_empty() {
+            // Suffix range: the last `end` bytes
+            let suffix: u64 = end.parse().ok()?;
+            if suffix == 0 || len == 0 {
+                continue;
+            }
+            (len.saturating_sub(suffix), len - 1)
+        } else {
+            let start: u64 = start.parse().ok()?;
+            let end = if end.is_empty() {
+                u64::MAX
+            } else {
+                end.parse().ok()?
+            };
+            if end < start {
+                return None;
+            }
+            if start >= len {
+                continue;
+            }
+            (start, end.min(len - 1))
+        };
+        ranges.push(range);
+    }
+    if ranges.is_empty() {
+        return Some(Ranges::Unsatisfiable);
+    }
+
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 + 1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    Some(Ranges::Satisfiable(merged))
+}
+
+/// Generate a multipart boundary.
+#[throws]
+fn new_boundary() -> String {
+    let mut bytes = [0; 12];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|err| anyhow!("failed to generate boundary: {}", err))?;
+    let mut boundary = String::with_capacity(24);
+    for b in bytes {
+        let _ = write!(boundary, "{:02x}", b);
+    }
+    boundary
+}
+
+impl Request {
+    /// Answer the request's `Range` header, if any, by cutting down a
+    /// file response body. A single range is sent as is with
+    /// `Content-Range`; multiple ranges as `multipart/byteranges`.
+    /// `If-Range` is compared with the `Last-Modified` header.
+    #[throws]
+    pub(crate) fn apply_range(&mut self) {
+        if self.method != "GET" || self.status != StatusCode::Ok {
+            return;
+        }
+        if !matches!(self.resp_body, ResponseBody::File { .. }) {
+            return;
+        }
+        self.set_header("Accept-Ranges", "bytes");
+        let header =
+            |name: &str| self.req_headers.get(&HeaderName::new(name.into()));
+        let range = match header("Range") {
+            Some(range) => range,
+            None => return,
+        };
+        if let Some(if_range) = header("If-Range") {
+            if Some(if_range.as_str()) != self.resp_header("Last-Modified") {
+                return;
+            }
+        }
+        let len = self.resp_body.len();
+        let ranges = match parse(range, len) {
+            Some(Ranges::Satisfiable(ranges)) => ranges,
+            Some(Ranges::Unsatisfiable) => {
+                self.set_status(StatusCode::RequestedRangeNotSatisfiable);
+                self.set_header("Content-Range", &format!("bytes */{}", len));
+                self.resp_body = ResponseBody::default();
+                return;
+            }
+            None => return,
+        };
+
+        let mut file = match &self.resp_body {
+            ResponseBody::File { file, .. } => file,
+            ResponseBody::Bytes(_) => return,
+        };
+        if let [(start, end)] = ranges[..] {
+            file.seek(SeekFrom::Start(start))?;
+            let content_range = format!("bytes {}-{}/{}", start, end, len);
+            self.set_header("Content-Range", &content_range);
+            if let ResponseBody::File { len, .. } = &mut self.resp_body {
+                *len = end - start + 1;
+            }
+        } else {
+            let total: u64 = ranges.iter().map(|(s, e)| e - s + 1).sum();
+            if total > MAX_MULTIPART_SIZE {
+                return;
+            }
+            let content_type = self
+                .resp_header("Content-Type")
+                .unwrap_or(crate::mime::OCTET_STREAM)
+                .to_string();
+            let boundary = new_boundary()?;
+            let mut body =
+                Vec::with_capacity(total as usize + 100 * ranges.len());
+            for (start, end) in ranges {
+                write!(
+                    body,
+                    "\r\n--{}\r\nContent-Type: {}\r\n\
+                     Content-Range: bytes {}-{}/{}\r\n\r\n",
+                    boundary, content_type, start, end, len
+                )?;
+                file.seek(SeekFrom::Start(start))?;
+                (&mut file).take(end - start + 1).read_to_end(&mut body)?;
+            }
+            write!(body, "\r\n--{}--\r\n", boundary)?;
+            self.resp_body = ResponseBody::Bytes(body);
+            self.set_content_type(&format!(
+                "multipart/byteranges; boundary={}",
+                boundary
+            ));
+        }
+        self.set_status(StatusCode::PartialContent);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let ok =
+            |ranges: &[(u64, u64)]| Some(Ranges::Satisfiable(ranges.to_vec()));
+        assert_eq!(parse("bytes=0-4", 10), ok(&[(0, 4)]));
+        assert_eq!(parse("bytes=5-", 10), ok(&[(5, 9)]));
+        assert_eq!(parse("bytes=-3", 10), ok(&[(7, 9)]));
+        assert_eq!(parse("bytes=-30", 10), ok(&[(0, 9)]));
+        assert_eq!(parse("bytes=8-20", 10), ok(&[(8, 9)]));
+        assert_eq!(parse("bytes=6-7, 0-1", 10), ok(&[(0, 1), (6, 7)]));
+        // Overlapping and adjacent ranges are merged
+        assert_eq!(parse("bytes=0-3,2-5,6-6", 10), ok(&[(0, 6)]));
+        // Unsatisfiable ranges are dropped
+        assert_eq!(parse("bytes=0-1,20-30", 10), ok(&[(0, 1)]));
+        assert_eq!(parse("bytes=20-30", 10), Some(Ranges::Unsatisfiable));
+        assert_eq!(parse("bytes=-0", 10), Some(Ranges::Unsatisfiable));
+        assert_eq!(parse("bytes=0-", 0), Some(Ranges::Unsatisfiable));
+
+        assert_eq!(parse("items=0-1", 10), None);
+        assert_eq!(parse("bytes=5-1", 10), None);
+        assert_eq!(parse("bytes=a-b", 10), None);
+        assert_eq!(parse("bytes=0", 10), None);
+        let many = vec!["0-0"; MAX_RANGES + 1].join(",");
+        assert_eq!(parse(&format!("bytes={}", many), 10), None);
+    }
+}
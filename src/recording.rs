@@ -0,0 +1,266 @@
+//! Recording incoming requests with [`Server::record_requests`] and
+//! replaying them with [`Server::replay`], for reproducing bugs and
+//! generating load in tests.
+
+use crate::{Request, RequestError, Server, TestRequest, TestResponse};
+use anyhow::{anyhow, Context, Error};
+use fehler::throws;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use url::Url;
+
+/// One line of a recording.
+#[derive(Debug, Deserialize, Serialize)]
+struct Record {
+    method: String,
+    url: String,
+    headers: BTreeMap<String, String>,
+    /// The body, if it's UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    /// The body in hex, if it isn't UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_hex: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    peer_addr: Option<SocketAddr>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[throws]
+fn from_hex(hex: &str) -> Vec<u8> {
+    if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+        fehler::throw!(anyhow!("invalid hex body"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<_, _>>()?
+}
+
+/// Appends requests to a recording file. Each request is a single
+/// write, so lines from different connections don't interleave.
+#[derive(Clone)]
+pub(crate) struct Recorder {
+    file: Arc<Mutex<File>>,
+}
+
+impl Recorder {
+    #[throws]
+    fn open(path: &Path) -> Recorder {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| {
+                format!("failed to open recording {}", path.display())
+            })?;
+        Recorder {
+            file: Arc::new(Mutex::new(file)),
+        }
+    }
+
+    /// Add `req` to the recording. This is done before the handler
+    /// runs, since it may take the body.
+    pub(crate) fn record(&self, req: &Request) {
+        if let Err(err) = self.try_record(req) {
+            log::error!("failed to record request: {}", err);
+        }
+    }
+
+    #[throws]
+    fn try_record(&self, req: &Request) {
+        let mut body = Vec::new();
+        req.body_reader()?.read_to_end(&mut body)?;
+        let (body, body_hex) = match String::from_utf8(body) {
+            Ok(body) if body.is_empty() => (None, None),
+            Ok(body) => (Some(body), None),
+            Err(err) => (None, Some(to_hex(err.as_bytes()))),
+        };
+        let record = Record {
+            method: req.method.clone(),
+            url: req.url.to_string(),
+            headers: req
+                .req_headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+            body,
+            body_hex,
+            peer_addr: req.peer_addr,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.file
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .write_all(&line)?;
+    }
+}
+
+impl TestRequest {
+    /// Read the requests recorded with [`Server::record_requests`] from
+    /// `path`, for example to send them with [`Server::test_request`]
+    /// or replay them against a running server. Blank lines are
+    /// skipped.
+    #[throws]
+    pub fn read_recording(path: impl AsRef<Path>) -> Vec<TestRequest> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| {
+            format!("failed to open recording {}", path.display())
+        })?;
+        let mut requests = Vec::new();
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let request = parse_record(&line).with_context(|| {
+                format!(
+                    "invalid record on line {} of {}",
+                    index + 1,
+                    path.display()
+                )
+            })?;
+            requests.push(request);
+        }
+        requests
+    }
+}
+
+#[throws]
+fn parse_record(line: &str) -> TestRequest {
+    let record: Record = serde_json::from_str(line)?;
+    let body = match (record.body, record.body_hex) {
+        (Some(body), _) => body.into_bytes(),
+        (None, Some(hex)) => from_hex(&hex)?,
+        (None, None) => Vec::new(),
+    };
+    TestRequest {
+        body,
+        method: record.method,
+        url: Url::parse(&record.url)?,
+        headers: record.headers.into_iter().collect(),
+        peer_addr: record.peer_addr,
+    }
+}
+
+impl<E: Debug + Display + 'static> Server<E> {
+    /// Append every request the server receives to the file at `path`,
+    /// one JSON object per line with the method, URL, headers, body,
+    /// and peer address. Bodies are recorded as received, before any
+    /// `Content-Encoding` is decoded, and are held in memory while
+    /// they're written, so this is meant for debugging rather than
+    /// for servers taking large uploads.
+    ///
+    /// The recording has everything the client sent, including
+    /// credentials and cookies, so keep it private. Replay it with
+    /// [`Server::replay`].
+    #[throws]
+    pub fn record_requests(&mut self, path: impl AsRef<Path>) {
+        self.settings.recorder = Some(Recorder::open(path.as_ref())?);
+    }
+
+    /// Dispatch each request recorded at `path` through the routes, as
+    /// with [`Server::test_request`], and return the results in order.
+    /// Fails only if the recording can't be read.
+    ///
+    /// ```no_run
+    /// # use anyhow::Error;
+    /// # use shs::Server;
+    /// let server = Server::<Error>::new("127.0.0.1:1234")?;
+    /// for result in server.replay("requests.ndjson")? {
+    ///     println!("{:?}", result.map(|resp| resp.status));
+    /// }
+    /// # Ok::<(), Error>(())
+    /// ```
+    #[throws]
+    pub fn replay(
+        &self,
+        path: impl AsRef<Path>,
+    ) -> Vec<Result<TestResponse, RequestError<E>>> {
+        TestRequest::read_recording(path)?
+            .iter()
+            .map(|req| self.test_request(req))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StatusCode;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0, 0xab, 0xff]), "00abff");
+        assert_eq!(from_hex("00abff").unwrap(), [0, 0xab, 0xff]);
+        assert!(from_hex("0").is_err());
+        assert!(from_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_read_recording() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            r#"{{"method":"POST","url":"http://example.com/a","headers":{{"X-A":"1"}},"body_hex":"00ff","peer_addr":"10.0.0.1:5"}}"#
+        )
+        .unwrap();
+        writeln!(file).unwrap();
+        writeln!(
+            file,
+            r#"{{"method":"GET","url":"http://example.com/b?x=1","headers":{{}}}}"#
+        )
+        .unwrap();
+        let requests = TestRequest::read_recording(file.path()).unwrap();
+        let mut expected =
+            TestRequest::new_with_body("POST /a", &[0, 0xff]).unwrap();
+        expected.set_header("X-A", "1");
+        expected.set_peer_addr("10.0.0.1:5".parse().unwrap());
+        assert_eq!(
+            requests,
+            [expected, TestRequest::new("GET /b?x=1").unwrap()]
+        );
+
+        writeln!(file, "not json").unwrap();
+        let err = TestRequest::read_recording(file.path()).unwrap_err();
+        assert!(err.to_string().contains("line 4"), "{}", err);
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            file,
+            r#"{{"method":"POST","url":"http://example.com/echo","headers":{{}},"body":"hi"}}"#
+        )
+        .unwrap();
+        writeln!(
+            file,
+            r#"{{"method":"GET","url":"http://example.com/missing","headers":{{}}}}"#
+        )
+        .unwrap();
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        let echo = |req: &mut Request| -> Result<(), Error> {
+            let body = req.body().to_vec();
+            req.write_bytes(&body);
+            Ok(())
+        };
+        server.routes.add("POST /echo", Box::new(echo)).unwrap();
+        let results = server.replay(file.path()).unwrap();
+        assert_eq!(results.len(), 2);
+        let resp = results[0].as_ref().unwrap();
+        assert_eq!(resp.status, StatusCode::Ok);
+        assert_eq!(resp.body, b"hi");
+        assert!(matches!(results[1], Err(RequestError::NotFound)));
+    }
+}
@@ -0,0 +1,233 @@
+use crate::body::ResponseBody;
+use crate::headers::ResponseHeaders;
+#[cfg(feature = "digest-auth")]
+use crate::DigestUser;
+use crate::{ApiKeyIdentity, Claims, HeaderName, Next, Request, StatusCode};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct Key {
+    method: String,
+    /// Host and port, already normalized by [`url::Url`], so that
+    /// virtual hosts sharing a route don't share responses.
+    host: Option<String>,
+    port: Option<u16>,
+    path: String,
+    query: Option<String>,
+    /// Values of the headers listed with [`ResponseCache::vary`].
+    headers: Vec<Option<String>>,
+}
+
+struct Entry {
+    expires: Instant,
+    status: StatusCode,
+    headers: ResponseHeaders,
+    body: Vec<u8>,
+}
+
+type Entries = Arc<Mutex<HashMap<Key, Entry>>>;
+
+/// In-memory cache of responses for a route, added with
+/// [`RouteHandle::cache`]. A cached response is served without
+/// running the route's handler; middleware still runs.
+///
+/// Only successful `GET` and `HEAD` responses are cached, and not
+/// ones that set cookies, use the session, are sent from a file, or
+/// have `Cache-Control: no-store` or `private`. Requests with an
+/// `Authorization` header or accepted by the built-in authentication
+/// middleware bypass the cache entirely. Responses are keyed by
+/// method, host, path, and query; if a response depends on a request
+/// header, list it with [`ResponseCache::vary`].
+///
+/// Example usage:
+/// ```
+/// use shs::ResponseCache;
+/// use std::time::Duration;
+///
+/// let cache = ResponseCache::new(Duration::from_secs(60))
+///     .max_entries(100)
+///     .vary("Accept-Language");
+/// let handle = cache.handle();
+/// // Later, when the underlying data changes:
+/// handle.clear();
+/// ```
+///
+/// [`RouteHandle::cache`]: crate::RouteHandle::cache
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    vary: Vec<String>,
+    entries: Entries,
+}
+
+impl ResponseCache {
+    /// Create a cache that keeps responses for `ttl`. By default it
+    /// holds at most 1000 responses.
+    pub fn new(ttl: Duration) -> ResponseCache {
+        ResponseCache {
+            ttl,
+            max_entries: 1000,
+            vary: Vec::new(),
+            entries: Arc::default(),
+        }
+    }
+
+    /// Set the maximum number of cached responses. When the cache is
+    /// full, the response closest to expiring is dropped.
+    pub fn max_entries(mut self, max_entries: usize) -> ResponseCache {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Cache responses separately for each value of the request header
+    /// `name`.
+    pub fn vary(mut self, name: &str) -> ResponseCache {
+        self.vary.push(name.into());
+        self
+    }
+
+    /// Get a handle for invalidating cached responses.
+    pub fn handle(&self) -> CacheHandle {
+        CacheHandle {
+            entries: self.entries.clone(),
+        }
+    }
+
+    fn key(&self, req: &Request) -> Key {
+        Key {
+            method: req.method.clone(),
+            host: req.url().host_str().map(Into::into),
+            port: req.url().port(),
+            path: req.url().path().into(),
+            query: req.url().query().map(Into::into),
+            headers: self
+                .vary
+                .iter()
+                .map(|name| {
+                    req.headers().get(&HeaderName::new(name.clone())).cloned()
+                })
+                .collect(),
+        }
+    }
+
+    /// Serve the request from the cache, or run the rest of the chain
+    /// and cache its response if possible.
+    pub(crate) fn run<E>(
+        &self,
+        req: &mut Request,
+        next: Next<'_, E>,
+    ) -> Result<(), E> {
+        if (req.method != "GET" && req.method != "HEAD")
+            || req.route_options.no_cache
+            || is_authenticated(req)
+        {
+            return next.run(req);
+        }
+        let key = self.key(req);
+        {
+            let entries = self.entries.lock().unwrap();
+            if let Some(entry) =
+                entries.get(&key).filter(|e| e.expires > Instant::now())
+            {
+                req.status = entry.status;
+                req.resp_headers.extend(&entry.headers);
+                req.resp_body = ResponseBody::Bytes(entry.body.clone());
+                return Ok(());
+            }
+        }
+
+        next.run(req)?;
+        if let Some(entry) = self.entry(req) {
+            let mut entries = self.entries.lock().unwrap();
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= self.max_entries {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+            if self.max_entries > 0 {
+                entries.insert(key, entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the response for caching, if it may be cached.
+    fn entry(&self, req: &Request) -> Option<Entry> {
+        // The session cookie is only added after the route returns, so
+        // check for the session itself. A response that reads it is
+        // specific to one client even if it doesn't change it.
+        if req.status != StatusCode::Ok
+            || req.streamed
+            || !req.resp_cookies.is_empty()
+            || req.resp_header("Set-Cookie").is_some()
+            || req.has_session()
+        {
+            return None;
+        }
+        let no_store = req.resp_header("Cache-Control").is_some_and(|value| {
+            value.split(',').any(|directive| {
+                let directive = directive.trim();
+                directive.eq_ignore_ascii_case("no-store")
+                    || directive.eq_ignore_ascii_case("private")
+            })
+        });
+        if no_store {
+            return None;
+        }
+        match &req.resp_body {
+            ResponseBody::Bytes(body) => Some(Entry {
+                expires: Instant::now() + self.ttl,
+                status: req.status,
+                headers: req.resp_headers.clone(),
+                body: body.clone(),
+            }),
+            ResponseBody::File { .. } => None,
+        }
+    }
+}
+
+/// Check for credentials, so that responses for one user are never
+/// stored or served to another. The auth middleware runs before the
+/// cache, so its identities are already set here.
+fn is_authenticated(req: &Request) -> bool {
+    #[cfg(feature = "digest-auth")]
+    if req.get_ext::<DigestUser>().is_some() {
+        return true;
+    }
+    req.headers()
+        .contains_key(&HeaderName::new("Authorization".into()))
+        || req.get_ext::<ApiKeyIdentity>().is_some()
+        || req.get_ext::<Claims>().is_some()
+}
+
+/// Handle for removing responses from a [`ResponseCache`], for
+/// example after the data they show has changed. Handles are cheap to
+/// clone and can be kept in shared state.
+#[derive(Clone)]
+pub struct CacheHandle {
+    entries: Entries,
+}
+
+impl CacheHandle {
+    /// Remove every cached response for `path`, regardless of method,
+    /// query, or headers.
+    pub fn invalidate(&self, path: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|key, _| key.path != path);
+    }
+
+    /// Remove every cached response.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
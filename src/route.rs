@@ -0,0 +1,464 @@
+use crate::guard::{self, Guard};
+use crate::health::Health;
+use crate::latency::LatencyRecorder;
+use crate::pattern::Pattern;
+use crate::router::{LinearRouter, Router};
+#[cfg(feature = "checksum")]
+use crate::BodyChecksum;
+#[cfg(feature = "digest-auth")]
+use crate::DigestAuth;
+use crate::{
+    ApiKeyAuth, Handler, Middleware, Next, Request, ResponseCache, Server,
+    TokenAuth,
+};
+use anyhow::{anyhow, Error};
+use fehler::{throw, throws};
+use serde::Serialize;
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub(crate) struct Route<E> {
+    pub(crate) method: String,
+    pub(crate) pattern: String,
+    pub(crate) path: Pattern,
+    pub(crate) name: Option<String>,
+    pub(crate) handler: Box<Handler<E>>,
+    pub(crate) middleware: Vec<Box<Middleware<E>>>,
+    pub(crate) guards: Vec<Box<Guard>>,
+    /// Set with [`RouteHandle::cache`]. It runs after all of the
+    /// route middleware, right before the handler.
+    pub(crate) cache: Option<ResponseCache>,
+    /// Set with [`RouteHandle::timeout`].
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) options: RouteOptions,
+}
+
+/// Server features a route opts out of, set with
+/// [`RouteHandle::no_compress`], [`RouteHandle::no_cache`], and
+/// [`RouteHandle::no_log_body`].
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RouteOptions {
+    pub(crate) no_compress: bool,
+    pub(crate) no_cache: bool,
+    pub(crate) no_log_body: bool,
+}
+
+impl<E> Route<E> {
+    /// Check if every guard accepts the request.
+    fn accepts(&self, req: &Request) -> bool {
+        self.guards.iter().all(|guard| guard(req))
+    }
+
+    fn info(&self) -> RouteInfo {
+        RouteInfo {
+            method: self.method.clone(),
+            pattern: self.pattern.clone(),
+            name: self.name.clone(),
+            middleware: self.middleware.len(),
+            guards: self.guards.len(),
+        }
+    }
+}
+
+/// All routes along with the router used to match them.
+pub(crate) struct RouteTable<E> {
+    /// Routes indexed by ID.
+    pub(crate) routes: Vec<Route<E>>,
+    /// Route IDs grouped by method and pattern. Routes in the same
+    /// group are told apart by their guards. The router maps requests
+    /// to an index in this list.
+    groups: Vec<Vec<usize>>,
+    /// Middleware that runs before every route's own middleware.
+    pub(crate) middleware: Vec<Box<Middleware<E>>>,
+    /// Path of the route listing enabled with
+    /// [`Server::enable_debug_routes`].
+    pub(crate) debug_path: Option<String>,
+    /// Endpoints enabled with [`Server::enable_health`].
+    pub(crate) health: Health,
+    pub(crate) latency: LatencyRecorder,
+    /// Set with [`Server::set_method_override`].
+    pub(crate) method_override: bool,
+    router: Box<dyn Router>,
+}
+
+impl<E> Default for RouteTable<E> {
+    fn default() -> RouteTable<E> {
+        RouteTable {
+            routes: Vec::new(),
+            groups: Vec::new(),
+            middleware: Vec::new(),
+            debug_path: None,
+            health: Health::default(),
+            latency: LatencyRecorder::default(),
+            method_override: false,
+            router: Box::new(LinearRouter::new()),
+        }
+    }
+}
+
+impl<E> RouteTable<E> {
+    /// Parse a route string and add it to the table. Returns the new
+    /// route's ID.
+    #[throws]
+    pub(crate) fn add(
+        &mut self,
+        route: &str,
+        handler: Box<Handler<E>>,
+    ) -> usize {
+        let mut iter = route.split_whitespace();
+        let method = iter.next().ok_or_else(|| anyhow!("missing method"))?;
+        let pattern = iter.next().ok_or_else(|| anyhow!("missing path"))?;
+        let path: Pattern = pattern.parse()?;
+        // Routes are never removed, so the length is a unique ID
+        let id = self.routes.len();
+        let existing = self.groups.iter().position(|group| {
+            let route = &self.routes[group[0]];
+            route.method == method && route.pattern == pattern
+        });
+        match existing {
+            Some(index) => {
+                // The earlier routes must have guards, otherwise this
+                // one could never match
+                let routes = &self.routes;
+                let group = &mut self.groups[index];
+                if group.iter().any(|id| routes[*id].guards.is_empty()) {
+                    throw!(anyhow!(
+                        "route {} {} is already defined without guards",
+                        method,
+                        pattern
+                    ));
+                }
+                group.push(id);
+            }
+            None => {
+                self.router.insert(method, pattern, self.groups.len())?;
+                self.groups.push(vec![id]);
+            }
+        }
+        self.routes.push(Route {
+            method: method.into(),
+            pattern: pattern.into(),
+            path,
+            name: None,
+            handler,
+            middleware: Vec::new(),
+            guards: Vec::new(),
+            cache: None,
+            timeout: None,
+            options: RouteOptions::default(),
+        });
+        id
+    }
+
+    /// Switch to a different router, adding the existing routes to it.
+    #[throws]
+    pub(crate) fn set_router(&mut self, mut router: Box<dyn Router>) {
+        for (index, group) in self.groups.iter().enumerate() {
+            let route = &self.routes[group[0]];
+            router.insert(&route.method, &route.pattern, index)?;
+        }
+        self.router = router;
+    }
+
+    /// Find the route for a request. Routes with the same method and
+    /// pattern are tried in the order they were added, and the first
+    /// one whose guards all accept the request wins. `req.path_params`
+    /// is set before the guards are called.
+    pub(crate) fn lookup(
+        &self,
+        path: &str,
+        req: &mut Request,
+    ) -> Option<&Route<E>> {
+        let found = self.router.lookup(&req.method, path)?;
+        let group = self.groups.get(found.id)?;
+        req.path_params = found.params;
+        group
+            .iter()
+            .map(|id| &self.routes[*id])
+            .find(|route| route.accepts(req))
+    }
+
+    /// Get metadata for all routes in the order they are matched.
+    pub(crate) fn infos(&self) -> Vec<RouteInfo> {
+        let mut routes = self.routes.iter().collect::<Vec<_>>();
+        routes.sort_by(|a, b| a.path.cmp_specificity(&b.path));
+        routes.into_iter().map(Route::info).collect()
+    }
+}
+
+/// Route table shared by the connection threads. It's moved out of
+/// the [`Server`] on launch, after which it can't change, so matching
+/// a request takes no lock.
+pub(crate) type Routes<E> = Arc<RouteTable<E>>;
+
+/// Description of a registered route, returned by [`Server::routes`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct RouteInfo {
+    /// Request method, e.g. `"GET"`.
+    pub method: String,
+
+    /// Path pattern as passed to [`Server::route`], e.g.
+    /// `"/resource/:key"`.
+    pub pattern: String,
+
+    /// Name set with [`RouteHandle::name`].
+    pub name: Option<String>,
+
+    /// Number of middleware added with [`RouteHandle::with`] or
+    /// methods such as [`RouteHandle::api_key_auth`], not counting
+    /// global middleware.
+    pub middleware: usize,
+
+    /// Number of guards added with [`RouteHandle::guard`] or the
+    /// `require_*` methods.
+    pub guards: usize,
+}
+
+/// Handle to a newly added route, returned by [`Server::route`]. Use
+/// it to set additional options on the route.
+pub struct RouteHandle<'a, E: Debug + Display> {
+    pub(crate) server: &'a mut Server<E>,
+    pub(crate) id: usize,
+}
+
+impl<'a, E: Debug + Display> RouteHandle<'a, E> {
+    /// Apply `f` to the route this handle refers to.
+    pub(crate) fn with_route<R>(
+        &mut self,
+        f: impl FnOnce(&mut Route<E>) -> R,
+    ) -> R {
+        let route = self
+            .server
+            .routes
+            .routes
+            .get_mut(self.id)
+            .expect("route handle refers to a missing route");
+        f(route)
+    }
+
+    /// Give the route a name so that links to it can be built with
+    /// [`Server::url_for`] or [`Request::url_for`]. Names must be
+    /// unique.
+    ///
+    /// [`Request::url_for`]: crate::Request::url_for
+    #[throws]
+    pub fn name(mut self, name: &str) -> RouteHandle<'a, E> {
+        if self.server.settings.route_names.contains_key(name) {
+            throw!(anyhow!("duplicate route name {}", name));
+        }
+        let path = self.with_route(|route| {
+            route.name = Some(name.into());
+            route.path.clone()
+        });
+        Arc::make_mut(&mut self.server.settings.route_names)
+            .insert(name.into(), path);
+        self
+    }
+
+    /// Only use this route for requests that `guard` accepts. This
+    /// allows several routes with the same method and path, for
+    /// example to choose a handler based on the `Accept` header. Such
+    /// routes are tried in the order they were added; a route without
+    /// guards can be added last as a fallback. If no route accepts the
+    /// request, it's handled as not found.
+    ///
+    /// All guards added to a route must accept the request.
+    pub fn guard(
+        mut self,
+        guard: impl Fn(&Request) -> bool + Send + Sync + 'static,
+    ) -> RouteHandle<'a, E> {
+        self.with_route(|route| route.guards.push(Box::new(guard)));
+        self
+    }
+
+    /// Only use this route if the request has the header `name` with
+    /// exactly `value`. See [`RouteHandle::guard`].
+    pub fn require_header(self, name: &str, value: &str) -> RouteHandle<'a, E> {
+        self.guard(guard::header(name, value))
+    }
+
+    /// Only use this route if the request's `Content-Type` is
+    /// `media_type`, ignoring parameters such as `charset`. See
+    /// [`RouteHandle::guard`].
+    pub fn require_content_type(self, media_type: &str) -> RouteHandle<'a, E> {
+        self.guard(guard::content_type(media_type))
+    }
+
+    /// Only use this route if the request's `Accept` header lists
+    /// `media_type`. Wildcards such as `*/*` in the header don't count,
+    /// so that a fallback route can handle those requests. See
+    /// [`RouteHandle::guard`].
+    pub fn require_accept(self, media_type: &str) -> RouteHandle<'a, E> {
+        self.guard(guard::accept(media_type))
+    }
+
+    /// Limit how long the handler, including route middleware, may
+    /// run. If it's still running when `timeout` expires, the client
+    /// gets `504 Gateway Timeout`, or has a streaming response cut off,
+    /// and the connection is closed. The handler can't be interrupted,
+    /// so it should use [`Request::remaining_time`] to give up on slow
+    /// work in time.
+    ///
+    /// [`Request::remaining_time`]: crate::Request::remaining_time
+    pub fn timeout(mut self, timeout: Duration) -> RouteHandle<'a, E> {
+        self.with_route(|route| route.timeout = Some(timeout));
+        self
+    }
+
+    /// Never compress this route's responses, even when
+    /// [`Server::set_compression`] is enabled, for example because
+    /// they're already compressed or must go out byte for byte.
+    pub fn no_compress(mut self) -> RouteHandle<'a, E> {
+        self.with_route(|route| route.options.no_compress = true);
+        self
+    }
+
+    /// Keep this route's responses out of every cache: a
+    /// [`ResponseCache`] passes its requests through, and responses
+    /// get `Cache-Control: no-store` unless the handler set
+    /// `Cache-Control` itself.
+    pub fn no_cache(mut self) -> RouteHandle<'a, E> {
+        self.with_route(|route| route.options.no_cache = true);
+        self
+    }
+
+    /// Leave the request and response bodies out of the records
+    /// passed to the [`AuditSink`], for routes that handle secrets
+    /// such as passwords. Their lengths are still recorded.
+    ///
+    /// [`AuditSink`]: crate::AuditSink
+    pub fn no_log_body(mut self) -> RouteHandle<'a, E> {
+        self.with_route(|route| route.options.no_log_body = true);
+        self
+    }
+
+    /// Serve responses for this route from `cache` when possible. The
+    /// cache always runs after the route's middleware, regardless of
+    /// the order they were added in, so authentication and other
+    /// middleware run for every request and only the handler is
+    /// skipped. Setting a cache again replaces the previous one.
+    pub fn cache(mut self, cache: ResponseCache) -> RouteHandle<'a, E> {
+        self.with_route(|route| route.cache = Some(cache));
+        self
+    }
+
+    /// Require an API key for this route. This is added like
+    /// middleware with [`RouteHandle::with`].
+    pub fn api_key_auth(mut self, auth: ApiKeyAuth) -> RouteHandle<'a, E> {
+        self.with_route(|route| {
+            route.middleware.push(Box::new(
+                move |req: &mut Request, next: Next<'_, E>| auth.run(req, next),
+            ))
+        });
+        self
+    }
+
+    /// Check request bodies against their checksums, and add checksums
+    /// to responses if configured. This is added like middleware with
+    /// [`RouteHandle::with`]. Requires the `checksum` feature.
+    #[cfg(feature = "checksum")]
+    pub fn checksum(mut self, checksum: BodyChecksum) -> RouteHandle<'a, E> {
+        self.with_route(|route| {
+            route.middleware.push(Box::new(
+                move |req: &mut Request, next: Next<'_, E>| {
+                    checksum.run(req, next)
+                },
+            ))
+        });
+        self
+    }
+
+    /// Require Digest authentication for this route. This is added
+    /// like middleware with [`RouteHandle::with`].
+    #[cfg(feature = "digest-auth")]
+    pub fn digest_auth(mut self, auth: DigestAuth) -> RouteHandle<'a, E> {
+        self.with_route(|route| {
+            route.middleware.push(Box::new(
+                move |req: &mut Request, next: Next<'_, E>| auth.run(req, next),
+            ))
+        });
+        self
+    }
+
+    /// Require a bearer token accepted by an [`AuthProvider`] for this
+    /// route. This is added like middleware with [`RouteHandle::with`].
+    ///
+    /// [`AuthProvider`]: crate::AuthProvider
+    pub fn token_auth(mut self, auth: TokenAuth) -> RouteHandle<'a, E> {
+        self.with_route(|route| {
+            route.middleware.push(Box::new(
+                move |req: &mut Request, next: Next<'_, E>| auth.run(req, next),
+            ))
+        });
+        self
+    }
+
+    /// Add middleware that runs only for this route, after any global
+    /// middleware. Middleware added to the same route runs in the
+    /// order it was added.
+    pub fn with(
+        mut self,
+        middleware: &'static Middleware<E>,
+    ) -> RouteHandle<'a, E> {
+        self.with_route(|route| route.middleware.push(Box::new(middleware)));
+        self
+    }
+}
+
+/// Set of routes that share a path prefix and middleware, returned by
+/// [`Server::group`].
+///
+/// ```
+/// # use anyhow::Error;
+/// # use shs::{Next, Request, Server};
+/// # fn require_admin(req: &mut Request, next: Next<'_, Error>) -> Result<(), Error> { next.run(req) }
+/// # fn list_users(req: &mut Request) -> Result<(), Error> { Ok(()) }
+/// let mut server = Server::new("127.0.0.1:1234")?;
+/// let mut admin = server.group("/admin").with(&require_admin);
+/// // Matches "/admin/users"
+/// admin.route("GET /users", &list_users)?;
+/// # Ok::<(), Error>(())
+/// ```
+pub struct RouteGroup<'a, E: Debug + Display + 'static> {
+    pub(crate) server: &'a mut Server<E>,
+    pub(crate) prefix: String,
+    pub(crate) middleware: Vec<&'static Middleware<E>>,
+}
+
+impl<'a, E: Debug + Display + 'static> RouteGroup<'a, E> {
+    /// Add middleware to every route subsequently added to the group.
+    pub fn with(mut self, middleware: &'static Middleware<E>) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+
+    /// Add a route as with [`Server::route`], with the group's prefix
+    /// in front of the path. The group's middleware runs before any
+    /// middleware added to the returned handle.
+    #[throws]
+    pub fn route(
+        &mut self,
+        route: &str,
+        handler: &'static Handler<E>,
+    ) -> RouteHandle<'_, E> {
+        let mut iter = route.split_whitespace();
+        let method = iter.next().ok_or_else(|| anyhow!("missing method"))?;
+        let path = iter.next().ok_or_else(|| anyhow!("missing path"))?;
+        let path = if path == "/" && !self.prefix.is_empty() {
+            self.prefix.clone()
+        } else {
+            format!("{}{}", self.prefix, path)
+        };
+        let middleware = &self.middleware;
+        let mut handle = self
+            .server
+            .route(&format!("{} {}", method, path), handler)?;
+        handle.with_route(|route| {
+            for m in middleware {
+                route.middleware.push(Box::new(*m));
+            }
+        });
+        handle
+    }
+}
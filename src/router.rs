@@ -0,0 +1,337 @@
+use crate::pattern::{Pattern, Segment};
+use crate::Path;
+use anyhow::{anyhow, Error};
+use fehler::{throw, throws};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// Strategy for matching requests to routes. Set it with
+/// [`Server::set_router`].
+///
+/// A router only stores route IDs; the server keeps track of the
+/// handlers. Patterns use the syntax described in [`Server::route`],
+/// and when several routes match a request, the router must pick the
+/// most specific one: segments are compared left to right, and static
+/// segments beat parameters, which beat wildcards.
+///
+/// Two routers are provided: [`LinearRouter`], the default, and
+/// [`TrieRouter`].
+///
+/// [`Server::set_router`]: crate::Server::set_router
+/// [`Server::route`]: crate::Server::route
+pub trait Router: Send + Sync {
+    /// Add a route. This fails if the pattern is invalid or if it
+    /// matches exactly the same paths as an existing route for the
+    /// same method.
+    fn insert(
+        &mut self,
+        method: &str,
+        pattern: &str,
+        id: usize,
+    ) -> Result<(), Error>;
+
+    /// Find the route for a request path.
+    fn lookup(&self, method: &str, path: &str) -> Option<RouteMatch>;
+}
+
+/// Successful result of [`Router::lookup`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RouteMatch {
+    /// ID of the matched route, as passed to [`Router::insert`].
+    pub id: usize,
+
+    /// Values of the path parameters, keyed by name without the
+    /// leading `:` or `*`.
+    pub params: HashMap<String, String>,
+}
+
+fn conflict(method: &str, pattern: &str, existing: &str) -> Error {
+    anyhow!(
+        "route {} {} conflicts with {} {}",
+        method,
+        pattern,
+        method,
+        existing
+    )
+}
+
+struct LinearRoute {
+    method: String,
+    pattern: String,
+    path: Pattern,
+    id: usize,
+}
+
+/// Router that checks each route in turn. Routes are kept sorted from
+/// most to least specific, so the first match wins.
+///
+/// This is the default router. It's simple and fast enough for small
+/// route tables; for large ones consider [`TrieRouter`].
+#[derive(Default)]
+pub struct LinearRouter {
+    routes: Vec<LinearRoute>,
+}
+
+impl LinearRouter {
+    /// Create an empty router.
+    pub fn new() -> LinearRouter {
+        LinearRouter::default()
+    }
+}
+
+impl Router for LinearRouter {
+    #[throws]
+    fn insert(&mut self, method: &str, pattern: &str, id: usize) {
+        let path: Pattern = pattern.parse()?;
+        if let Some(existing) = self
+            .routes
+            .iter()
+            .find(|r| r.method == method && r.path.is_ambiguous_with(&path))
+        {
+            throw!(conflict(method, pattern, &existing.pattern));
+        }
+        let index = self.routes.partition_point(|r| {
+            r.path.cmp_specificity(&path) != Ordering::Greater
+        });
+        self.routes.insert(
+            index,
+            LinearRoute {
+                method: method.into(),
+                pattern: pattern.into(),
+                path,
+                id,
+            },
+        );
+    }
+
+    fn lookup(&self, method: &str, path: &str) -> Option<RouteMatch> {
+        let path: Path = path.parse().unwrap();
+        self.routes
+            .iter()
+            .filter(|route| route.method == method)
+            .find_map(|route| {
+                route.path.matches(&path).map(|params| RouteMatch {
+                    id: route.id,
+                    params,
+                })
+            })
+    }
+}
+
+/// Route stored at the end of a path through the trie.
+struct Leaf {
+    id: usize,
+    pattern: String,
+    /// Names of the parameters along the path, in order.
+    names: Vec<String>,
+}
+
+#[derive(Default)]
+struct Node {
+    statics: HashMap<String, Node>,
+    param: Option<Box<Node>>,
+    /// Routes ending in a wildcard here, by method.
+    wildcards: HashMap<String, Leaf>,
+    /// Routes ending here, by method.
+    leaves: HashMap<String, Leaf>,
+}
+
+/// Split off the first segment of a path.
+fn split_segment(path: &str) -> (&str, Option<&str>) {
+    match path.find('/') {
+        Some(index) => (&path[..index], Some(&path[index + 1..])),
+        None => (path, None),
+    }
+}
+
+impl Node {
+    /// Find the most specific route for the rest of the path. `rest` is
+    /// `None` once every segment has been consumed. Parameter values
+    /// are pushed onto `values` along the way.
+    fn find<'a, 'p>(
+        &'a self,
+        method: &str,
+        rest: Option<&'p str>,
+        values: &mut Vec<&'p str>,
+    ) -> Option<&'a Leaf> {
+        let rest = match rest {
+            Some(rest) => rest,
+            None => return self.leaves.get(method),
+        };
+        let (segment, next) = split_segment(rest);
+        if let Some(leaf) = self
+            .statics
+            .get(segment)
+            .and_then(|child| child.find(method, next, values))
+        {
+            return Some(leaf);
+        }
+        if let Some(child) = &self.param {
+            values.push(segment);
+            if let Some(leaf) = child.find(method, next, values) {
+                return Some(leaf);
+            }
+            values.pop();
+        }
+        let leaf = self.wildcards.get(method)?;
+        values.push(rest);
+        Some(leaf)
+    }
+}
+
+/// Router that stores routes in a trie keyed by path segment.
+///
+/// Matching takes time proportional to the length of the path rather
+/// than the number of routes, and a match for a route without
+/// parameters doesn't allocate.
+#[derive(Default)]
+pub struct TrieRouter {
+    root: Node,
+}
+
+impl TrieRouter {
+    /// Create an empty router.
+    pub fn new() -> TrieRouter {
+        TrieRouter::default()
+    }
+}
+
+impl Router for TrieRouter {
+    #[throws]
+    fn insert(&mut self, method: &str, pattern: &str, id: usize) {
+        let path: Pattern = pattern.parse()?;
+        let mut node = &mut self.root;
+        let mut names = Vec::new();
+        let mut wildcard = false;
+        for segment in path.segments {
+            match segment {
+                Segment::Static(s) => {
+                    node = node.statics.entry(s).or_default();
+                }
+                Segment::Param(name) => {
+                    names.push(name);
+                    node = node.param.get_or_insert_with(Default::default);
+                }
+                Segment::Wildcard(name) => {
+                    names.push(name);
+                    wildcard = true;
+                }
+            }
+        }
+        let leaves = if wildcard {
+            &mut node.wildcards
+        } else {
+            &mut node.leaves
+        };
+        if let Some(existing) = leaves.get(method) {
+            throw!(conflict(method, pattern, &existing.pattern));
+        }
+        leaves.insert(
+            method.into(),
+            Leaf {
+                id,
+                pattern: pattern.into(),
+                names,
+            },
+        );
+    }
+
+    fn lookup(&self, method: &str, path: &str) -> Option<RouteMatch> {
+        let mut values = Vec::new();
+        let leaf = self.root.find(method, Some(path), &mut values)?;
+        Some(RouteMatch {
+            id: leaf.id,
+            params: leaf
+                .names
+                .iter()
+                .cloned()
+                .zip(values.into_iter().map(String::from))
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Expected route ID and parameters for a request.
+    type Expected<'a> = Option<(usize, &'a [(&'a str, &'a str)])>;
+
+    /// Check that both routers agree on a set of routes and requests.
+    fn check(routes: &[&str], requests: &[(&str, Expected)]) {
+        let mut linear = LinearRouter::new();
+        let mut trie = TrieRouter::new();
+        for (id, route) in routes.iter().enumerate() {
+            let (method, pattern) = route.split_once(' ').unwrap();
+            linear.insert(method, pattern, id).unwrap();
+            trie.insert(method, pattern, id).unwrap();
+        }
+        for (request, expected) in requests {
+            let (method, path) = request.split_once(' ').unwrap();
+            let expected = expected.map(|(id, params)| RouteMatch {
+                id,
+                params: params
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            });
+            assert_eq!(linear.lookup(method, path), expected, "{}", request);
+            assert_eq!(trie.lookup(method, path), expected, "{}", request);
+        }
+    }
+
+    #[test]
+    fn test_routers() {
+        check(
+            &[
+                "GET /users/:id",
+                "GET /users/me",
+                "POST /users/:name",
+                "GET /static/*path",
+                "GET /static/index.html",
+                "GET /:a/b",
+                "GET /x/:b",
+            ],
+            &[
+                ("GET /users/me", Some((1, &[]))),
+                ("GET /users/bob", Some((0, &[("id", "bob")]))),
+                ("POST /users/bob", Some((2, &[("name", "bob")]))),
+                ("GET /users/bob/extra", None),
+                ("GET /static/a/b.css", Some((3, &[("path", "a/b.css")]))),
+                ("GET /static/index.html", Some((4, &[]))),
+                ("GET /static", None),
+                // The static first segment wins even though the second
+                // segment of the other route would be static
+                ("GET /x/b", Some((6, &[("b", "b")]))),
+                ("GET /y/b", Some((5, &[("a", "y")]))),
+                ("DELETE /users/me", None),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_backtracking() {
+        // The trie has to back out of the static branch to find the
+        // parameter route
+        check(
+            &["GET /a/b/c", "GET /a/:x/d"],
+            &[("GET /a/b/d", Some((1, &[("x", "b")])))],
+        );
+    }
+
+    #[test]
+    fn test_conflicts() {
+        for router in &mut [
+            Box::new(LinearRouter::new()) as Box<dyn Router>,
+            Box::new(TrieRouter::new()),
+        ] {
+            router.insert("GET", "/u/:id", 0).unwrap();
+            assert!(router.insert("GET", "/u/:name", 1).is_err());
+            router.insert("POST", "/u/:name", 1).unwrap();
+            router.insert("GET", "/u/*rest", 2).unwrap();
+            assert!(router.insert("GET", "/u/*other", 3).is_err());
+            assert!(router.insert("GET", "/u/*x/y", 3).is_err());
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! Periodic jobs that run alongside the server, added with
+//! [`Server::schedule`].
+
+use crate::{panic_message, Server};
+use anyhow::Error;
+use log::{error, warn};
+use std::fmt::{Debug, Display};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+type JobFn = dyn Fn() -> Result<(), Error> + Send + Sync;
+
+pub(crate) struct Job {
+    every: Duration,
+    job: Arc<JobFn>,
+}
+
+/// Stop flag shared with the job threads.
+type Stop = Arc<(Mutex<bool>, Condvar)>;
+
+/// The running jobs, started when the server starts. Dropping it tells
+/// the jobs to stop and waits for any run in progress to finish.
+pub(crate) struct Scheduler {
+    stop: Stop,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    pub(crate) fn start(jobs: &[Job]) -> Scheduler {
+        let stop: Stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let threads = jobs
+            .iter()
+            .enumerate()
+            .filter_map(|(index, job)| {
+                let stop = stop.clone();
+                let every = job.every;
+                let f = job.job.clone();
+                thread::Builder::new()
+                    .name(format!("shs-job-{}", index))
+                    .spawn(move || run_job(&stop, every, &*f))
+                    .map_err(|err| {
+                        error!("failed to spawn job thread: {}", err)
+                    })
+                    .ok()
+            })
+            .collect();
+        Scheduler { stop, threads }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        *self.stop.0.lock().unwrap_or_else(|err| err.into_inner()) = true;
+        self.stop.1.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run_job(stop: &Stop, every: Duration, job: &JobFn) {
+    let mut next = Instant::now() + every;
+    loop {
+        let stopped = stop.0.lock().unwrap_or_else(|err| err.into_inner());
+        let timeout = next.saturating_duration_since(Instant::now());
+        let (stopped, _) = stop
+            .1
+            .wait_timeout_while(stopped, timeout, |stopped| {
+                !*stopped && Instant::now() < next
+            })
+            .unwrap_or_else(|err| err.into_inner());
+        if *stopped {
+            return;
+        }
+        drop(stopped);
+
+        let start = Instant::now();
+        match panic::catch_unwind(AssertUnwindSafe(job)) {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => error!("scheduled job failed: {}", err),
+            Err(payload) => {
+                error!("scheduled job panicked: {}", panic_message(&*payload))
+            }
+        }
+        // Runs that were missed while this one ran are skipped rather
+        // than made up
+        next += every;
+        if next < Instant::now() {
+            warn!(
+                "scheduled job took {:?}, longer than its interval",
+                start.elapsed()
+            );
+            next = Instant::now() + every;
+        }
+    }
+}
+
+impl<E: Debug + Display> Server<E> {
+    /// Run `job` every `every` while the server is running, for
+    /// upkeep such as evicting caches, removing expired sessions, or
+    /// flushing metrics. The first run is one interval after the
+    /// server starts. Each job gets its own thread, so a slow job
+    /// doesn't hold up the others or the requests; if a run takes
+    /// longer than the interval, the missed runs are skipped. Errors
+    /// and panics are logged and the job keeps its schedule.
+    ///
+    /// When the server stops, the jobs stop too, after any run in
+    /// progress finishes.
+    pub fn schedule(
+        &mut self,
+        every: Duration,
+        job: impl Fn() -> Result<(), Error> + Send + Sync + 'static,
+    ) {
+        self.jobs.push(Job {
+            every,
+            job: Arc::new(job),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_scheduler() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let jobs = vec![
+            Job {
+                every: Duration::from_millis(10),
+                job: Arc::new(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }),
+            },
+            Job {
+                every: Duration::from_millis(10),
+                job: Arc::new(|| Err(anyhow!("failing job"))),
+            },
+            Job {
+                every: Duration::from_millis(10),
+                job: Arc::new(|| panic!("panicking job")),
+            },
+            // Stopping doesn't wait for the interval to pass
+            Job {
+                every: Duration::from_secs(3600),
+                job: Arc::new(|| Ok(())),
+            },
+        ];
+        let scheduler = Scheduler::start(&jobs);
+        thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        drop(scheduler);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let runs = count.load(Ordering::SeqCst);
+        assert!(runs >= 3, "{}", runs);
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(count.load(Ordering::SeqCst), runs);
+    }
+}
@@ -0,0 +1,101 @@
+/// Set of security-related response headers added to every response.
+///
+/// The default value sets:
+/// - `Strict-Transport-Security: max-age=31536000; includeSubDomains`
+/// - `X-Content-Type-Options: nosniff`
+/// - `X-Frame-Options: DENY`
+/// - `Referrer-Policy: strict-origin-when-cross-origin`
+///
+/// `Content-Security-Policy` is not set by default since a useful
+/// policy depends on the application. Each header can be changed or
+/// removed; passing `None` to a setter removes that header.
+///
+/// The headers are applied before the handler runs, so a handler can
+/// still override any of them with [`Request::set_header`].
+///
+/// Example usage:
+/// ```
+/// use shs::SecurityHeaders;
+///
+/// let headers = SecurityHeaders::default()
+///     .frame_options(Some("SAMEORIGIN"))
+///     .content_security_policy(Some("default-src 'self'"));
+/// ```
+///
+/// [`Request::set_header`]: crate::Request::set_header
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SecurityHeaders {
+    strict_transport_security: Option<String>,
+    content_type_options: Option<String>,
+    frame_options: Option<String>,
+    referrer_policy: Option<String>,
+    content_security_policy: Option<String>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> SecurityHeaders {
+        SecurityHeaders {
+            strict_transport_security: Some(
+                "max-age=31536000; includeSubDomains".into(),
+            ),
+            content_type_options: Some("nosniff".into()),
+            frame_options: Some("DENY".into()),
+            referrer_policy: Some("strict-origin-when-cross-origin".into()),
+            content_security_policy: None,
+        }
+    }
+}
+
+impl SecurityHeaders {
+    /// Set the `Strict-Transport-Security` header.
+    pub fn strict_transport_security(
+        mut self,
+        value: Option<&str>,
+    ) -> SecurityHeaders {
+        self.strict_transport_security = value.map(|v| v.into());
+        self
+    }
+
+    /// Set the `X-Content-Type-Options` header.
+    pub fn content_type_options(
+        mut self,
+        value: Option<&str>,
+    ) -> SecurityHeaders {
+        self.content_type_options = value.map(|v| v.into());
+        self
+    }
+
+    /// Set the `X-Frame-Options` header.
+    pub fn frame_options(mut self, value: Option<&str>) -> SecurityHeaders {
+        self.frame_options = value.map(|v| v.into());
+        self
+    }
+
+    /// Set the `Referrer-Policy` header.
+    pub fn referrer_policy(mut self, value: Option<&str>) -> SecurityHeaders {
+        self.referrer_policy = value.map(|v| v.into());
+        self
+    }
+
+    /// Set the `Content-Security-Policy` header.
+    pub fn content_security_policy(
+        mut self,
+        value: Option<&str>,
+    ) -> SecurityHeaders {
+        self.content_security_policy = value.map(|v| v.into());
+        self
+    }
+
+    /// Iterate over the headers that are set.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        vec![
+            ("Strict-Transport-Security", &self.strict_transport_security),
+            ("X-Content-Type-Options", &self.content_type_options),
+            ("X-Frame-Options", &self.frame_options),
+            ("Referrer-Policy", &self.referrer_policy),
+            ("Content-Security-Policy", &self.content_security_policy),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|v| (name, v)))
+    }
+}
@@ -0,0 +1,193 @@
+//! Running [`tower`] services as handlers with
+//! [`Server::route_service`]. Requires the `tower` feature.
+//!
+//! [`tower`]: https://docs.rs/tower
+
+use crate::{Request, RouteHandle, Server};
+use anyhow::{anyhow, Error};
+use fehler::throws;
+use std::convert::TryFrom;
+use std::fmt::{Debug, Display};
+use std::future::{self, Future};
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use tower_service::Service;
+
+/// Error type of most tower services.
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Wakes a thread parked in [`block_on`].
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run `future` on this thread until it completes. Handlers already
+/// have a thread each, so there's no need for a runtime.
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        // Wakeups that came before parking aren't lost, since unpark
+        // makes the next park return immediately
+        thread::park();
+    }
+}
+
+impl<E: Debug + Display + From<Error> + 'static> Server<E> {
+    /// Add a route handled by a [`tower`] service, so that middleware
+    /// and services written for the tower ecosystem can run here. The
+    /// request is converted to an `http::Request` and the service's
+    /// `http::Response` becomes the response, as with
+    /// [`Request::write_http_response`]. Requires the `tower` feature.
+    ///
+    /// The service runs on the connection's thread: each request gets
+    /// a clone of it, and its futures are polled on that thread until
+    /// they finish. Services that need an async runtime, such as
+    /// tokio's timers or sockets, have to bring their own.
+    ///
+    /// ```
+    /// # use anyhow::Error;
+    /// # use shs::Server;
+    /// use std::convert::Infallible;
+    /// use std::future::{ready, Ready};
+    /// use std::task::{Context, Poll};
+    ///
+    /// #[derive(Clone)]
+    /// struct Hello;
+    ///
+    /// impl tower_service::Service<http::Request<Vec<u8>>> for Hello {
+    ///     type Response = http::Response<Vec<u8>>;
+    ///     type Error = Infallible;
+    ///     type Future = Ready<Result<Self::Response, Infallible>>;
+    ///
+    ///     fn poll_ready(
+    ///         &mut self,
+    ///         _cx: &mut Context,
+    ///     ) -> Poll<Result<(), Infallible>> {
+    ///         Poll::Ready(Ok(()))
+    ///     }
+    ///
+    ///     fn call(&mut self, _: http::Request<Vec<u8>>) -> Self::Future {
+    ///         ready(Ok(http::Response::new(b"hello".to_vec())))
+    ///     }
+    /// }
+    ///
+    /// let mut server = Server::<Error>::new("127.0.0.1:1234")?;
+    /// server.route_service("GET /hello", Hello)?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    ///
+    /// [`tower`]: https://docs.rs/tower
+    #[throws]
+    pub fn route_service<S>(
+        &mut self,
+        route: &str,
+        service: S,
+    ) -> RouteHandle<'_, E>
+    where
+        S: Service<http::Request<Vec<u8>>, Response = http::Response<Vec<u8>>>
+            + Clone
+            + Send
+            + 'static,
+        S::Error: Into<BoxError>,
+    {
+        let service = Mutex::new(service);
+        let handler = move |req: &mut Request| -> Result<(), E> {
+            let http_req = http::Request::try_from(&*req)?;
+            // Clone so the lock isn't held while the service runs
+            let mut service = service
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .clone();
+            let resp = block_on(async {
+                future::poll_fn(|cx| service.poll_ready(cx)).await?;
+                service.call(http_req).await
+            })
+            .map_err(|err| {
+                let err: BoxError = err.into();
+                anyhow!(err)
+            })?;
+            req.write_http_response(resp)?;
+            Ok(())
+        };
+        let id = self.routes.add(route, Box::new(handler))?;
+        RouteHandle { server: self, id }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{StatusCode, TestRequest};
+    use std::pin::Pin;
+
+    /// Future that isn't ready the first time it's polled.
+    struct YieldOnce<T>(Option<T>, bool);
+
+    impl<T: Unpin> Future for YieldOnce<T> {
+        type Output = T;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+            if !self.1 {
+                self.1 = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(self.0.take().unwrap())
+        }
+    }
+
+    /// Echoes the request body, or fails if it's empty.
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Service<http::Request<Vec<u8>>> for Echo {
+        type Response = http::Response<Vec<u8>>;
+        type Error = BoxError;
+        type Future = YieldOnce<Result<Self::Response, BoxError>>;
+
+        fn poll_ready(
+            &mut self,
+            _cx: &mut Context,
+        ) -> Poll<Result<(), BoxError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: http::Request<Vec<u8>>) -> Self::Future {
+            let result = if req.body().is_empty() {
+                Err("empty body".into())
+            } else {
+                Ok(http::Response::builder()
+                    .status(201)
+                    .body(req.into_body())
+                    .unwrap())
+            };
+            YieldOnce(Some(result), false)
+        }
+    }
+
+    #[test]
+    fn test_route_service() {
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        server.route_service("POST /echo", Echo).unwrap();
+
+        let req = TestRequest::new_with_body("POST /echo", b"hello").unwrap();
+        let resp = server.test_request(&req).unwrap();
+        assert_eq!(resp.status, StatusCode::Created);
+        assert_eq!(resp.body, b"hello");
+
+        let req = TestRequest::new("POST /echo").unwrap();
+        let err = server.test_request(&req).unwrap_err();
+        assert!(err.to_string().contains("empty body"), "{}", err);
+    }
+}
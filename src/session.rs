@@ -0,0 +1,463 @@
+use crate::{Cookie, Request, SameSite};
+use anyhow::{anyhow, Context, Error};
+use fehler::{throw, throws};
+use log::error;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write as _};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tempfile::NamedTempFile;
+
+/// Values stored in a session, keyed by name.
+pub type SessionData = HashMap<String, serde_json::Value>;
+
+/// Storage backend for sessions.
+///
+/// The server only stores a random session ID in a cookie; the data
+/// itself lives in the store. [`MemoryStore`] and [`FileStore`] are
+/// provided, and other backends such as Redis or a database can be
+/// added by implementing this trait.
+///
+/// Session IDs are randomly generated by the server and consist of
+/// lowercase hex digits. Stores are shared between handler threads, so
+/// methods take `&self`; use a lock or a connection pool internally as
+/// needed.
+pub trait SessionStore: Send + Sync {
+    /// Load a session's data. Returns `None` if there's no such
+    /// session or it has expired.
+    fn load(&self, id: &str) -> Result<Option<SessionData>, Error>;
+
+    /// Create or replace a session. The session should expire after
+    /// `ttl` unless it's saved again.
+    fn save(
+        &self,
+        id: &str,
+        data: &SessionData,
+        ttl: Duration,
+    ) -> Result<(), Error>;
+
+    /// Delete a session. Deleting a session that doesn't exist is not
+    /// an error.
+    fn destroy(&self, id: &str) -> Result<(), Error>;
+}
+
+/// Session store that keeps sessions in memory. Sessions are lost
+/// when the server restarts.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, (Instant, SessionData)>>,
+}
+
+impl MemoryStore {
+    /// Create an empty store.
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Result<Option<SessionData>, Error> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(sessions
+            .get(id)
+            .filter(|(expires, _)| *expires > Instant::now())
+            .map(|(_, data)| data.clone()))
+    }
+
+    fn save(
+        &self,
+        id: &str,
+        data: &SessionData,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        // Drop expired sessions so the map doesn't grow forever
+        sessions.retain(|_, (expires, _)| *expires > now);
+        sessions.insert(id.into(), (now + ttl, data.clone()));
+        Ok(())
+    }
+
+    fn destroy(&self, id: &str) -> Result<(), Error> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct StoredSession {
+    /// Expiration time in seconds since the Unix epoch.
+    expires: u64,
+    data: SessionData,
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Session store that keeps each session in a JSON file in a
+/// directory. Expired files are removed when they are next loaded.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    /// Create a store that keeps sessions in `dir`, creating it if
+    /// needed.
+    #[throws]
+    pub fn new(dir: impl Into<PathBuf>) -> FileStore {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).with_context(|| {
+            format!("failed to create session directory {}", dir.display())
+        })?;
+        FileStore { dir }
+    }
+
+    #[throws]
+    fn path(&self, id: &str) -> PathBuf {
+        // Don't let a crafted ID escape the directory
+        if id.is_empty() || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+            throw!(anyhow!("invalid session ID"));
+        }
+        self.dir.join(format!("{}.json", id))
+    }
+}
+
+impl SessionStore for FileStore {
+    #[throws]
+    fn load(&self, id: &str) -> Option<SessionData> {
+        // A malformed ID, for example from a forged cookie, can't name
+        // a stored session
+        let path = match self.path(id) {
+            Ok(path) => path,
+            Err(_) => return None,
+        };
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+            Err(err) => throw!(err),
+        };
+        let stored: StoredSession = serde_json::from_slice(&contents)?;
+        if stored.expires <= unix_time(SystemTime::now()) {
+            self.destroy(id)?;
+            return None;
+        }
+        Some(stored.data)
+    }
+
+    #[throws]
+    fn save(&self, id: &str, data: &SessionData, ttl: Duration) {
+        let stored = StoredSession {
+            expires: unix_time(SystemTime::now() + ttl),
+            data: data.clone(),
+        };
+        // Write to a uniquely named temporary file first so that a
+        // concurrent load never sees a partial file, and concurrent
+        // saves of one session don't write to the same file
+        let path = self.path(id)?;
+        let mut tmp = NamedTempFile::new_in(&self.dir)?;
+        tmp.write_all(&serde_json::to_vec(&stored)?)?;
+        tmp.persist(&path)?;
+    }
+
+    #[throws]
+    fn destroy(&self, id: &str) {
+        match fs::remove_file(self.path(id)?) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => throw!(err),
+        }
+    }
+}
+
+/// Session configuration. Set it with [`Server::set_sessions`], then
+/// use [`Request::session`] in handlers.
+///
+/// Example usage:
+/// ```
+/// use shs::{MemoryStore, Sessions};
+/// use std::time::Duration;
+///
+/// let sessions = Sessions::new(MemoryStore::new())
+///     .cookie_name("sid")
+///     .ttl(Duration::from_secs(3600));
+/// ```
+///
+/// [`Server::set_sessions`]: crate::Server::set_sessions
+#[derive(Clone)]
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    cookie_name: String,
+    ttl: Duration,
+    secure: bool,
+}
+
+impl Sessions {
+    /// Create a session configuration using `store`. The default
+    /// cookie name is `shs-session` and sessions expire after a day.
+    pub fn new(store: impl SessionStore + 'static) -> Sessions {
+        Sessions {
+            store: Arc::new(store),
+            cookie_name: "shs-session".into(),
+            ttl: Duration::from_secs(24 * 60 * 60),
+            secure: false,
+        }
+    }
+
+    /// Set the name of the cookie that holds the session ID.
+    pub fn cookie_name(mut self, name: &str) -> Sessions {
+        self.cookie_name = name.into();
+        self
+    }
+
+    /// Set how long a session lasts after it was last modified.
+    pub fn ttl(mut self, ttl: Duration) -> Sessions {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Set the `Secure` attribute on the session cookie. Enable this
+    /// when the server is only reached over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Sessions {
+        self.secure = secure;
+        self
+    }
+
+    fn cookie(&self, value: &str) -> Cookie {
+        Cookie::new(&self.cookie_name, value)
+            .path(Some("/"))
+            .max_age(Some(self.ttl))
+            .secure(self.secure)
+            .same_site(Some(SameSite::Lax))
+    }
+}
+
+/// Generate a random session ID.
+#[throws]
+fn new_session_id() -> String {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|err| anyhow!("failed to generate session ID: {}", err))?;
+    let mut id = String::with_capacity(32);
+    for b in bytes {
+        let _ = write!(id, "{:02x}", b);
+    }
+    id
+}
+
+/// Data for the current client's session, returned by
+/// [`Request::session`]. Changes are saved to the store after the
+/// handler returns.
+#[derive(Debug)]
+pub struct Session {
+    id: String,
+    data: SessionData,
+    modified: bool,
+    destroyed: bool,
+}
+
+impl Session {
+    /// Get the session ID.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Get a value. Returns `None` if the key is missing or the value
+    /// can't be deserialized as `T`.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value = self.data.get(key)?;
+        serde_json::from_value(value.clone()).ok()
+    }
+
+    /// Set a value.
+    #[throws]
+    pub fn insert<T: Serialize>(&mut self, key: &str, value: &T) {
+        self.data.insert(key.into(), serde_json::to_value(value)?);
+        self.modified = true;
+    }
+
+    /// Remove a value.
+    pub fn remove(&mut self, key: &str) {
+        if self.data.remove(key).is_some() {
+            self.modified = true;
+        }
+    }
+
+    /// Delete the session from the store and tell the client to drop
+    /// the cookie, for example when logging out.
+    pub fn destroy(&mut self) {
+        self.data.clear();
+        self.destroyed = true;
+    }
+}
+
+impl Request {
+    /// Get the session for this client, loading it from the store on
+    /// first use. If the client has no valid session, a new empty one
+    /// is started; it's only saved, and the cookie only sent, once a
+    /// value has been set. Fails if sessions weren't enabled with
+    /// [`Server::set_sessions`].
+    ///
+    /// [`Server::set_sessions`]: crate::Server::set_sessions
+    #[throws]
+    pub fn session(&mut self) -> &mut Session {
+        if self.extensions.get::<Session>().is_none() {
+            let sessions = self
+                .state::<Sessions>()
+                .ok_or_else(|| anyhow!("sessions are not enabled"))?;
+            let existing = match self.cookie(&sessions.cookie_name) {
+                Some(id) => {
+                    sessions.store.load(id)?.map(|data| (id.to_string(), data))
+                }
+                None => None,
+            };
+            let session = match existing {
+                Some((id, data)) => Session {
+                    id,
+                    data,
+                    modified: false,
+                    destroyed: false,
+                },
+                // Never reuse an ID chosen by the client, to prevent
+                // session fixation
+                None => Session {
+                    id: new_session_id()?,
+                    data: SessionData::new(),
+                    modified: false,
+                    destroyed: false,
+                },
+            };
+            self.extensions.insert(session);
+        }
+        self.extensions.get_mut::<Session>().unwrap()
+    }
+
+    /// Whether the session was used while handling this request.
+    pub(crate) fn has_session(&self) -> bool {
+        self.extensions.get::<Session>().is_some()
+    }
+
+    /// Save or destroy the session if the handler changed it. Errors
+    /// are logged, since the response has already been produced.
+    pub(crate) fn finish_session(&mut self) {
+        let session = match self.extensions.remove::<Session>() {
+            Some(session) => session,
+            None => return,
+        };
+        let sessions = match self.state::<Sessions>() {
+            Some(sessions) => sessions,
+            None => return,
+        };
+        if session.destroyed {
+            if let Err(err) = sessions.store.destroy(&session.id) {
+                error!("failed to destroy session: {}", err);
+            }
+            if self.cookie(&sessions.cookie_name).is_some() {
+                self.remove_cookie(sessions.cookie(""));
+            }
+        } else if session.modified {
+            match sessions
+                .store
+                .save(&session.id, &session.data, sessions.ttl)
+            {
+                Ok(()) => self.set_cookie(sessions.cookie(&session.id)),
+                Err(err) => error!("failed to save session: {}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Server, StatusCode, TestRequest};
+
+    fn check_store(store: &dyn SessionStore) {
+        let id = new_session_id().unwrap();
+        assert_eq!(id.len(), 32);
+        assert!(store.load(&id).unwrap().is_none());
+
+        let mut data = SessionData::new();
+        data.insert("user".into(), "alice".into());
+        store.save(&id, &data, Duration::from_secs(60)).unwrap();
+        assert_eq!(store.load(&id).unwrap(), Some(data.clone()));
+
+        store.destroy(&id).unwrap();
+        assert!(store.load(&id).unwrap().is_none());
+        store.destroy(&id).unwrap();
+
+        store.save(&id, &data, Duration::from_secs(0)).unwrap();
+        assert!(store.load(&id).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_memory_store() {
+        check_store(&MemoryStore::new());
+    }
+
+    #[test]
+    fn test_file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path().join("sessions")).unwrap();
+        check_store(&store);
+        let ttl = Duration::from_secs(60);
+        assert!(store.load("../etc/passwd").unwrap().is_none());
+        assert!(store
+            .save("../etc/passwd", &SessionData::new(), ttl)
+            .is_err());
+    }
+
+    #[test]
+    fn test_file_store_concurrent_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path()).unwrap();
+        let ttl = Duration::from_secs(60);
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let store = &store;
+                scope.spawn(move || {
+                    let mut data = SessionData::new();
+                    data.insert("i".into(), i.into());
+                    for _ in 0..20 {
+                        store.save("ab", &data, ttl).unwrap();
+                        assert!(store.load("ab").unwrap().is_some());
+                    }
+                });
+            }
+        });
+        // Only the session file is left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_invalid_session_cookie() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        server.set_sessions(Sessions::new(FileStore::new(dir.path()).unwrap()));
+        let handler = |req: &mut Request| -> Result<(), Error> {
+            let user: Option<String> = req.session()?.get("user");
+            assert_eq!(user, None);
+            req.session()?.insert("user", &"alice")?;
+            Ok(())
+        };
+        server.routes.add("GET /", Box::new(handler)).unwrap();
+
+        for id in &["../etc/passwd", "not hex", ""] {
+            let mut req = TestRequest::new("GET /").unwrap();
+            req.cookie("shs-session", id);
+            let resp = server.test_request(&req).unwrap();
+            assert_eq!(resp.status, StatusCode::Ok, "{:?}", id);
+            // A new session is started rather than reusing the ID
+            let cookie = resp.cookie("shs-session").unwrap();
+            assert_ne!(cookie.value(), *id);
+            assert_eq!(cookie.value().len(), 32);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::acceptor::AnyAcceptor;
+use crate::stream::Listener;
+use std::io;
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Shared flag that tells the accept loop to stop.
+///
+/// Setting the flag alone wouldn't interrupt a blocking `accept`, so
+/// triggering it also makes a connection to the listener to wake the
+/// loop up.
+#[derive(Clone, Debug)]
+pub(crate) struct Shutdown {
+    flag: Arc<AtomicBool>,
+    wake: Wake,
+}
+
+/// Where to connect to wake the accept loop.
+#[derive(Clone, Debug)]
+enum Wake {
+    Tcp(SocketAddr),
+    #[cfg(windows)]
+    NamedPipe(String),
+    Acceptor(Arc<dyn AnyAcceptor>),
+}
+
+impl Shutdown {
+    pub(crate) fn new(listener: &Listener) -> io::Result<Shutdown> {
+        let wake = match listener {
+            Listener::Tcp(listener) => Wake::Tcp(wake_addr(listener)?),
+            #[cfg(windows)]
+            Listener::NamedPipe(listener) => {
+                Wake::NamedPipe(listener.name().into())
+            }
+            Listener::Acceptor(acceptor) => Wake::Acceptor(acceptor.clone()),
+        };
+        Ok(Shutdown {
+            flag: Arc::new(AtomicBool::new(false)),
+            wake,
+        })
+    }
+
+    pub(crate) fn trigger(&self) {
+        if !self.flag.swap(true, Ordering::SeqCst) {
+            // If this fails the listener is already gone
+            match &self.wake {
+                Wake::Tcp(addr) => {
+                    let _ = TcpStream::connect(addr);
+                }
+                #[cfg(windows)]
+                Wake::NamedPipe(name) => {
+                    crate::named_pipe::PipeListener::wake(name)
+                }
+                Wake::Acceptor(acceptor) => acceptor.wake(),
+            }
+        }
+    }
+
+    pub(crate) fn is_triggered(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+fn wake_addr(listener: &TcpListener) -> io::Result<SocketAddr> {
+    let mut addr = listener.local_addr()?;
+    // Can't connect to the unspecified address on every platform
+    if addr.ip().is_unspecified() {
+        addr.set_ip(match addr.ip() {
+            IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    Ok(addr)
+}
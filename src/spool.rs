@@ -0,0 +1,205 @@
+//! Request bodies that may be spooled to a temporary file.
+
+use crate::Request;
+use anyhow::Error;
+use fehler::{throw, throws};
+use std::io::{self, Read, Write};
+use tempfile::NamedTempFile;
+
+/// Default for [`Server::set_max_body_size`].
+///
+/// [`Server::set_max_body_size`]: crate::Server::set_max_body_size
+pub(crate) const DEFAULT_MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Request body, held in memory unless it's larger than the threshold
+/// set with [`Server::set_spool_threshold`].
+///
+/// [`Server::set_spool_threshold`]: crate::Server::set_spool_threshold
+#[derive(Debug)]
+pub enum Body {
+    /// The whole body.
+    InMemory(Vec<u8>),
+    /// Temporary file holding the body. It's deleted when dropped
+    /// unless it's persisted with [`NamedTempFile::persist`].
+    File(NamedTempFile),
+}
+
+/// What to do with a `POST`, `PUT`, or `PATCH` request that has
+/// neither `Content-Length` nor `Transfer-Encoding`, set with
+/// [`Server::set_unknown_length_policy`]. By the HTTP/1.1 rules such a
+/// request has no body, but a client that meant to send one will
+/// otherwise see it silently dropped.
+///
+/// [`Server::set_unknown_length_policy`]: crate::Server::set_unknown_length_policy
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum UnknownLengthPolicy {
+    /// Treat the body as empty. This is the default.
+    #[default]
+    Empty,
+    /// Respond with `411 Length Required`.
+    LengthRequired,
+    /// For HTTP/1.0 requests, read the body until the client closes
+    /// its side of the connection, as old clients may expect. Other
+    /// requests are treated as having an empty body.
+    ReadToEof,
+}
+
+impl Default for Body {
+    fn default() -> Body {
+        Body::InMemory(Vec::new())
+    }
+}
+
+impl Body {
+    /// Read `len` bytes from `reader`, spooling them to a temporary
+    /// file if `len` is larger than `threshold`.
+    #[throws(io::Error)]
+    pub(crate) fn read_from(
+        reader: &mut impl Read,
+        len: usize,
+        threshold: Option<usize>,
+    ) -> Body {
+        match threshold {
+            Some(threshold) if len > threshold => {
+                let mut file = NamedTempFile::new()?;
+                let copied = io::copy(&mut reader.take(len as u64), &mut file)?;
+                if copied != len as u64 {
+                    throw!(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "request body is shorter than its Content-Length"
+                    ));
+                }
+                Body::File(file)
+            }
+            _ => {
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body)?;
+                Body::InMemory(body)
+            }
+        }
+    }
+
+    /// Read `reader` to the end, spooling to a temporary file once
+    /// there is more than `threshold`. Returns `None` if there are
+    /// more than `max` bytes; only `max + 1` bytes are read then.
+    #[throws(io::Error)]
+    pub(crate) fn read_to_eof_limited(
+        reader: &mut impl Read,
+        threshold: Option<usize>,
+        max: Option<usize>,
+    ) -> Option<Body> {
+        let max = match max {
+            Some(max) => max as u64,
+            None => return Some(Body::read_to_eof(reader, threshold)?),
+        };
+        let body = Body::read_to_eof(&mut reader.take(max + 1), threshold)?;
+        if body.len()? > max {
+            return None;
+        }
+        Some(body)
+    }
+
+    /// Read `reader` to the end, spooling to a temporary file once
+    /// there is more than `threshold`.
+    #[throws(io::Error)]
+    pub(crate) fn read_to_eof(
+        reader: &mut impl Read,
+        threshold: Option<usize>,
+    ) -> Body {
+        let mut body = Vec::new();
+        let threshold = match threshold {
+            Some(threshold) => threshold,
+            None => {
+                reader.read_to_end(&mut body)?;
+                return Body::InMemory(body);
+            }
+        };
+        reader.take(threshold as u64 + 1).read_to_end(&mut body)?;
+        if body.len() <= threshold {
+            return Body::InMemory(body);
+        }
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&body)?;
+        io::copy(reader, &mut file)?;
+        Body::File(file)
+    }
+
+    /// Get the body if it's in memory.
+    pub fn bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::InMemory(bytes) => Some(bytes),
+            Body::File(_) => None,
+        }
+    }
+
+    /// Get a reader for the body, starting from the beginning.
+    #[throws(io::Error)]
+    pub fn reader(&self) -> Box<dyn Read + '_> {
+        let reader: Box<dyn Read> = match self {
+            Body::InMemory(bytes) => Box::new(&bytes[..]),
+            Body::File(file) => Box::new(file.reopen()?),
+        };
+        reader
+    }
+
+    /// Length of the body in bytes.
+    #[throws(io::Error)]
+    pub fn len(&self) -> u64 {
+        match self {
+            Body::InMemory(bytes) => bytes.len() as u64,
+            Body::File(file) => file.as_file().metadata()?.len(),
+        }
+    }
+
+    /// Whether the body is empty.
+    #[throws(io::Error)]
+    pub fn is_empty(&self) -> bool {
+        self.len()? == 0
+    }
+}
+
+impl Request {
+    /// Get a reader for the request body. Unlike [`Request::body`],
+    /// this works for bodies that were spooled to a file.
+    #[throws]
+    pub fn body_reader(&self) -> Box<dyn Read + '_> {
+        self.req_body.reader()?
+    }
+
+    /// Get the request body, which may be in memory or spooled to a
+    /// file.
+    pub fn spooled_body(&self) -> &Body {
+        &self.req_body
+    }
+
+    /// Take ownership of the request body, leaving it empty. This is
+    /// how to keep a spooled upload, for example by persisting its
+    /// temporary file.
+    pub fn take_body(&mut self) -> Body {
+        std::mem::take(&mut self.req_body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_from() {
+        let body = Body::read_from(&mut &b"hello"[..], 5, Some(5)).unwrap();
+        assert_eq!(body.bytes(), Some(&b"hello"[..]));
+
+        let body = Body::read_from(&mut &b"hello"[..], 5, Some(4)).unwrap();
+        assert!(matches!(body, Body::File(_)));
+        assert_eq!(body.len().unwrap(), 5);
+        let mut contents = String::new();
+        body.reader()
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "hello");
+
+        assert!(Body::read_from(&mut &b"hi"[..], 5, Some(1)).is_err());
+        assert!(Body::read_from(&mut &b"hi"[..], 5, None).is_err());
+    }
+}
@@ -435,8 +435,8 @@ impl StatusCode {
     /// If this returns `true` it indicates that the request was received,
     /// continuing process.
     pub fn is_informational(&self) -> bool {
-        let num: u16 = self.clone().into();
-        num >= 100 && num < 200
+        let num: u16 = (*self).into();
+        (100..200).contains(&num)
     }
 
     /// Returns `true` if the status code is the `2xx` range.
@@ -444,8 +444,8 @@ impl StatusCode {
     /// If this returns `true` it indicates that the request was successfully
     /// received, understood, and accepted.
     pub fn is_success(&self) -> bool {
-        let num: u16 = self.clone().into();
-        num >= 200 && num < 300
+        let num: u16 = (*self).into();
+        (200..300).contains(&num)
     }
 
     /// Returns `true` if the status code is the `3xx` range.
@@ -453,8 +453,8 @@ impl StatusCode {
     /// If this returns `true` it indicates that further action needs to be
     /// taken in order to complete the request.
     pub fn is_redirection(&self) -> bool {
-        let num: u16 = self.clone().into();
-        num >= 300 && num < 400
+        let num: u16 = (*self).into();
+        (300..400).contains(&num)
     }
 
     /// Returns `true` if the status code is the `4xx` range.
@@ -462,8 +462,8 @@ impl StatusCode {
     /// If this returns `true` it indicates that the request contains bad syntax
     /// or cannot be fulfilled.
     pub fn is_client_error(&self) -> bool {
-        let num: u16 = self.clone().into();
-        num >= 400 && num < 500
+        let num: u16 = (*self).into();
+        (400..500).contains(&num)
     }
 
     /// Returns `true` if the status code is the `5xx` range.
@@ -471,8 +471,8 @@ impl StatusCode {
     /// If this returns `true` it indicates that the server failed to fulfill an
     /// apparently valid request.
     pub fn is_server_error(&self) -> bool {
-        let num: u16 = self.clone().into();
-        num >= 500 && num < 600
+        let num: u16 = (*self).into();
+        (500..600).contains(&num)
     }
 
     /// The canonical reason for a given status code
@@ -496,7 +496,7 @@ impl StatusCode {
             StatusCode::MovedPermanently => "Moved Permanently",
             StatusCode::Found => "Found",
             StatusCode::SeeOther => "See Other",
-            StatusCode::NotModified => "Modified",
+            StatusCode::NotModified => "Not Modified",
             StatusCode::TemporaryRedirect => "Temporary Redirect",
             StatusCode::PermanentRedirect => "Permanent Redirect",
             StatusCode::BadRequest => "Bad Request",
@@ -0,0 +1,127 @@
+//! The connections requests arrive on. These are usually TCP
+//! connections, but they can also be named pipes on Windows or come
+//! from an [`Acceptor`]; the rest of the server only sees the
+//! [`Stream`] trait.
+//!
+//! [`Acceptor`]: crate::Acceptor
+
+use crate::acceptor::AnyAcceptor;
+use crate::body::SendFile;
+use crate::connection;
+#[cfg(windows)]
+use crate::named_pipe::PipeListener;
+use std::io::{self, Read};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A connection to a client.
+pub(crate) trait Stream: Read + SendFile + Send {
+    /// Open another handle to the same connection, so the response
+    /// can be written from outside the connection thread, as for
+    /// streaming responses and handler timeouts.
+    fn try_clone(&self) -> io::Result<Connection>;
+
+    /// Limit how long each read waits for data. `None` waits forever.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Limit how long each write waits for the client to make room.
+    /// `None` waits forever.
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Close one or both directions of the connection, through every
+    /// handle to it.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    /// Check without blocking whether the client is still connected.
+    fn is_connected(&self) -> bool;
+
+    /// Close the connection once the response has been written,
+    /// without losing any of the response the client hasn't read yet.
+    fn close(&self);
+
+    /// Whether one handle can wait to read while another writes, as a
+    /// tunnel needs.
+    fn is_full_duplex(&self) -> bool {
+        true
+    }
+
+    /// Get the TCP connection, if that's what this is.
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        None
+    }
+}
+
+pub(crate) type Connection = Box<dyn Stream>;
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Connection> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn is_connected(&self) -> bool {
+        connection::is_connected(self)
+    }
+
+    fn close(&self) {
+        connection::close_gracefully(self)
+    }
+
+    fn as_tcp(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+impl SendFile for Connection {
+    fn send_file(&mut self, file: std::fs::File, len: u64) -> io::Result<()> {
+        (**self).send_file(file, len)
+    }
+}
+
+/// The bound listener a server accepts connections from.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(windows)]
+    NamedPipe(PipeListener),
+    Acceptor(Arc<dyn AnyAcceptor>),
+}
+
+impl Listener {
+    /// Wait for the next connection. TCP connections come with the
+    /// address of the peer.
+    pub(crate) fn accept(
+        &mut self,
+    ) -> io::Result<(Connection, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept()?;
+                Ok((Box::new(stream), Some(peer_addr)))
+            }
+            #[cfg(windows)]
+            Listener::NamedPipe(listener) => Ok((listener.accept()?, None)),
+            Listener::Acceptor(acceptor) => acceptor.accept(),
+        }
+    }
+
+    /// Get the TCP listener, if that's what this is.
+    pub(crate) fn as_tcp(&self) -> Option<&TcpListener> {
+        match self {
+            Listener::Tcp(listener) => Some(listener),
+            #[cfg(windows)]
+            Listener::NamedPipe(_) => None,
+            Listener::Acceptor(_) => None,
+        }
+    }
+}
@@ -0,0 +1,460 @@
+//! A small template engine for [`Request::render`].
+//!
+//! Templates are text with tags in double braces:
+//!
+//! - `{{ name }}` inserts a value from the context, HTML-escaped.
+//!   Nested values are reached with dots, as in `{{ user.name }}`.
+//! - `{{{ name }}}` inserts a value without escaping.
+//! - `{{#if name}} ... {{else}} ... {{/if}}` includes the first part if
+//!   the value is truthy, that is, not `null`, `false`, `0`, or an
+//!   empty string, array, or object. The `{{else}}` part is optional.
+//! - `{{#each name}} ... {{/each}}` repeats its contents for each item
+//!   of an array. Inside, `this` is the current item, `@index` its
+//!   position, and other names are looked up in the item first and
+//!   then in the enclosing context.
+
+use crate::html::escape_html;
+use crate::{mime, Request, Server};
+use anyhow::{anyhow, Context, Error};
+use fehler::{throw, throws};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    Text(String),
+    Var {
+        path: String,
+        escape: bool,
+    },
+    If {
+        path: String,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+    Each {
+        path: String,
+        body: Vec<Node>,
+    },
+}
+
+/// A parsed template.
+#[derive(Debug)]
+pub(crate) struct Template {
+    nodes: Vec<Node>,
+}
+
+/// The block being parsed, and where its nodes go.
+enum Block {
+    Root,
+    If {
+        path: String,
+        then: Option<Vec<Node>>,
+    },
+    Each {
+        path: String,
+    },
+}
+
+impl Template {
+    #[throws]
+    pub(crate) fn parse(source: &str) -> Template {
+        // Each open block, with the nodes collected before it started
+        let mut stack: Vec<(Block, Vec<Node>)> = Vec::new();
+        let mut block = Block::Root;
+        let mut nodes = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                nodes.push(Node::Text(rest[..start].into()));
+            }
+            let (tag, escape, after) = if rest[start..].starts_with("{{{") {
+                let end = rest[start..]
+                    .find("}}}")
+                    .ok_or_else(|| anyhow!("unclosed {{{{{{ tag"))?;
+                (&rest[start + 3..start + end], false, start + end + 3)
+            } else {
+                let end = rest[start..]
+                    .find("}}")
+                    .ok_or_else(|| anyhow!("unclosed {{{{ tag"))?;
+                (&rest[start + 2..start + end], true, start + end + 2)
+            };
+            rest = &rest[after..];
+            let tag = tag.trim();
+
+            if let Some(path) = tag.strip_prefix("#if ") {
+                let outer = std::mem::take(&mut nodes);
+                let open = Block::If {
+                    path: path.trim().into(),
+                    then: None,
+                };
+                stack.push((std::mem::replace(&mut block, open), outer));
+            } else if let Some(path) = tag.strip_prefix("#each ") {
+                let outer = std::mem::take(&mut nodes);
+                let open = Block::Each {
+                    path: path.trim().into(),
+                };
+                stack.push((std::mem::replace(&mut block, open), outer));
+            } else if tag == "else" {
+                match &mut block {
+                    Block::If {
+                        then: then @ None, ..
+                    } => {
+                        *then = Some(std::mem::take(&mut nodes));
+                    }
+                    _ => throw!(anyhow!("{{{{else}}}} outside of #if")),
+                }
+            } else if tag == "/if" || tag == "/each" {
+                let (outer, outer_nodes) = stack
+                    .pop()
+                    .ok_or_else(|| anyhow!("unexpected {{{{{}}}}}", tag))?;
+                let inner = std::mem::take(&mut nodes);
+                let node = match (std::mem::replace(&mut block, outer), tag) {
+                    (Block::If { path, then }, "/if") => match then {
+                        Some(then) => Node::If {
+                            path,
+                            then,
+                            otherwise: inner,
+                        },
+                        None => Node::If {
+                            path,
+                            then: inner,
+                            otherwise: Vec::new(),
+                        },
+                    },
+                    (Block::Each { path }, "/each") => {
+                        Node::Each { path, body: inner }
+                    }
+                    _ => throw!(anyhow!("mismatched {{{{{}}}}}", tag)),
+                };
+                nodes = outer_nodes;
+                nodes.push(node);
+            } else if tag.is_empty() || tag.starts_with(['#', '/']) {
+                throw!(anyhow!("invalid tag {{{{{}}}}}", tag));
+            } else {
+                nodes.push(Node::Var {
+                    path: tag.into(),
+                    escape,
+                });
+            }
+        }
+        if !matches!(block, Block::Root) {
+            throw!(anyhow!("unclosed block"));
+        }
+        if !rest.is_empty() {
+            nodes.push(Node::Text(rest.into()));
+        }
+        Template { nodes }
+    }
+
+    /// Render with `context` as the outermost scope.
+    pub(crate) fn render(&self, context: &Value) -> String {
+        let mut out = String::new();
+        let mut scopes = vec![Scope {
+            value: context,
+            index: None,
+        }];
+        render_nodes(&self.nodes, &mut scopes, &mut out);
+        out
+    }
+}
+
+struct Scope<'v> {
+    value: &'v Value,
+    index: Option<usize>,
+}
+
+/// Look up a dotted path, or `None` if any part of it is missing.
+/// `@index` is handled by the caller since it isn't in the context.
+fn lookup<'v>(scopes: &[Scope<'v>], path: &str) -> Option<&'v Value> {
+    let mut parts = path.split('.');
+    let first = parts.next()?;
+    let mut value = if first == "this" {
+        scopes.last()?.value
+    } else {
+        scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.value.get(first))?
+    };
+    for part in parts {
+        value = match value {
+            Value::Array(items) => items.get(part.parse::<usize>().ok()?)?,
+            _ => value.get(part)?,
+        };
+    }
+    Some(value)
+}
+
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(b) => *b,
+        Value::Number(n) => n.as_f64() != Some(0.0),
+        Value::String(s) => !s.is_empty(),
+        Value::Array(items) => !items.is_empty(),
+        Value::Object(map) => !map.is_empty(),
+    }
+}
+
+fn render_nodes<'v>(
+    nodes: &[Node],
+    scopes: &mut Vec<Scope<'v>>,
+    out: &mut String,
+) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var { path, escape } => {
+                let text = if path == "@index" {
+                    scopes
+                        .last()
+                        .and_then(|scope| scope.index)
+                        .map(|index| index.to_string())
+                        .unwrap_or_default()
+                } else {
+                    match lookup(scopes, path) {
+                        None | Some(Value::Null) => String::new(),
+                        Some(Value::String(s)) => s.clone(),
+                        Some(value) => value.to_string(),
+                    }
+                };
+                if *escape {
+                    out.push_str(&escape_html(&text));
+                } else {
+                    out.push_str(&text);
+                }
+            }
+            Node::If {
+                path,
+                then,
+                otherwise,
+            } => {
+                let truthy = lookup(scopes, path).is_some_and(is_truthy);
+                render_nodes(
+                    if truthy { then } else { otherwise },
+                    scopes,
+                    out,
+                );
+            }
+            Node::Each { path, body } => {
+                let items = match lookup(scopes, path) {
+                    Some(Value::Array(items)) => items,
+                    _ => continue,
+                };
+                for (index, item) in items.iter().enumerate() {
+                    scopes.push(Scope {
+                        value: item,
+                        index: Some(index),
+                    });
+                    render_nodes(body, scopes, out);
+                    scopes.pop();
+                }
+            }
+        }
+    }
+}
+
+/// Templates loaded from a directory, parsed on first use and kept
+/// for the life of the server.
+#[derive(Debug)]
+pub(crate) struct Templates {
+    dir: PathBuf,
+    cache: Mutex<HashMap<String, Arc<Template>>>,
+}
+
+impl Templates {
+    fn new(dir: PathBuf) -> Templates {
+        Templates {
+            dir,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    #[throws]
+    fn get(&self, name: &str) -> Arc<Template> {
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(template) = cache.get(name) {
+            return template.clone();
+        }
+        let relative = Path::new(name);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            throw!(anyhow!("invalid template name {}", name));
+        }
+        let path = self.dir.join(relative);
+        let source = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let template = Arc::new(
+            Template::parse(&source)
+                .with_context(|| format!("failed to parse {}", name))?,
+        );
+        cache.insert(name.into(), template.clone());
+        template
+    }
+}
+
+impl Request {
+    /// Render the template `name` from the directory set with
+    /// [`Server::set_template_dir`] and make it the response body.
+    /// The content type is guessed from the template's extension,
+    /// defaulting to HTML. See the [`template`](crate::template)
+    /// module for the syntax.
+    ///
+    /// ```
+    /// # use anyhow::Error;
+    /// # use fehler::throws;
+    /// # use serde::Serialize;
+    /// # use shs::Request;
+    /// #[derive(Serialize)]
+    /// struct Page {
+    ///     title: String,
+    ///     items: Vec<String>,
+    /// }
+    ///
+    /// #[throws]
+    /// fn index(req: &mut Request) {
+    ///     let page = Page {
+    ///         title: "Shopping".into(),
+    ///         items: vec!["eggs".into(), "milk".into()],
+    ///     };
+    ///     req.render("index.html", &page)?;
+    /// }
+    /// ```
+    #[throws]
+    pub fn render(&mut self, name: &str, context: &impl Serialize) {
+        let templates = self
+            .templates
+            .as_ref()
+            .ok_or_else(|| anyhow!("no template directory set"))?;
+        let template = templates.get(name)?;
+        let output = template.render(&serde_json::to_value(context)?);
+        self.write_bytes(output.as_bytes());
+        match mime::from_path(name) {
+            Some(content_type) => self.set_content_type(content_type),
+            None => self.set_content_type("text/html; charset=UTF-8"),
+        }
+    }
+}
+
+impl<E: Debug + Display> Server<E> {
+    /// Set the directory that [`Request::render`] loads templates from.
+    /// Each template is read and parsed the first time it's rendered,
+    /// so changes to a template after that need a restart.
+    pub fn set_template_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.settings.templates = Some(Arc::new(Templates::new(dir.into())));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn render(source: &str, context: Value) -> String {
+        Template::parse(source).unwrap().render(&context)
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render(
+                "Hi {{ user.name }}{{missing}}, {{ html }} {{{ html }}}",
+                json!({"user": {"name": "Al"}, "html": "<b>"}),
+            ),
+            "Hi Al, &lt;b&gt; <b>"
+        );
+        assert_eq!(
+            render(
+                "{{#if a}}yes{{else}}no{{/if}} {{#if b}}yes{{else}}no{{/if}}",
+                json!({"a": [1], "b": ""}),
+            ),
+            "yes no"
+        );
+        assert_eq!(
+            render(
+                "{{#each items}}{{@index}}:{{ name }}{{ sep }} {{/each}}\
+                 {{#each nums}}[{{ this }}]{{/each}}",
+                json!({
+                    "items": [{"name": "a"}, {"name": "b", "sep": "!"}],
+                    "sep": ",",
+                    "nums": [1, 2],
+                }),
+            ),
+            "0:a, 1:b! [1][2]"
+        );
+        assert_eq!(
+            render(
+                "{{#each rows}}{{#if this.on}}{{ this.id }}{{/if}}{{/each}}",
+                json!({"rows": [{"id": 1, "on": true}, {"id": 2}]}),
+            ),
+            "1"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for source in &[
+            "{{ a",
+            "{{#if a}}",
+            "{{/if}}",
+            "{{#if a}}{{/each}}",
+            "{{else}}",
+            "{{#if a}}{{else}}{{else}}{{/if}}",
+            "{{}}",
+            "{{#unless a}}{{/unless}}",
+        ] {
+            assert!(Template::parse(source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.html"), "<p>{{ x }}</p>").unwrap();
+        let templates = Templates::new(dir.path().into());
+        let template = templates.get("a.html").unwrap();
+        assert_eq!(template.render(&json!({"x": 1})), "<p>1</p>");
+
+        // Cached, so a change on disk isn't picked up
+        fs::write(dir.path().join("a.html"), "changed").unwrap();
+        assert!(Arc::ptr_eq(&template, &templates.get("a.html").unwrap()));
+
+        assert!(templates.get("../a.html").is_err());
+        assert!(templates.get("/etc/passwd").is_err());
+        assert!(templates.get("missing.html").is_err());
+    }
+
+    #[test]
+    fn test_request_render() {
+        use crate::{HeaderName, TestRequest};
+
+        #[throws]
+        fn page(req: &mut Request) {
+            req.render("page.html", &json!({"title": "Hi"}))?;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("page.html"), "<h1>{{ title }}</h1>")
+            .unwrap();
+        let mut server = Server::new("127.0.0.1:1234").unwrap();
+        server.route("GET /page", &page).unwrap();
+        let req = TestRequest::new("GET /page").unwrap();
+        assert!(server.test_request(&req).is_err());
+
+        server.set_template_dir(dir.path());
+        let resp = server.test_request(&req).unwrap();
+        assert_eq!(resp.body, b"<h1>Hi</h1>");
+        assert_eq!(
+            resp.headers[&HeaderName::new("Content-Type".into())],
+            "text/html; charset=UTF-8"
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! Test client that keeps cookies between requests, like a browser.
+
+use crate::{RequestError, Server, TestRequest, TestResponse};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display};
+
+/// Sends test requests with cookies set by earlier responses, so that
+/// tests can go through a flow such as logging in and then using the
+/// session. Create one with [`Server::test_session`].
+///
+/// Cookies are sent with every request regardless of their `Path`,
+/// `Domain`, and `Secure` attributes. A cookie is dropped when a
+/// response removes it or sets it with an expiry in the past.
+///
+/// ```
+/// # use anyhow::Error;
+/// # use fehler::throws;
+/// # use shs::{MemoryStore, Request, Server, Sessions, TestRequest};
+/// #[throws]
+/// fn login(req: &mut Request) {
+///     req.session()?.insert("user", &"alice")?;
+/// }
+///
+/// #[throws]
+/// fn whoami(req: &mut Request) {
+///     let user: Option<String> = req.session()?.get("user");
+///     req.write_text(&user.unwrap_or_default());
+/// }
+///
+/// let mut server = Server::new("127.0.0.1:1234")?;
+/// server.set_sessions(Sessions::new(MemoryStore::new()));
+/// server.route("POST /login", &login)?;
+/// server.route("GET /whoami", &whoami)?;
+///
+/// let mut session = server.test_session();
+/// session.request(&TestRequest::new("POST /login")?)?;
+/// let resp = session.request(&TestRequest::new("GET /whoami")?)?;
+/// assert_eq!(resp.body, b"alice");
+/// # Ok::<(), Error>(())
+/// ```
+pub struct TestSession<'a, E: Debug + Display> {
+    server: &'a Server<E>,
+    cookies: BTreeMap<String, String>,
+}
+
+impl<'a, E: Debug + Display + 'static> TestSession<'a, E> {
+    /// Send `req` with [`Server::test_request`], adding the stored
+    /// cookies, then store the cookies the response sets. A cookie
+    /// already set on `req` is sent instead of the stored one.
+    pub fn request(
+        &mut self,
+        req: &TestRequest,
+    ) -> Result<TestResponse, RequestError<E>> {
+        let mut req = req.clone();
+        for (name, value) in &self.cookies {
+            if req.find_cookie(name).is_none() {
+                req.cookie(name, value);
+            }
+        }
+        let resp = self.server.test_request(&req)?;
+        for cookie in resp.cookies() {
+            if cookie.is_expired() {
+                self.cookies.remove(cookie.name());
+            } else {
+                self.cookies
+                    .insert(cookie.name().into(), cookie.value().into());
+            }
+        }
+        Ok(resp)
+    }
+
+    /// Get the value of a stored cookie.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies.get(name).map(String::as_str)
+    }
+
+    /// Store a cookie, as if a response had set it.
+    pub fn set_cookie(&mut self, name: &str, value: &str) {
+        self.cookies.insert(name.into(), value.into());
+    }
+
+    /// Remove all stored cookies, like a new browser session.
+    pub fn clear_cookies(&mut self) {
+        self.cookies.clear();
+    }
+}
+
+impl<E: Debug + Display + 'static> Server<E> {
+    /// Start a [`TestSession`] with no cookies.
+    pub fn test_session(&self) -> TestSession<'_, E> {
+        TestSession {
+            server: self,
+            cookies: BTreeMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cookie, Request};
+    use anyhow::Error;
+    use fehler::throws;
+
+    #[throws]
+    fn set_cookies(req: &mut Request) {
+        req.set_cookie(Cookie::new("a", "1"));
+        req.set_cookie(Cookie::new("b", "2"));
+    }
+
+    #[throws]
+    fn remove_a(req: &mut Request) {
+        req.remove_cookie(Cookie::new("a", ""));
+    }
+
+    #[throws]
+    fn show(req: &mut Request) {
+        let header = req.headers().get(&"Cookie".into()).cloned();
+        req.write_text(&header.unwrap_or_default());
+    }
+
+    #[test]
+    fn test_session_cookies() {
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        server.route("POST /set", &set_cookies).unwrap();
+        server.route("POST /remove", &remove_a).unwrap();
+        server.route("GET /show", &show).unwrap();
+        let show = TestRequest::new("GET /show").unwrap();
+
+        let mut session = server.test_session();
+        assert_eq!(session.request(&show).unwrap().body, b"");
+        let resp = session
+            .request(&TestRequest::new("POST /set").unwrap())
+            .unwrap();
+        assert_eq!(resp.cookie("a").unwrap().value(), "1");
+        let names: Vec<_> = resp
+            .cookies()
+            .iter()
+            .map(|c| c.name().to_string())
+            .collect();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(session.cookie("a"), Some("1"));
+        assert_eq!(session.request(&show).unwrap().body, b"a=1; b=2");
+
+        // Cookies on the request take precedence
+        let mut req = show.clone();
+        req.cookie("a", "x");
+        assert_eq!(session.request(&req).unwrap().body, b"a=x; b=2");
+
+        session
+            .request(&TestRequest::new("POST /remove").unwrap())
+            .unwrap();
+        assert_eq!(session.cookie("a"), None);
+        assert_eq!(session.request(&show).unwrap().body, b"b=2");
+
+        session.clear_cookies();
+        session.set_cookie("c", "3");
+        assert_eq!(session.request(&show).unwrap().body, b"c=3");
+    }
+}
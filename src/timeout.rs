@@ -0,0 +1,159 @@
+//! Handler timeouts, set per route with [`RouteHandle::timeout`].
+//!
+//! A handler can't be stopped from outside, so when one runs too long
+//! a watchdog thread answers the client with `504 Gateway Timeout`
+//! and shuts the connection down. The handler keeps its thread until
+//! it returns, but any further reads or writes on the connection fail,
+//! and whatever response it produces is discarded.
+//!
+//! [`RouteHandle::timeout`]: crate::RouteHandle::timeout
+
+use crate::stream::Connection;
+use crate::{write_status_only, Request, StatusCode};
+use log::warn;
+use std::net::Shutdown;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    /// The handler is running and nothing has been sent.
+    Running,
+    /// The handler started a streaming response.
+    Streaming,
+    /// The handler returned in time.
+    Finished,
+    /// The timeout fired first.
+    TimedOut,
+}
+
+type Shared = Arc<(Mutex<State>, Condvar)>;
+
+pub(crate) struct Watchdog {
+    shared: Shared,
+    deadline: Instant,
+    /// Whether a watchdog thread is running. If not, the timeout is
+    /// only checked when the handler finishes.
+    watched: bool,
+}
+
+fn lock(shared: &Shared) -> std::sync::MutexGuard<'_, State> {
+    shared.0.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+impl Watchdog {
+    /// Start timing a handler. Without a connection, as in
+    /// [`Server::test_request`], nothing is sent when the timeout
+    /// expires, but [`Watchdog::finish`] still reports it.
+    ///
+    /// [`Server::test_request`]: crate::Server::test_request
+    pub(crate) fn start(
+        timeout: Duration,
+        connection: Option<Connection>,
+        route: String,
+    ) -> Watchdog {
+        let shared: Shared =
+            Arc::new((Mutex::new(State::Running), Condvar::new()));
+        let watched = connection.is_some_and(|connection| {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("shs-watchdog".into())
+                .spawn(move || watch(&shared, timeout, connection, &route))
+                .map_err(|err| {
+                    warn!("failed to spawn watchdog thread: {}", err)
+                })
+                .is_ok()
+        });
+        Watchdog {
+            shared,
+            deadline: Instant::now() + timeout,
+            watched,
+        }
+    }
+
+    /// Note that the handler is starting a streaming response. Returns
+    /// false if it already timed out.
+    pub(crate) fn start_streaming(&self) -> bool {
+        let mut state = lock(&self.shared);
+        if *state == State::Running {
+            *state = State::Streaming;
+        }
+        *state == State::Streaming
+    }
+
+    /// Stop the watchdog once the handler returns. Returns true if the
+    /// handler ran past its timeout.
+    pub(crate) fn finish(self) -> bool {
+        let mut state = lock(&self.shared);
+        let timed_out = if self.watched {
+            *state == State::TimedOut
+        } else {
+            Instant::now() > self.deadline
+        };
+        *state = State::Finished;
+        self.shared.1.notify_one();
+        timed_out
+    }
+}
+
+fn watch(
+    shared: &Shared,
+    timeout: Duration,
+    mut connection: Connection,
+    route: &str,
+) {
+    let state = lock(shared);
+    let (mut state, _) = shared
+        .1
+        .wait_timeout_while(state, timeout, |state| {
+            matches!(state, State::Running | State::Streaming)
+        })
+        .unwrap_or_else(|err| err.into_inner());
+    let streaming = match *state {
+        State::Running => false,
+        State::Streaming => true,
+        State::Finished | State::TimedOut => return,
+    };
+    *state = State::TimedOut;
+    warn!(
+        "handler for {} exceeded its timeout of {:?}",
+        route, timeout
+    );
+    if !streaming {
+        // Best effort; the client may already be gone
+        let _ = write_status_only(&mut connection, StatusCode::GatewayTimeout);
+    }
+    let _ = connection.shutdown(Shutdown::Both);
+}
+
+impl Request {
+    /// Time left before the route's timeout expires, if it has one.
+    /// Pass this on as the timeout for slow calls, such as requests to
+    /// other services, so they give up in time. See
+    /// [`RouteHandle::timeout`].
+    ///
+    /// [`RouteHandle::timeout`]: crate::RouteHandle::timeout
+    pub fn remaining_time(&self) -> Option<Duration> {
+        self.watchdog
+            .as_ref()
+            .map(|w| w.deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finish() {
+        let watchdog =
+            Watchdog::start(Duration::from_secs(10), None, "GET /".into());
+        assert!(watchdog.start_streaming());
+        assert!(!watchdog.finish());
+
+        let watchdog = Watchdog::start(Duration::ZERO, None, "GET /".into());
+        thread::sleep(Duration::from_millis(1));
+        assert!(watchdog.finish());
+    }
+}
@@ -0,0 +1,284 @@
+//! Bearer token authentication backed by a pluggable provider.
+
+use crate::{HeaderName, Next, Request, StatusCode};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// What an [`AuthProvider`] knows about a valid token. Stored in the
+/// request's extensions by [`TokenAuth`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Claims {
+    /// Who the token was issued to.
+    pub subject: String,
+    /// Scopes the token grants.
+    pub scopes: Vec<String>,
+    /// Anything else the provider returned, such as fields of an
+    /// OAuth2 introspection response.
+    pub extra: HashMap<String, serde_json::Value>,
+}
+
+impl Claims {
+    /// Create claims for `subject` with no scopes.
+    pub fn new(subject: &str) -> Claims {
+        Claims {
+            subject: subject.into(),
+            ..Claims::default()
+        }
+    }
+
+    /// Add a scope.
+    pub fn scope(mut self, scope: &str) -> Claims {
+        self.scopes.push(scope.into());
+        self
+    }
+
+    /// Whether the claims include `scope`.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+/// Error returned by [`AuthProvider::validate`].
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    /// The token is unknown, expired, or revoked. The client gets 401.
+    #[error("invalid token: {0}")]
+    InvalidToken(String),
+
+    /// The token couldn't be checked, for example because the
+    /// introspection service is down. The client gets 503.
+    #[error("auth provider unavailable: {0}")]
+    Unavailable(anyhow::Error),
+}
+
+/// Service that checks bearer tokens, for example by OAuth2 token
+/// introspection (RFC 7662), an LDAP lookup, or a call to a custom
+/// auth service. Used by [`TokenAuth`].
+///
+/// Closures taking a token and returning `Result<Claims, AuthError>`
+/// implement this too.
+pub trait AuthProvider: Send + Sync {
+    /// Check `token`, returning its claims if it's valid.
+    fn validate(&self, token: &str) -> Result<Claims, AuthError>;
+}
+
+impl<F> AuthProvider for F
+where
+    F: Fn(&str) -> Result<Claims, AuthError> + Send + Sync,
+{
+    fn validate(&self, token: &str) -> Result<Claims, AuthError> {
+        self(token)
+    }
+}
+
+/// [`AuthProvider`] with a fixed set of tokens held in memory, for
+/// tests and local development.
+///
+/// Example usage:
+/// ```
+/// use shs::{AuthProvider, Claims, MemoryAuthProvider};
+///
+/// let provider = MemoryAuthProvider::new();
+/// provider.insert("token1", Claims::new("alice").scope("read"));
+/// assert_eq!(provider.validate("token1").unwrap().subject, "alice");
+/// assert!(provider.validate("token2").is_err());
+/// ```
+#[derive(Debug, Default)]
+pub struct MemoryAuthProvider {
+    tokens: Mutex<HashMap<String, Claims>>,
+}
+
+impl MemoryAuthProvider {
+    /// Create a provider with no valid tokens.
+    pub fn new() -> MemoryAuthProvider {
+        MemoryAuthProvider::default()
+    }
+
+    /// Make `token` valid with `claims`.
+    pub fn insert(&self, token: &str, claims: Claims) {
+        self.tokens.lock().unwrap().insert(token.into(), claims);
+    }
+
+    /// Revoke `token`.
+    pub fn remove(&self, token: &str) {
+        self.tokens.lock().unwrap().remove(token);
+    }
+}
+
+impl AuthProvider for MemoryAuthProvider {
+    fn validate(&self, token: &str) -> Result<Claims, AuthError> {
+        self.tokens
+            .lock()
+            .unwrap()
+            .get(token)
+            .cloned()
+            .ok_or_else(|| AuthError::InvalidToken("unknown token".into()))
+    }
+}
+
+/// Get the credentials from an `Authorization` value if it uses
+/// `scheme`, compared case-insensitively.
+pub(crate) fn strip_scheme<'a>(
+    value: &'a str,
+    scheme: &str,
+) -> Option<&'a str> {
+    let (name, rest) = value.trim().split_once(' ')?;
+    name.eq_ignore_ascii_case(scheme).then(|| rest.trim_start())
+}
+
+/// Middleware requiring an `Authorization: Bearer` token accepted by
+/// an [`AuthProvider`], added to a route with
+/// [`RouteHandle::token_auth`]. On success the token's [`Claims`] are
+/// stored in the request's extensions.
+///
+/// Missing or invalid tokens get 401 and tokens without a required
+/// scope get 403, with a `WWW-Authenticate` header as described in
+/// RFC 6750. If the provider is unavailable the response is 503.
+///
+/// [`RouteHandle::token_auth`]: crate::RouteHandle::token_auth
+pub struct TokenAuth {
+    provider: Box<dyn AuthProvider>,
+    scopes: Vec<String>,
+}
+
+impl TokenAuth {
+    /// Create token authentication checked by `provider`.
+    pub fn new(provider: impl AuthProvider + 'static) -> TokenAuth {
+        TokenAuth {
+            provider: Box::new(provider),
+            scopes: Vec::new(),
+        }
+    }
+
+    /// Require tokens to have `scope`. Can be called more than once to
+    /// require several scopes.
+    pub fn require_scope(mut self, scope: &str) -> TokenAuth {
+        self.scopes.push(scope.into());
+        self
+    }
+
+    /// Check the request's token, continuing with `next` if it's valid.
+    pub fn run<E>(
+        &self,
+        req: &mut Request,
+        next: Next<'_, E>,
+    ) -> Result<(), E> {
+        let token = req
+            .headers()
+            .get(&HeaderName::new("Authorization".into()))
+            .and_then(|value| strip_scheme(value, "Bearer"))
+            .map(str::to_string);
+        let token = match token {
+            Some(token) if !token.is_empty() => token,
+            _ => {
+                req.set_status(StatusCode::Unauthorized);
+                req.set_header("WWW-Authenticate", "Bearer");
+                return Ok(());
+            }
+        };
+        let claims = match self.provider.validate(&token) {
+            Ok(claims) => claims,
+            Err(AuthError::InvalidToken(reason)) => {
+                log::warn!(
+                    "rejecting token for {}: {}",
+                    req.url().path(),
+                    reason
+                );
+                req.set_status(StatusCode::Unauthorized);
+                req.set_header(
+                    "WWW-Authenticate",
+                    "Bearer error=\"invalid_token\"",
+                );
+                return Ok(());
+            }
+            Err(err @ AuthError::Unavailable(_)) => {
+                log::error!("{}", err);
+                req.set_status(StatusCode::ServiceUnavailable);
+                return Ok(());
+            }
+        };
+        if let Some(missing) =
+            self.scopes.iter().find(|scope| !claims.has_scope(scope))
+        {
+            req.set_status(StatusCode::Forbidden);
+            req.set_header(
+                "WWW-Authenticate",
+                &format!(
+                    "Bearer error=\"insufficient_scope\", scope=\"{}\"",
+                    missing
+                ),
+            );
+            return Ok(());
+        }
+        req.extensions_mut().insert(claims);
+        next.run(req)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Server, TestRequest};
+    use anyhow::{anyhow, Error};
+    use fehler::throws;
+
+    #[test]
+    fn test_strip_scheme() {
+        assert_eq!(strip_scheme("bearer abc", "Bearer"), Some("abc"));
+        assert_eq!(strip_scheme("Basic abc", "Bearer"), None);
+        assert_eq!(strip_scheme("Bearer", "Bearer"), None);
+    }
+
+    #[test]
+    fn test_token_auth() {
+        #[throws]
+        fn whoami(req: &mut Request) {
+            let subject =
+                req.extensions().get::<Claims>().unwrap().subject.clone();
+            req.write_text(&subject);
+        }
+
+        let provider = MemoryAuthProvider::new();
+        provider.insert("t1", Claims::new("alice").scope("read"));
+        provider.insert("t2", Claims::new("bob"));
+        let mut server: Server<Error> = Server::new("127.0.0.1:1234").unwrap();
+        server
+            .route("GET /whoami", &whoami)
+            .unwrap()
+            .token_auth(TokenAuth::new(provider).require_scope("read"));
+        server
+            .route("GET /down", &whoami)
+            .unwrap()
+            .token_auth(TokenAuth::new(
+                |_: &str| -> Result<Claims, AuthError> {
+                    Err(AuthError::Unavailable(anyhow!("connection refused")))
+                },
+            ));
+
+        let get = |path: &str, token: Option<&str>| {
+            let mut req = TestRequest::new(&format!("GET {}", path)).unwrap();
+            if let Some(token) = token {
+                req.set_header("Authorization", &format!("Bearer {}", token));
+            }
+            server.test_request(&req).unwrap()
+        };
+        let www_authenticate = HeaderName::new("WWW-Authenticate".into());
+
+        let resp = get("/whoami", None);
+        assert_eq!(resp.status, StatusCode::Unauthorized);
+        assert_eq!(resp.headers[&www_authenticate], "Bearer");
+        let resp = get("/whoami", Some("nope"));
+        assert_eq!(resp.status, StatusCode::Unauthorized);
+        assert!(resp.headers[&www_authenticate].contains("invalid_token"));
+        let resp = get("/whoami", Some("t2"));
+        assert_eq!(resp.status, StatusCode::Forbidden);
+        assert!(resp.headers[&www_authenticate].contains("insufficient_scope"));
+        let resp = get("/whoami", Some("t1"));
+        assert_eq!(resp.status, StatusCode::Ok);
+        assert_eq!(resp.body, b"alice");
+        assert_eq!(
+            get("/down", Some("t1")).status,
+            StatusCode::ServiceUnavailable
+        );
+    }
+}
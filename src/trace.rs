@@ -0,0 +1,69 @@
+//! Optional integration with the tracing crate. Without the `tracing`
+//! feature all of these are no-ops.
+
+use crate::StatusCode;
+use std::fmt::Display;
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+
+/// Span covering the dispatch of a single request.
+pub(crate) struct RequestSpan {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    #[cfg(feature = "tracing")]
+    start: Instant,
+}
+
+impl RequestSpan {
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn new(method: &str, path: &str) -> RequestSpan {
+        RequestSpan {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!(
+                "request",
+                method = %method,
+                path = %path,
+                status = tracing::field::Empty,
+                duration_ms = tracing::field::Empty,
+            ),
+            #[cfg(feature = "tracing")]
+            start: Instant::now(),
+        }
+    }
+
+    /// Run `f` with the span entered.
+    pub(crate) fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "tracing")]
+        let _guard = self.span.enter();
+        f()
+    }
+
+    /// Record the final status and the elapsed time.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn finish(self, status: StatusCode) {
+        #[cfg(feature = "tracing")]
+        {
+            self.span.record("status", u16::from(status));
+            self.span.record(
+                "duration_ms",
+                self.start.elapsed().as_secs_f64() * 1000.0,
+            );
+        }
+    }
+}
+
+/// Emit an event for an error returned (or a panic raised) by a
+/// handler. Should be called inside the request span.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn handler_error(err: &dyn Display) {
+    #[cfg(feature = "tracing")]
+    tracing::error!(error = %err, "handler error");
+}
+
+/// Emit an event for a connection that failed, for example because
+/// the request could not be parsed.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn connection_error(err: &dyn Display) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!(error = %err, "connection error");
+}
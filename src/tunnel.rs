@@ -0,0 +1,71 @@
+//! `CONNECT` tunnels, so the server can act as a simple forward proxy.
+
+use crate::deadline::DeadlineStream;
+use crate::{write_status_only, Request, StatusCode};
+use anyhow::{anyhow, Error};
+use bufstream::BufStream;
+use fehler::throws;
+use log::warn;
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+
+/// Callback that decides whether a `CONNECT` request may open a
+/// tunnel. It can check the destination with [`Request::url`], and
+/// the client with [`Request::client_ip`] or the request headers.
+///
+/// Example usage:
+/// ```
+/// use shs::Request;
+///
+/// fn only_https(req: &Request) -> bool {
+///     req.url().port() == Some(443)
+/// }
+/// ```
+pub type ConnectFilter = dyn Fn(&Request) -> bool + Send + Sync;
+
+/// Open a tunnel to `authority` if `filter` allows it, then copy
+/// bytes in both directions until both sides are done.
+#[throws]
+pub(crate) fn run(
+    mut client: BufStream<DeadlineStream>,
+    req: &Request,
+    authority: &str,
+    filter: &ConnectFilter,
+) {
+    if !filter(req) {
+        write_status_only(&mut client, StatusCode::Forbidden)?;
+        return;
+    }
+    if !client.get_mut().get_mut().is_full_duplex() {
+        write_status_only(&mut client, StatusCode::NotImplemented)?;
+        return;
+    }
+    let mut upstream = match TcpStream::connect(authority) {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            warn!("failed to connect to {}: {}", authority, err);
+            write_status_only(&mut client, StatusCode::BadGateway)?;
+            return;
+        }
+    };
+    client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")?;
+    client.flush()?;
+
+    let mut to_client = client.get_mut().get_mut().try_clone()?;
+    let mut from_upstream = upstream.try_clone()?;
+    let downstream = thread::spawn(move || {
+        let result = io::copy(&mut from_upstream, &mut to_client);
+        let _ = to_client.shutdown(Shutdown::Write);
+        result
+    });
+    // Read through the buffered stream so bytes the client sent right
+    // after the request head aren't lost
+    let result = io::copy(&mut client, &mut upstream);
+    let _ = upstream.shutdown(Shutdown::Write);
+    let downstream = downstream
+        .join()
+        .map_err(|_| anyhow!("tunnel thread panicked"))?;
+    result?;
+    downstream?;
+}